@@ -1,61 +1,231 @@
-use anyhow::Result;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
-use futures_util::{StreamExt, SinkExt};
+use anyhow::{Result, anyhow, bail};
+use std::{collections::VecDeque, io::{BufRead, Write}, path::Path};
+use tokio_tungstenite::{
+    connect_async_tls_with_config, Connector,
+    tungstenite::{client::IntoClientRequest, http::header::AUTHORIZATION, protocol::Message as WsMessage},
+    MaybeTlsStream, WebSocketStream,
+};
+use futures_util::{stream::{SplitSink, SplitStream}, StreamExt, SinkExt};
 use tracing::{info, warn, error, debug};
-use tokio::sync::mpsc;
+use tokio::{net::TcpStream, sync::mpsc};
 
-use remote_hid_shared::{Message, MessagePayload, MessageType, SessionControlMessage, HidEvent};
+use remote_hid_shared::{Message, MessagePayload, MessageType, SessionControlMessage, HidEvent, AuthMessage, StatusMessage, ClientInfo, ClientType, Credentials, FirstFrame, JoinMode, LoginPrompts, TokenRefresher, client_login};
+use remote_hid_shared::terminal::stdin_lines;
 use crate::input_capture::{InputCapture, InputEvent};
 
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsSender = SplitSink<WsStream, WsMessage>;
+type WsReceiver = SplitStream<WsStream>;
+
 pub struct Commander {
     server_url: String,
     target_client_id: String,
+    credentials: Credentials,
+    tls_connector: Option<Connector>,
+    totp_code: Option<String>,
+    join_mode: JoinMode,
 }
 
 impl Commander {
-    pub fn new(server_url: String, target_client_id: String) -> Result<Self> {
+    /// `ca_bundle` replaces the default web PKI roots for `wss://` servers;
+    /// `client_cert` is a certificate and key presented for mutual TLS
+    pub fn new(
+        server_url: String,
+        target_client_id: String,
+        credentials: Credentials,
+        ca_bundle: Option<&Path>,
+        client_cert: Option<(&Path, &Path)>,
+    ) -> Result<Self> {
+        let tls_connector = remote_hid_shared::tls::connector_config(ca_bundle, client_cert)?.map(Connector::Rustls);
         Ok(Self {
             server_url,
             target_client_id,
+            credentials,
+            tls_connector,
+            totp_code: None,
+            join_mode: JoinMode::Control,
         })
     }
     
-    pub async fn run(&self) -> Result<()> {
+    /// One-time code to answer the server's TOTP prompt with, instead of asking on the terminal
+    pub fn with_totp_code(mut self, code: Option<String>) -> Self {
+        self.totp_code = code;
+        self
+    }
+    
+    /// Control the target, or only watch the session another commander is driving
+    pub fn with_join_mode(mut self, mode: JoinMode) -> Self {
+        self.join_mode = mode;
+        self
+    }
+    
+    /// Connect to the session server and authenticate.
+    /// The returned refresher holds the refresh token issued for a password login.
+    async fn connect(&self) -> Result<(WsSender, WsReceiver, TokenRefresher)> {
         info!("Connecting to session server at {}", self.server_url);
         
-        let (ws_stream, _) = connect_async(&self.server_url).await?;
+        let mut request = self.server_url.as_str().into_client_request()?;
+        if let Credentials::Token(token) = &self.credentials {
+            request.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+        
+        let (ws_stream, _) = connect_async_tls_with_config(request, None, false, self.tls_connector.clone()).await?;
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
         
+        // Authenticate before joining; a token was already sent with the upgrade request
+        let first_frame = match &self.credentials {
+            Credentials::Password { username, password } => Some(FirstFrame::password(username, password, ClientType::Commander, None)),
+            Credentials::Certificate => Some(FirstFrame::new(AuthMessage::CertificateLogin {
+                client_type: ClientType::Commander,
+                client_id: None,
+            })),
+            _ => None,
+        };
+        let refresher = client_login(&mut ws_sender, &mut ws_receiver, first_frame, self).await?;
+        
+        Ok((ws_sender, ws_receiver, refresher))
+    }
+    
+    /// Ask the server which HID clients are connected
+    pub async fn list_clients(&self) -> Result<Vec<ClientInfo>> {
+        let (mut ws_sender, mut ws_receiver, _) = self.connect().await?;
+        
+        let list_clients = Message::session_control(None, SessionControlMessage::ListClients);
+        ws_sender.send(WsMessage::Text(serde_json::to_string(&list_clients)?)).await?;
+        
+        while let Some(msg) = ws_receiver.next().await {
+            if let WsMessage::Text(text) = msg? {
+                match serde_json::from_str::<Message>(&text)?.payload {
+                    MessagePayload::SessionControl(SessionControlMessage::ClientList { clients }) => {
+                        ws_sender.close().await.ok();
+                        return Ok(clients);
+                    }
+                    MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                        bail!("Could not list clients ({}): {}", error_code, error_message);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(anyhow!("Connection closed before the client list arrived"))
+    }
+    
+    /// Revoke a token by JWT ID and/or every token of a user; returns how many
+    /// live connections the server dropped as a result
+    pub async fn revoke(&self, jti: Option<String>, username: Option<String>) -> Result<usize> {
+        let (mut ws_sender, mut ws_receiver, _) = self.connect().await?;
+        
+        let revoke = Message::auth(AuthMessage::Revoke { jti, username });
+        ws_sender.send(WsMessage::Text(serde_json::to_string(&revoke)?)).await?;
+        
+        while let Some(msg) = ws_receiver.next().await {
+            if let WsMessage::Text(text) = msg? {
+                match serde_json::from_str::<Message>(&text)?.payload {
+                    MessagePayload::Auth(AuthMessage::Revoked { disconnected }) => {
+                        ws_sender.close().await.ok();
+                        return Ok(disconnected);
+                    }
+                    MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                        bail!("Could not revoke ({}): {}", error_code, error_message);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(anyhow!("Connection closed before the revocation was confirmed"))
+    }
+    
+    pub async fn run(&self) -> Result<()> {
+        let (mut ws_sender, mut ws_receiver, mut refresher) = self.connect().await?;
+        
         // Send initial join session message
         let join_session = Message::session_control(
             None,
             SessionControlMessage::JoinSession {
                 target_client_id: self.target_client_id.clone(),
+                mode: self.join_mode,
             },
         );
         
         let msg_json = serde_json::to_string(&join_session)?;
         ws_sender.send(WsMessage::Text(msg_json)).await?;
         
-        info!("Joined session for HID client: {}", self.target_client_id);
+        // HID events are only accepted when they carry the session id the server assigned.
+        // A busy client puts us in line until the session is handed to us, and a
+        // client whose user approves commanders holds the session until they do.
+        let session_id = loop {
+            let text = tokio::select! {
+                msg = ws_receiver.next() => match msg {
+                    Some(Ok(WsMessage::Text(text))) => text,
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                        return Err(anyhow!("Connection closed while joining session"));
+                    }
+                    Some(Ok(_)) => continue,
+                },
+                _ = refresher.refresh_due() => {
+                    if let Some(refresh_token) = refresher.take() {
+                        let refresh = Message::auth(AuthMessage::Refresh { refresh_token });
+                        ws_sender.send(WsMessage::Text(serde_json::to_string(&refresh)?)).await?;
+                    }
+                    continue;
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Giving up on joining");
+                    let leave = Message::session_control(None, SessionControlMessage::LeaveQueue);
+                    ws_sender.send(WsMessage::Text(serde_json::to_string(&leave)?)).await?;
+                    return Ok(());
+                }
+            };
+            let message = serde_json::from_str::<Message>(&text)?;
+            match message.payload {
+                MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id, .. }) => break session_id,
+                MessagePayload::SessionControl(SessionControlMessage::QueuePosition { target_client_id, position }) => {
+                    info!("{} is busy; you are number {} in line (Ctrl+C to leave)", target_client_id, position);
+                }
+                MessagePayload::SessionControl(SessionControlMessage::AwaitingConsent { target_client_id, timeout_secs }) => {
+                    info!("Waiting up to {}s for the user at {} to approve (Ctrl+C to give up)", timeout_secs, target_client_id);
+                }
+                MessagePayload::SessionControl(SessionControlMessage::SessionEnded { reason }) => {
+                    bail!("Stopped waiting to join: {}", reason);
+                }
+                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                    let reply = Message::status(message.session_id, StatusMessage::Heartbeat);
+                    ws_sender.send(WsMessage::Text(serde_json::to_string(&reply)?)).await?;
+                }
+                MessagePayload::Auth(AuthMessage::Response { success: true, expires_at, refresh_token, .. }) => {
+                    refresher.update(refresh_token, expires_at);
+                }
+                MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                    bail!("Could not join session ({}): {}", error_code, error_message);
+                }
+                _ => bail!("Unexpected response to join request"),
+            }
+        };
         
-        // Start input capture
-        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
-        let mut input_capture = InputCapture::new(input_tx)?;
+        info!("Joined session {} for HID client: {}", session_id, self.target_client_id);
         
-        let _input_handle = tokio::spawn(async move {
-            if let Err(e) = input_capture.start().await {
-                error!("Input capture error: {}", e);
-            }
-        });
+        // Input is only captured once this commander is in control
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+        let (typed_tx, typed_rx) = mpsc::unbounded_channel();
+        let mut capture = Some((input_tx, typed_rx));
+        let mut handoff = Handoff::new(self.join_mode == JoinMode::Control);
+        if handoff.in_control {
+            start_input_capture(&mut capture)?;
+        }
+        handoff.print_hint();
+        // The only reader of stdin: each line either answers the handoff prompt or is typed remotely
+        let mut terminal = stdin_lines();
         
         // Main event loop
         loop {
             tokio::select! {
                 // Handle input events from local capture
                 Some(input_event) = input_rx.recv() => {
+                    if !handoff.in_control {
+                        continue;
+                    }
                     if let Some(hid_event) = self.convert_input_to_hid(input_event) {
-                        let message = Message::hid_event(uuid::Uuid::new_v4(), hid_event);
+                        let message = Message::hid_event(session_id, hid_event);
                         let msg_json = serde_json::to_string(&message)?;
                         
                         if let Err(e) = ws_sender.send(WsMessage::Text(msg_json)).await {
@@ -65,12 +235,78 @@ impl Commander {
                     }
                 }
                 
+                // Answer requests for control, or ask for it, from the terminal;
+                // otherwise the line is typed on the remote machine
+                Some(line) = terminal.recv() => {
+                    if !handoff.prompting() {
+                        let _ = typed_tx.send(line);
+                        continue;
+                    }
+                    if let Some(control) = handoff.answer(&line) {
+                        let message = Message::session_control(Some(session_id), control);
+                        ws_sender.send(WsMessage::Text(serde_json::to_string(&message)?)).await?;
+                    }
+                    handoff.print_hint();
+                }
+                
+                // Renew the access token before the server drops us for letting it expire
+                _ = refresher.refresh_due() => {
+                    if let Some(refresh_token) = refresher.take() {
+                        debug!("Refreshing access token");
+                        let refresh = Message::auth(AuthMessage::Refresh { refresh_token });
+                        ws_sender.send(WsMessage::Text(serde_json::to_string(&refresh)?)).await?;
+                    }
+                }
+                
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Logging out");
+                    let logout = Message::auth(AuthMessage::Logout);
+                    ws_sender.send(WsMessage::Text(serde_json::to_string(&logout)?)).await?;
+                    break;
+                }
+                
                 // Handle messages from server
                 msg = ws_receiver.next() => {
                     match msg {
                         Some(Ok(WsMessage::Text(text))) => {
                             if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                                self.handle_server_message(message).await?;
+                                match message.payload {
+                                    // Answer heartbeats right away so the server can measure latency
+                                    MessagePayload::Status(StatusMessage::Heartbeat) => {
+                                        let reply = Message::status(message.session_id, StatusMessage::Heartbeat);
+                                        ws_sender.send(WsMessage::Text(serde_json::to_string(&reply)?)).await?;
+                                    }
+                                    MessagePayload::Auth(AuthMessage::Response { success: true, expires_at, refresh_token, .. }) => {
+                                        debug!("Access token refreshed (expires {:?})", expires_at);
+                                        refresher.update(refresh_token, expires_at);
+                                    }
+                                    MessagePayload::Auth(AuthMessage::Response { error_message, .. }) => {
+                                        warn!("Session server rejected token: {}", error_message.unwrap_or_default());
+                                    }
+                                    MessagePayload::SessionControl(SessionControlMessage::ControlRequested { participant_id, username }) => {
+                                        handoff.requests.push_back((participant_id, username));
+                                        handoff.print_hint();
+                                    }
+                                    MessagePayload::SessionControl(SessionControlMessage::AwaitingConsent { target_client_id, timeout_secs }) => {
+                                        info!("Waiting up to {}s for the user at {} to approve handing you control", timeout_secs, target_client_id);
+                                    }
+                                    MessagePayload::SessionControl(SessionControlMessage::ControlDenied { reason }) => {
+                                        warn!("Control not granted: {}", reason);
+                                    }
+                                    MessagePayload::SessionControl(SessionControlMessage::ControlChanged { controller, in_control }) => {
+                                        info!("{} is now in control", if in_control { "You are" } else { controller.as_str() });
+                                        handoff.control_changed(in_control);
+                                        if in_control {
+                                            start_input_capture(&mut capture)?;
+                                        }
+                                        handoff.print_hint();
+                                    }
+                                    _ => {
+                                        if !self.handle_server_message(message).await? {
+                                            break;
+                                        }
+                                    }
+                                }
                             }
                         }
                         Some(Ok(WsMessage::Close(_))) => {
@@ -91,27 +327,39 @@ impl Commander {
         Ok(())
     }
     
-    async fn handle_server_message(&self, message: Message) -> Result<()> {
+    /// Handle a message from the server; returns `false` once the session is over
+    async fn handle_server_message(&self, message: Message) -> Result<bool> {
         match message.message_type {
             MessageType::SessionControl => {
-                if let MessagePayload::SessionControl(control) = message.payload {
-                    match control {
-                        SessionControlMessage::SessionEnded { reason } => {
-                            info!("Session ended: {}", reason);
-                        }
-                        _ => {}
-                    }
+                if let MessagePayload::SessionControl(SessionControlMessage::SessionEnded { reason }) = message.payload {
+                    info!("Session ended: {}", reason);
+                    return Ok(false);
                 }
             }
-            MessageType::Status => {
-                debug!("Received status message from server");
+            // Mirrored from the controlling commander while observing
+            MessageType::HidEvent => {
+                if let MessagePayload::HidEvent(event) = message.payload {
+                    info!("Observed: {:?}", event);
+                }
             }
+            MessageType::Status => match message.payload {
+                MessagePayload::Status(StatusMessage::ConnectionStatus { connected: true, latency_ms }) => {
+                    info!("HID client link: {}", latency_ms.map_or("up".to_string(), |ms| format!("{} ms", ms)));
+                }
+                MessagePayload::Status(StatusMessage::ConnectionStatus { connected: false, .. }) => {
+                    warn!("HID client link lost");
+                }
+                MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                    warn!("Server error ({}): {}", error_code, error_message);
+                }
+                _ => debug!("Received status message from server"),
+            },
             _ => {
                 debug!("Ignoring server message type: {:?}", message.message_type);
             }
         }
         
-        Ok(())
+        Ok(true)
     }
     
     fn convert_input_to_hid(&self, input: InputEvent) -> Option<HidEvent> {
@@ -130,4 +378,100 @@ impl Commander {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Where this commander stands in handing control of the session back and forth
+pub(crate) struct Handoff {
+    /// Started the session, so it can take control back at any time
+    owner: bool,
+    pub(crate) in_control: bool,
+    /// Requests for control waiting for an answer, oldest first: (participant id, username)
+    pub(crate) requests: VecDeque<(String, String)>,
+}
+
+impl Handoff {
+    pub(crate) fn new(owner: bool) -> Self {
+        Self {
+            owner,
+            in_control: owner,
+            requests: VecDeque::new(),
+        }
+    }
+    
+    pub(crate) fn control_changed(&mut self, in_control: bool) {
+        self.in_control = in_control;
+        // Those requests went to this commander as the one in control
+        if !in_control {
+            self.requests.clear();
+        }
+    }
+    
+    /// Whether lines typed on the terminal are for the handoff prompt: while a
+    /// request for control waits for an answer, or to ask for control. Otherwise
+    /// they are input for the remote machine.
+    pub(crate) fn prompting(&self) -> bool {
+        !self.requests.is_empty() || !self.in_control
+    }
+    
+    /// What a line typed on the terminal asks the server for: an answer to the
+    /// oldest request for control, otherwise control itself
+    pub(crate) fn answer(&mut self, line: &str) -> Option<SessionControlMessage> {
+        if let Some((participant_id, username)) = self.requests.pop_front() {
+            let grant = matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes");
+            info!("{} control to {}", if grant { "Granting" } else { "Denying" }, username);
+            return Some(if grant {
+                SessionControlMessage::GrantControl { participant_id }
+            } else {
+                SessionControlMessage::DenyControl { participant_id }
+            });
+        }
+        match (self.in_control, self.owner) {
+            (true, _) => None,
+            (false, true) => Some(SessionControlMessage::RevokeControl),
+            (false, false) => Some(SessionControlMessage::RequestControl),
+        }
+    }
+    
+    fn print_hint(&self) {
+        if let Some((_, username)) = self.requests.front() {
+            eprintln!("{} asks for control; grant it? [y/N]", username);
+        } else if !self.in_control {
+            eprintln!("Press Enter to {}", if self.owner { "take control back" } else { "ask for control" });
+        }
+    }
+}
+
+/// Start capturing local input the first time this commander gets control
+fn start_input_capture(capture: &mut Option<(mpsc::UnboundedSender<InputEvent>, mpsc::UnboundedReceiver<String>)>) -> Result<()> {
+    let Some((input_tx, typed_rx)) = capture.take() else {
+        return Ok(());
+    };
+    let mut input_capture = InputCapture::new(input_tx, typed_rx)?;
+    tokio::spawn(async move {
+        if let Err(e) = input_capture.start().await {
+            error!("Input capture error: {}", e);
+        }
+    });
+    Ok(())
+}
+
+impl LoginPrompts for Commander {
+    async fn totp_code(&self) -> Result<String> {
+        match &self.totp_code {
+            Some(code) => Ok(code.clone()),
+            None => prompt_totp_code().await,
+        }
+    }
+}
+
+/// Ask on the terminal for the code from the user's authenticator app
+async fn prompt_totp_code() -> Result<String> {
+    tokio::task::spawn_blocking(|| {
+        eprint!("One-time code: ");
+        std::io::stderr().flush().ok();
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        Ok(line.trim().to_string())
+    })
+    .await?
+}
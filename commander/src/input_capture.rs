@@ -1,4 +1,5 @@
 use anyhow::{Result, anyhow};
+use std::io::BufRead;
 use tokio::sync::mpsc;
 use remote_hid_shared::{MouseButton, KeyCode, KeyModifiers};
 use tracing::{debug, warn, error, info};
@@ -20,13 +21,16 @@ pub struct InputCapture {
 }
 
 impl InputCapture {
-    pub fn new(sender: mpsc::UnboundedSender<InputEvent>) -> Result<Self> {
+    /// `typed` carries the terminal lines meant for the remote machine, from [`stdin_lines`](remote_hid_shared::terminal::stdin_lines)
+    pub fn new(sender: mpsc::UnboundedSender<InputEvent>, typed: mpsc::UnboundedReceiver<String>) -> Result<Self> {
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        drop(typed);
         Ok(Self {
             sender: sender.clone(),
             #[cfg(target_os = "macos")]
-            inner: MacOSInputCapture::new(sender)?,
+            inner: MacOSInputCapture::new(sender, typed)?,
             #[cfg(target_os = "windows")]
-            inner: WindowsInputCapture::new(sender)?,
+            inner: WindowsInputCapture::new(sender, typed)?,
         })
     }
     
@@ -47,12 +51,13 @@ impl InputCapture {
 #[cfg(target_os = "macos")]
 struct MacOSInputCapture {
     sender: mpsc::UnboundedSender<InputEvent>,
+    typed: Option<mpsc::UnboundedReceiver<String>>,
 }
 
 #[cfg(target_os = "macos")]
 impl MacOSInputCapture {
-    fn new(sender: mpsc::UnboundedSender<InputEvent>) -> Result<Self> {
-        Ok(Self { sender })
+    fn new(sender: mpsc::UnboundedSender<InputEvent>, typed: mpsc::UnboundedReceiver<String>) -> Result<Self> {
+        Ok(Self { sender, typed: Some(typed) })
     }
     
     async fn start(&mut self) -> Result<()> {
@@ -86,78 +91,42 @@ impl MacOSInputCapture {
             }
         });
         
-        // For demo purposes, also capture some keyboard input from stdin
+        // For demo purposes, also capture the lines typed on the terminal
         self.capture_keyboard_input().await
     }
     
-    async fn capture_keyboard_input(&self) -> Result<()> {
-        use std::io::{self, BufRead};
-        
+    async fn capture_keyboard_input(&mut self) -> Result<()> {
         println!("Type characters and press Enter to send them (simplified input capture):");
         
-        let sender = self.sender.clone();
-        tokio::task::spawn_blocking(move || {
-            let stdin = io::stdin();
-            for line in stdin.lock().lines() {
-                if let Ok(text) = line {
-                    for ch in text.chars() {
-                        if let Some(key_code) = char_to_keycode(ch) {
-                            // Send key down
-                            let event = InputEvent::KeyEvent {
-                                key: key_code,
-                                pressed: true,
-                                modifiers: KeyModifiers::default(),
-                            };
-                            if sender.send(event).is_err() {
-                                return;
-                            }
-                            
-                            // Send key up
-                            let event = InputEvent::KeyEvent {
-                                key: key_code,
-                                pressed: false,
-                                modifiers: KeyModifiers::default(),
-                            };
-                            if sender.send(event).is_err() {
-                                return;
-                            }
-                        }
+        let Some(mut typed) = self.typed.take() else {
+            return Ok(());
+        };
+        while let Some(text) = typed.recv().await {
+            for ch in text.chars() {
+                if let Some(key_code) = char_to_keycode(ch) {
+                    if !send_key_stroke(&self.sender, key_code) {
+                        return Ok(());
                     }
-                    
-                    // Send Enter
-                    let event = InputEvent::KeyEvent {
-                        key: KeyCode::Enter,
-                        pressed: true,
-                        modifiers: KeyModifiers::default(),
-                    };
-                    sender.send(event).ok();
-                    
-                    let event = InputEvent::KeyEvent {
-                        key: KeyCode::Enter,
-                        pressed: false,
-                        modifiers: KeyModifiers::default(),
-                    };
-                    sender.send(event).ok();
                 }
             }
-        });
-        
-        // Keep the function running
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            
+            // Send Enter
+            send_key_stroke(&self.sender, KeyCode::Enter);
         }
+        Ok(())
     }
 }
 
 #[cfg(target_os = "windows")]
 struct WindowsInputCapture {
     sender: mpsc::UnboundedSender<InputEvent>,
+    typed: Option<mpsc::UnboundedReceiver<String>>,
 }
 
 #[cfg(target_os = "windows")]
 impl WindowsInputCapture {
-    fn new(sender: mpsc::UnboundedSender<InputEvent>) -> Result<Self> {
-        Ok(Self { sender })
+    fn new(sender: mpsc::UnboundedSender<InputEvent>, typed: mpsc::UnboundedReceiver<String>) -> Result<Self> {
+        Ok(Self { sender, typed: Some(typed) })
     }
     
     async fn start(&mut self) -> Result<()> {
@@ -173,50 +142,33 @@ impl WindowsInputCapture {
         self.capture_keyboard_input().await
     }
     
-    async fn capture_keyboard_input(&self) -> Result<()> {
-        use std::io::{self, BufRead};
-        
+    async fn capture_keyboard_input(&mut self) -> Result<()> {
         println!("Type characters and press Enter to send them (simplified input capture):");
         
-        let sender = self.sender.clone();
-        tokio::task::spawn_blocking(move || {
-            let stdin = io::stdin();
-            for line in stdin.lock().lines() {
-                if let Ok(text) = line {
-                    for ch in text.chars() {
-                        if let Some(key_code) = char_to_keycode(ch) {
-                            // Send key down
-                            let event = InputEvent::KeyEvent {
-                                key: key_code,
-                                pressed: true,
-                                modifiers: KeyModifiers::default(),
-                            };
-                            if sender.send(event).is_err() {
-                                return;
-                            }
-                            
-                            // Send key up
-                            let event = InputEvent::KeyEvent {
-                                key: key_code,
-                                pressed: false,
-                                modifiers: KeyModifiers::default(),
-                            };
-                            if sender.send(event).is_err() {
-                                return;
-                            }
-                        }
+        let Some(mut typed) = self.typed.take() else {
+            return Ok(());
+        };
+        while let Some(text) = typed.recv().await {
+            for ch in text.chars() {
+                if let Some(key_code) = char_to_keycode(ch) {
+                    if !send_key_stroke(&self.sender, key_code) {
+                        return Ok(());
                     }
                 }
             }
-        });
-        
-        // Keep the function running
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         }
+        Ok(())
     }
 }
 
+/// Send a key down and up; `false` once nobody is listening
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn send_key_stroke(sender: &mpsc::UnboundedSender<InputEvent>, key: KeyCode) -> bool {
+    [true, false].into_iter().all(|pressed| {
+        sender.send(InputEvent::KeyEvent { key, pressed, modifiers: KeyModifiers::default() }).is_ok()
+    })
+}
+
 pub fn char_to_keycode(ch: char) -> Option<KeyCode> {
     match ch.to_ascii_uppercase() {
         'A' => Some(KeyCode::A),
@@ -1,14 +1,19 @@
 use anyhow::Result;
 use clap::Parser;
+use std::path::PathBuf;
 use tracing::{info, error};
 
 mod client;
+// Input capture is only implemented for macOS and Windows
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code, unused_imports))]
 mod input_capture;
 
 #[cfg(test)]
+#[allow(clippy::module_inception, unused_imports, unused_variables)]
 mod tests;
 
 use client::Commander;
+use remote_hid_shared::{ClientInfo, Credentials, JoinMode};
 
 #[derive(Parser, Debug)]
 #[command(name = "commander")]
@@ -20,8 +25,53 @@ struct Args {
     server: String,
     
     /// Target HID client ID to control
+    #[arg(short, long, required_unless_present_any = ["list", "revoke_jti", "revoke_user"])]
+    target: Option<String>,
+    
+    /// Watch the session another commander is driving on the target instead of controlling it
+    #[arg(long, requires = "target")]
+    observe: bool,
+    
+    /// List the HID clients connected to the server and exit
     #[arg(short, long)]
-    target: String,
+    list: bool,
+    
+    /// Revoke the token with this JWT ID and exit (admin only)
+    #[arg(long)]
+    revoke_jti: Option<String>,
+    
+    /// Revoke every token issued to this user so far and exit (admin only)
+    #[arg(long)]
+    revoke_user: Option<String>,
+    
+    /// Username for authenticating with the session server
+    #[arg(short, long, required_unless_present_any = ["token", "cert"], requires = "password")]
+    username: Option<String>,
+    
+    /// Password for authenticating with the session server
+    #[arg(long, requires = "username")]
+    password: Option<String>,
+    
+    /// Previously issued access token (instead of username/password)
+    #[arg(long, conflicts_with_all = ["username", "password"])]
+    token: Option<String>,
+    
+    /// One-time code for accounts with TOTP enabled; prompted for when needed and omitted
+    #[arg(long)]
+    totp_code: Option<String>,
+    
+    /// PEM bundle of CAs to trust for wss:// servers (instead of the public roots)
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    
+    /// PEM client certificate for mutual TLS; logs in as the identity the server
+    /// maps it to unless --username or --token is given
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    
+    /// PEM private key for --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
     
     /// Enable debug logging
     #[arg(short, long)]
@@ -40,23 +90,57 @@ async fn main() -> Result<()> {
     
     info!("Starting Remote HID Commander v{}", env!("CARGO_PKG_VERSION"));
     info!("Connecting to server: {}", args.server);
-    info!("Target HID client: {}", args.target);
+    
+    let credentials = match (args.token, args.username, args.password) {
+        (Some(token), _, _) => Credentials::Token(token),
+        (None, Some(username), Some(password)) => Credentials::Password { username, password },
+        _ if args.cert.is_some() => Credentials::Certificate,
+        _ => unreachable!("clap requires --token, --username/--password or --cert"),
+    };
+    let client_cert = args.cert.as_deref().zip(args.key.as_deref());
+    
+    if args.list {
+        let commander = Commander::new(args.server, String::new(), credentials, args.ca_cert.as_deref(), client_cert)?
+            .with_totp_code(args.totp_code);
+        print_clients(&commander.list_clients().await?);
+        return Ok(());
+    }
+    
+    if args.revoke_jti.is_some() || args.revoke_user.is_some() {
+        let commander = Commander::new(args.server, String::new(), credentials, args.ca_cert.as_deref(), client_cert)?
+            .with_totp_code(args.totp_code);
+        let disconnected = commander.revoke(args.revoke_jti, args.revoke_user).await?;
+        println!("Revoked; {} live connection(s) disconnected", disconnected);
+        return Ok(());
+    }
+    
+    let target = args.target.expect("clap requires --target unless --list is given");
+    info!("Target HID client: {}", target);
     
     println!("===============================================");
     println!("Remote HID Commander");
     println!("===============================================");
-    println!("Target: {}", args.target);
+    println!("Target: {}", target);
     println!("Server: {}", args.server);
     println!();
     println!("Instructions:");
-    println!("- Move your mouse to control the remote cursor");
-    println!("- Click mouse buttons to send clicks");
-    println!("- Type on keyboard to send key events");
+    if args.observe {
+        println!("- Observing; events sent by the commander in control are logged");
+        println!("- Press Enter to ask for control");
+    } else {
+        println!("- Move your mouse to control the remote cursor");
+        println!("- Click mouse buttons to send clicks");
+        println!("- Type on keyboard to send key events");
+        println!("- Answer y when someone asks for control to hand it over, Enter to take it back");
+    }
     println!("- Press Ctrl+C to exit");
     println!("===============================================");
     
     // Create and run the commander
-    let commander = Commander::new(args.server, args.target)?;
+    let join_mode = if args.observe { JoinMode::Observe } else { JoinMode::Control };
+    let commander = Commander::new(args.server, target, credentials, args.ca_cert.as_deref(), client_cert)?
+        .with_totp_code(args.totp_code)
+        .with_join_mode(join_mode);
     
     match commander.run().await {
         Ok(_) => {
@@ -68,4 +152,24 @@ async fn main() -> Result<()> {
             Err(e)
         }
     }
-}
\ No newline at end of file
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    if clients.is_empty() {
+        println!("No HID clients connected");
+        return;
+    }
+    
+    println!("{:<24} {:<24} {:<10} {:<20} {:<12} OBSERVERS", "CLIENT ID", "NAME", "PLATFORM", "CONNECTED", "STATUS");
+    for client in clients {
+        println!(
+            "{:<24} {:<24} {:<10} {:<20} {:<12} {}",
+            client.client_id,
+            client.client_name.as_deref().unwrap_or("-"),
+            client.platform,
+            client.connected_at.format("%Y-%m-%d %H:%M:%S"),
+            if client.commander_connected { "in session" } else { "available" },
+            if client.observers.is_empty() { "-".to_string() } else { client.observers.join(", ") },
+        );
+    }
+}
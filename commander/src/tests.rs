@@ -168,6 +168,7 @@ mod tests {
             None,
             SessionControlMessage::JoinSession {
                 target_client_id: target_client_id.clone(),
+                mode: JoinMode::Control,
             },
         );
         
@@ -175,7 +176,7 @@ mod tests {
         assert!(join_message.session_id.is_none());
         
         match join_message.payload {
-            MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id: msg_target }) => {
+            MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id: msg_target, .. }) => {
                 assert_eq!(msg_target, target_client_id);
             }
             _ => panic!("Wrong message type"),
@@ -345,6 +346,7 @@ mod client_tests {
             None,
             SessionControlMessage::JoinSession {
                 target_client_id: target_client_id.clone(),
+                mode: JoinMode::Control,
             }
         );
         
@@ -352,7 +354,7 @@ mod client_tests {
         let deserialized: Message = serde_json::from_str(&json).unwrap();
         
         match deserialized.payload {
-            MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id: msg_target }) => {
+            MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id: msg_target, .. }) => {
                 assert_eq!(msg_target, target_client_id);
             }
             _ => panic!("Wrong message type"),
@@ -401,6 +403,36 @@ mod client_tests {
             _ => panic!("Wrong status message type"),
         }
     }
+    
+    #[test]
+    fn test_handoff_answers_from_terminal() {
+        use crate::client::Handoff;
+        
+        // The commander in control answers requests in order, and only with an explicit yes
+        let mut owner = Handoff::new(true);
+        assert!(!owner.prompting());
+        assert!(owner.answer("").is_none());
+        owner.requests.push_back(("p1".to_string(), "bob".to_string()));
+        assert!(owner.prompting());
+        owner.requests.push_back(("p2".to_string(), "carol".to_string()));
+        assert!(matches!(owner.answer("n"), Some(SessionControlMessage::DenyControl { participant_id }) if participant_id == "p1"));
+        assert!(matches!(owner.answer(" Y "), Some(SessionControlMessage::GrantControl { participant_id }) if participant_id == "p2"));
+        // With no request waiting, typed lines go to the remote machine again
+        assert!(!owner.prompting());
+        
+        // Having handed control over, the owner can take it back
+        owner.control_changed(false);
+        assert!(matches!(owner.answer(""), Some(SessionControlMessage::RevokeControl)));
+        
+        // Observers ask for it; stale requests are dropped when control moves on
+        let mut observer = Handoff::new(false);
+        assert!(!observer.in_control);
+        assert!(matches!(observer.answer(""), Some(SessionControlMessage::RequestControl)));
+        observer.control_changed(true);
+        observer.requests.push_back(("p3".to_string(), "dave".to_string()));
+        observer.control_changed(false);
+        assert!(observer.requests.is_empty());
+    }
 }
 
 #[cfg(test)]
@@ -51,10 +51,10 @@ echo "  # or directly:"
 echo "  ./target/release/session-server"
 echo ""
 echo "Terminal 2 - HID Client (on target machine):"
-echo "  ./target/release/hid-client --client-id \"demo-client\" --client-name \"Demo Machine\""
+echo "  ./target/release/hid-client --client-id \"demo-client\" --client-name \"Demo Machine\" --username admin --password admin123"
 echo ""
 echo "Terminal 3 - Commander (on operator machine):"
-echo "  ./target/release/commander --target \"demo-client\""
+echo "  ./target/release/commander --target \"demo-client\" --username admin --password admin123"
 echo ""
 echo "📝 Usage Notes:"
 echo "==============="
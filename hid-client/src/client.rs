@@ -1,42 +1,98 @@
-use anyhow::Result;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
+use anyhow::{Result, bail};
+use std::path::Path;
+use tokio_tungstenite::{
+    connect_async_tls_with_config, Connector,
+    tungstenite::{client::IntoClientRequest, http::header::AUTHORIZATION, protocol::Message as WsMessage},
+};
 use futures_util::{StreamExt, SinkExt};
 use tracing::{info, warn, error, debug};
 
-use remote_hid_shared::{Message, MessagePayload, MessageType, SessionControlMessage, HidEvent};
+use remote_hid_shared::{Message, MessagePayload, MessageType, SessionControlMessage, AuthMessage, StatusMessage, ClientType, Credentials, DeviceCredential, FirstFrame, LoginPrompts, client_login};
+use crate::consent::{Consent, ConsentPolicy};
 use crate::hid::HidHandler;
 
 pub struct HidClient {
     server_url: String,
     client_id: String,
     client_name: Option<String>,
+    credentials: Credentials,
+    tls_connector: Option<Connector>,
     hid_handler: HidHandler,
+    consent_policy: ConsentPolicy,
 }
 
 impl HidClient {
-    pub fn new(server_url: String, client_id: String, client_name: Option<String>) -> Result<Self> {
+    /// `ca_bundle` replaces the default web PKI roots for `wss://` servers;
+    /// `client_cert` is a certificate and key presented for mutual TLS
+    pub fn new(
+        server_url: String,
+        client_id: String,
+        client_name: Option<String>,
+        credentials: Credentials,
+        ca_bundle: Option<&Path>,
+        client_cert: Option<(&Path, &Path)>,
+    ) -> Result<Self> {
         let hid_handler = HidHandler::new()?;
+        let tls_connector = remote_hid_shared::tls::connector_config(ca_bundle, client_cert)?.map(Connector::Rustls);
         
         Ok(Self {
             server_url,
             client_id,
             client_name,
+            credentials,
+            tls_connector,
             hid_handler,
+            consent_policy: ConsentPolicy::AutoApprove,
         })
     }
     
+    /// How commanders asking to take control are approved; by default they are let in without asking
+    pub fn with_consent_policy(mut self, consent_policy: ConsentPolicy) -> Self {
+        self.consent_policy = consent_policy;
+        self
+    }
+    
     pub async fn run(&self) -> Result<()> {
         info!("Connecting to session server at {}", self.server_url);
         
-        let (ws_stream, _) = connect_async(&self.server_url).await?;
+        let mut request = self.server_url.as_str().into_client_request()?;
+        if let Credentials::Token(token) = &self.credentials {
+            request.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+        
+        let (ws_stream, _) = connect_async_tls_with_config(request, None, false, self.tls_connector.clone()).await?;
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
         
+        // Authenticate before registering; a token was already sent with the upgrade request
+        let first_frame = match &self.credentials {
+            Credentials::Password { username, password } => {
+                Some(FirstFrame::password(username, password, ClientType::HidClient, Some(self.client_id.clone())))
+            }
+            Credentials::PairingCode { code, .. } => Some(FirstFrame::new(AuthMessage::Pair {
+                pairing_code: code.clone(),
+                client_id: self.client_id.clone(),
+            })),
+            Credentials::Device(credential) => Some(FirstFrame::new(AuthMessage::DeviceLogin {
+                client_id: credential.client_id.clone(),
+                device_secret: credential.device_secret.clone(),
+            })),
+            Credentials::Certificate => Some(FirstFrame::new(AuthMessage::CertificateLogin {
+                client_type: ClientType::HidClient,
+                client_id: Some(self.client_id.clone()),
+            })),
+            Credentials::Token(_) => None,
+        };
+        let mut refresher = client_login(&mut ws_sender, &mut ws_receiver, first_frame, self).await?;
+        
         // Send initial session creation message
+        let mut consent = Consent::new(self.consent_policy.clone());
         let create_session = Message::session_control(
             None,
             SessionControlMessage::CreateSession {
                 client_id: self.client_id.clone(),
                 client_name: self.client_name.clone(),
+                platform: Some(platform_name().to_string()),
+                requires_consent: consent.required(),
             },
         );
         
@@ -46,26 +102,74 @@ impl HidClient {
         info!("Registered as HID client: {}", self.client_id);
         
         // Main message loop
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(WsMessage::Text(text)) => {
-                    if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                        if let Err(e) = self.handle_message(message).await {
-                            error!("Failed to handle message: {}", e);
-                        }
-                    } else {
-                        warn!("Failed to parse message: {}", text);
+        loop {
+            tokio::select! {
+                // Renew the access token before the server drops us for letting it expire
+                _ = refresher.refresh_due() => {
+                    if let Some(refresh_token) = refresher.take() {
+                        debug!("Refreshing access token");
+                        let refresh = Message::auth(AuthMessage::Refresh { refresh_token });
+                        ws_sender.send(WsMessage::Text(serde_json::to_string(&refresh)?)).await?;
                     }
                 }
-                Ok(WsMessage::Close(_)) => {
-                    info!("Server closed connection");
-                    break;
+                
+                (session_id, approved) = consent.next_answer() => {
+                    info!("{} the request to take control", if approved { "Approved" } else { "Declined" });
+                    let answer = Message::session_control(Some(session_id), SessionControlMessage::ConsentResponse { session_id, approved });
+                    ws_sender.send(WsMessage::Text(serde_json::to_string(&answer)?)).await?;
                 }
-                Ok(_) => {}
-                Err(e) => {
-                    error!("WebSocket error: {}", e);
+                
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Logging out");
+                    let logout = Message::auth(AuthMessage::Logout);
+                    ws_sender.send(WsMessage::Text(serde_json::to_string(&logout)?)).await?;
                     break;
                 }
+                
+                msg = ws_receiver.next() => match msg {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        if let Ok(message) = serde_json::from_str::<Message>(&text) {
+                            match message.payload {
+                                // Answer heartbeats right away so the server can measure latency
+                                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                                    let reply = Message::status(message.session_id, StatusMessage::Heartbeat);
+                                    ws_sender.send(WsMessage::Text(serde_json::to_string(&reply)?)).await?;
+                                }
+                                MessagePayload::Auth(AuthMessage::Response { success: true, expires_at, refresh_token, .. }) => {
+                                    debug!("Access token refreshed (expires {:?})", expires_at);
+                                    refresher.update(refresh_token, expires_at);
+                                }
+                                MessagePayload::Auth(AuthMessage::Response { error_message, .. }) => {
+                                    warn!("Session server rejected token: {}", error_message.unwrap_or_default());
+                                }
+                                MessagePayload::SessionControl(SessionControlMessage::ConsentRequest { session_id, username, timeout_secs }) => {
+                                    consent.request(session_id, &username, timeout_secs);
+                                }
+                                _ => {
+                                    // Ending a session that was never approved withdraws the request
+                                    if let (Some(session_id), MessagePayload::SessionControl(SessionControlMessage::SessionEnded { .. })) = (message.session_id, &message.payload) {
+                                        consent.withdraw(session_id);
+                                    }
+                                    if let Err(e) = self.handle_message(message).await {
+                                        error!("Failed to handle message: {}", e);
+                                    }
+                                }
+                            }
+                        } else {
+                            warn!("Failed to parse message: {}", text);
+                        }
+                    }
+                    Some(Ok(WsMessage::Close(_))) => {
+                        info!("Server closed connection");
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        error!("WebSocket error: {}", e);
+                        break;
+                    }
+                    None => break,
+                },
             }
         }
         
@@ -105,4 +209,30 @@ impl HidClient {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Unattended: accounts that need a one-time code cannot log in, and pairing saves the credential
+impl LoginPrompts for HidClient {
+    async fn totp_code(&self) -> Result<String> {
+        bail!("This account needs a one-time code to log in; pair this machine as a device instead")
+    }
+
+    fn paired(&self, credential: DeviceCredential) -> Result<()> {
+        let Credentials::PairingCode { credential_path, .. } = &self.credentials else {
+            bail!("Unexpected device credential from session server");
+        };
+        credential.save(credential_path)?;
+        info!("Paired with session server; device credential saved to {}", credential_path.display());
+        Ok(())
+    }
+}
+
+/// Human readable name of the operating system this client runs on
+pub fn platform_name() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "macOS",
+        "windows" => "Windows",
+        "linux" => "Linux",
+        other => other,
+    }
+}
@@ -0,0 +1,161 @@
+//! Asking the person at this machine before a commander takes control of it.
+//!
+//! The server holds a commander's session until the HID client answers its
+//! `ConsentRequest`. Answers come from the terminal, from a hook command, or
+//! straight away for unattended machines; no answer by the deadline declines.
+
+use std::time::Duration;
+use tokio::{sync::mpsc, task::AbortHandle, time::Instant};
+use tracing::{info, warn};
+use remote_hid_shared::terminal::stdin_lines;
+use uuid::Uuid;
+
+/// How requests to take control of this machine are answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsentPolicy {
+    /// Ask on this terminal
+    Prompt,
+    /// Run a shell command; exit status 0 approves
+    Hook(String),
+    /// Approve every commander without asking, for unattended machines
+    AutoApprove,
+}
+
+/// A request the local user has yet to answer
+struct Pending {
+    session_id: Uuid,
+    deadline: Instant,
+    // A running hook, stopped if the request is withdrawn
+    hook: Option<AbortHandle>,
+}
+
+/// Requests for consent and the answers to them
+pub struct Consent {
+    policy: ConsentPolicy,
+    pending: Option<Pending>,
+    lines: Option<mpsc::UnboundedReceiver<String>>,
+    answers_tx: mpsc::UnboundedSender<(Uuid, bool)>,
+    answers: mpsc::UnboundedReceiver<(Uuid, bool)>,
+}
+
+impl Consent {
+    pub fn new(policy: ConsentPolicy) -> Self {
+        let lines = (policy == ConsentPolicy::Prompt).then(stdin_lines);
+        let (answers_tx, answers) = mpsc::unbounded_channel();
+        Self { policy, pending: None, lines, answers_tx, answers }
+    }
+
+    /// Whether the server should hold sessions until they are approved
+    pub fn required(&self) -> bool {
+        self.policy != ConsentPolicy::AutoApprove
+    }
+
+    /// Start asking about a commander; the answer comes from `next_answer`
+    pub fn request(&mut self, session_id: Uuid, username: &str, timeout_secs: u64) {
+        self.withdraw_pending();
+        let mut hook = None;
+        match &self.policy {
+            ConsentPolicy::Prompt => {
+                // Lines typed before the question are not answers to it
+                if let Some(lines) = self.lines.as_mut() {
+                    while lines.try_recv().is_ok() {}
+                }
+                println!("{} wants to take control of this machine. Allow? [y/N] ({}s)", username, timeout_secs);
+            }
+            ConsentPolicy::Hook(command) => {
+                info!("Asking consent hook about {}", username);
+                hook = Some(run_hook(command, session_id, username, timeout_secs, self.answers_tx.clone()));
+            }
+            ConsentPolicy::AutoApprove => {
+                let _ = self.answers_tx.send((session_id, true));
+            }
+        }
+        self.pending = Some(Pending {
+            session_id,
+            deadline: Instant::now() + Duration::from_secs(timeout_secs),
+            hook,
+        });
+    }
+
+    /// Forget a request the server withdrew, e.g. because the commander left
+    pub fn withdraw(&mut self, session_id: Uuid) {
+        if self.pending.as_ref().is_some_and(|pending| pending.session_id == session_id) {
+            info!("Request to take control was withdrawn");
+            self.withdraw_pending();
+        }
+    }
+
+    fn withdraw_pending(&mut self) {
+        if let Some(hook) = self.pending.take().and_then(|pending| pending.hook) {
+            hook.abort();
+        }
+    }
+
+    /// Wait for the answer to the pending request; declines once its deadline passes.
+    /// Never resolves while nothing is pending. Safe to use in `select!`.
+    pub async fn next_answer(&mut self) -> (Uuid, bool) {
+        loop {
+            let Some((session_id, deadline)) = self.pending.as_ref().map(|p| (p.session_id, p.deadline)) else {
+                return std::future::pending().await;
+            };
+            let approved = tokio::select! {
+                Some(line) = next_line(&mut self.lines) => parse_answer(&line),
+                Some((answered, approved)) = self.answers.recv() => {
+                    // A hook that finished after its request was withdrawn
+                    if answered != session_id {
+                        continue;
+                    }
+                    approved
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    info!("No answer in time; declining");
+                    false
+                }
+            };
+            self.pending = None;
+            return (session_id, approved);
+        }
+    }
+}
+
+/// Whether a line typed at the prompt approves; anything but yes declines
+pub(crate) fn parse_answer(line: &str) -> bool {
+    matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+async fn next_line(lines: &mut Option<mpsc::UnboundedReceiver<String>>) -> Option<String> {
+    match lines {
+        Some(lines) => lines.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Run the hook with the request in its environment, killing it if it outlives the request
+fn run_hook(command: &str, session_id: Uuid, username: &str, timeout_secs: u64, answers: mpsc::UnboundedSender<(Uuid, bool)>) -> AbortHandle {
+    let mut hook = if cfg!(windows) {
+        let mut hook = tokio::process::Command::new("cmd");
+        hook.arg("/C").arg(command);
+        hook
+    } else {
+        let mut hook = tokio::process::Command::new("sh");
+        hook.arg("-c").arg(command);
+        hook
+    };
+    hook.env("REMOTE_HID_USERNAME", username)
+        .env("REMOTE_HID_SESSION_ID", session_id.to_string())
+        .env("REMOTE_HID_TIMEOUT_SECS", timeout_secs.to_string())
+        .kill_on_drop(true);
+
+    tokio::spawn(async move {
+        let approved = match tokio::time::timeout(Duration::from_secs(timeout_secs), hook.status()).await {
+            Ok(Ok(status)) => status.success(),
+            Ok(Err(e)) => {
+                warn!("Failed to run consent hook: {}", e);
+                false
+            }
+            Err(_) => false,
+        };
+        let _ = answers.send((session_id, approved));
+    })
+    .abort_handle()
+}
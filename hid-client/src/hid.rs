@@ -1,6 +1,10 @@
 use anyhow::{Result, anyhow};
-use remote_hid_shared::{HidEvent, MouseButton, KeyCode, KeyModifiers};
-use tracing::{debug, warn};
+use remote_hid_shared::HidEvent;
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+use remote_hid_shared::{MouseButton, KeyCode, KeyModifiers};
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+use tracing::warn;
+use tracing::debug;
 
 // Platform-specific implementations are defined inline below
 
@@ -413,6 +417,7 @@ mod windows {
 
 // Stub implementation for unsupported platforms
 #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+#[allow(dead_code)]
 mod unsupported {
     use super::*;
     
@@ -1,14 +1,19 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
+use std::path::PathBuf;
 use tracing::{info, error};
 
 mod client;
+mod consent;
 mod hid;
 
 #[cfg(test)]
+#[allow(clippy::module_inception, unused_imports, unused_variables)]
 mod tests;
 
 use client::HidClient;
+use consent::ConsentPolicy;
+use remote_hid_shared::{Credentials, DeviceCredential};
 
 #[derive(Parser, Debug)]
 #[command(name = "hid-client")]
@@ -27,14 +32,70 @@ struct Args {
     #[arg(long)]
     client_name: Option<String>,
     
+    /// Username for authenticating with the session server
+    #[arg(short, long, required_unless_present_any = ["token", "pairing_code", "device_credential", "cert"], requires = "password")]
+    username: Option<String>,
+    
+    /// Password for authenticating with the session server
+    #[arg(long, requires = "username")]
+    password: Option<String>,
+    
+    /// Previously issued access token (instead of username/password)
+    #[arg(long, conflicts_with_all = ["username", "password"])]
+    token: Option<String>,
+    
+    /// One-time pairing code from `session-server device pair`; enrolls --client-id
+    #[arg(long, requires_all = ["client_id", "device_credential"], conflicts_with_all = ["username", "password", "token"])]
+    pairing_code: Option<String>,
+    
+    /// Device credential file: written when pairing, used to authenticate afterwards
+    #[arg(long, conflicts_with_all = ["username", "password", "token"])]
+    device_credential: Option<PathBuf>,
+    
+    /// PEM bundle of CAs to trust for wss:// servers (instead of the public roots)
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    
+    /// PEM client certificate for mutual TLS; logs in as the identity the server
+    /// maps it to unless another credential is given
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    
+    /// PEM private key for --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    
+    /// Ask on this terminal before each commander takes control; without this or
+    /// --consent-hook, every commander is let in without asking
+    #[arg(long, conflicts_with = "consent_hook")]
+    prompt_consent: bool,
+    
+    /// Shell command that decides whether a commander may take control; exit
+    /// status 0 approves. REMOTE_HID_USERNAME, REMOTE_HID_SESSION_ID and
+    /// REMOTE_HID_TIMEOUT_SECS describe the request
+    #[arg(long)]
+    consent_hook: Option<String>,
+    
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
 }
 
+impl Args {
+    /// Unattended machines, the default, let every commander in without asking
+    fn consent_policy(&self) -> ConsentPolicy {
+        match &self.consent_hook {
+            Some(command) => ConsentPolicy::Hook(command.clone()),
+            None if self.prompt_consent => ConsentPolicy::Prompt,
+            None => ConsentPolicy::AutoApprove,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    let consent_policy = args.consent_policy();
     
     // Initialize logging
     let log_level = if args.debug { "debug" } else { "info" };
@@ -44,16 +105,33 @@ async fn main() -> Result<()> {
     
     info!("Starting Remote HID Client v{}", env!("CARGO_PKG_VERSION"));
     
-    // Generate client ID if not provided
-    let client_id = args.client_id.unwrap_or_else(|| {
-        format!("hid-{}", uuid::Uuid::new_v4().simple())
-    });
+    let credentials = match (args.token, args.username, args.password, args.pairing_code, args.device_credential) {
+        (Some(token), ..) => Credentials::Token(token),
+        (None, Some(username), Some(password), ..) => Credentials::Password { username, password },
+        (.., Some(code), Some(credential_path)) => Credentials::PairingCode { code, credential_path },
+        (.., None, Some(path)) => Credentials::Device(DeviceCredential::load(&path)?),
+        _ if args.cert.is_some() => Credentials::Certificate,
+        _ => unreachable!("clap requires --token, --username/--password, a device credential or --cert"),
+    };
+    
+    // A paired device is bound to the client id it was enrolled with
+    let client_id = match (&credentials, args.client_id) {
+        (Credentials::Device(credential), Some(client_id)) if client_id != credential.client_id => {
+            bail!("Device credential is for client id {}, not {}", credential.client_id, client_id);
+        }
+        (Credentials::Device(credential), _) => credential.client_id.clone(),
+        // Generate client ID if not provided
+        (_, client_id) => client_id.unwrap_or_else(|| format!("hid-{}", uuid::Uuid::new_v4().simple())),
+    };
+    
+    let client_cert = args.cert.as_deref().zip(args.key.as_deref());
     
     info!("Client ID: {}", client_id);
     info!("Connecting to server: {}", args.server);
     
     // Create and run the client
-    let client = HidClient::new(args.server, client_id, args.client_name)?;
+    let client = HidClient::new(args.server, client_id, args.client_name, credentials, args.ca_cert.as_deref(), client_cert)?
+        .with_consent_policy(consent_policy);
     
     match client.run().await {
         Ok(_) => {
@@ -113,6 +113,8 @@ mod tests {
         let create_session = SessionControlMessage::CreateSession {
             client_id: "hid-client-123".to_string(),
             client_name: Some("Test HID Client".to_string()),
+            platform: None,
+            requires_consent: false,
         };
         
         let message = Message::session_control(None, create_session);
@@ -120,7 +122,7 @@ mod tests {
         let deserialized: Message = serde_json::from_str(&json).unwrap();
         
         match deserialized.payload {
-            MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name }) => {
+            MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name, .. }) => {
                 assert_eq!(client_id, "hid-client-123");
                 assert_eq!(client_name, Some("Test HID Client".to_string()));
             }
@@ -275,6 +277,8 @@ mod client_tests {
             SessionControlMessage::CreateSession {
                 client_id: client_id.clone(),
                 client_name: client_name.clone(),
+                platform: None,
+                requires_consent: false,
             }
         );
         
@@ -282,7 +286,8 @@ mod client_tests {
         match create_session_msg.payload {
             MessagePayload::SessionControl(SessionControlMessage::CreateSession { 
                 client_id: msg_client_id, 
-                client_name: msg_client_name 
+                client_name: msg_client_name,
+                ..
             }) => {
                 assert_eq!(msg_client_id, client_id);
                 assert_eq!(msg_client_name, client_name);
@@ -448,4 +453,88 @@ mod hid_handler_tests {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod platform_tests {
+    use crate::client::platform_name;
+
+    #[test]
+    fn test_platform_name() {
+        let name = platform_name();
+        assert!(!name.is_empty());
+        #[cfg(target_os = "linux")]
+        assert_eq!(name, "Linux");
+        #[cfg(target_os = "macos")]
+        assert_eq!(name, "macOS");
+        #[cfg(target_os = "windows")]
+        assert_eq!(name, "Windows");
+    }
+}
+
+#[cfg(test)]
+mod consent_tests {
+    use crate::consent::{parse_answer, Consent, ConsentPolicy};
+    use crate::Args;
+    use clap::Parser;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_asking_is_opt_in() {
+        let policy = |extra: &[&str]| {
+            let args = ["hid-client", "--token", "t"].iter().chain(extra).copied();
+            Args::try_parse_from(args).unwrap().consent_policy()
+        };
+        assert_eq!(policy(&[]), ConsentPolicy::AutoApprove);
+        assert_eq!(policy(&["--prompt-consent"]), ConsentPolicy::Prompt);
+        assert_eq!(policy(&["--consent-hook", "true"]), ConsentPolicy::Hook("true".to_string()));
+        assert!(Args::try_parse_from(["hid-client", "--token", "t", "--prompt-consent", "--consent-hook", "true"]).is_err());
+    }
+
+    #[test]
+    fn test_only_yes_approves() {
+        assert!(parse_answer("y"));
+        assert!(parse_answer(" YES\n"));
+        assert!(!parse_answer(""));
+        assert!(!parse_answer("no"));
+        assert!(!parse_answer("yep"));
+    }
+
+    #[tokio::test]
+    async fn test_unattended_machines_approve_without_asking() {
+        let mut consent = Consent::new(ConsentPolicy::AutoApprove);
+        assert!(!consent.required());
+        let session_id = Uuid::new_v4();
+        consent.request(session_id, "alice", 30);
+        assert_eq!(consent.next_answer().await, (session_id, true));
+
+        // Nothing is pending any more
+        assert!(tokio::time::timeout(Duration::from_millis(50), consent.next_answer()).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook_decides_and_slow_hooks_decline() {
+        let mut consent = Consent::new(ConsentPolicy::Hook(r#"test "$REMOTE_HID_USERNAME" = alice"#.to_string()));
+        assert!(consent.required());
+
+        let approved = Uuid::new_v4();
+        consent.request(approved, "alice", 30);
+        assert_eq!(consent.next_answer().await, (approved, true));
+
+        let declined = Uuid::new_v4();
+        consent.request(declined, "mallory", 30);
+        assert_eq!(consent.next_answer().await, (declined, false));
+
+        let mut consent = Consent::new(ConsentPolicy::Hook("sleep 10".to_string()));
+        let unanswered = Uuid::new_v4();
+        consent.request(unanswered, "alice", 1);
+        assert_eq!(consent.next_answer().await, (unanswered, false));
+
+        // A withdrawn request is never answered
+        let withdrawn = Uuid::new_v4();
+        consent.request(withdrawn, "alice", 1);
+        consent.withdraw(withdrawn);
+        assert!(tokio::time::timeout(Duration::from_millis(1500), consent.next_answer()).await.is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! `session-recording`: read the session recordings the session server writes
+//! to `recording.directory`.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use uuid::Uuid;
+
+use remote_hid_shared::{parse_recording_file_name, MessagePayload, RecordingReader};
+
+#[derive(Parser, Debug)]
+#[command(name = "session-recording")]
+#[command(about = "Read Remote HID session recordings")]
+#[command(version = "0.1.0")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Summarize the recorded sessions in a directory
+    List { directory: PathBuf },
+    /// Print the messages in recording files, in session and segment order
+    Show {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Print each record as a JSON line instead of a summary
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    match Args::parse().command {
+        Command::List { directory } => list(&directory),
+        Command::Show { files, json } => show(files, json),
+    }
+}
+
+/// What `list` shows about one session
+#[derive(Default)]
+struct Summary {
+    hid_client_id: String,
+    segments: usize,
+    messages: usize,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+}
+
+fn list(directory: &Path) -> Result<()> {
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(directory).with_context(|| format!("Failed to read {}", directory.display()))? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str().filter(|name| parse_recording_file_name(name).is_some()) {
+            files.push((name.to_string(), entry.path()));
+        }
+    }
+    if files.is_empty() {
+        println!("No recordings in {}", directory.display());
+        return Ok(());
+    }
+
+    let mut sessions: BTreeMap<Uuid, Summary> = BTreeMap::new();
+    for (name, path) in sort_segments(files) {
+        let reader = RecordingReader::open(&path).with_context(|| format!("Failed to read {}", name))?;
+        let summary = sessions.entry(reader.header().session_id).or_insert_with(|| Summary {
+            hid_client_id: reader.header().hid_client_id.clone(),
+            ..Summary::default()
+        });
+        summary.segments += 1;
+        for recorded in reader {
+            let recorded = recorded.with_context(|| format!("Failed to read {}", name))?;
+            summary.messages += 1;
+            summary.first.get_or_insert(recorded.received_at);
+            summary.last = Some(recorded.received_at);
+        }
+    }
+    for (session_id, summary) in sessions {
+        let span = match (summary.first, summary.last) {
+            (Some(first), Some(last)) => format!("{} - {}", first.format("%Y-%m-%d %H:%M:%S"), last.format("%H:%M:%S")),
+            _ => "empty".to_string(),
+        };
+        println!("{}  {:<20} {:>3} file(s) {:>8} message(s)  {}", session_id, summary.hid_client_id, summary.segments, summary.messages, span);
+    }
+    Ok(())
+}
+
+fn show(files: Vec<PathBuf>, json: bool) -> Result<()> {
+    let named = files.into_iter().map(|path| {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+        (name, path)
+    });
+    for (_, path) in sort_segments(named.collect()) {
+        let reader = RecordingReader::open(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let header = reader.header().clone();
+        if !json {
+            println!("# session {} on {} (segment {}, format version {})", header.session_id, header.hid_client_id, header.segment, header.version);
+        }
+        for recorded in reader {
+            let recorded = recorded.with_context(|| format!("Failed to read {}", path.display()))?;
+            if json {
+                println!("{}", serde_json::to_string(&recorded)?);
+                continue;
+            }
+            let payload = match &recorded.message.payload {
+                MessagePayload::HidEvent(event) => format!("{:?}", event),
+                other => format!("{:?}", other),
+            };
+            // Version 1 recordings only hold what went to the HID client
+            let sent_to = if recorded.sent_to.is_empty() { header.hid_client_id.clone() } else { recorded.sent_to.join(", ") };
+            println!(
+                "{}  {} ({})  -> {}  {}",
+                recorded.received_at.format("%Y-%m-%d %H:%M:%S%.3f"),
+                recorded.username,
+                recorded.commander_id,
+                sent_to,
+                payload,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Order files by session, then segment; files not named like recordings go last, by name
+fn sort_segments(mut files: Vec<(String, PathBuf)>) -> Vec<(String, PathBuf)> {
+    files.sort_by_key(|(name, _)| (parse_recording_file_name(name).is_none(), parse_recording_file_name(name), name.clone()));
+    files
+}
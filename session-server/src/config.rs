@@ -1,12 +1,20 @@
 use serde::{Deserialize, Serialize};
-use std::fs;
-use anyhow::Result;
+use std::{collections::HashMap, fs, io};
+use anyhow::{Context, Result};
+
+use remote_hid_shared::{PasswordHashing, PasswordPolicy};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub auth: AuthConfig,
     pub session: SessionConfig,
+    /// Recording forwarded messages to disk for audit; off by default
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    /// Tags per HID client id, matched against users' `allowed_tags`
+    #[serde(default)]
+    pub client_tags: HashMap<String, Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,14 +23,145 @@ pub struct ServerConfig {
     pub port: u16,
     pub max_connections: usize,
     pub heartbeat_interval_secs: u64,
+    /// Unanswered heartbeats after which a peer is disconnected
+    #[serde(default = "default_max_missed_heartbeats")]
+    pub max_missed_heartbeats: u32,
+    /// Seconds to wait for queued messages to be delivered when shutting down
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// Seconds a new connection has to finish TLS, the upgrade and logging in
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
+    /// Serve `wss://` instead of plain `ws://` when set
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the server certificate chain
+    pub cert_path: String,
+    /// PEM file with the server private key
+    pub key_path: String,
+    /// PEM bundle of CAs that issue client certificates; when set, clients must present one
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    /// Also accept clients without a certificate, leaving them to log in some other way
+    #[serde(default)]
+    pub client_cert_optional: bool,
+}
+
+fn default_max_missed_heartbeats() -> u32 {
+    3
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    5
+}
+
+// Long enough for someone to type a one-time code
+fn default_handshake_timeout_secs() -> u64 {
+    60
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthConfig {
+    /// HS256 secret, used when no `signing_key_id` is set
     pub jwt_secret: String,
+    /// Lifetime of access tokens; clients refresh them before they run out
     pub token_expiry_hours: i64,
+    /// Lifetime of the single-use refresh tokens issued alongside access tokens
+    #[serde(default = "default_refresh_token_expiry_hours")]
+    pub refresh_token_expiry_hours: i64,
+    /// JSON user database, managed with `session-server user ...`
+    #[serde(default = "default_users_file")]
+    pub users_file: String,
+    /// JSON file of pairing codes and enrolled HID clients, managed with `session-server device ...`
+    #[serde(default = "default_devices_file")]
+    pub devices_file: String,
+    /// JSON file revoked tokens are persisted to; revocations are kept in memory only when unset
+    #[serde(default)]
+    pub revocation_file: Option<String>,
     pub max_failed_attempts: u32,
     pub lockout_duration_mins: u32,
+    /// Accept the legacy login that sends the password itself; off by default in favour of challenge/response
+    #[serde(default)]
+    pub allow_password_login: bool,
+    /// Scheme and cost for newly hashed passwords; older hashes are upgraded on login
+    #[serde(default)]
+    pub password_hashing: PasswordHashing,
+    /// Rules for passwords set with `session-server user add` and `user passwd`
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    /// Client certificate subject common names and who each logs in as
+    /// (`user:<username>` or `client:<HID client id>`)
+    #[serde(default)]
+    pub client_certificates: HashMap<String, CertificateIdentity>,
+    /// Id of the key in `keys` that signs new tokens
+    #[serde(default)]
+    pub signing_key_id: Option<String>,
+    /// Asymmetric keys; tokens signed with any of them are accepted, which lets keys be rotated
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    /// File the public keys are published to as a JWK set
+    #[serde(default)]
+    pub jwks_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyConfig {
+    /// Key id, sent as the token's `kid` header
+    pub id: String,
+    /// `EdDSA` or `RS256`
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// PEM public key (`BEGIN PUBLIC KEY`)
+    pub public_key_path: String,
+    /// PEM private key; only needed for the signing key
+    #[serde(default)]
+    pub private_key_path: Option<String>,
+}
+
+/// Identity a client certificate authenticates as
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CertificateIdentity {
+    /// An account from the user database, with its roles and allow lists
+    User(String),
+    /// A HID client that may only register this client id
+    Client(String),
+}
+
+impl TryFrom<String> for CertificateIdentity {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.split_once(':') {
+            Some(("user", username)) if !username.is_empty() => Ok(Self::User(username.to_string())),
+            Some(("client", client_id)) if !client_id.is_empty() => Ok(Self::Client(client_id.to_string())),
+            _ => Err(format!("expected user:<username> or client:<client id>, got {:?}", value)),
+        }
+    }
+}
+
+impl From<CertificateIdentity> for String {
+    fn from(identity: CertificateIdentity) -> Self {
+        match identity {
+            CertificateIdentity::User(username) => format!("user:{}", username),
+            CertificateIdentity::Client(client_id) => format!("client:{}", client_id),
+        }
+    }
+}
+
+fn default_refresh_token_expiry_hours() -> i64 {
+    24 * 30
+}
+
+fn default_users_file() -> String {
+    "users.json".to_string()
+}
+
+fn default_devices_file() -> String {
+    "devices.json".to_string()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,6 +169,50 @@ pub struct SessionConfig {
     pub max_sessions: usize,
     pub session_timeout_mins: u64,
     pub cleanup_interval_secs: u64,
+    /// View-only participants allowed to watch one session at a time
+    #[serde(default = "default_max_observers")]
+    pub max_observers: usize,
+    /// Commanders that may wait in line for a busy HID client; 0 refuses them with `CLIENT_BUSY`
+    #[serde(default = "default_max_queue_length")]
+    pub max_queue_length: usize,
+    /// Seconds a HID client's user has to approve a commander before the request is refused
+    #[serde(default = "default_consent_timeout_secs")]
+    pub consent_timeout_secs: u64,
+}
+
+fn default_max_observers() -> usize {
+    5
+}
+
+fn default_max_queue_length() -> usize {
+    10
+}
+
+fn default_consent_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    pub enabled: bool,
+    /// Directory the per-session recording files are written to
+    pub directory: String,
+    /// Size after which a session's recording continues in a new file
+    pub max_file_bytes: u64,
+    /// Days recordings are kept before they are deleted; 0 keeps them forever
+    pub retention_days: u64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "recordings".to_string(),
+            max_file_bytes: 16 * 1024 * 1024,
+            retention_days: 90,
+        }
+    }
 }
 
 impl Default for Config {
@@ -40,18 +223,38 @@ impl Default for Config {
                 port: 8080,
                 max_connections: 1000,
                 heartbeat_interval_secs: 30,
+                max_missed_heartbeats: default_max_missed_heartbeats(),
+                shutdown_grace_secs: default_shutdown_grace_secs(),
+                handshake_timeout_secs: default_handshake_timeout_secs(),
+                tls: None,
             },
             auth: AuthConfig {
                 jwt_secret: "your-secret-key-change-this-in-production".to_string(),
-                token_expiry_hours: 24,
+                token_expiry_hours: 1,
+                refresh_token_expiry_hours: default_refresh_token_expiry_hours(),
+                users_file: default_users_file(),
+                devices_file: default_devices_file(),
+                revocation_file: None,
+                signing_key_id: None,
+                keys: Vec::new(),
+                jwks_path: None,
                 max_failed_attempts: 3,
                 lockout_duration_mins: 15,
+                allow_password_login: false,
+                password_hashing: PasswordHashing::default(),
+                password_policy: PasswordPolicy::default(),
+                client_certificates: HashMap::new(),
             },
             session: SessionConfig {
                 max_sessions: 100,
                 session_timeout_mins: 60,
                 cleanup_interval_secs: 300, // 5 minutes
+                max_observers: default_max_observers(),
+                max_queue_length: default_max_queue_length(),
+                consent_timeout_secs: default_consent_timeout_secs(),
             },
+            recording: RecordingConfig::default(),
+            client_tags: HashMap::new(),
         }
     }
 }
@@ -63,12 +266,23 @@ impl Config {
         Ok(config)
     }
     
+    /// Load `path`, or the defaults if there is no such file; a file that cannot be
+    /// read or parsed is an error
+    pub fn load_or_default(path: &str) -> Result<Self> {
+        match fs::metadata(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            _ => Self::load(path).with_context(|| format!("failed to load config file {}", path)),
+        }
+    }
+    
+    #[allow(dead_code)]
     pub fn save(&self, path: &str) -> Result<()> {
         let content = toml::to_string_pretty(self)?;
         fs::write(path, content)?;
         Ok(())
     }
     
+    #[allow(dead_code)]
     pub fn create_default_config(path: &str) -> Result<()> {
         let config = Config::default();
         config.save(path)
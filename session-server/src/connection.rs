@@ -0,0 +1,206 @@
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures_util::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
+use tokio::{io::{AsyncRead, AsyncWrite}, sync::{mpsc::{self, error::TrySendError}, watch}};
+use tokio_tungstenite::{tungstenite::protocol::Message as WsMessage, WebSocketStream};
+use tracing::{debug, warn};
+
+use remote_hid_shared::{Message, StatusMessage};
+
+/// Byte stream a WebSocket runs over: plain TCP or TLS
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type WsStream = WebSocketStream<Box<dyn Transport>>;
+
+/// Outbound frames buffered per connection before the peer is considered stalled
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+
+/// Cloneable handle for pushing frames to a connection's writer task.
+///
+/// Sending never waits on the socket: frames are queued and written by a
+/// dedicated task, so routing between peers cannot be blocked by a slow one.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    peer: SocketAddr,
+    outbound: mpsc::Sender<WsMessage>,
+    shutdown: Arc<watch::Sender<bool>>,
+    heartbeat: Arc<Mutex<HeartbeatState>>,
+}
+
+/// Outstanding heartbeat for a connection
+#[derive(Default)]
+struct HeartbeatState {
+    sent_at: Option<Instant>,
+    missed: u32,
+}
+
+/// Reading half of a connection, owned by the task serving the peer
+pub struct ConnectionReader {
+    peer: SocketAddr,
+    stream: SplitStream<WsStream>,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// Split a WebSocket into a queued writer task and a reader half
+pub fn split(ws_stream: WsStream, peer: SocketAddr) -> (ConnectionHandle, ConnectionReader) {
+    let (sink, stream) = ws_stream.split();
+    let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let (shutdown, shutdown_rx) = watch::channel(false);
+
+    tokio::spawn(write_loop(peer, sink, outbound_rx, shutdown_rx.clone()));
+
+    let handle = ConnectionHandle {
+        peer,
+        outbound,
+        shutdown: Arc::new(shutdown),
+        heartbeat: Arc::default(),
+    };
+    let reader = ConnectionReader {
+        peer,
+        stream,
+        shutdown: shutdown_rx,
+    };
+    (handle, reader)
+}
+
+impl ConnectionHandle {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Queue a protocol message for delivery.
+    /// Returns `false` if the message could not be queued.
+    pub fn send(&self, message: &Message) -> bool {
+        match serde_json::to_string(message) {
+            Ok(json) => self.send_text(json),
+            Err(e) => {
+                warn!("Failed to serialize message for {}: {}", self.peer, e);
+                false
+            }
+        }
+    }
+
+    /// Queue an already serialized message for delivery.
+    ///
+    /// A peer whose queue is full is not keeping up; it is disconnected rather
+    /// than silently losing frames or holding up the sender.
+    pub fn send_text(&self, text: String) -> bool {
+        if *self.shutdown.borrow() {
+            return false;
+        }
+        match self.outbound.try_send(WsMessage::Text(text)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Outbound queue for {} is full, disconnecting slow peer", self.peer);
+                self.close();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Flush already queued frames, close the socket and stop the reader
+    pub fn close(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Send a heartbeat, unless `max_missed` earlier ones already went unanswered.
+    /// Returns `false` when the peer should be considered gone.
+    pub fn ping(&self, max_missed: u32) -> bool {
+        {
+            let mut heartbeat = self.heartbeat.lock().unwrap();
+            if heartbeat.sent_at.is_some() {
+                heartbeat.missed += 1;
+            }
+            if heartbeat.missed >= max_missed {
+                return false;
+            }
+            heartbeat.sent_at = Some(Instant::now());
+        }
+        self.send(&Message::status(None, StatusMessage::Heartbeat))
+    }
+
+    /// Record a heartbeat reply, returning the round trip of the outstanding heartbeat
+    pub fn pong(&self) -> Option<Duration> {
+        let mut heartbeat = self.heartbeat.lock().unwrap();
+        heartbeat.missed = 0;
+        heartbeat.sent_at.take().map(|sent_at| sent_at.elapsed())
+    }
+
+    /// Resolve once the writer task has flushed its queue and exited
+    pub async fn flushed(&self) {
+        self.outbound.closed().await
+    }
+
+    /// Whether the connection was closed or its writer task has exited
+    pub fn is_closed(&self) -> bool {
+        *self.shutdown.borrow() || self.outbound.is_closed()
+    }
+}
+
+impl ConnectionReader {
+    /// Wait for the next protocol message from the peer.
+    ///
+    /// Returns `None` once the peer disconnects or the connection is closed
+    /// through its handle. Frames that are not valid messages are skipped.
+    pub async fn next_message(&mut self) -> Option<Message> {
+        loop {
+            let frame = tokio::select! {
+                frame = self.stream.next() => frame,
+                _ = closed(&mut self.shutdown) => return None,
+            };
+
+            match frame {
+                Some(Ok(WsMessage::Text(text))) => match serde_json::from_str::<Message>(&text) {
+                    Ok(message) => return Some(message),
+                    Err(e) => warn!("Invalid message from {}: {}", self.peer, e),
+                },
+                Some(Ok(WsMessage::Close(_))) | None => return None,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    debug!("Read error from {}: {}", self.peer, e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+async fn write_loop(
+    peer: SocketAddr,
+    mut sink: SplitSink<WsStream, WsMessage>,
+    mut outbound: mpsc::Receiver<WsMessage>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+            frame = outbound.recv() => match frame {
+                Some(frame) => {
+                    if let Err(e) = sink.send(frame).await {
+                        debug!("Write error to {}: {}", peer, e);
+                        return;
+                    }
+                }
+                None => break,
+            },
+            _ = closed(&mut shutdown) => {
+                // Deliver what was queued before the close was requested
+                while let Ok(frame) = outbound.try_recv() {
+                    if sink.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                break;
+            }
+        }
+    }
+    sink.close().await.ok();
+}
+
+/// Resolve once the connection has been asked to close
+async fn closed(shutdown: &mut watch::Receiver<bool>) {
+    // The borrowed value is not `Send`; drop it before returning
+    let _ = shutdown.wait_for(|closed| *closed).await;
+}
@@ -0,0 +1,62 @@
+use anyhow::{bail, Result};
+use chrono::Duration;
+use clap::Subcommand;
+
+use remote_hid_shared::DeviceStore;
+
+use crate::config::AuthConfig;
+
+/// `session-server device ...`: enroll unattended HID clients.
+///
+/// The running server re-reads the devices file on every pairing and device
+/// login, so changes apply without a restart.
+#[derive(Subcommand, Debug)]
+pub enum DeviceCommand {
+    /// Create a one-time pairing code that enrolls a client id
+    Pair {
+        client_id: String,
+        /// Minutes the code stays valid
+        #[arg(long, default_value_t = 15)]
+        ttl_mins: i64,
+    },
+    /// Unenroll a client; its device credential stops working
+    Remove { client_id: String },
+    /// Show enrolled clients and outstanding pairing codes
+    List,
+}
+
+/// Apply a device command to the store named in `auth.devices_file`
+pub fn run(command: DeviceCommand, auth: &AuthConfig) -> Result<()> {
+    let mut store = DeviceStore::load(&auth.devices_file)?;
+
+    match command {
+        DeviceCommand::Pair { client_id, ttl_mins } => {
+            if ttl_mins <= 0 {
+                bail!("--ttl-mins must be positive");
+            }
+            let code = store.create_pairing_code(&client_id, Duration::minutes(ttl_mins));
+            store.save()?;
+            println!("Pairing code for {}: {}", client_id, code);
+            println!("Valid once for {} minute(s): hid-client --client-id {} --pairing-code {} --device-credential <path>", ttl_mins, client_id, code);
+        }
+        DeviceCommand::Remove { client_id } => {
+            if store.remove_device(&client_id).is_none() {
+                bail!("No such device: {}", client_id);
+            }
+            store.save()?;
+            println!("Removed device {}", client_id);
+        }
+        DeviceCommand::List => {
+            if store.devices().is_empty() && store.pending_pairings().is_empty() {
+                println!("No devices in {}", auth.devices_file);
+            }
+            for device in store.devices() {
+                println!("{:<24} enrolled {}", device.client_id, device.enrolled_at.format("%Y-%m-%d %H:%M"));
+            }
+            for (client_id, expires_at) in store.pending_pairings() {
+                println!("{:<24} pairing code valid until {}", client_id, expires_at.format("%Y-%m-%d %H:%M"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use remote_hid_shared::{AuthManager, SigningKey, VerificationKey};
+
+use crate::config::{AuthConfig, Config};
+
+/// Build the token signer and verifier described by the auth config.
+///
+/// Without a `signing_key_id` tokens are signed with the HS256 `jwt_secret`,
+/// or with a random secret for this run while it is still the placeholder.
+/// Otherwise every key in `keys` is accepted for verification, the one named
+/// by `signing_key_id` signs new tokens, and the public keys are published to
+/// `jwks_path` if set.
+pub fn auth_manager(config: &AuthConfig) -> anyhow::Result<AuthManager> {
+    let Some(signing_key_id) = &config.signing_key_id else {
+        if config.jwt_secret == Config::default().auth.jwt_secret {
+            warn!("jwt_secret is still the placeholder; signing with a random secret, so tokens will not survive a restart");
+            return Ok(AuthManager::with_random_secret(config.token_expiry_hours));
+        }
+        return Ok(AuthManager::new(&config.jwt_secret, config.token_expiry_hours));
+    };
+
+    let mut signing_key = None;
+    let mut verification_keys = Vec::new();
+    for key in &config.keys {
+        let public_pem = fs::read(&key.public_key_path)
+            .with_context(|| format!("failed to read public key {}", key.public_key_path))?;
+        verification_keys.push(VerificationKey::from_pem(&key.id, key.algorithm, &public_pem)?);
+
+        if key.id == *signing_key_id {
+            let private_key_path = key.private_key_path.as_ref()
+                .ok_or_else(|| anyhow!("signing key {} has no private_key_path", key.id))?;
+            let private_pem = fs::read(private_key_path)
+                .with_context(|| format!("failed to read private key {}", private_key_path))?;
+            signing_key = Some(SigningKey::from_pem(&key.id, key.algorithm, &private_pem)?);
+        }
+    }
+    let signing_key = signing_key.ok_or_else(|| anyhow!("signing_key_id {} is not one of auth.keys", signing_key_id))?;
+
+    let auth_manager = AuthManager::with_keys(signing_key, verification_keys, config.token_expiry_hours)?;
+    info!("Signing tokens with key {} ({} verification key(s))", signing_key_id, config.keys.len());
+
+    if let Some(jwks_path) = &config.jwks_path {
+        let jwks = serde_json::to_string_pretty(&auth_manager.jwk_set())?;
+        fs::write(jwks_path, jwks).with_context(|| format!("failed to publish key set to {}", jwks_path))?;
+        info!("Published verification keys to {}", jwks_path);
+    }
+    Ok(auth_manager)
+}
@@ -4,14 +4,23 @@ use std::sync::Arc;
 use tracing::{info, error};
 
 mod server;
+mod connection;
 mod session;
 mod config;
+mod tls;
+mod keys;
+mod users;
+mod devices;
+mod recording;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
-use config::Config;
+use config::{Config, TlsConfig};
 use server::SessionServer;
+use users::UserCommand;
+use devices::DeviceCommand;
 
 #[derive(Parser, Debug)]
 #[command(name = "session-server")]
@@ -30,9 +39,42 @@ struct Args {
     #[arg(short, long, default_value_t = 8080)]
     port: u16,
     
+    /// PEM certificate chain; serves wss:// together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    
+    /// PEM bundle of CAs whose client certificates are required
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<String>,
+    
+    /// Also accept clients without a certificate (they must log in another way)
+    #[arg(long, requires = "tls_client_ca")]
+    tls_client_cert_optional: bool,
+    
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
+    
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Manage accounts in the user database (`auth.users_file`)
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Enroll unattended HID clients (`auth.devices_file`)
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
 }
 
 #[tokio::main]
@@ -45,24 +87,45 @@ async fn main() -> Result<()> {
         .with_env_filter(format!("session_server={},remote_hid_shared={}", log_level, log_level))
         .init();
     
-    info!("Starting Remote HID Session Server v{}", env!("CARGO_PKG_VERSION"));
-    
     // Load configuration
-    let config = Config::load(&args.config).unwrap_or_else(|_| {
-        info!("Could not load config file, using defaults with CLI overrides");
-        Config::default()
-    });
+    if !std::path::Path::new(&args.config).exists() {
+        info!("No config file at {}, using defaults with CLI overrides", args.config);
+    }
+    let config = Config::load_or_default(&args.config)?;
+    
+    match args.command {
+        Some(Command::User { command }) => return users::run(command, &config.auth),
+        Some(Command::Device { command }) => return devices::run(command, &config.auth),
+        None => {}
+    }
+    
+    info!("Starting Remote HID Session Server v{}", env!("CARGO_PKG_VERSION"));
     
     // Override config with CLI arguments
     let mut config = config;
     config.server.host = args.host;
     config.server.port = args.port;
+    if let (Some(cert_path), Some(key_path)) = (args.tls_cert, args.tls_key) {
+        config.server.tls = Some(TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: args.tls_client_ca,
+            client_cert_optional: args.tls_client_cert_optional,
+        });
+    }
     
     info!("Server configuration: {:?}", config.server);
     
     // Create and start the server
     let server = Arc::new(SessionServer::new(config).await?);
     
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received, draining connections");
+        shutdown.shutdown();
+    });
+    
     match server.run().await {
         Ok(_) => {
             info!("Server shutdown gracefully");
@@ -73,4 +136,26 @@ async fn main() -> Result<()> {
             Err(e)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Wait for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                error!("Could not install SIGTERM handler: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
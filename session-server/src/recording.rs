@@ -0,0 +1,200 @@
+//! Recording the messages forwarded in each session, for audit and incident review.
+//!
+//! Every session gets its own append-only files in `recording.directory`, in the
+//! format described in `remote_hid_shared::recording`. Files are written on a
+//! thread of their own so forwarding never waits on the disk; if that thread
+//! falls too far behind, messages are left out of the recording instead.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use remote_hid_shared::{parse_recording_file_name, recording_file_name, RecordedMessage, RecordingHeader};
+
+use crate::config::RecordingConfig;
+
+/// How often recordings past their retention are looked for
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Entries waiting for the recording thread before further ones are dropped
+const QUEUE_LENGTH: usize = 4096;
+
+enum Entry {
+    Message { session_id: Uuid, hid_client_id: String, recorded: Box<RecordedMessage> },
+    Finished(Uuid),
+}
+
+/// Handle to the recording thread; recording stops when it is dropped
+pub struct Recorder {
+    entries: mpsc::SyncSender<Entry>,
+}
+
+impl Recorder {
+    /// Create the recording directory and start writing, after deleting expired recordings
+    pub fn start(config: RecordingConfig) -> Result<Self> {
+        fs::create_dir_all(&config.directory)
+            .with_context(|| format!("Failed to create recording directory {}", config.directory))?;
+        info!("Recording sessions to {}", config.directory);
+        let (entries, received) = mpsc::sync_channel(QUEUE_LENGTH);
+        let mut writer = RecordingWriter::new(config);
+        std::thread::Builder::new()
+            .name("session-recorder".to_string())
+            .spawn(move || writer.run(received))?;
+        Ok(Self { entries })
+    }
+
+    /// Append a message sent in the session on `hid_client_id` to its recording
+    pub fn record(&self, session_id: Uuid, hid_client_id: &str, recorded: RecordedMessage) {
+        let entry = Entry::Message { session_id, hid_client_id: hid_client_id.to_string(), recorded: Box::new(recorded) };
+        if let Err(mpsc::TrySendError::Full(_)) = self.entries.try_send(entry) {
+            warn!("Recording is falling behind; left a message of session {} out", session_id);
+        }
+    }
+
+    /// Close the session's recording file
+    pub fn finish(&self, session_id: Uuid) {
+        if let Err(mpsc::TrySendError::Full(_)) = self.entries.try_send(Entry::Finished(session_id)) {
+            warn!("Recording is falling behind; the file of session {} stays open", session_id);
+        }
+    }
+}
+
+/// The segment a session is currently being recorded to
+struct Segment {
+    file: File,
+    number: u32,
+    size: u64,
+    records: usize,
+}
+
+/// Writes recordings, rotating and pruning files as configured
+pub struct RecordingWriter {
+    config: RecordingConfig,
+    open: HashMap<Uuid, Segment>,
+}
+
+impl RecordingWriter {
+    pub fn new(config: RecordingConfig) -> Self {
+        Self { config, open: HashMap::new() }
+    }
+
+    fn run(&mut self, entries: mpsc::Receiver<Entry>) {
+        self.prune(SystemTime::now());
+        let mut last_pruned = Instant::now();
+        loop {
+            match entries.recv_timeout(PRUNE_INTERVAL) {
+                Ok(Entry::Message { session_id, hid_client_id, recorded }) => {
+                    if let Err(e) = self.record(session_id, &hid_client_id, &recorded) {
+                        warn!("Failed to record message of session {}: {}", session_id, e);
+                    }
+                }
+                Ok(Entry::Finished(session_id)) => self.finish(session_id),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if last_pruned.elapsed() >= PRUNE_INTERVAL {
+                self.prune(SystemTime::now());
+                last_pruned = Instant::now();
+            }
+        }
+    }
+
+    /// Append one message, starting a new segment if the current one is full
+    pub fn record(&mut self, session_id: Uuid, hid_client_id: &str, recorded: &RecordedMessage) -> io::Result<()> {
+        let mut line = serde_json::to_vec(recorded)?;
+        line.push(b'\n');
+
+        let next = match self.open.get(&session_id) {
+            None => Some(0),
+            // Every segment holds at least one message, however large
+            Some(segment) if segment.records > 0 && segment.size + line.len() as u64 > self.config.max_file_bytes => Some(segment.number + 1),
+            Some(_) => None,
+        };
+        if let Some(number) = next {
+            let segment = self.create_segment(session_id, hid_client_id, number)?;
+            self.open.insert(session_id, segment);
+        }
+
+        let segment = self.open.get_mut(&session_id).expect("segment opened above");
+        // A single write per line keeps lines whole when appending
+        segment.file.write_all(&line)?;
+        segment.size += line.len() as u64;
+        segment.records += 1;
+        Ok(())
+    }
+
+    /// Close the session's segment; a later message would start a new one
+    pub fn finish(&mut self, session_id: Uuid) {
+        if let Some(segment) = self.open.remove(&session_id) {
+            debug!("Closed recording of session {} after segment {}", session_id, segment.number);
+        }
+    }
+
+    fn create_segment(&self, session_id: Uuid, hid_client_id: &str, mut number: u32) -> io::Result<Segment> {
+        loop {
+            let path = self.path(&recording_file_name(session_id, number));
+            let mut options = OpenOptions::new();
+            // Never reopen, let alone truncate, a file that was already written
+            options.append(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(mut file) => {
+                    let mut header = serde_json::to_vec(&RecordingHeader::new(session_id, hid_client_id.to_string(), number))?;
+                    header.push(b'\n');
+                    file.write_all(&header)?;
+                    return Ok(Segment { file, number, size: header.len() as u64, records: 0 });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Delete recordings last written more than `retention_days` before `now`
+    pub fn prune(&self, now: SystemTime) {
+        if self.config.retention_days == 0 {
+            return;
+        }
+        let retention = Duration::from_secs(self.config.retention_days * 24 * 60 * 60);
+        let Some(cutoff) = now.checked_sub(retention) else { return };
+        let entries = match fs::read_dir(&self.config.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read recording directory {}: {}", self.config.directory, e);
+                return;
+            }
+        };
+
+        let mut pruned = 0;
+        for entry in entries.flatten() {
+            // Only files named like recordings, and never one still being written
+            let name = entry.file_name();
+            let Some((session_id, number)) = name.to_str().and_then(parse_recording_file_name) else { continue };
+            if self.open.get(&session_id).is_some_and(|segment| segment.number == number) {
+                continue;
+            }
+            let expired = entry.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified < cutoff);
+            if expired {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => pruned += 1,
+                    Err(e) => warn!("Failed to delete expired recording {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+        if pruned > 0 {
+            info!("Deleted {} recording file(s) older than {} days", pruned, self.config.retention_days);
+        }
+    }
+
+    fn path(&self, file_name: &str) -> PathBuf {
+        PathBuf::from(&self.config.directory).join(file_name)
+    }
+}
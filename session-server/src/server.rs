@@ -1,51 +1,165 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::{net::{TcpListener, TcpStream}, sync::{Mutex, RwLock}};
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message as WsMessage};
-use futures_util::{StreamExt, SinkExt};
-use tracing::{info, warn, error, debug};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, Mutex, RwLock},
+    time::{Instant, Interval, MissedTickBehavior},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::header::AUTHORIZATION,
+    },
+};
+use tracing::{info, warn, debug};
+use tokio_rustls::TlsAcceptor;
 use uuid::Uuid;
+use chrono::{DateTime, TimeZone, Utc};
 
-use remote_hid_shared::{Message, MessagePayload, MessageType, AuthMessage, SessionControlMessage, ClientInfo};
+use remote_hid_shared::{
+    Message, MessagePayload, MessageType, AuthMessage, SessionControlMessage, StatusMessage, ClientInfo, JoinMode,
+    AuthManager, AuthError, Claims, ClientType, LoginLimiter, RefreshTokenStore, RevocationList, TokenPair, UserStore,
+    Role, ScramCredentials, ScramServer, User, DeviceStore, RecordedMessage, device_subject, tls::certificate_common_name,
+};
 
-use crate::config::Config;
+use crate::config::{CertificateIdentity, Config};
+use crate::connection::{self, ConnectionHandle, ConnectionReader, Transport};
+use crate::recording::Recorder;
+use crate::session::{PendingHandoff, Session, SessionManager};
 
-#[derive(Clone)]
 pub struct SessionServer {
     config: Config,
+    tls: Option<TlsAcceptor>,
+    auth_manager: AuthManager,
+    user_store: RwLock<UserStore>,
+    // Consecutive failed logins, counted per username and per source address
+    user_lockouts: Mutex<LoginLimiter>,
+    address_lockouts: Mutex<LoginLimiter>,
+    refresh_tokens: Mutex<RefreshTokenStore>,
+    // Keys the stand-in challenge for unknown users, so it is stable per username
+    scram_secret: [u8; 16],
+    // Serializes read-modify-write of the devices file, which admin commands edit too
+    devices_lock: Mutex<()>,
+    // Same for the users file, written back when a login upgrades a password hash
+    users_lock: Mutex<()>,
+    // Writes forwarded messages to disk when `recording.enabled` is set
+    recorder: Option<Recorder>,
     state: Arc<ServerState>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// Asks a running server to shut down gracefully
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// Stop accepting connections and drain the existing ones
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
 }
 
 #[derive(Default)]
 struct ServerState {
-    // Map of client_id -> sender channel to HID client
+    // Map of client_id -> HID client connection
     hid_clients: RwLock<HashMap<String, ClientConnection>>,
-    // Map of commander_id -> connection
+    // Map of commander_id -> connection, for controlling commanders and observers alike
     commanders: RwLock<HashMap<String, ClientConnection>>,
-    // Map of session_id -> (commander_id, client_id)
-    sessions: RwLock<HashMap<Uuid, (String, String)>>,
+    // Active sessions; the source of truth for which commander drives which client
+    sessions: RwLock<SessionManager>,
+    // Every open connection, registered or not, so shutdown can reach them all
+    connections: Mutex<HashMap<SocketAddr, ConnectionHandle>>,
 }
 
 #[derive(Clone)]
 struct ClientConnection {
-    peer: SocketAddr,
-    tx: Arc<Mutex<tokio_tungstenite::WebSocketStream<TcpStream>>>,
+    claims: Claims,
+    handle: ConnectionHandle,
+    connected_at: DateTime<Utc>,
+    // Details a HID client registered with; always `None` for commanders
+    client_name: Option<String>,
+    platform: Option<String>,
+    // Whether the HID client's user approves each commander; always `false` for commanders
+    requires_consent: bool,
+}
+
+/// Outcome of a commander asking to control a HID client
+enum Joined {
+    Started(Uuid),
+    /// Held until the HID client's user approves it
+    AwaitingConsent(Uuid),
+    /// Waiting in line for the busy client, at this 1-based position
+    Queued(usize),
+}
+
+impl ClientConnection {
+    fn new(claims: Claims, handle: ConnectionHandle, client_name: Option<String>, platform: Option<String>) -> Self {
+        Self {
+            claims,
+            handle,
+            connected_at: Utc::now(),
+            client_name,
+            platform,
+            requires_consent: false,
+        }
+    }
 }
 
 impl SessionServer {
     pub async fn new(config: Config) -> anyhow::Result<Self> {
+        let revocations = match &config.auth.revocation_file {
+            Some(path) => RevocationList::load(path)?,
+            None => RevocationList::new(),
+        };
+        let auth_manager = crate::keys::auth_manager(&config.auth)?
+            .with_refresh_token_expiry(config.auth.refresh_token_expiry_hours)
+            .with_password_hashing(config.auth.password_hashing.clone())
+            .with_revocation_list(revocations);
+        let user_store = UserStore::load(&config.auth.users_file)?;
+        if user_store.is_empty() {
+            warn!("No user accounts in {}; add one with `session-server user add <username>`", config.auth.users_file);
+        }
+
+        let tls = config.server.tls.as_ref().map(crate::tls::acceptor).transpose()?;
+        let client_ca = config.server.tls.as_ref().and_then(|tls| tls.client_ca_path.as_ref());
+        if !config.auth.client_certificates.is_empty() && client_ca.is_none() {
+            warn!("auth.client_certificates is set but no client CA is configured; certificate logins will fail");
+        }
+        let recorder = config.recording.enabled.then(|| Recorder::start(config.recording.clone())).transpose()?;
+        let lockouts = || Mutex::new(LoginLimiter::new(config.auth.max_failed_attempts, config.auth.lockout_duration_mins));
         Ok(Self {
+            user_lockouts: lockouts(),
+            address_lockouts: lockouts(),
+            refresh_tokens: Mutex::new(RefreshTokenStore::new()),
+            scram_secret: Uuid::new_v4().into_bytes(),
+            devices_lock: Mutex::new(()),
+            users_lock: Mutex::new(()),
+            recorder,
             config,
+            tls,
+            auth_manager,
+            user_store: RwLock::new(user_store),
             state: Arc::new(ServerState::default()),
+            shutdown: Arc::new(watch::channel(false).0),
         })
     }
 
     pub async fn run(self: &Arc<Self>) -> anyhow::Result<()> {
         let addr = format!("{}:{}", self.config.server.host, self.config.server.port);
         let listener = TcpListener::bind(&addr).await?;
-        info!("Listening on {}", addr);
+        info!("Listening on {}://{}", if self.tls.is_some() { "wss" } else { "ws" }, addr);
+        self.serve(listener).await
+    }
 
+    /// Accept connections from an already bound listener until shut down
+    pub async fn serve(self: &Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        tokio::spawn(Arc::clone(self).reap_sessions());
+        tokio::spawn(Arc::clone(self).expire_consent_requests());
         loop {
-            let (stream, peer) = listener.accept().await?;
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown_requested() => break,
+            };
             let server = Arc::clone(self);
             tokio::spawn(async move {
                 if let Err(e) = server.handle_connection(stream, peer).await {
@@ -53,127 +167,1894 @@ impl SessionServer {
                 }
             });
         }
+
+        drop(listener);
+        info!("Shutting down, no longer accepting connections");
+        self.drain().await;
+        Ok(())
+    }
+
+    /// Handle for stopping the server from a signal handler or test
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.shutdown))
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolve once shutdown has been requested
+    async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        // The borrowed value is not `Send`; drop it before returning
+        let _ = shutdown.wait_for(|requested| *requested).await;
+    }
+
+    /// End every session, tell every client the server is going away and give
+    /// queued messages up to the grace period to reach the wire
+    async fn drain(&self) {
+        const REASON: &str = "server shutting down";
+
+        let handles: Vec<ConnectionHandle> = self.state.connections.lock().await.values().cloned().collect();
+
+        let sessions: Vec<(Uuid, String)> = self.state.sessions.read().await
+            .list_sessions()
+            .iter()
+            .map(|session| (session.id, session.hid_client_id.clone()))
+            .collect();
+        for (session_id, _) in &sessions {
+            self.end_session(*session_id, REASON).await;
+        }
+
+        // Connections outside a session have not been told yet: idle HID clients,
+        // commanders still waiting in line and peers that have not registered.
+        // Commanders and observers of the ended sessions are already closed.
+        let ended = Message::session_control(None, SessionControlMessage::SessionEnded {
+            reason: REASON.to_string(),
+        });
+        let told: Vec<SocketAddr> = self.state.hid_clients.read().await.iter()
+            .filter(|(client_id, _)| sessions.iter().any(|(_, in_session)| in_session == *client_id))
+            .map(|(_, conn)| conn.handle.peer())
+            .collect();
+        for handle in &handles {
+            if !handle.is_closed() && !told.contains(&handle.peer()) {
+                handle.send(&ended);
+            }
+        }
+
+        for handle in &handles {
+            handle.close();
+        }
+        let grace = Duration::from_secs(self.config.server.shutdown_grace_secs);
+        let flushed = futures_util::future::join_all(handles.iter().map(ConnectionHandle::flushed));
+        if tokio::time::timeout(grace, flushed).await.is_err() {
+            warn!("Grace period of {:?} elapsed before all connections were flushed", grace);
+        }
+        info!("Closed {} connection(s)", handles.len());
+    }
+
+    /// Periodically end idle sessions and drop connections whose socket is gone
+    async fn reap_sessions(self: Arc<Self>) {
+        let period = Duration::from_secs(self.config.session.cleanup_interval_secs.max(1));
+        let mut ticker = tokio::time::interval(period);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = self.shutdown_requested() => return,
+            }
+
+            let expired = self.state.sessions.write().await.cleanup_expired_sessions(self.config.session.session_timeout_mins);
+            if !expired.is_empty() {
+                info!("Reaped {} idle session(s)", expired.len());
+            }
+            for session in expired {
+                self.notify_session_ended(session, "idle timeout").await;
+            }
+
+            self.reap_orphaned_connections().await;
+        }
+    }
+
+    /// Refuse sessions whose HID client's user has not answered in time
+    async fn expire_consent_requests(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = self.shutdown_requested() => return,
+            }
+
+            let (unanswered, lapsed) = {
+                let mut sessions = self.state.sessions.write().await;
+                (sessions.take_unanswered(Utc::now()), sessions.take_unanswered_handoffs(Utc::now()))
+            };
+            for session in unanswered {
+                self.refuse_session(session, "CONSENT_TIMEOUT", "Nobody at the HID client approved the session in time").await;
+            }
+            for (session_id, handoff) in lapsed {
+                self.refuse_handoff(session_id, &handoff, "nobody at the HID client approved the handoff in time").await;
+            }
+        }
+    }
+
+    /// Remove registered connections whose writer has already shut down
+    async fn reap_orphaned_connections(&self) {
+        remove_closed(&mut *self.state.commanders.write().await, "Commander");
+        let orphaned_clients = remove_closed(&mut *self.state.hid_clients.write().await, "HID client");
+
+        for client_id in orphaned_clients {
+            let session_id = self.state.sessions.read().await.get_session_by_client(&client_id).map(|s| s.id);
+            if let Some(session_id) = session_id {
+                self.end_session(session_id, "HID client disconnected").await;
+            }
+        }
     }
 
     async fn handle_connection(&self, stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
-        let ws_stream = accept_async(stream).await?;
+        // Peers that stall anywhere before logging in are dropped at this deadline
+        let deadline = Instant::now() + Duration::from_secs(self.config.server.handshake_timeout_secs);
+
+        // The verifier has already checked the chain; only the subject is kept for `CertificateLogin`
+        let mut certificate_name = None;
+        let mut bearer = None;
+        let upgrade = async {
+            let stream: Box<dyn Transport> = match &self.tls {
+                Some(acceptor) => {
+                    let stream = acceptor.accept(stream).await?;
+                    certificate_name = stream.get_ref().1.peer_certificates()
+                        .and_then(|chain| chain.first())
+                        .and_then(certificate_common_name);
+                    Box::new(stream)
+                }
+                None => Box::new(stream),
+            };
+
+            // Capture an `Authorization: Bearer` header from the upgrade request, if any
+            #[allow(clippy::result_large_err)]
+            let ws_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+                bearer = request
+                    .headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(|token| token.trim().to_string());
+                Ok(response)
+            })
+            .await?;
+            anyhow::Ok(ws_stream)
+        };
+        let ws_stream = tokio::time::timeout_at(deadline, upgrade).await
+            .map_err(|_| anyhow::anyhow!("{} did not complete the WebSocket handshake in time", peer))??;
         info!("New WebSocket connection from {}", peer);
-        let tx = Arc::new(Mutex::new(ws_stream));
-        
-        // Simple handshake: expect an auth request first
-        // Note: For brevity, this example omits JWT validation; add per DESIGN.md
-        // Read first message for identification
-        let mut guard = tx.lock().await;
-        let msg = match guard.next().await {
-            Some(Ok(WsMessage::Text(text))) => text,
-            Some(Ok(_)) => {
+        let (handle, reader) = connection::split(ws_stream, peer);
+
+        self.state.connections.lock().await.insert(peer, handle.clone());
+        let served = self.serve_connection(handle, reader, peer, bearer, certificate_name, deadline).await;
+        self.state.connections.lock().await.remove(&peer);
+        served
+    }
+
+    async fn serve_connection(
+        &self,
+        handle: ConnectionHandle,
+        mut reader: ConnectionReader,
+        peer: SocketAddr,
+        bearer: Option<String>,
+        certificate_name: Option<String>,
+        handshake_deadline: Instant,
+    ) -> anyhow::Result<()> {
+        // Accepted while the server was draining, after it collected the open connections
+        if self.is_shutting_down() {
+            handle.send(&Message::session_control(None, SessionControlMessage::SessionEnded {
+                reason: "server shutting down".to_string(),
+            }));
+            handle.close();
+            return Ok(());
+        }
+
+        // Nothing but authentication is accepted until the peer holds valid claims
+        let authenticated = tokio::time::timeout_at(
+            handshake_deadline,
+            self.authenticate(&handle, &mut reader, bearer, certificate_name),
+        ).await;
+        let mut claims = match authenticated {
+            Ok(Some(claims)) => claims,
+            Ok(None) => {
+                handle.close();
+                return Ok(());
+            }
+            Err(_) => {
+                warn!("{} did not authenticate within {}s, disconnecting", peer, self.config.server.handshake_timeout_secs);
+                handle.close();
+                return Ok(());
+            }
+        };
+
+        // Wait for the peer to register; commanders may look up clients first
+        let mut heartbeats = self.heartbeat_ticker();
+        let parsed = loop {
+            let Some(message) = self.next_message(&handle, &mut reader, &mut heartbeats, &mut claims).await else {
                 return Ok(());
+            };
+            match message.payload {
+                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                    handle.pong();
+                }
+                MessagePayload::SessionControl(SessionControlMessage::ListClients) => {
+                    self.send_client_list(&handle, &claims).await;
+                }
+                _ => break message,
             }
-            _ => return Ok(()),
         };
-        drop(guard);
 
-        let parsed: Message = serde_json::from_str(&msg)?;
+        // Connections that were still registering when the server drained are not served
+        if self.is_shutting_down() {
+            handle.send(&Message::session_control(None, SessionControlMessage::SessionEnded {
+                reason: "server shutting down".to_string(),
+            }));
+            handle.close();
+            return Ok(());
+        }
+
         match (&parsed.message_type, &parsed.payload) {
-            (MessageType::SessionControl, MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name })) => {
-                self.register_hid_client(client_id.clone(), tx.clone(), peer, client_name.clone()).await;
-                self.serve_hid_client(client_id.clone(), tx.clone(), peer).await
+            (MessageType::SessionControl, MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name, platform, requires_consent })) => {
+                // A token minted for a specific client id may only register that id
+                if claims.client_id.as_deref().is_some_and(|bound| bound != client_id) {
+                    warn!("{} tried to register as {} with a token bound to {:?}", peer, client_id, claims.client_id);
+                    handle.send(&Message::status(None, StatusMessage::Error {
+                        error_code: "CLIENT_ID_MISMATCH".to_string(),
+                        error_message: "Token is not valid for this client id".to_string(),
+                    }));
+                    handle.close();
+                    return Ok(());
+                }
+                // The id of an enrolled device is its own, whatever account a token was minted for
+                if claims.sub != device_subject(client_id) && (self.certificate_client(client_id) || self.device_enrolled(client_id, None).await) {
+                    warn!("{} ({}) tried to register as enrolled device {}", peer, claims.sub, client_id);
+                    handle.send(&Message::status(None, StatusMessage::Error {
+                        error_code: "CLIENT_ID_RESERVED".to_string(),
+                        error_message: "Client id belongs to an enrolled device".to_string(),
+                    }));
+                    handle.close();
+                    return Ok(());
+                }
+                if let Err(error) = self.register_hid_client(client_id.clone(), handle.clone(), claims.clone(), client_name.clone(), platform.clone(), *requires_consent).await {
+                    handle.send(&Message::status(None, error));
+                    handle.close();
+                    return Ok(());
+                }
+                self.serve_hid_client(client_id.clone(), handle, reader, claims).await
             }
-            (MessageType::SessionControl, MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id })) => {
-                self.register_commander(peer.to_string(), tx.clone(), peer).await;
-                self.serve_commander(peer.to_string(), target_client_id.clone(), tx.clone(), peer).await
+            (MessageType::SessionControl, MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id, mode: JoinMode::Observe })) => {
+                let observer_id = peer.to_string();
+                // Registered before attaching so a session ending in between still reaches the observer
+                self.register_commander(observer_id.clone(), handle.clone(), claims.clone()).await;
+                let attached = match self.authorize_join(&claims, target_client_id, JoinMode::Observe) {
+                    Ok(()) => self.attach_observer(&observer_id, &claims.sub, target_client_id).await,
+                    Err(error) => Err(error),
+                };
+                let session_id = match attached {
+                    Ok(session_id) => session_id,
+                    Err(error) => {
+                        self.state.commanders.write().await.remove(&observer_id);
+                        handle.send(&Message::status(None, error));
+                        handle.close();
+                        return Ok(());
+                    }
+                };
+                let joined = Message::session_control(Some(session_id), SessionControlMessage::SessionJoined {
+                    session_id,
+                    target_client_id: target_client_id.clone(),
+                });
+                self.record_sent(session_id, vec![observer_id.clone()], &joined).await;
+                handle.send(&joined);
+                self.serve_commander(observer_id, session_id, handle, reader, claims, None).await
+            }
+            (MessageType::SessionControl, MessagePayload::SessionControl(SessionControlMessage::JoinSession { target_client_id, mode: JoinMode::Control })) => {
+                let commander_id = peer.to_string();
+                // Registered first so a waiter whose turn comes right away is still told
+                self.register_commander(commander_id.clone(), handle.clone(), claims.clone()).await;
+                let started = match self.authorize_join(&claims, target_client_id, JoinMode::Control) {
+                    Ok(()) => self.start_session(&commander_id, target_client_id).await,
+                    Err(error) => Err(error),
+                };
+                match started {
+                    Ok(Joined::Started(session_id)) => {
+                        let joined = Message::session_control(Some(session_id), SessionControlMessage::SessionJoined {
+                            session_id,
+                            target_client_id: target_client_id.clone(),
+                        });
+                        self.record_sent(session_id, vec![commander_id.clone()], &joined).await;
+                        handle.send(&joined);
+                        self.serve_commander(commander_id, session_id, handle, reader, claims, None).await
+                    }
+                    Ok(Joined::AwaitingConsent(session_id)) => {
+                        self.ask_consent(session_id, target_client_id, &commander_id).await;
+                        self.wait_in_queue(commander_id, target_client_id.clone(), handle, reader, claims).await
+                    }
+                    Ok(Joined::Queued(position)) => {
+                        handle.send(&Message::session_control(None, SessionControlMessage::QueuePosition {
+                            target_client_id: target_client_id.clone(),
+                            position,
+                        }));
+                        self.wait_in_queue(commander_id, target_client_id.clone(), handle, reader, claims).await
+                    }
+                    Err(error) => {
+                        self.state.commanders.write().await.remove(&commander_id);
+                        handle.send(&Message::status(None, error));
+                        handle.close();
+                        Ok(())
+                    }
+                }
             }
             _ => {
                 warn!("{} sent unexpected first message: {:?}", peer, parsed.message_type);
+                handle.close();
                 Ok(())
             }
         }
     }
 
-    async fn register_hid_client(&self, client_id: String, tx: Arc<Mutex<tokio_tungstenite::WebSocketStream<TcpStream>>>, peer: SocketAddr, client_name: Option<String>) {
-        let mut map = self.state.hid_clients.write().await;
-        map.insert(client_id.clone(), ClientConnection { peer, tx });
-        info!("Registered HID client {} from {} ({:?})", client_id, peer, client_name);
+    /// Run the authentication handshake on a freshly accepted connection.
+    ///
+    /// The peer either presented a bearer token on the upgrade request or must
+    /// open with a `ScramStart` challenge/response, an `AuthMessage::Refresh`, a
+    /// HID client's `Pair` or `DeviceLogin`, a `CertificateLogin` over mutual TLS,
+    /// or (when `allow_password_login` is set) a legacy `AuthMessage::Request`.
+    /// Returns `None` when authentication fails; the failure has already been
+    /// reported to the peer.
+    async fn authenticate(
+        &self,
+        handle: &ConnectionHandle,
+        reader: &mut ConnectionReader,
+        bearer: Option<String>,
+        certificate_name: Option<String>,
+    ) -> Option<Claims> {
+        let peer = handle.peer();
+        let result = match bearer {
+            Some(token) => self.auth_manager.validate_token(&token).map(|claims| (token, claims, None)),
+            None => {
+                let message = reader.next_message().await?;
+                let issued = match message.payload {
+                    MessagePayload::Auth(AuthMessage::ScramStart { username, client_nonce, client_type, client_id }) => {
+                        self.scram_login(handle, reader, &username, &client_nonce, client_type, client_id).await
+                    }
+                    MessagePayload::Auth(AuthMessage::Pair { pairing_code, client_id }) => {
+                        self.pair_device(handle, &pairing_code, &client_id).await
+                    }
+                    MessagePayload::Auth(AuthMessage::DeviceLogin { client_id, device_secret }) => {
+                        self.device_login(&client_id, &device_secret, peer).await
+                    }
+                    MessagePayload::Auth(AuthMessage::CertificateLogin { client_type, client_id }) => {
+                        self.certificate_login(handle, reader, certificate_name.as_deref(), client_type, client_id).await
+                    }
+                    MessagePayload::Auth(AuthMessage::Request { username, password, client_type, client_id }) => {
+                        if self.config.auth.allow_password_login {
+                            self.login(handle, reader, &username, &password, client_type, client_id).await
+                        } else {
+                            warn!(username, "Rejected password login from {}; only challenge/response is enabled", peer);
+                            Err(AuthError::PasswordLoginDisabled)
+                        }
+                    }
+                    MessagePayload::Auth(AuthMessage::Refresh { refresh_token }) => {
+                        self.refresh(&refresh_token, None).await
+                    }
+                    _ => {
+                        warn!("{} sent {:?} before authenticating", peer, message.message_type);
+                        handle.send(&Message::status(None, StatusMessage::Error {
+                            error_code: "AUTH_REQUIRED".to_string(),
+                            error_message: "Authenticate before sending session or HID traffic".to_string(),
+                        }));
+                        return None;
+                    }
+                };
+                issued.map(|pair| (pair.access_token, pair.access_claims, Some(pair.refresh_token)))
+            }
+        };
+
+        match result {
+            Ok((token, claims, refresh_token)) => {
+                info!("{} authenticated as {} ({})", peer, claims.sub, claims.client_type);
+                handle.send(&auth_success(token, &claims, refresh_token));
+                Some(claims)
+            }
+            Err(e) => {
+                warn!("Authentication failed for {}: {}", peer, e);
+                handle.send(&auth_failure(&e));
+                None
+            }
+        }
     }
 
-    async fn register_commander(&self, commander_id: String, tx: Arc<Mutex<tokio_tungstenite::WebSocketStream<TcpStream>>>, peer: SocketAddr) {
-        let mut map = self.state.commanders.write().await;
-        map.insert(commander_id.clone(), ClientConnection { peer, tx });
-        info!("Registered Commander {} from {}", commander_id, peer);
+    /// Verify username/password and mint a token pair for the session.
+    /// Repeated failures lock out the username and the source address.
+    async fn login(
+        &self,
+        handle: &ConnectionHandle,
+        reader: &mut ConnectionReader,
+        username: &str,
+        password: &str,
+        client_type: ClientType,
+        client_id: Option<String>,
+    ) -> Result<TokenPair, AuthError> {
+        let peer = handle.peer();
+        self.check_lockout(username, peer).await?;
+        self.reload_users().await;
+
+        let (valid, upgraded) = {
+            let mut users = self.user_store.write().await;
+            let stored = users.get_user(username)
+                .map(|user| (user.password_hash.clone(), user.scram.as_ref().map(|scram| scram.salt.clone())));
+            let valid = users.authenticate(username, password, &self.auth_manager)?;
+            let upgraded = match (valid, stored, users.get_user(username)) {
+                (true, Some((old_hash, old_salt)), Some(user))
+                    if user.password_hash != old_hash || user.scram.as_ref().map(|scram| &scram.salt) != old_salt.as_ref() =>
+                {
+                    Some((old_hash, user.clone()))
+                }
+                _ => None,
+            };
+            (valid, upgraded)
+        };
+        if !valid {
+            self.record_login_failure(username, peer).await;
+            return Err(AuthError::InvalidCredentials);
+        }
+        if let Some((old_hash, user)) = upgraded {
+            self.save_upgraded_user(&old_hash, &user).await;
+        }
+        self.second_factor(handle, reader, username).await?;
+        self.record_login_success(username, peer).await;
+
+        self.issue_tokens(username, &client_type.to_string(), client_id).await
     }
 
-    async fn serve_hid_client(&self, client_id: String, tx: Arc<Mutex<tokio_tungstenite::WebSocketStream<TcpStream>>>, peer: SocketAddr) -> anyhow::Result<()> {
-        let mut rx = tx.lock().await;
-        while let Some(msg) = rx.next().await {
-            match msg {
-                Ok(WsMessage::Text(text)) => {
-                    if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                        debug!("HID client {} -> server: {:?}", client_id, message.message_type);
-                        // For now we only handle status/heartbeat from HID client
-                    }
+    /// Re-read the users file before a login or refresh, so accounts added, changed,
+    /// disabled or removed with `session-server user` apply without a restart.
+    /// If the file cannot be read, the accounts last loaded stay in use.
+    async fn reload_users(&self) {
+        if let Err(e) = self.user_store.write().await.reload() {
+            warn!("Could not reload {}: {}", self.config.auth.users_file, e);
+        }
+    }
+
+    /// Write a password hash upgraded during login back to the users file.
+    ///
+    /// The file is re-read first so admin edits are kept, and left alone if the
+    /// password was changed in the meantime.
+    async fn save_upgraded_user(&self, old_hash: &str, user: &User) {
+        let path = &self.config.auth.users_file;
+        let _guard = self.users_lock.lock().await;
+        let mut store = match UserStore::load(path) {
+            Ok(store) => store,
+            Err(e) => {
+                warn!("Could not load {} to save an upgraded password hash: {}", path, e);
+                return;
+            }
+        };
+        let Some(stored) = store.get_user_mut(&user.username).filter(|stored| stored.password_hash == old_hash) else {
+            return;
+        };
+        stored.password_hash = user.password_hash.clone();
+        stored.scram = user.scram.clone();
+        match store.save() {
+            Ok(()) => info!(username = %user.username, "Upgraded stored password hash"),
+            Err(e) => warn!("Could not save upgraded password hash to {}: {}", path, e),
+        }
+    }
+
+    /// Run the challenge/response exchange opened by a `ScramStart` and mint a token pair.
+    /// The server signature is sent before the caller sends the token `Response`.
+    async fn scram_login(
+        &self,
+        handle: &ConnectionHandle,
+        reader: &mut ConnectionReader,
+        username: &str,
+        client_nonce: &str,
+        client_type: ClientType,
+        client_id: Option<String>,
+    ) -> Result<TokenPair, AuthError> {
+        let peer = handle.peer();
+        self.check_lockout(username, peer).await?;
+        self.reload_users().await;
+
+        // Unknown and disabled users get a challenge too, so it does not reveal which usernames exist
+        let credentials = self.user_store.read().await.scram_credentials(username).cloned()
+            .unwrap_or_else(|| {
+                ScramCredentials::unknown_user(username, &self.scram_secret, self.config.auth.password_hashing.scram_iterations)
+            });
+        let exchange = ScramServer::new(username, client_nonce, credentials);
+        handle.send(&Message::auth(exchange.challenge()));
+
+        let Some(message) = reader.next_message().await else {
+            return Err(AuthError::InvalidCredentials);
+        };
+        let MessagePayload::Auth(AuthMessage::ScramProof { nonce, proof }) = message.payload else {
+            warn!("{} sent {:?} instead of a challenge proof", peer, message.message_type);
+            return Err(AuthError::InvalidCredentials);
+        };
+
+        let server_signature = match exchange.verify(&nonce, &proof) {
+            Ok(signature) => signature,
+            Err(e) => {
+                self.record_login_failure(username, peer).await;
+                return Err(e);
+            }
+        };
+
+        // The server proves itself before the client is asked for a one-time code
+        handle.send(&Message::auth(AuthMessage::ScramServerFinal { server_signature }));
+        self.second_factor(handle, reader, username).await?;
+        self.record_login_success(username, peer).await;
+        if let Some(user) = self.user_store.write().await.get_user_mut(username) {
+            user.update_last_login();
+        }
+        self.issue_tokens(username, &client_type.to_string(), client_id).await
+    }
+
+    /// Enroll a HID client: spend its pairing code, send it the new device
+    /// credential and mint tokens bound to its client id
+    async fn pair_device(&self, handle: &ConnectionHandle, pairing_code: &str, client_id: &str) -> Result<TokenPair, AuthError> {
+        let peer = handle.peer();
+        let subject = device_subject(client_id);
+        self.check_lockout(&subject, peer).await?;
+
+        let paired = {
+            let _guard = self.devices_lock.lock().await;
+            let mut devices = DeviceStore::load(&self.config.auth.devices_file).map_err(|e| {
+                warn!("Could not load {}: {}", self.config.auth.devices_file, e);
+                AuthError::InvalidCredentials
+            })?;
+            devices.pair(pairing_code, client_id).and_then(|secret| match devices.save() {
+                Ok(()) => Ok(secret),
+                Err(e) => {
+                    warn!("Could not save {}: {}", self.config.auth.devices_file, e);
+                    Err(AuthError::InvalidCredentials)
                 }
-                Ok(WsMessage::Close(_)) => {
-                    info!("HID client {} disconnected", client_id);
-                    break;
+            })
+        };
+        let device_secret = match paired {
+            Ok(secret) => secret,
+            Err(e) => {
+                self.record_login_failure(&subject, peer).await;
+                return Err(e);
+            }
+        };
+        self.record_login_success(&subject, peer).await;
+        info!(client_id, "Enrolled HID client from {}", peer);
+
+        handle.send(&Message::auth(AuthMessage::Paired { client_id: client_id.to_string(), device_secret }));
+        self.issue_tokens(&subject, &ClientType::HidClient.to_string(), Some(client_id.to_string())).await
+    }
+
+    /// Authenticate a paired HID client by its device credential
+    async fn device_login(&self, client_id: &str, device_secret: &str, peer: SocketAddr) -> Result<TokenPair, AuthError> {
+        let subject = device_subject(client_id);
+        self.check_lockout(&subject, peer).await?;
+
+        if !self.device_enrolled(client_id, Some(device_secret)).await {
+            self.record_login_failure(&subject, peer).await;
+            return Err(AuthError::InvalidCredentials);
+        }
+        self.record_login_success(&subject, peer).await;
+        self.issue_tokens(&subject, &ClientType::HidClient.to_string(), Some(client_id.to_string())).await
+    }
+
+    /// Whether a device is enrolled, and if a secret is given, whether it matches.
+    /// The file is re-read so admin changes apply without a restart.
+    async fn device_enrolled(&self, client_id: &str, device_secret: Option<&str>) -> bool {
+        let _guard = self.devices_lock.lock().await;
+        match DeviceStore::load(&self.config.auth.devices_file) {
+            Ok(devices) => match device_secret {
+                Some(secret) => devices.authenticate(client_id, secret),
+                None => devices.get_device(client_id).is_some(),
+            },
+            Err(e) => {
+                warn!("Could not load {}: {}", self.config.auth.devices_file, e);
+                false
+            }
+        }
+    }
+
+    /// Authenticate as the user or HID client the peer's TLS client certificate is mapped to
+    async fn certificate_login(
+        &self,
+        handle: &ConnectionHandle,
+        reader: &mut ConnectionReader,
+        certificate_name: Option<&str>,
+        client_type: ClientType,
+        client_id: Option<String>,
+    ) -> Result<TokenPair, AuthError> {
+        let peer = handle.peer();
+        let Some(name) = certificate_name else {
+            warn!("{} asked for a certificate login without presenting a client certificate", peer);
+            return Err(AuthError::InvalidCredentials);
+        };
+        match self.config.auth.client_certificates.get(name) {
+            Some(CertificateIdentity::User(username)) => {
+                self.reload_users().await;
+                if !self.user_store.read().await.get_user(username).is_some_and(|user| user.active) {
+                    warn!(username, "Certificate {:?} from {} maps to an unknown or disabled user", name, peer);
+                    return Err(AuthError::InvalidCredentials);
+                }
+                self.check_lockout(username, peer).await?;
+                self.second_factor(handle, reader, username).await?;
+                self.record_login_success(username, peer).await;
+                if let Some(user) = self.user_store.write().await.get_user_mut(username) {
+                    user.update_last_login();
+                }
+                self.issue_tokens(username, &client_type.to_string(), client_id).await
+            }
+            Some(CertificateIdentity::Client(bound)) => {
+                if !matches!(client_type, ClientType::HidClient) || client_id.as_ref().is_some_and(|id| id != bound) {
+                    warn!("Certificate {:?} from {} is only valid for HID client {}", name, peer, bound);
+                    return Err(AuthError::InvalidCredentials);
+                }
+                self.issue_tokens(&device_subject(bound), &ClientType::HidClient.to_string(), Some(bound.clone())).await
+            }
+            None => {
+                warn!("Certificate {:?} from {} is not mapped to a user or client", name, peer);
+                Err(AuthError::InvalidCredentials)
+            }
+        }
+    }
+
+    /// Ask for a one-time code if the account has TOTP enrolled, whichever client type
+    /// is logging in; only paired devices and HID client certificates, which have no
+    /// account, are never asked. Wrong codes count towards the login lockout like wrong passwords.
+    async fn second_factor(&self, handle: &ConnectionHandle, reader: &mut ConnectionReader, username: &str) -> Result<(), AuthError> {
+        if !self.user_store.read().await.requires_totp(username) {
+            return Ok(());
+        }
+        handle.send(&Message::auth(AuthMessage::TotpRequired));
+
+        let Some(message) = reader.next_message().await else {
+            return Err(AuthError::InvalidTotpCode);
+        };
+        let MessagePayload::Auth(AuthMessage::TotpCode { code }) = message.payload else {
+            warn!("{} sent {:?} instead of a one-time code", handle.peer(), message.message_type);
+            return Err(AuthError::InvalidTotpCode);
+        };
+        if !self.user_store.write().await.verify_totp(username, &code) {
+            self.record_login_failure(username, handle.peer()).await;
+            return Err(AuthError::InvalidTotpCode);
+        }
+        Ok(())
+    }
+
+    /// Whether a client certificate is configured to authenticate as this HID client
+    fn certificate_client(&self, client_id: &str) -> bool {
+        self.config.auth.client_certificates.values()
+            .any(|identity| matches!(identity, CertificateIdentity::Client(bound) if bound == client_id))
+    }
+
+    /// Reject a login attempt while the username or source address is locked out
+    async fn check_lockout(&self, username: &str, peer: SocketAddr) -> Result<(), AuthError> {
+        let address = peer.ip().to_string();
+        let locked_until = self.user_lockouts.lock().await.locked_until(username)
+            .max(self.address_lockouts.lock().await.locked_until(&address));
+        match locked_until {
+            Some(until) => {
+                warn!(username, address = %address, locked_until = %until, "Rejected login attempt during lockout");
+                Err(AuthError::LockedOut { until })
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn record_login_failure(&self, username: &str, peer: SocketAddr) {
+        let address = peer.ip().to_string();
+        let mut user_lockouts = self.user_lockouts.lock().await;
+        let mut address_lockouts = self.address_lockouts.lock().await;
+        let user_locked = user_lockouts.record_failure(username);
+        let address_locked = address_lockouts.record_failure(&address);
+        if let Some(until) = user_locked.max(address_locked) {
+            warn!(
+                username,
+                address = %address,
+                user_failures = user_lockouts.failure_count(username),
+                address_failures = address_lockouts.failure_count(&address),
+                locked_until = %until,
+                "Locking out after repeated failed logins"
+            );
+        }
+    }
+
+    async fn record_login_success(&self, username: &str, peer: SocketAddr) {
+        self.user_lockouts.lock().await.record_success(username);
+        self.address_lockouts.lock().await.record_success(&peer.ip().to_string());
+    }
+
+    /// Mint an access/refresh token pair carrying the user's current scopes and remember the refresh token
+    async fn issue_tokens(&self, username: &str, client_type: &str, client_id: Option<String>) -> Result<TokenPair, AuthError> {
+        let scopes = self.user_store.read().await.get_user(username).map(User::scopes).unwrap_or_default();
+        let pair = self.auth_manager.generate_token_pair(username, client_type, client_id, scopes)?;
+        self.refresh_tokens.lock().await.insert(&pair);
+        Ok(pair)
+    }
+
+    /// Exchange a refresh token for a new pair, consuming it.
+    /// On a live connection the token must belong to the identity already authenticated.
+    async fn refresh(&self, refresh_token: &str, current: Option<&Claims>) -> Result<TokenPair, AuthError> {
+        let claims = self.auth_manager.validate_refresh_token(refresh_token)?;
+        if current.is_some_and(|current| {
+            current.sub != claims.sub || current.client_type != claims.client_type || current.client_id != claims.client_id
+        }) {
+            return Err(AuthError::InvalidToken);
+        }
+        if !self.refresh_tokens.lock().await.redeem(&claims) {
+            warn!(username = %claims.sub, jti = %claims.jti, "Rejected refresh token that was already used or revoked");
+            return Err(AuthError::InvalidToken);
+        }
+        let active = match claims.client_id.as_deref() {
+            Some(client_id) if claims.sub == device_subject(client_id) => {
+                self.certificate_client(client_id) || self.device_enrolled(client_id, None).await
+            }
+            _ => {
+                self.reload_users().await;
+                self.user_store.read().await.get_user(&claims.sub).is_some_and(|user| user.active)
+            }
+        };
+        if !active {
+            return Err(AuthError::InvalidCredentials);
+        }
+        self.issue_tokens(&claims.sub, &claims.client_type, claims.client_id).await
+    }
+
+    /// Handle `Refresh`, `Logout` and `Revoke` from an authenticated peer.
+    /// Returns `false` once the peer has logged out.
+    async fn handle_auth_message(&self, handle: &ConnectionHandle, claims: &mut Claims, auth: AuthMessage) -> bool {
+        match auth {
+            AuthMessage::Refresh { refresh_token } => match self.refresh(&refresh_token, Some(claims)).await {
+                Ok(pair) => {
+                    debug!("Refreshed token for {} ({})", claims.sub, handle.peer());
+                    *claims = pair.access_claims;
+                    self.update_claims(handle, claims).await;
+                    handle.send(&auth_success(pair.access_token, claims, Some(pair.refresh_token)));
                 }
-                Ok(_) => {}
                 Err(e) => {
-                    error!("HID client {} error: {}", client_id, e);
+                    warn!("Token refresh failed for {} ({}): {}", claims.sub, handle.peer(), e);
+                    handle.send(&auth_failure(&e));
+                }
+            },
+            AuthMessage::Logout => {
+                if let Err(e) = self.auth_manager.revoke_token(&claims.jti) {
+                    warn!("Failed to persist revocation of {}: {}", claims.jti, e);
+                }
+                self.refresh_tokens.lock().await.revoke_for_access_token(&claims.jti);
+                info!("{} logged out from {}", claims.sub, handle.peer());
+                handle.close();
+                return false;
+            }
+            AuthMessage::Revoke { jti, username } => {
+                if !claims.has_role(Role::Admin) {
+                    warn!("{} ({}) is not allowed to revoke tokens", claims.sub, handle.peer());
+                    handle.send(&Message::status(None, StatusMessage::Error {
+                        error_code: "FORBIDDEN".to_string(),
+                        error_message: "Only admin users may revoke tokens".to_string(),
+                    }));
+                } else if jti.is_none() && username.is_none() {
+                    handle.send(&Message::status(None, StatusMessage::Error {
+                        error_code: "INVALID_REQUEST".to_string(),
+                        error_message: "Revoke needs a jti or a username".to_string(),
+                    }));
+                } else {
+                    let disconnected = self.revoke(&claims.sub, jti.as_deref(), username.as_deref()).await;
+                    handle.send(&Message::auth(AuthMessage::Revoked { disconnected }));
+                }
+            }
+            other => debug!("Ignoring {:?} from authenticated peer {}", other, handle.peer()),
+        }
+        true
+    }
+
+    /// Revoke a token and/or every token of a user, then disconnect the live
+    /// connections that relied on them. Returns how many were disconnected.
+    async fn revoke(&self, admin: &str, jti: Option<&str>, username: Option<&str>) -> usize {
+        if let Some(jti) = jti {
+            if let Err(e) = self.auth_manager.revoke_token(jti) {
+                warn!("Failed to persist revocation of {}: {}", jti, e);
+            }
+            self.refresh_tokens.lock().await.revoke_for_access_token(jti);
+        }
+        if let Some(username) = username {
+            if let Err(e) = self.auth_manager.revoke_user(username) {
+                warn!("Failed to persist revocation of {}'s tokens: {}", username, e);
+            }
+        }
+
+        let mut disconnected = 0;
+        for connections in [&self.state.hid_clients, &self.state.commanders] {
+            for conn in connections.read().await.values() {
+                if self.auth_manager.is_revoked(&conn.claims) {
+                    conn.handle.send(&auth_failure(&AuthError::TokenRevoked));
+                    conn.handle.close();
+                    disconnected += 1;
+                }
+            }
+        }
+        warn!(admin, jti, username, disconnected, "Revoked tokens");
+        disconnected
+    }
+
+    /// Replace the claims recorded for a registered connection after a refresh
+    async fn update_claims(&self, handle: &ConnectionHandle, claims: &Claims) {
+        for connections in [&self.state.hid_clients, &self.state.commanders] {
+            if let Some(conn) = connections.write().await.values_mut().find(|conn| conn.handle.peer() == handle.peer()) {
+                conn.claims = claims.clone();
+            }
+        }
+    }
+
+    /// Register a HID client under its id. Only the identity already connected
+    /// under that id may take it over, e.g. after a network drop; the older
+    /// connection is closed and its session ended.
+    async fn register_hid_client(&self, client_id: String, handle: ConnectionHandle, claims: Claims, client_name: Option<String>, platform: Option<String>, requires_consent: bool) -> Result<(), StatusMessage> {
+        let peer = handle.peer();
+        let replaced = {
+            let mut map = self.state.hid_clients.write().await;
+            if let Some(live) = map.get(&client_id).filter(|conn| !conn.handle.is_closed() && conn.claims.sub != claims.sub) {
+                warn!("{} ({}) tried to register as HID client {}, connected from {} as {}", peer, claims.sub, client_id, live.handle.peer(), live.claims.sub);
+                return Err(StatusMessage::Error {
+                    error_code: "CLIENT_ID_IN_USE".to_string(),
+                    error_message: format!("HID client {} is already connected", client_id),
+                });
+            }
+            info!("Registered HID client {} from {} ({:?} on {:?}) for user {}", client_id, peer, client_name, platform, claims.sub);
+            map.insert(client_id.clone(), ClientConnection {
+                requires_consent,
+                ..ClientConnection::new(claims, handle, client_name, platform)
+            })
+        };
+        if let Some(old) = replaced.filter(|old| !old.handle.is_closed()) {
+            info!("HID client {} reconnected from {}; closing its connection from {}", client_id, peer, old.handle.peer());
+            old.handle.close();
+            let session_id = self.state.sessions.read().await.get_session_by_client(&client_id).map(|s| s.id);
+            if let Some(session_id) = session_id {
+                self.end_session(session_id, "HID client reconnected").await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn register_commander(&self, commander_id: String, handle: ConnectionHandle, claims: Claims) {
+        info!("Registered Commander {} from {} for user {}", commander_id, handle.peer(), claims.sub);
+        let mut map = self.state.commanders.write().await;
+        map.insert(commander_id, ClientConnection::new(claims, handle, None, None));
+    }
+
+    /// Connected HID clients the holder of `claims` may reach, with whether a
+    /// commander is currently driving each and who is watching
+    async fn client_list(&self, claims: &Claims) -> Vec<ClientInfo> {
+        let hid_clients = self.state.hid_clients.read().await;
+        let sessions = self.state.sessions.read().await;
+        let mut clients: Vec<ClientInfo> = hid_clients
+            .iter()
+            .filter(|(client_id, _)| claims.may_access_client(client_id, self.client_tags(client_id)))
+            .map(|(client_id, conn)| {
+                let session = sessions.get_session_by_client(client_id);
+                let mut observers: Vec<String> = session.map_or_else(Vec::new, |s| s.observers.values().cloned().collect());
+                observers.sort();
+                ClientInfo {
+                    client_id: client_id.clone(),
+                    client_name: conn.client_name.clone(),
+                    platform: conn.platform.clone().unwrap_or_else(|| "unknown".to_string()),
+                    connected_at: conn.connected_at,
+                    commander_connected: session.is_some(),
+                    observers,
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    async fn send_client_list(&self, handle: &ConnectionHandle, claims: &Claims) {
+        let clients = self.client_list(claims).await;
+        debug!("Sending list of {} client(s) to {}", clients.len(), handle.peer());
+        handle.send(&Message::session_control(None, SessionControlMessage::ClientList { clients }));
+    }
+
+    /// Tags assigned to a HID client in the server config
+    fn client_tags(&self, client_id: &str) -> &[String] {
+        self.config.client_tags.get(client_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Check that a commander may take control of, or watch, the target client
+    fn authorize_join(&self, claims: &Claims, target_client_id: &str, mode: JoinMode) -> Result<(), StatusMessage> {
+        let reason = if mode == JoinMode::Control && !claims.has_role(Role::Control) {
+            "Your account may not take control of HID clients"
+        } else if mode == JoinMode::Observe && !claims.has_role(Role::View) {
+            "Your account may not watch HID clients"
+        } else if !claims.may_access_client(target_client_id, self.client_tags(target_client_id)) {
+            "Your account may not access this HID client"
+        } else {
+            return Ok(());
+        };
+        warn!(username = %claims.sub, target_client_id, "Refused session: {}", reason);
+        Err(StatusMessage::Error {
+            error_code: "FORBIDDEN".to_string(),
+            error_message: reason.to_string(),
+        })
+    }
+
+    /// Open a session between a commander and a connected HID client, or put the
+    /// commander in line if another one is already controlling it
+    async fn start_session(&self, commander_id: &str, target_client_id: &str) -> Result<Joined, StatusMessage> {
+        // Hold the client map so the target cannot disconnect while the session is created
+        let hid_clients = self.state.hid_clients.read().await;
+        let Some(target) = hid_clients.get(target_client_id) else {
+            return Err(StatusMessage::Error {
+                error_code: "CLIENT_NOT_CONNECTED".to_string(),
+                error_message: format!("HID client {} is not connected", target_client_id),
+            });
+        };
+
+        let mut sessions = self.state.sessions.write().await;
+        let max_sessions = self.config.session.max_sessions;
+        // Waiting for a busy client does not add a session
+        let busy = sessions.get_session_by_client(target_client_id).is_some();
+        if !busy && sessions.list_sessions().len() >= max_sessions {
+            warn!("Refusing session for {}: limit of {} sessions reached", commander_id, max_sessions);
+            return Err(StatusMessage::Error {
+                error_code: "SESSION_LIMIT".to_string(),
+                error_message: format!("Server is at its limit of {} sessions", max_sessions),
+            });
+        }
+
+        match sessions.create_session(commander_id.to_string(), target_client_id.to_string()) {
+            Ok(session_id) if target.requires_consent => {
+                self.hold_for_consent(&mut sessions, session_id);
+                info!("Commander {} waiting for approval to control HID client {} in session {}", commander_id, target_client_id, session_id);
+                Ok(Joined::AwaitingConsent(session_id))
+            }
+            Ok(session_id) => {
+                info!("Commander {} controlling HID client {} in session {}", commander_id, target_client_id, session_id);
+                Ok(Joined::Started(session_id))
+            }
+            Err(error_message) => {
+                if sessions.queue(target_client_id).len() >= self.config.session.max_queue_length {
+                    return Err(StatusMessage::Error {
+                        error_code: "CLIENT_BUSY".to_string(),
+                        error_message,
+                    });
+                }
+                let position = sessions.enqueue(target_client_id, commander_id.to_string());
+                info!("Commander {} waiting for HID client {} at position {}", commander_id, target_client_id, position);
+                Ok(Joined::Queued(position))
+            }
+        }
+    }
+
+    /// Keep a commander waiting in line for a busy HID client, or for the client's
+    /// user to approve it. Once its session is established it is sent `SessionJoined`
+    /// and served as the session's commander; until then it may list clients or leave.
+    async fn wait_in_queue(&self, commander_id: String, target_client_id: String, handle: ConnectionHandle, mut reader: ConnectionReader, mut claims: Claims) -> anyhow::Result<()> {
+        let mut heartbeats = self.heartbeat_ticker();
+        while let Some(message) = self.next_message(&handle, &mut reader, &mut heartbeats, &mut claims).await {
+            // Promoted and approved since the last message: serve the session, starting with this one
+            let promoted = {
+                let sessions = self.state.sessions.read().await;
+                sessions.session_of_commander(&commander_id)
+                    .and_then(|session_id| sessions.get_session(session_id))
+                    .filter(|session| !session.awaiting_consent())
+                    .map(|session| session.id)
+            };
+            if let Some(session_id) = promoted {
+                return self.serve_commander(commander_id, session_id, handle, reader, claims, Some(message)).await;
+            }
+            match message.payload {
+                MessagePayload::SessionControl(SessionControlMessage::LeaveQueue | SessionControlMessage::EndSession) => {
+                    handle.send(&Message::session_control(None, SessionControlMessage::SessionEnded {
+                        reason: "left the queue".to_string(),
+                    }));
+                    handle.close();
                     break;
                 }
+                MessagePayload::SessionControl(SessionControlMessage::ListClients) => {
+                    self.send_client_list(&handle, &claims).await;
+                }
+                MessagePayload::HidEvent(_) => {
+                    handle.send(&Message::status(None, StatusMessage::Error {
+                        error_code: "QUEUED".to_string(),
+                        error_message: format!("Still waiting for HID client {}", target_client_id),
+                    }));
+                }
+                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                    handle.pong();
+                }
+                _ => {}
+            }
+        }
+        info!("Commander {} stopped waiting for HID client {}", commander_id, target_client_id);
+
+        let left = self.state.sessions.write().await.leave_queue(&target_client_id, &commander_id);
+        if left {
+            self.send_queue_positions(&target_client_id).await;
+        } else {
+            // Its turn came after its last message, or it was still waiting for approval
+            let promoted = self.state.sessions.read().await.session_of_commander(&commander_id);
+            if let Some(session_id) = promoted {
+                self.leave_session(&commander_id, session_id, "commander disconnected").await;
             }
         }
-        // Cleanup
-        self.state.hid_clients.write().await.remove(&client_id);
+        if let Some(conn) = self.state.commanders.write().await.remove(&commander_id) {
+            conn.handle.close();
+        }
         Ok(())
     }
 
-    async fn serve_commander(&self, commander_id: String, target_client_id: String, tx: Arc<Mutex<tokio_tungstenite::WebSocketStream<TcpStream>>>, peer: SocketAddr) -> anyhow::Result<()> {
-        // Create a session id
-        let session_id = Uuid::new_v4();
-        self.state.sessions.write().await.insert(session_id, (commander_id.clone(), target_client_id.clone()));
-        
-        info!("Commander {} controlling HID client {} in session {}", commander_id, target_client_id, session_id);
-        
-        // Forward messages from commander to target HID client
-        let mut commander_ws = tx.lock().await;
-        while let Some(msg) = commander_ws.next().await {
-            match msg {
-                Ok(WsMessage::Text(text)) => {
-                    if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                        match message.message_type {
-                            MessageType::HidEvent => {
-                                // Forward to HID client
-                                if let Some(conn) = self.state.hid_clients.read().await.get(&target_client_id).cloned() {
-                                    let mut hid_ws = conn.tx.lock().await;
-                                    if let Err(e) = hid_ws.send(WsMessage::Text(text)).await {
-                                        error!("Failed to forward to HID client {}: {}", target_client_id, e);
-                                    }
-                                } else {
-                                    warn!("HID client {} not connected", target_client_id);
-                                }
+    /// Tell everyone waiting for a HID client where they are in line
+    async fn send_queue_positions(&self, client_id: &str) {
+        let queue = self.state.sessions.read().await.queue(client_id);
+        let commanders = self.state.commanders.read().await;
+        for (index, commander_id) in queue.iter().enumerate() {
+            if let Some(conn) = commanders.get(commander_id) {
+                conn.handle.send(&Message::session_control(None, SessionControlMessage::QueuePosition {
+                    target_client_id: client_id.to_string(),
+                    position: index + 1,
+                }));
+            }
+        }
+    }
+
+    /// Hand a HID client that has just become free to the next commander in line.
+    /// If the client is gone, or the server is going away, everyone waiting is sent away.
+    async fn promote_waiter(&self, client_id: &str) {
+        let promoted = {
+            // Hold the client map so the client cannot disconnect while the session is created
+            let hid_clients = self.state.hid_clients.read().await;
+            let mut sessions = self.state.sessions.write().await;
+            match hid_clients.get(client_id) {
+                Some(conn) if !self.is_shutting_down() => {
+                    let promoted = sessions.promote_next(client_id);
+                    if let Some((session_id, _)) = promoted.as_ref().filter(|_| conn.requires_consent) {
+                        self.hold_for_consent(&mut sessions, *session_id);
+                    }
+                    Ok(promoted.map(|(session_id, commander_id)| (session_id, commander_id, conn.requires_consent)))
+                }
+                _ => Err(sessions.take_queue(client_id)),
+            }
+        };
+        match promoted {
+            Ok(Some((session_id, commander_id, true))) => {
+                info!("Commander {} is next in line; waiting for approval to control HID client {} in session {}", commander_id, client_id, session_id);
+                self.ask_consent(session_id, client_id, &commander_id).await;
+                self.send_queue_positions(client_id).await;
+            }
+            Ok(Some((session_id, commander_id, false))) => {
+                info!("Commander {} is next in line; controlling HID client {} in session {}", commander_id, client_id, session_id);
+                let joined = Message::session_control(Some(session_id), SessionControlMessage::SessionJoined {
+                    session_id,
+                    target_client_id: client_id.to_string(),
+                });
+                self.record_sent(session_id, vec![commander_id.clone()], &joined).await;
+                if let Some(conn) = self.state.commanders.read().await.get(&commander_id) {
+                    conn.handle.send(&joined);
+                }
+                self.send_queue_positions(client_id).await;
+            }
+            Ok(None) => {}
+            Err(waiting) => {
+                let reason = if self.is_shutting_down() { "server shutting down" } else { "HID client disconnected" };
+                let ended = Message::session_control(None, SessionControlMessage::SessionEnded { reason: reason.to_string() });
+                let commanders = self.state.commanders.read().await;
+                for commander_id in waiting {
+                    if let Some(conn) = commanders.get(&commander_id) {
+                        conn.handle.send(&ended);
+                        conn.handle.close();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mark a freshly created session as held until the HID client's user approves it
+    fn hold_for_consent(&self, sessions: &mut SessionManager, session_id: Uuid) {
+        let timeout = chrono::Duration::seconds(self.config.session.consent_timeout_secs as i64);
+        if let Some(session) = sessions.get_session_mut(session_id) {
+            session.consent_deadline = Some(Utc::now() + timeout);
+        }
+    }
+
+    /// Ask the HID client's user to approve the commander of a held session, and
+    /// tell the commander it is waiting for them
+    async fn ask_consent(&self, session_id: Uuid, client_id: &str, commander_id: &str) {
+        let timeout_secs = self.config.session.consent_timeout_secs;
+        let username = {
+            let commanders = self.state.commanders.read().await;
+            // A commander that is already gone ends the session when its connection is cleaned up
+            let Some(conn) = commanders.get(commander_id) else { return };
+            conn.handle.send(&Message::session_control(None, SessionControlMessage::AwaitingConsent {
+                target_client_id: client_id.to_string(),
+                timeout_secs,
+            }));
+            conn.claims.sub.clone()
+        };
+        let request = Message::session_control(Some(session_id), SessionControlMessage::ConsentRequest {
+            session_id,
+            username,
+            timeout_secs,
+        });
+        self.record_sent(session_id, vec![client_id.to_string()], &request).await;
+        if let Some(conn) = self.state.hid_clients.read().await.get(client_id) {
+            conn.handle.send(&request);
+        }
+    }
+
+    /// Act on the HID client's user approving or declining a held session or handoff
+    async fn answer_consent(&self, client_id: &str, session_id: Uuid, approved: bool) {
+        let handoff = match self.state.sessions.write().await.get_session_mut(session_id) {
+            Some(session) if session.hid_client_id == client_id => session.pending_handoff.take(),
+            _ => None,
+        };
+        if let Some(handoff) = handoff {
+            if !approved {
+                self.refuse_handoff(session_id, &handoff, "declined by the user at the HID client").await;
+            } else if !self.transfer_control(session_id, &handoff.from, &handoff.to, true).await {
+                self.refuse_handoff(session_id, &handoff, "control changed hands before the user at the HID client approved").await;
+            }
+            return;
+        }
+
+        let answered = {
+            let mut sessions = self.state.sessions.write().await;
+            match sessions.get_session_mut(session_id) {
+                Some(session) if session.hid_client_id == client_id && session.awaiting_consent() => {
+                    if approved {
+                        session.consent_deadline = None;
+                        session.update_activity();
+                        Some(Ok(session.commander_id.clone()))
+                    } else {
+                        sessions.end_session(session_id).map(Err)
+                    }
+                }
+                _ => None,
+            }
+        };
+        match answered {
+            Some(Ok(commander_id)) => {
+                info!("HID client {} approved commander {} in session {}", client_id, commander_id, session_id);
+                let joined = Message::session_control(Some(session_id), SessionControlMessage::SessionJoined {
+                    session_id,
+                    target_client_id: client_id.to_string(),
+                });
+                self.record_sent(session_id, vec![commander_id.clone()], &joined).await;
+                if let Some(conn) = self.state.commanders.read().await.get(&commander_id) {
+                    conn.handle.send(&joined);
+                }
+            }
+            Some(Err(session)) => {
+                self.refuse_session(session, "CONSENT_DENIED", "The user at the HID client declined the session").await;
+            }
+            None => debug!("HID client {} answered for session {}, which is not waiting for approval", client_id, session_id),
+        }
+    }
+
+    /// Hold a granted handoff until the HID client's user approves the new controller,
+    /// and tell the participant getting control it is waiting for them
+    async fn ask_handoff_consent(&self, handle: &ConnectionHandle, session_id: Uuid, from: &str, to: &str) {
+        let timeout_secs = self.config.session.consent_timeout_secs;
+        let Some(username) = self.state.commanders.read().await.get(to).map(|conn| conn.claims.sub.clone()) else {
+            return;
+        };
+        let held = match self.state.sessions.write().await.get_session_mut(session_id) {
+            Some(session) if session.pending_handoff.is_none() => {
+                session.pending_handoff = Some(PendingHandoff {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    deadline: Utc::now() + chrono::Duration::seconds(timeout_secs as i64),
+                });
+                Some(session.hid_client_id.clone())
+            }
+            _ => None,
+        };
+        let Some(hid_client_id) = held else {
+            handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                error_code: "HANDOFF_PENDING".to_string(),
+                error_message: "The user at the HID client has yet to answer an earlier handoff".to_string(),
+            }));
+            let denied = Message::session_control(Some(session_id), SessionControlMessage::ControlDenied {
+                reason: "another handoff is waiting for the user at the HID client".to_string(),
+            });
+            self.record_sent(session_id, vec![to.to_string()], &denied).await;
+            if let Some(conn) = self.state.commanders.read().await.get(to) {
+                conn.handle.send(&denied);
+            }
+            return;
+        };
+        info!("Handoff of session {} from {} to {} is waiting for approval at HID client {}", session_id, from, to, hid_client_id);
+
+        let awaiting = Message::session_control(Some(session_id), SessionControlMessage::AwaitingConsent {
+            target_client_id: hid_client_id.clone(),
+            timeout_secs,
+        });
+        self.record_sent(session_id, vec![to.to_string()], &awaiting).await;
+        if let Some(conn) = self.state.commanders.read().await.get(to) {
+            conn.handle.send(&awaiting);
+        }
+        let request = Message::session_control(Some(session_id), SessionControlMessage::ConsentRequest {
+            session_id,
+            username,
+            timeout_secs,
+        });
+        self.record_sent(session_id, vec![hid_client_id.clone()], &request).await;
+        if let Some(conn) = self.state.hid_clients.read().await.get(&hid_client_id) {
+            conn.handle.send(&request);
+        }
+    }
+
+    /// Tell the participant a withdrawn handoff was meant for that it is not getting control
+    async fn refuse_handoff(&self, session_id: Uuid, handoff: &PendingHandoff, reason: &str) {
+        info!("Handoff of session {} from {} to {} did not happen: {}", session_id, handoff.from, handoff.to, reason);
+        let denied = Message::session_control(Some(session_id), SessionControlMessage::ControlDenied {
+            reason: reason.to_string(),
+        });
+        self.record_sent(session_id, vec![handoff.to.clone()], &denied).await;
+        if let Some(conn) = self.state.commanders.read().await.get(&handoff.to) {
+            conn.handle.send(&denied);
+        }
+    }
+
+    /// Turn away the commander of an already removed session that was never approved
+    async fn refuse_session(&self, session: Session, error_code: &str, error_message: &str) {
+        if let Some(conn) = self.state.commanders.read().await.get(&session.commander_id) {
+            conn.handle.send(&Message::status(None, StatusMessage::Error {
+                error_code: error_code.to_string(),
+                error_message: error_message.to_string(),
+            }));
+        }
+        self.notify_session_ended(session, error_message).await;
+    }
+
+    /// Add a view-only observer to the session a commander is driving on the target client
+    async fn attach_observer(&self, observer_id: &str, username: &str, target_client_id: &str) -> Result<Uuid, StatusMessage> {
+        let mut sessions = self.state.sessions.write().await;
+        let max_observers = self.config.session.max_observers;
+        let session = sessions.get_session_by_client(target_client_id);
+        if session.is_some_and(Session::awaiting_consent) {
+            return Err(StatusMessage::Error {
+                error_code: "NO_SESSION".to_string(),
+                error_message: format!("HID client {} has not approved its commander yet", target_client_id),
+            });
+        }
+        let watching = session.map(|s| s.observers.len());
+        if watching.is_some_and(|watching| watching >= max_observers) {
+            warn!("Refusing observer {} for {}: limit of {} observers reached", observer_id, target_client_id, max_observers);
+            return Err(StatusMessage::Error {
+                error_code: "OBSERVER_LIMIT".to_string(),
+                error_message: format!("Session is at its limit of {} observers", max_observers),
+            });
+        }
+
+        let session_id = sessions
+            .add_observer(target_client_id, observer_id.to_string(), username.to_string())
+            .ok_or_else(|| StatusMessage::Error {
+                error_code: "NO_SESSION".to_string(),
+                error_message: format!("HID client {} is not in a session", target_client_id),
+            })?;
+        info!("{} ({}) observing HID client {} in session {}", observer_id, username, target_client_id, session_id);
+        Ok(session_id)
+    }
+
+    /// Send a message to every observer of a session that is still connected
+    async fn send_to_observers(&self, observers: &[String], message: &Message) {
+        if observers.is_empty() {
+            return;
+        }
+        let commanders = self.state.commanders.read().await;
+        for observer_id in observers {
+            if let Some(conn) = commanders.get(observer_id) {
+                conn.handle.send(message);
+            }
+        }
+    }
+
+    /// End a session and notify whichever endpoints are still connected.
+    /// The commander's and observers' connections are closed since they only serve one session.
+    async fn end_session(&self, session_id: Uuid, reason: &str) {
+        let Some(session) = self.state.sessions.write().await.end_session(session_id) else {
+            return;
+        };
+        self.notify_session_ended(session, reason).await;
+    }
+
+    /// Tell both endpoints and the observers of an already removed session that it is over.
+    /// Whatever the commander in control still held down is released on the HID client first,
+    /// so the next commander in line does not inherit a stuck key.
+    async fn notify_session_ended(&self, mut session: Session, reason: &str) {
+        let session_id = session.id;
+        info!(
+            "Session {} between {} and {} ended after {}s: {}",
+            session_id, session.commander_id, session.hid_client_id,
+            (Utc::now() - session.created_at).num_seconds(), reason,
+        );
+
+        let ended = Message::session_control(Some(session_id), SessionControlMessage::SessionEnded {
+            reason: reason.to_string(),
+        });
+        let controller_name = {
+            let commanders = self.state.commanders.read().await;
+            let controller_name = commanders.get(&session.commander_id)
+                .map_or_else(String::new, |conn| conn.claims.sub.clone());
+            for commander_id in std::iter::once(&session.commander_id).chain(session.observers.keys()) {
+                if let Some(conn) = commanders.get(commander_id) {
+                    conn.handle.send(&ended);
+                    conn.handle.close();
+                }
+            }
+            controller_name
+        };
+        if let Some(conn) = self.state.hid_clients.read().await.get(&session.hid_client_id) {
+            for event in session.release_input() {
+                let message = Message::hid_event(session_id, event);
+                conn.handle.send(&message);
+                // Recorded as the controller's, whose input they end
+                let recorded = RecordedMessage::new(&session.commander_id, &controller_name, vec![session.hid_client_id.clone()], &message);
+                self.record(session_id, &session.hid_client_id, recorded);
+            }
+            conn.handle.send(&ended);
+        }
+        if let Some(recorder) = &self.recorder {
+            let sent_to = std::iter::once(&session.commander_id).chain(session.observers.keys()).chain([&session.hid_client_id]).cloned().collect();
+            recorder.record(session_id, &session.hid_client_id, RecordedMessage::new(&session.commander_id, &controller_name, sent_to, &ended));
+            recorder.finish(session_id);
+        }
+        self.promote_waiter(&session.hid_client_id).await;
+    }
+
+    /// Add a message sent in the session on `hid_client_id` to its recording, if recording is on
+    fn record(&self, session_id: Uuid, hid_client_id: &str, recorded: RecordedMessage) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(session_id, hid_client_id, recorded);
+        }
+    }
+
+    /// Record a message the server is about to send to `sent_to` in a running session,
+    /// attributed to the commander in control. Recording before sending keeps it ahead
+    /// of whatever the recipients do in response. Call without holding the session or
+    /// connection maps.
+    async fn record_sent(&self, session_id: Uuid, sent_to: Vec<String>, message: &Message) {
+        if self.recorder.is_none() {
+            return;
+        }
+        let session = self.state.sessions.read().await
+            .get_session(session_id)
+            .map(|s| (s.hid_client_id.clone(), s.commander_id.clone()));
+        let Some((hid_client_id, commander_id)) = session else { return };
+        let username = self.state.commanders.read().await
+            .get(&commander_id)
+            .map_or_else(String::new, |conn| conn.claims.sub.clone());
+        self.record(session_id, &hid_client_id, RecordedMessage::new(&commander_id, &username, sent_to, message));
+    }
+
+    /// Start the heartbeat schedule for a registered connection
+    fn heartbeat_ticker(&self) -> Interval {
+        let period = Duration::from_secs(self.config.server.heartbeat_interval_secs.max(1));
+        let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    }
+
+    /// Wait for the peer's next message, sending heartbeats while it is quiet.
+    ///
+    /// Token refreshes, logouts and revocations are handled here, and the access
+    /// token is re-checked on every heartbeat. Messages the peer's client type may
+    /// not send are answered with `PROTOCOL_VIOLATION` and dropped. Returns `None`
+    /// once the peer disconnects, logs out, misses too many heartbeats or its
+    /// token expires or is revoked.
+    async fn next_message(&self, handle: &ConnectionHandle, reader: &mut ConnectionReader, heartbeats: &mut Interval, claims: &mut Claims) -> Option<Message> {
+        loop {
+            tokio::select! {
+                message = reader.next_message() => {
+                    let message = message?;
+                    if !may_send(&claims.client_type, &message) {
+                        warn!(
+                            username = %claims.sub,
+                            client_type = %claims.client_type,
+                            "{} sent {:?} its client type may not send: {:?}",
+                            handle.peer(), message.message_type, message.payload
+                        );
+                        handle.send(&Message::status(message.session_id, StatusMessage::Error {
+                            error_code: "PROTOCOL_VIOLATION".to_string(),
+                            error_message: format!("A {} may not send this message", claims.client_type),
+                        }));
+                        continue;
+                    }
+                    match message.payload {
+                        MessagePayload::Auth(auth) => {
+                            if !self.handle_auth_message(handle, claims, auth).await {
+                                return None;
                             }
-                            MessageType::SessionControl => {
-                                // Handle EndSession, etc.
+                        }
+                        _ => return Some(message),
+                    }
+                }
+                _ = heartbeats.tick() => {
+                    let invalid = if claims.exp <= Utc::now().timestamp() {
+                        Some(AuthError::TokenExpired)
+                    } else if self.auth_manager.is_revoked(claims) {
+                        Some(AuthError::TokenRevoked)
+                    } else {
+                        None
+                    };
+                    if let Some(e) = invalid {
+                        warn!("Access token of {} ({}) is no longer valid, disconnecting: {}", claims.sub, handle.peer(), e);
+                        handle.send(&auth_failure(&e));
+                        handle.close();
+                        return None;
+                    }
+                    let max_missed = self.config.server.max_missed_heartbeats;
+                    if !handle.ping(max_missed) {
+                        warn!("{} missed {} heartbeats, disconnecting", handle.peer(), max_missed);
+                        handle.close();
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Push the HID client's link quality to the commander driving it and its observers
+    async fn send_link_status(&self, client_id: &str, connected: bool, latency: Option<Duration>) {
+        let session = self.state.sessions.read().await
+            .get_session_by_client(client_id)
+            .filter(|s| !s.awaiting_consent())
+            .map(|s| (s.id, s.commander_id.clone(), s.observers.keys().cloned().collect::<Vec<_>>()));
+        let Some((session_id, commander_id, observers)) = session else { return };
+
+        let status = Message::status(Some(session_id), StatusMessage::ConnectionStatus {
+            connected,
+            latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        });
+        if let Some(conn) = self.state.commanders.read().await.get(&commander_id) {
+            conn.handle.send(&status);
+        }
+        self.send_to_observers(&observers, &status).await;
+    }
+
+    async fn serve_hid_client(&self, client_id: String, handle: ConnectionHandle, mut reader: ConnectionReader, mut claims: Claims) -> anyhow::Result<()> {
+        let mut heartbeats = self.heartbeat_ticker();
+        while let Some(message) = self.next_message(&handle, &mut reader, &mut heartbeats, &mut claims).await {
+            debug!("HID client {} -> server: {:?}", client_id, message.message_type);
+            match message.payload {
+                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                    if let Some(latency) = handle.pong() {
+                        debug!("HID client {} round trip {:?}", client_id, latency);
+                        self.send_link_status(&client_id, true, Some(latency)).await;
+                    }
+                }
+                MessagePayload::SessionControl(SessionControlMessage::EndSession) => {
+                    let session_id = self.state.sessions.read().await.get_session_by_client(&client_id).map(|s| s.id);
+                    if let Some(session_id) = session_id {
+                        self.end_session(session_id, "ended by HID client").await;
+                    }
+                }
+                MessagePayload::SessionControl(SessionControlMessage::ConsentResponse { session_id, approved }) => {
+                    self.answer_consent(&client_id, session_id, approved).await;
+                }
+                MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                    warn!("HID client {} reported an error ({}): {}", client_id, error_code, error_message);
+                }
+                _ => {}
+            }
+        }
+        info!("HID client {} disconnected", client_id);
+
+        // Cleanup, unless a newer connection has already taken over this client id
+        let removed = {
+            let mut map = self.state.hid_clients.write().await;
+            match map.get(&client_id) {
+                Some(conn) if conn.handle.peer() == handle.peer() => map.remove(&client_id),
+                _ => None,
+            }
+        };
+        if let Some(conn) = removed {
+            info!("Removed HID client {} ({} from {})", client_id, conn.claims.sub, conn.handle.peer());
+            conn.handle.close();
+            self.send_link_status(&client_id, false, None).await;
+            let session_id = self.state.sessions.read().await.get_session_by_client(&client_id).map(|s| s.id);
+            if let Some(session_id) = session_id {
+                self.end_session(session_id, "HID client disconnected").await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve a commander taking part in a session, whether in control or observing.
+    ///
+    /// Only the HID events of the commander in control are forwarded; the others
+    /// receive a mirror of them and may ask for control. `pending` is a message
+    /// already read while the commander waited in line.
+    async fn serve_commander(&self, commander_id: String, session_id: Uuid, handle: ConnectionHandle, mut reader: ConnectionReader, mut claims: Claims, mut pending: Option<Message>) -> anyhow::Result<()> {
+        let mut heartbeats = self.heartbeat_ticker();
+        loop {
+            let message = match pending.take() {
+                Some(message) => message,
+                None => match self.next_message(&handle, &mut reader, &mut heartbeats, &mut claims).await {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
+            match &message.payload {
+                MessagePayload::HidEvent(event) => {
+                    let received_at = Utc::now();
+                    if message.session_id != Some(session_id) {
+                        handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                            error_code: "INVALID_SESSION".to_string(),
+                            error_message: "HID events must carry the id from SessionJoined".to_string(),
+                        }));
+                        continue;
+                    }
+
+                    let target = {
+                        let mut sessions = self.state.sessions.write().await;
+                        // The session is gone once either side has ended it
+                        let Some(session) = sessions.get_session_mut(session_id) else { break };
+                        // Only the commander in control drives the HID client
+                        let target = (session.commander_id == commander_id).then(|| {
+                            session.track_input(event);
+                            (session.hid_client_id.clone(), session.observers.keys().cloned().collect::<Vec<_>>())
+                        });
+                        sessions.update_session_activity(session_id);
+                        target
+                    };
+                    let Some((target_client_id, observers)) = target else {
+                        debug!(username = %claims.sub, "Dropped HID event from {}, which is not in control", commander_id);
+                        handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                            error_code: "VIEW_ONLY".to_string(),
+                            error_message: "Only the commander in control may send HID events".to_string(),
+                        }));
+                        continue;
+                    };
+
+                    let target = self.state.hid_clients.read().await.get(&target_client_id).map(|conn| conn.handle.clone());
+                    match target {
+                        Some(hid_handle) => {
+                            if !hid_handle.send(&message) {
+                                warn!("Failed to forward to HID client {}", target_client_id);
                             }
-                            _ => {}
+                            // Observers see exactly what was forwarded
+                            self.send_to_observers(&observers, &message).await;
+                            let sent_to = std::iter::once(target_client_id.clone()).chain(observers).collect();
+                            let recorded = RecordedMessage { received_at, ..RecordedMessage::new(&commander_id, &claims.sub, sent_to, &message) };
+                            self.record(session_id, &target_client_id, recorded);
+                        }
+                        None => {
+                            warn!("HID client {} not connected", target_client_id);
+                            handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                                error_code: "CLIENT_NOT_CONNECTED".to_string(),
+                                error_message: format!("HID client {} is not connected", target_client_id),
+                            }));
                         }
                     }
                 }
-                Ok(WsMessage::Close(_)) => {
-                    info!("Commander {} disconnected", commander_id);
-                    break;
+                MessagePayload::SessionControl(control) => match control {
+                    SessionControlMessage::EndSession => {
+                        // Anyone but the commander that started the session only leaves it
+                        let ended = self.leave_session(&commander_id, session_id, "ended by commander").await;
+                        if !ended {
+                            let left = Message::session_control(Some(session_id), SessionControlMessage::SessionEnded {
+                                reason: "left the session".to_string(),
+                            });
+                            self.record_sent(session_id, vec![commander_id.clone()], &left).await;
+                            handle.send(&left);
+                            handle.close();
+                            break;
+                        }
+                    }
+                    SessionControlMessage::ListClients => {
+                        self.send_client_list(&handle, &claims).await;
+                    }
+                    SessionControlMessage::RequestControl => {
+                        self.request_control(&handle, &commander_id, session_id, &claims).await;
+                    }
+                    SessionControlMessage::GrantControl { participant_id } => {
+                        self.answer_control_request(&handle, &commander_id, session_id, participant_id, true).await;
+                    }
+                    SessionControlMessage::DenyControl { participant_id } => {
+                        self.answer_control_request(&handle, &commander_id, session_id, participant_id, false).await;
+                    }
+                    SessionControlMessage::RevokeControl => {
+                        self.revoke_control(&handle, &commander_id, session_id).await;
+                    }
+                    _ => {}
+                },
+                MessagePayload::Status(StatusMessage::Heartbeat) => {
+                    if let Some(latency) = handle.pong() {
+                        debug!("Commander {} round trip {:?}", commander_id, latency);
+                    }
                 }
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Commander {} error: {}", commander_id, e);
-                    break;
+                MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                    warn!("Commander {} reported an error ({}): {}", commander_id, error_code, error_message);
                 }
+                _ => {}
             }
         }
+        info!("Commander {} disconnected", commander_id);
 
         // Cleanup session
-        self.state.sessions.write().await.retain(|_, v| !(v.0 == commander_id && v.1 == target_client_id));
+        self.leave_session(&commander_id, session_id, "commander disconnected").await;
+        if let Some(conn) = self.state.commanders.write().await.remove(&commander_id) {
+            info!("Removed Commander {} ({} from {})", commander_id, conn.claims.sub, conn.handle.peer());
+            conn.handle.close();
+        }
         Ok(())
     }
+
+    /// Take a commander out of its session. The session ends when the commander
+    /// that started it leaves; anyone else in control hands control back to it
+    /// first. Returns whether the session is over for everyone.
+    async fn leave_session(&self, commander_id: &str, session_id: Uuid, reason: &str) -> bool {
+        let roles = self.state.sessions.read().await
+            .get_session(session_id)
+            .map(|s| (s.owner_id.clone(), s.commander_id == commander_id));
+        match roles {
+            None => true,
+            Some((owner_id, _)) if owner_id == commander_id => {
+                self.end_session(session_id, reason).await;
+                true
+            }
+            Some((owner_id, in_control)) => {
+                if in_control {
+                    self.transfer_control(session_id, commander_id, &owner_id, false).await;
+                }
+                self.state.sessions.write().await.remove_observer(session_id, commander_id);
+                info!("{} left session {}", commander_id, session_id);
+                false
+            }
+        }
+    }
+
+    /// Pass a participant's request for control on to the commander in control
+    async fn request_control(&self, handle: &ConnectionHandle, requester_id: &str, session_id: Uuid, claims: &Claims) {
+        if !claims.has_role(Role::Control) {
+            handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                error_code: "FORBIDDEN".to_string(),
+                error_message: "Your account may not take control of HID clients".to_string(),
+            }));
+            return;
+        }
+        let controller = {
+            let mut sessions = self.state.sessions.write().await;
+            match sessions.get_session_mut(session_id) {
+                Some(session) if session.commander_id != requester_id => {
+                    session.control_requests.insert(requester_id.to_string());
+                    Some(session.commander_id.clone())
+                }
+                _ => None,
+            }
+        };
+        let Some(controller) = controller else {
+            handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                error_code: "INVALID_REQUEST".to_string(),
+                error_message: "You are already in control".to_string(),
+            }));
+            return;
+        };
+
+        info!("{} ({}) requested control of session {}", requester_id, claims.sub, session_id);
+        let requested = Message::session_control(Some(session_id), SessionControlMessage::ControlRequested {
+            participant_id: requester_id.to_string(),
+            username: claims.sub.clone(),
+        });
+        self.record_sent(session_id, vec![controller.clone()], &requested).await;
+        if let Some(conn) = self.state.commanders.read().await.get(&controller) {
+            conn.handle.send(&requested);
+        }
+    }
+
+    /// Grant or deny a pending request for control; only the commander in control may answer
+    async fn answer_control_request(&self, handle: &ConnectionHandle, commander_id: &str, session_id: Uuid, participant_id: &str, grant: bool) {
+        let pending = match self.state.sessions.write().await.get_session_mut(session_id) {
+            Some(session) if session.commander_id == commander_id => session.control_requests.remove(participant_id),
+            _ => false,
+        };
+        if !pending {
+            handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+                error_code: "INVALID_REQUEST".to_string(),
+                error_message: "No pending request for control from this participant".to_string(),
+            }));
+            return;
+        }
+
+        if grant && self.handoff_needs_consent(session_id).await {
+            self.ask_handoff_consent(handle, session_id, commander_id, participant_id).await;
+        } else if grant {
+            self.transfer_control(session_id, commander_id, participant_id, true).await;
+        } else {
+            info!("{} denied control of session {} to {}", commander_id, session_id, participant_id);
+            let denied = Message::session_control(Some(session_id), SessionControlMessage::ControlDenied {
+                reason: "denied by the commander in control".to_string(),
+            });
+            self.record_sent(session_id, vec![participant_id.to_string()], &denied).await;
+            if let Some(conn) = self.state.commanders.read().await.get(participant_id) {
+                conn.handle.send(&denied);
+            }
+        }
+    }
+
+    /// Whether the session's HID client asks its user before someone new takes control
+    async fn handoff_needs_consent(&self, session_id: Uuid) -> bool {
+        let hid_client_id = self.state.sessions.read().await.get_session(session_id).map(|s| s.hid_client_id.clone());
+        let Some(hid_client_id) = hid_client_id else { return false };
+        self.state.hid_clients.read().await.get(&hid_client_id).is_some_and(|conn| conn.requires_consent)
+    }
+
+    /// Let the commander that started a session take control back without asking.
+    /// The HID client's user approved the owner when the session started, so it is not asked again
+    async fn revoke_control(&self, handle: &ConnectionHandle, commander_id: &str, session_id: Uuid) {
+        let roles = self.state.sessions.read().await
+            .get_session(session_id)
+            .map(|s| (s.owner_id == commander_id, s.commander_id.clone()));
+        let error = match roles {
+            Some((true, controller)) if controller != commander_id => {
+                self.transfer_control(session_id, &controller, commander_id, true).await;
+                return;
+            }
+            Some((true, _)) => ("INVALID_REQUEST", "You are already in control"),
+            _ => ("FORBIDDEN", "Only the commander that started the session may take control back"),
+        };
+        handle.send(&Message::status(Some(session_id), StatusMessage::Error {
+            error_code: error.0.to_string(),
+            error_message: error.1.to_string(),
+        }));
+    }
+
+    /// Move control of a session from `from` to the observer `to`, release whatever
+    /// `from` held down on the HID client and tell every participant who is in control.
+    /// Unless `from_stays`, it leaves the session instead of becoming an observer.
+    async fn transfer_control(&self, session_id: Uuid, from: &str, to: &str, from_stays: bool) -> bool {
+        // Looked up before taking the sessions lock, which is never held while locking commanders
+        let previous_name = self.state.commanders.read().await
+            .get(from)
+            .map_or_else(String::new, |conn| conn.claims.sub.clone());
+        let transferred = match self.state.sessions.write().await.get_session_mut(session_id) {
+            Some(session) if session.commander_id == from => session.transfer_control(to, previous_name.clone())
+                .map(|(controller, released)| {
+                    if !from_stays {
+                        session.observers.remove(from);
+                    }
+                    (controller, released, session.hid_client_id.clone(), session.observers.keys().cloned().collect::<Vec<_>>())
+                }),
+            _ => None,
+        };
+        let Some((controller, released, hid_client_id, observers)) = transferred else {
+            return false;
+        };
+        info!("Control of session {} passed from {} to {} ({})", session_id, from, to, controller);
+
+        // Recorded as the previous controller's, whose input the releases end
+        let record = |sent_to: Vec<String>, message: &Message| {
+            self.record(session_id, &hid_client_id, RecordedMessage::new(from, &previous_name, sent_to, message));
+        };
+        let released: Vec<Message> = released.into_iter().map(|event| Message::hid_event(session_id, event)).collect();
+        let changed = |in_control| Message::session_control(Some(session_id), SessionControlMessage::ControlChanged {
+            controller: controller.clone(),
+            in_control,
+        });
+        for message in &released {
+            record(std::iter::once(&hid_client_id).chain(&observers).cloned().collect(), message);
+        }
+        record(vec![to.to_string()], &changed(true));
+        if !observers.is_empty() {
+            record(observers.clone(), &changed(false));
+        }
+
+        if let Some(conn) = self.state.hid_clients.read().await.get(&hid_client_id) {
+            for message in &released {
+                conn.handle.send(message);
+            }
+        }
+        for message in &released {
+            self.send_to_observers(&observers, message).await;
+        }
+        let commanders = self.state.commanders.read().await;
+        for participant_id in std::iter::once(to).chain(observers.iter().map(String::as_str)) {
+            if let Some(conn) = commanders.get(participant_id) {
+                conn.handle.send(&changed(participant_id == to));
+            }
+        }
+        true
+    }
+
+}
+
+/// Successful `AuthMessage::Response` carrying a (possibly refreshed) token
+fn auth_success(token: String, claims: &Claims, refresh_token: Option<String>) -> Message {
+    Message::auth(AuthMessage::Response {
+        success: true,
+        token: Some(token),
+        expires_at: Utc.timestamp_opt(claims.exp, 0).single(),
+        error_message: None,
+        error_code: None,
+        refresh_token,
+    })
+}
+
+fn auth_failure(error: &AuthError) -> Message {
+    Message::auth(AuthMessage::Response {
+        success: false,
+        token: None,
+        expires_at: None,
+        error_message: Some(error.to_string()),
+        error_code: Some(error.code().to_string()),
+        refresh_token: None,
+    })
+}
+
+/// The direction matrix: which messages each client type may send to the server.
+///
+/// HID clients register and report; commanders join, list and drive input.
+/// Once logged in, both may refresh their tokens and log out, but only
+/// commanders may revoke other tokens. The type tag must agree with the payload so one kind of message cannot be
+/// smuggled through as another.
+fn may_send(client_type: &str, message: &Message) -> bool {
+    let commander = client_type == ClientType::Commander.to_string();
+    let hid_client = client_type == ClientType::HidClient.to_string();
+    match (&message.message_type, &message.payload) {
+        (MessageType::Status, MessagePayload::Status(status)) => match status {
+            // Heartbeat replies, and failures a client reports, e.g. input it could not inject
+            StatusMessage::Heartbeat | StatusMessage::Error { .. } => commander || hid_client,
+            // Link quality is measured by the server
+            StatusMessage::ConnectionStatus { .. } => false,
+        },
+        (MessageType::HidEvent, MessagePayload::HidEvent(_)) => commander,
+        (MessageType::Auth, MessagePayload::Auth(auth)) => match auth {
+            AuthMessage::Refresh { .. } | AuthMessage::Logout => commander || hid_client,
+            AuthMessage::Revoke { .. } => commander,
+            // Login steps belong to the handshake, before a connection has a client type
+            AuthMessage::Request { .. }
+            | AuthMessage::ScramStart { .. }
+            | AuthMessage::ScramProof { .. }
+            | AuthMessage::Pair { .. }
+            | AuthMessage::DeviceLogin { .. }
+            | AuthMessage::CertificateLogin { .. }
+            | AuthMessage::TotpCode { .. } => false,
+            // Sent by the server only
+            AuthMessage::Response { .. }
+            | AuthMessage::ScramChallenge { .. }
+            | AuthMessage::ScramServerFinal { .. }
+            | AuthMessage::Paired { .. }
+            | AuthMessage::TotpRequired
+            | AuthMessage::Revoked { .. } => false,
+        },
+        (MessageType::SessionControl, MessagePayload::SessionControl(control)) => match control {
+            SessionControlMessage::CreateSession { .. } | SessionControlMessage::ConsentResponse { .. } => hid_client,
+            SessionControlMessage::JoinSession { .. } | SessionControlMessage::ListClients => commander,
+            SessionControlMessage::RequestControl
+            | SessionControlMessage::GrantControl { .. }
+            | SessionControlMessage::DenyControl { .. }
+            | SessionControlMessage::RevokeControl
+            | SessionControlMessage::LeaveQueue => commander,
+            SessionControlMessage::EndSession => commander || hid_client,
+            // Sent by the server only
+            SessionControlMessage::SessionJoined { .. }
+            | SessionControlMessage::ClientList { .. }
+            | SessionControlMessage::SessionEnded { .. }
+            | SessionControlMessage::ControlRequested { .. }
+            | SessionControlMessage::ControlDenied { .. }
+            | SessionControlMessage::ControlChanged { .. }
+            | SessionControlMessage::QueuePosition { .. }
+            | SessionControlMessage::ConsentRequest { .. }
+            | SessionControlMessage::AwaitingConsent { .. } => false,
+        },
+        _ => false,
+    }
+}
+
+/// Drop entries whose connection has closed, returning their ids
+fn remove_closed(connections: &mut HashMap<String, ClientConnection>, kind: &str) -> Vec<String> {
+    let mut removed = Vec::new();
+    connections.retain(|id, conn| {
+        if !conn.handle.is_closed() {
+            return true;
+        }
+        info!("Dropping orphaned {} {} ({} from {})", kind, id, conn.claims.sub, conn.handle.peer());
+        conn.handle.close();
+        removed.push(id.clone());
+        false
+    });
+    removed
 }
@@ -1,16 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
-use remote_hid_shared::ClientInfo;
+use remote_hid_shared::{HidEvent, KeyCode, KeyModifiers, MouseButton};
+
+/// A `GrantControl` held until the HID client's user approves the new controller
+#[derive(Debug, Clone)]
+pub struct PendingHandoff {
+    /// The commander that granted control; the handoff lapses if it is no longer in control
+    pub from: String,
+    pub to: String,
+    pub deadline: DateTime<Utc>,
+}
 
 /// Session state management
 #[derive(Debug, Clone)]
 pub struct Session {
     pub id: Uuid,
+    /// The commander currently in control; the only one whose events are forwarded
     pub commander_id: String,
+    /// The commander that started the session; it may take control back at any time
+    pub owner_id: String,
     pub hid_client_id: String,
+    /// View-only participants: connection id -> username
+    pub observers: HashMap<String, String>,
+    /// Observers waiting for an answer to `RequestControl`
+    pub control_requests: HashSet<String>,
     pub created_at: DateTime<Utc>,
     pub last_activity: DateTime<Utc>,
+    /// Set while the HID client's user has yet to approve the commander; the
+    /// session is refused if nobody answers by then
+    pub consent_deadline: Option<DateTime<Utc>>,
+    /// Set while the HID client's user has yet to approve a handoff of control
+    pub pending_handoff: Option<PendingHandoff>,
+    // Held down on the HID client by forwarded events, released when control changes
+    pressed_keys: Vec<KeyCode>,
+    pressed_buttons: Vec<MouseButton>,
 }
 
 impl Session {
@@ -18,13 +42,63 @@ impl Session {
         let now = Utc::now();
         Self {
             id: Uuid::new_v4(),
+            owner_id: commander_id.clone(),
             commander_id,
             hid_client_id,
+            observers: HashMap::new(),
+            control_requests: HashSet::new(),
             created_at: now,
             last_activity: now,
+            consent_deadline: None,
+            pending_handoff: None,
+            pressed_keys: Vec::new(),
+            pressed_buttons: Vec::new(),
+        }
+    }
+    
+    /// Record which keys and buttons a forwarded event leaves held down
+    pub fn track_input(&mut self, event: &HidEvent) {
+        match event {
+            HidEvent::KeyEvent { key, pressed, .. } => track(&mut self.pressed_keys, *key, *pressed),
+            HidEvent::MouseClick { button, pressed, .. } => track(&mut self.pressed_buttons, *button, *pressed),
+            HidEvent::MouseMove { .. } | HidEvent::MouseScroll { .. } => {}
         }
     }
     
+    /// Events releasing every key and button still held down
+    pub fn release_input(&mut self) -> Vec<HidEvent> {
+        let keys = self.pressed_keys.drain(..).map(|key| HidEvent::KeyEvent {
+            key,
+            pressed: false,
+            modifiers: KeyModifiers::default(),
+        });
+        let buttons = self.pressed_buttons.drain(..).map(|button| HidEvent::MouseClick {
+            button,
+            pressed: false,
+            x: None,
+            y: None,
+        });
+        keys.chain(buttons).collect()
+    }
+    
+    /// Hand control to the observer `to`; the previous controller stays on as an
+    /// observer named `previous_name`. Returns the new controller's username and
+    /// the events releasing whatever the previous controller held down, or `None`
+    /// if `to` is not observing.
+    pub fn transfer_control(&mut self, to: &str, previous_name: String) -> Option<(String, Vec<HidEvent>)> {
+        let username = self.observers.remove(to)?;
+        let previous = std::mem::replace(&mut self.commander_id, to.to_string());
+        self.observers.insert(previous, previous_name);
+        // Requests were addressed to the previous controller
+        self.control_requests.clear();
+        Some((username, self.release_input()))
+    }
+    
+    /// Whether the session is held until the HID client's user approves it
+    pub fn awaiting_consent(&self) -> bool {
+        self.consent_deadline.is_some()
+    }
+    
     pub fn update_activity(&mut self) {
         self.last_activity = Utc::now();
     }
@@ -40,9 +114,11 @@ impl Session {
 pub struct SessionManager {
     sessions: HashMap<Uuid, Session>,
     client_sessions: HashMap<String, Uuid>, // client_id -> session_id
+    wait_queues: HashMap<String, VecDeque<String>>, // client_id -> commanders waiting, first in line first
 }
 
 impl SessionManager {
+    #[allow(dead_code)]
     pub fn new() -> Self {
         Self::default()
     }
@@ -62,6 +138,58 @@ impl SessionManager {
         Ok(session_id)
     }
     
+    /// Put a commander in line for a busy HID client; returns its 1-based position
+    pub fn enqueue(&mut self, hid_client_id: &str, commander_id: String) -> usize {
+        let queue = self.wait_queues.entry(hid_client_id.to_string()).or_default();
+        queue.push_back(commander_id);
+        queue.len()
+    }
+    
+    /// Take a commander out of line; returns whether it was waiting
+    pub fn leave_queue(&mut self, hid_client_id: &str, commander_id: &str) -> bool {
+        let Some(queue) = self.wait_queues.get_mut(hid_client_id) else {
+            return false;
+        };
+        let before = queue.len();
+        queue.retain(|waiting| waiting != commander_id);
+        let left = queue.len() != before;
+        if queue.is_empty() {
+            self.wait_queues.remove(hid_client_id);
+        }
+        left
+    }
+    
+    /// Commanders waiting for a HID client, first in line first
+    pub fn queue(&self, hid_client_id: &str) -> Vec<String> {
+        self.wait_queues.get(hid_client_id).map_or_else(Vec::new, |queue| queue.iter().cloned().collect())
+    }
+    
+    /// Remove everyone waiting for a HID client
+    pub fn take_queue(&mut self, hid_client_id: &str) -> Vec<String> {
+        self.wait_queues.remove(hid_client_id).map_or_else(Vec::new, Vec::from)
+    }
+    
+    /// Start a session for the first commander waiting for an idle HID client
+    pub fn promote_next(&mut self, hid_client_id: &str) -> Option<(Uuid, String)> {
+        if self.client_sessions.contains_key(hid_client_id) {
+            return None;
+        }
+        let queue = self.wait_queues.get_mut(hid_client_id)?;
+        let commander_id = queue.pop_front()?;
+        if queue.is_empty() {
+            self.wait_queues.remove(hid_client_id);
+        }
+        let session_id = self.create_session(commander_id.clone(), hid_client_id.to_string()).ok()?;
+        Some((session_id, commander_id))
+    }
+    
+    /// Session a commander controls or started
+    pub fn session_of_commander(&self, commander_id: &str) -> Option<Uuid> {
+        self.sessions.values()
+            .find(|session| session.commander_id == commander_id || session.owner_id == commander_id)
+            .map(|session| session.id)
+    }
+    
     pub fn end_session(&mut self, session_id: Uuid) -> Option<Session> {
         if let Some(session) = self.sessions.remove(&session_id) {
             self.client_sessions.remove(&session.hid_client_id);
@@ -71,10 +199,28 @@ impl SessionManager {
         }
     }
     
+    /// Attach an observer to the HID client's session, returning its id, or
+    /// `None` when nobody is controlling the client
+    pub fn add_observer(&mut self, hid_client_id: &str, observer_id: String, username: String) -> Option<Uuid> {
+        let session_id = *self.client_sessions.get(hid_client_id)?;
+        self.sessions.get_mut(&session_id)?.observers.insert(observer_id, username);
+        Some(session_id)
+    }
+    
+    pub fn remove_observer(&mut self, session_id: Uuid, observer_id: &str) -> Option<String> {
+        let session = self.sessions.get_mut(&session_id)?;
+        session.control_requests.remove(observer_id);
+        session.observers.remove(observer_id)
+    }
+    
     pub fn get_session(&self, session_id: Uuid) -> Option<&Session> {
         self.sessions.get(&session_id)
     }
     
+    pub fn get_session_mut(&mut self, session_id: Uuid) -> Option<&mut Session> {
+        self.sessions.get_mut(&session_id)
+    }
+    
     pub fn get_session_by_client(&self, client_id: &str) -> Option<&Session> {
         self.client_sessions.get(client_id)
             .and_then(|&session_id| self.sessions.get(&session_id))
@@ -89,7 +235,7 @@ impl SessionManager {
     pub fn cleanup_expired_sessions(&mut self, timeout_mins: u64) -> Vec<Session> {
         let mut expired = Vec::new();
         
-        self.sessions.retain(|&session_id, session| {
+        self.sessions.retain(|_, session| {
             if session.is_expired(timeout_mins) {
                 self.client_sessions.remove(&session.hid_client_id);
                 expired.push(session.clone());
@@ -102,7 +248,36 @@ impl SessionManager {
         expired
     }
     
+    /// Remove the sessions whose HID client's user did not answer by their deadline
+    pub fn take_unanswered(&mut self, now: DateTime<Utc>) -> Vec<Session> {
+        let unanswered: Vec<Uuid> = self.sessions.values()
+            .filter(|session| session.consent_deadline.is_some_and(|deadline| deadline <= now))
+            .map(|session| session.id)
+            .collect();
+        unanswered.into_iter().filter_map(|session_id| self.end_session(session_id)).collect()
+    }
+    
+    /// Withdraw the handoffs whose HID client's user did not answer by their deadline
+    pub fn take_unanswered_handoffs(&mut self, now: DateTime<Utc>) -> Vec<(Uuid, PendingHandoff)> {
+        self.sessions.values_mut()
+            .filter(|session| session.pending_handoff.as_ref().is_some_and(|handoff| handoff.deadline <= now))
+            .filter_map(|session| Some((session.id, session.pending_handoff.take()?)))
+            .collect()
+    }
+    
     pub fn list_sessions(&self) -> Vec<&Session> {
         self.sessions.values().collect()
     }
-}
\ No newline at end of file
+}
+
+/// Add `item` to the held set when pressed, drop it when released
+fn track<T: PartialEq>(held: &mut Vec<T>, item: T, pressed: bool) {
+    let position = held.iter().position(|h| *h == item);
+    match (pressed, position) {
+        (true, None) => held.push(item),
+        (false, Some(index)) => {
+            held.remove(index);
+        }
+        _ => {}
+    }
+}
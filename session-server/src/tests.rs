@@ -1,11 +1,7 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
     use crate::session::SessionManager;
-    use remote_hid_shared::{Message, MessagePayload, MessageType, SessionControlMessage, HidEvent, MouseButton, KeyCode, KeyModifiers};
-    use uuid::Uuid;
     use std::time::Duration;
-    use chrono::{DateTime, Utc};
 
     #[test]
     fn test_session_manager_creation() {
@@ -166,13 +162,99 @@ mod tests {
         assert!(manager.get_session(session1).is_some());
         assert!(manager.get_session(session2).is_some());
     }
+
+    #[test]
+    fn test_observers_attach_to_existing_session() {
+        let mut manager = SessionManager::new();
+        assert_eq!(manager.add_observer("client1", "observer1".to_string(), "alice".to_string()), None);
+
+        let session_id = manager.create_session("commander1".to_string(), "client1".to_string()).unwrap();
+        assert_eq!(manager.add_observer("client1", "observer1".to_string(), "alice".to_string()), Some(session_id));
+        assert_eq!(manager.get_session(session_id).unwrap().observers["observer1"], "alice");
+
+        assert_eq!(manager.remove_observer(session_id, "observer1").as_deref(), Some("alice"));
+        assert_eq!(manager.remove_observer(session_id, "observer1"), None);
+        assert!(manager.get_session(session_id).unwrap().observers.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_control_releases_held_input() {
+        use remote_hid_shared::{HidEvent, KeyCode, KeyModifiers, MouseButton};
+
+        let mut manager = SessionManager::new();
+        let session_id = manager.create_session("commander1".to_string(), "client1".to_string()).unwrap();
+        manager.add_observer("client1", "observer1".to_string(), "bob".to_string());
+        let session = manager.get_session_mut(session_id).unwrap();
+
+        let key = |key, pressed| HidEvent::KeyEvent { key, pressed, modifiers: KeyModifiers::default() };
+        session.track_input(&key(KeyCode::LeftShift, true));
+        session.track_input(&key(KeyCode::A, true));
+        session.track_input(&key(KeyCode::A, false));
+        session.track_input(&HidEvent::MouseClick { button: MouseButton::Left, pressed: true, x: None, y: None });
+        session.control_requests.insert("observer1".to_string());
+
+        assert!(session.transfer_control("nobody", "alice".to_string()).is_none());
+        let (username, released) = session.transfer_control("observer1", "alice".to_string()).unwrap();
+        assert_eq!(username, "bob");
+        assert!(matches!(released[..], [
+            HidEvent::KeyEvent { key: KeyCode::LeftShift, pressed: false, .. },
+            HidEvent::MouseClick { button: MouseButton::Left, pressed: false, .. },
+        ]));
+        assert_eq!(session.commander_id, "observer1");
+        assert_eq!(session.owner_id, "commander1");
+        assert_eq!(session.observers["commander1"], "alice");
+        assert!(session.control_requests.is_empty());
+        assert!(session.release_input().is_empty());
+    }
+
+    #[test]
+    fn test_wait_queue_promotes_in_order() {
+        let mut manager = SessionManager::new();
+        let first = manager.create_session("commander1".to_string(), "client1".to_string()).unwrap();
+        assert!(manager.create_session("commander2".to_string(), "client1".to_string()).is_err());
+        assert_eq!(manager.enqueue("client1", "commander2".to_string()), 1);
+        assert_eq!(manager.enqueue("client1", "commander3".to_string()), 2);
+        assert_eq!(manager.enqueue("client1", "commander4".to_string()), 3);
+
+        // Nobody moves up while the client is busy
+        assert_eq!(manager.promote_next("client1"), None);
+        assert!(manager.leave_queue("client1", "commander3"));
+        assert!(!manager.leave_queue("client1", "commander3"));
+        assert_eq!(manager.queue("client1"), ["commander2", "commander4"]);
+
+        manager.end_session(first);
+        let (second, commander) = manager.promote_next("client1").unwrap();
+        assert_eq!(commander, "commander2");
+        assert_eq!(manager.session_of_commander("commander2"), Some(second));
+        assert_eq!(manager.queue("client1"), ["commander4"]);
+
+        assert_eq!(manager.take_queue("client1"), ["commander4"]);
+        manager.end_session(second);
+        assert_eq!(manager.promote_next("client1"), None);
+    }
+
+    #[test]
+    fn test_unanswered_consent_requests_taken() {
+        let mut manager = SessionManager::new();
+        let held = manager.create_session("commander1".to_string(), "client1".to_string()).unwrap();
+        let approved = manager.create_session("commander2".to_string(), "client2".to_string()).unwrap();
+        let now = chrono::Utc::now();
+        manager.get_session_mut(held).unwrap().consent_deadline = Some(now + chrono::Duration::seconds(5));
+        assert!(manager.get_session(held).unwrap().awaiting_consent());
+        assert!(!manager.get_session(approved).unwrap().awaiting_consent());
+
+        assert!(manager.take_unanswered(now).is_empty());
+        let unanswered = manager.take_unanswered(now + chrono::Duration::seconds(5));
+        assert_eq!(unanswered.len(), 1);
+        assert_eq!(unanswered[0].id, held);
+        assert!(manager.get_session_by_client("client1").is_none());
+        assert!(manager.get_session(approved).is_some());
+    }
 }
 
 #[cfg(test)]
 mod server_tests {
-    use super::*;
 use crate::config::{Config, ServerConfig, AuthConfig, SessionConfig};
-    use std::collections::HashMap;
     
     fn create_test_config() -> Config {
         Config {
@@ -181,18 +263,38 @@ use crate::config::{Config, ServerConfig, AuthConfig, SessionConfig};
                 port: 8080,
                 max_connections: 100,
                 heartbeat_interval_secs: 30,
+                max_missed_heartbeats: 3,
+                shutdown_grace_secs: 5,
+                handshake_timeout_secs: 30,
+                tls: None,
             },
             auth: AuthConfig {
                 jwt_secret: "test_secret".to_string(),
                 token_expiry_hours: 24,
+                refresh_token_expiry_hours: 720,
+                users_file: "users.json".to_string(),
+                devices_file: "devices.json".to_string(),
+                revocation_file: None,
+                signing_key_id: None,
+                keys: Vec::new(),
+                jwks_path: None,
                 max_failed_attempts: 3,
                 lockout_duration_mins: 15,
+                allow_password_login: false,
+                password_hashing: Default::default(),
+                password_policy: Default::default(),
+                client_certificates: Default::default(),
             },
             session: SessionConfig {
                 max_sessions: 10,
                 session_timeout_mins: 30,
                 cleanup_interval_secs: 60,
+                max_observers: 2,
+                max_queue_length: 2,
+                consent_timeout_secs: 2,
             },
+            recording: Default::default(),
+            client_tags: Default::default(),
         }
     }
 
@@ -208,7 +310,6 @@ use crate::config::{Config, ServerConfig, AuthConfig, SessionConfig};
 
 #[cfg(test)]
 mod config_tests {
-    use super::*;
     use crate::config::Config;
     use tempfile::NamedTempFile;
     use std::io::Write;
@@ -245,14 +346,30 @@ cleanup_interval_secs = 300
         assert_eq!(config.server.port, 9090);
         assert_eq!(config.server.max_connections, 500);
         assert_eq!(config.server.heartbeat_interval_secs, 60);
+        assert_eq!(config.server.max_missed_heartbeats, 3);
         assert_eq!(config.auth.jwt_secret, "my_secret_key");
         assert_eq!(config.auth.token_expiry_hours, 12);
+        assert_eq!(config.auth.refresh_token_expiry_hours, 720);
+        assert_eq!(config.auth.revocation_file, None);
+        assert!(config.client_tags.is_empty());
         assert_eq!(config.auth.max_failed_attempts, 5);
         assert_eq!(config.auth.lockout_duration_mins, 30);
         assert_eq!(config.session.max_sessions, 50);
         assert_eq!(config.session.session_timeout_mins, 120);
     }
 
+    #[test]
+    fn test_missing_config_falls_back_but_invalid_one_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("server.toml");
+        let config = Config::load_or_default(missing.to_str().unwrap()).unwrap();
+        assert_eq!(config.server.port, Config::default().server.port);
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(b"[server]\nport = \"not a port\"\n").unwrap();
+        assert!(Config::load_or_default(temp_file.path().to_str().unwrap()).is_err());
+    }
+
     #[test]
     fn test_config_default() {
         let config = Config::default();
@@ -274,7 +391,6 @@ cleanup_interval_secs = 300
 
 #[cfg(test)]
 mod message_handling_tests {
-    use super::*;
     use remote_hid_shared::*;
     use uuid::Uuid;
 
@@ -303,6 +419,8 @@ mod message_handling_tests {
         let create_session = SessionControlMessage::CreateSession {
             client_id: "test_client".to_string(),
             client_name: Some("Test Client".to_string()),
+            platform: None,
+            requires_consent: false,
         };
         
         let message = Message::session_control(None, create_session);
@@ -311,7 +429,7 @@ mod message_handling_tests {
         assert!(message.session_id.is_none());
         
         match message.payload {
-            MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name }) => {
+            MessagePayload::SessionControl(SessionControlMessage::CreateSession { client_id, client_name, .. }) => {
                 assert_eq!(client_id, "test_client");
                 assert_eq!(client_name, Some("Test Client".to_string()));
             }
@@ -421,4 +539,2870 @@ mod message_handling_tests {
             _ => panic!("Wrong status message type"),
         }
     }
-}
\ No newline at end of file
+}
+/// Helpers for driving a live server over real WebSocket connections
+#[cfg(test)]
+mod support {
+    use crate::config::Config;
+    use crate::server::{SessionServer, ShutdownHandle};
+    use futures_util::{SinkExt, StreamExt};
+    use remote_hid_shared::*;
+    use std::{net::SocketAddr, sync::{Arc, OnceLock}, time::Duration};
+    use tokio::{net::{TcpListener, TcpStream}, task::JoinHandle};
+    use uuid::Uuid;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, http::header::AUTHORIZATION, protocol::Message as WsMessage},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    pub type TestSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Secret test servers sign tokens with when the config leaves the placeholder
+    pub const TEST_JWT_SECRET: &str = "test_secret";
+
+    pub async fn start_server(config: Config) -> SocketAddr {
+        start_server_with_shutdown(config).await.0
+    }
+
+    /// Start a server that can be stopped; the join handle yields `serve`'s result.
+    /// Unless the config names its own user database, `admin`/`admin123` can log in,
+    /// and unless it sets its own secret, tokens are signed with `TEST_JWT_SECRET`.
+    /// Challenge/response keys use the fewest iterations clients accept, to keep logins quick.
+    pub async fn start_server_with_shutdown(mut config: Config) -> (SocketAddr, ShutdownHandle, JoinHandle<anyhow::Result<()>>) {
+        if config.auth.users_file == Config::default().auth.users_file {
+            config.auth.users_file = test_users_file();
+        }
+        if config.auth.jwt_secret == Config::default().auth.jwt_secret {
+            config.auth.jwt_secret = TEST_JWT_SECRET.to_string();
+        }
+        config.auth.password_hashing.scram_iterations = MIN_SCRAM_ITERATIONS;
+        let server = Arc::new(SessionServer::new(config).await.unwrap());
+        let shutdown = server.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+        (addr, shutdown, serving)
+    }
+
+    /// User database holding `admin`/`admin123`, hashed once per test run
+    fn test_users_file() -> String {
+        static USERS: OnceLock<tempfile::TempPath> = OnceLock::new();
+        let path = USERS.get_or_init(|| {
+            let mut admin = User::new("admin".to_string(), "admin123", &fast_scram()).unwrap();
+            admin.roles = vec![Role::Admin];
+            let mut store = UserStore::new();
+            store.add_user(admin);
+            let file = tempfile::NamedTempFile::new().unwrap();
+            serde_json::to_writer(&file, &store).unwrap();
+            file.into_temp_path()
+        });
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Password hashing as the test servers are configured: default hashes, quick challenge/response keys
+    pub fn fast_scram() -> AuthManager {
+        AuthManager::new("test_secret", 1).with_password_hashing(PasswordHashing {
+            scram_iterations: MIN_SCRAM_ITERATIONS,
+            ..PasswordHashing::default()
+        })
+    }
+
+    pub async fn connect(addr: SocketAddr, token: Option<&str>) -> TestSocket {
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+        if let Some(token) = token {
+            request.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        }
+        let (ws, _) = connect_async(request).await.unwrap();
+        ws
+    }
+
+    pub async fn send(ws: &mut TestSocket, message: Message) {
+        let json = serde_json::to_string(&message).unwrap();
+        ws.send(WsMessage::Text(json)).await.unwrap();
+    }
+
+    /// Next protocol message, or `None` once the server closes the connection
+    pub async fn recv(ws: &mut TestSocket) -> Option<Message> {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(10), ws.next())
+                .await
+                .expect("timed out waiting for server");
+            match frame {
+                Some(Ok(WsMessage::Text(text))) => return Some(serde_json::from_str(&text).unwrap()),
+                Some(Ok(WsMessage::Close(_))) | None | Some(Err(_)) => return None,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    /// Connect with a token minted for the default admin, skipping the password check
+    pub async fn connect_as(addr: SocketAddr, client_type: ClientType, client_id: Option<&str>) -> TestSocket {
+        connect_as_user(addr, "admin", client_type, client_id).await
+    }
+
+    /// Connect with a token minted for `username`, skipping the password check
+    pub async fn connect_as_user(addr: SocketAddr, username: &str, client_type: ClientType, client_id: Option<&str>) -> TestSocket {
+        let token = mint_token(username, client_type, client_id);
+        connect_with_token(addr, &token).await
+    }
+
+    /// Mint a token with the scopes the test user database would grant:
+    /// `admin` is an administrator, anyone else may control every client
+    pub fn mint_token(username: &str, client_type: ClientType, client_id: Option<&str>) -> String {
+        let scopes: &[&str] = if username == "admin" { &["admin"] } else { &["control", "client:*"] };
+        mint_scoped_token(username, client_type, client_id, scopes)
+    }
+
+    pub fn mint_scoped_token(username: &str, client_type: ClientType, client_id: Option<&str>, scopes: &[&str]) -> String {
+        AuthManager::new(TEST_JWT_SECRET, 1)
+            .generate_scoped_token(username, &client_type.to_string(), client_id.map(str::to_string), scopes.iter().map(|s| s.to_string()).collect())
+            .unwrap()
+    }
+
+    /// Connect presenting `token` as a bearer token and expect it to be accepted
+    pub async fn connect_with_token(addr: SocketAddr, token: &str) -> TestSocket {
+        let mut ws = connect(addr, Some(token)).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success: true, .. })) => ws,
+            other => panic!("token login failed: {:?}", other),
+        }
+    }
+
+    pub async fn connect_hid_client(addr: SocketAddr, client_id: &str) -> TestSocket {
+        let mut ws = connect_as(addr, ClientType::HidClient, Some(client_id)).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: client_id.to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })).await;
+        // Give the server a moment to register the client
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        ws
+    }
+
+    pub async fn connect_commander(addr: SocketAddr, target: &str) -> (TestSocket, Option<Message>) {
+        let mut ws = connect_as(addr, ClientType::Commander, None).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: target.to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        let reply = recv(&mut ws).await;
+        (ws, reply)
+    }
+
+    pub async fn join(addr: SocketAddr, target: &str) -> (TestSocket, Uuid) {
+        match connect_commander(addr, target).await {
+            (ws, Some(Message { payload: MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id, .. }), .. })) => (ws, session_id),
+            (_, other) => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    pub fn assert_error(message: Option<Message>, expected: &str) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::Status(StatusMessage::Error { error_code, .. })) => assert_eq!(error_code, expected),
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    pub fn assert_session_ended(message: Option<Message>, session_id: Uuid) {
+        match message {
+            Some(Message { session_id: id, payload: MessagePayload::SessionControl(SessionControlMessage::SessionEnded { .. }), .. }) => {
+                assert_eq!(id, Some(session_id));
+            }
+            other => panic!("expected SessionEnded, got {:?}", other),
+        }
+    }
+
+    pub fn mouse_move(session_id: Uuid, x: i32) -> Message {
+        Message::hid_event(session_id, HidEvent::MouseMove { x, y: 0, absolute: false })
+    }
+
+    /// Log in with username/password and return the issued token
+    pub async fn login(ws: &mut TestSocket, username: &str, password: &str, client_type: ClientType, client_id: Option<&str>) -> String {
+        login_with_refresh(ws, username, password, client_type, client_id).await.0
+    }
+
+    /// Log in with username/password and return the issued access and refresh tokens
+    pub async fn login_with_refresh(ws: &mut TestSocket, username: &str, password: &str, client_type: ClientType, client_id: Option<&str>) -> (String, String) {
+        expect_tokens(scram_login(ws, username, password, client_type, client_id).await)
+    }
+
+    /// Run the challenge/response login and return the server's final reply.
+    /// A server signature, when sent, must verify against the password.
+    pub async fn scram_login(ws: &mut TestSocket, username: &str, password: &str, client_type: ClientType, client_id: Option<&str>) -> Option<Message> {
+        let mut client = ScramClient::new(username, password);
+        send(ws, Message::auth(client.start(client_type, client_id.map(str::to_string)))).await;
+
+        let message = recv(ws).await?;
+        let MessagePayload::Auth(AuthMessage::ScramChallenge { nonce, salt, iterations }) = &message.payload else {
+            return Some(message);
+        };
+        send(ws, Message::auth(client.respond(nonce, salt, *iterations).unwrap())).await;
+
+        let message = recv(ws).await?;
+        let MessagePayload::Auth(AuthMessage::ScramServerFinal { server_signature }) = &message.payload else {
+            return Some(message);
+        };
+        assert!(client.verify_server(server_signature), "server signature did not verify");
+        recv(ws).await
+    }
+
+    /// Access and refresh token from a successful `AuthMessage::Response`
+    pub fn expect_tokens(message: Option<Message>) -> (String, String) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success: true, token: Some(token), refresh_token: Some(refresh_token), .. })) => {
+                (token, refresh_token)
+            }
+            other => panic!("expected tokens, got {:?}", other),
+        }
+    }
+
+    pub fn assert_auth_error(message: Option<Message>, expected: &str) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success: false, error_code, .. })) => {
+                assert_eq!(error_code.as_deref(), Some(expected));
+            }
+            other => panic!("expected {}, got {:?}", expected, other),
+        }
+    }
+
+    pub fn refresh(refresh_token: &str) -> Message {
+        Message::auth(AuthMessage::Refresh { refresh_token: refresh_token.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod handshake_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+
+    #[tokio::test]
+    async fn test_silent_peer_dropped_at_handshake_deadline() {
+        let mut config = Config::default();
+        config.server.handshake_timeout_secs = 1;
+        let addr = start_server(config).await;
+
+        // Sending nothing at all...
+        let mut silent = connect(addr, None).await;
+        assert!(recv(&mut silent).await.is_none());
+
+        // ...or stopping halfway through a challenge
+        let mut stalled = connect(addr, None).await;
+        send(&mut stalled, Message::auth(ScramClient::new("admin", "admin123").start(ClientType::Commander, None))).await;
+        assert!(matches!(recv(&mut stalled).await.map(|m| m.payload), Some(MessagePayload::Auth(AuthMessage::ScramChallenge { .. }))));
+        assert!(recv(&mut stalled).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_control_rejected_before_auth() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+
+        send(&mut ws, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "client1".to_string(),
+            mode: JoinMode::Control,
+        })).await;
+
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::Status(StatusMessage::Error { error_code, .. })) => {
+                assert_eq!(error_code, "AUTH_REQUIRED");
+            }
+            other => panic!("expected AUTH_REQUIRED, got {:?}", other),
+        }
+        assert!(recv(&mut ws).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_password_login_issues_token() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+
+        let token = login(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        let claims = AuthManager::new(TEST_JWT_SECRET, 24)
+            .validate_token(&token)
+            .unwrap();
+        assert_eq!(claims.sub, "admin");
+        assert_eq!(claims.client_type, "Commander");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_rejected() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+
+        match scram_login(&mut ws, "admin", "wrong", ClientType::Commander, None).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success, token, error_message, .. })) => {
+                assert!(!success);
+                assert!(token.is_none());
+                assert!(error_message.is_some());
+            }
+            other => panic!("expected auth response, got {:?}", other),
+        }
+        assert!(recv(&mut ws).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_accepted() {
+        let config = Config::default();
+        let token = AuthManager::new(TEST_JWT_SECRET, 1)
+            .generate_token("admin", "Commander", None)
+            .unwrap();
+        let addr = start_server(config).await;
+
+        let mut ws = connect(addr, Some(&token)).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success, expires_at, .. })) => {
+                assert!(success);
+                assert!(expires_at.is_some());
+            }
+            other => panic!("expected auth response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_bearer_token_rejected() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, Some("not-a-token")).await;
+
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success, .. })) => assert!(!success),
+            other => panic!("expected auth response, got {:?}", other),
+        }
+        assert!(recv(&mut ws).await.is_none());
+    }
+}
+
+#[cfg(test)]
+mod forwarding_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_commander_events_reach_hid_client() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        for x in 0..3 {
+            send(&mut commander, mouse_move(session_id, x)).await;
+        }
+
+        for expected in 0..3 {
+            match recv(&mut hid).await {
+                Some(Message { session_id: id, payload: MessagePayload::HidEvent(HidEvent::MouseMove { x, .. }), .. }) => {
+                    assert_eq!(id, Some(session_id));
+                    assert_eq!(x, expected);
+                }
+                other => panic!("expected forwarded mouse move, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_hid_client_does_not_stall_commander() {
+        let addr = start_server(Config::default()).await;
+        // This HID client never reads, so its socket buffers eventually fill up
+        let _stalled = connect_hid_client(addr, "stalled").await;
+        let (mut commander, session_id) = join(addr, "stalled").await;
+
+        for _ in 0..2000 {
+            send(&mut commander, mouse_move(session_id, 1)).await;
+        }
+
+        // Other connections are still served while the HID client lags
+        let (_, reply) = connect_commander(addr, "missing").await;
+        assert_error(reply, "CLIENT_NOT_CONNECTED");
+    }
+
+    #[tokio::test]
+    async fn test_second_commander_rejected_while_busy() {
+        // Without a wait queue a busy client is refused outright
+        let mut config = Config::default();
+        config.session.max_queue_length = 0;
+        let addr = start_server(config).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (_first, _) = join(addr, "client1").await;
+
+        let (mut second, reply) = connect_commander(addr, "client1").await;
+        assert_error(reply, "CLIENT_BUSY");
+        assert!(recv(&mut second).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_event_with_wrong_session_id_rejected() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, _) = join(addr, "client1").await;
+
+        send(&mut commander, mouse_move(Uuid::new_v4(), 1)).await;
+        assert_error(recv(&mut commander).await, "INVALID_SESSION");
+    }
+
+    #[tokio::test]
+    async fn test_commander_disconnect_ends_session() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (commander, session_id) = join(addr, "client1").await;
+
+        drop(commander);
+        assert_session_ended(recv(&mut hid).await, session_id);
+
+        // The client is free for the next commander
+        join(addr, "client1").await;
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_disconnect_ends_session() {
+        let addr = start_server(Config::default()).await;
+        let hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        drop(hid);
+        match recv(&mut commander).await.map(|m| m.payload) {
+            Some(MessagePayload::Status(StatusMessage::ConnectionStatus { connected, .. })) => assert!(!connected),
+            other => panic!("expected ConnectionStatus, got {:?}", other),
+        }
+        assert_session_ended(recv(&mut commander).await, session_id);
+        assert!(recv(&mut commander).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_end_session_notifies_both_sides() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        send(&mut commander, Message::session_control(Some(session_id), SessionControlMessage::EndSession)).await;
+        assert_session_ended(recv(&mut commander).await, session_id);
+        assert_session_ended(recv(&mut hid).await, session_id);
+    }
+}
+
+#[cfg(test)]
+mod reaper_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+
+    #[tokio::test]
+    async fn test_idle_session_is_reaped() {
+        let mut config = Config::default();
+        config.session.session_timeout_mins = 0;
+        config.session.cleanup_interval_secs = 1;
+        let addr = start_server(config).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        for ws in [&mut commander, &mut hid] {
+            match recv(ws).await {
+                Some(Message { session_id: id, payload: MessagePayload::SessionControl(SessionControlMessage::SessionEnded { reason }), .. }) => {
+                    assert_eq!(id, Some(session_id));
+                    assert_eq!(reason, "idle timeout");
+                }
+                other => panic!("expected SessionEnded, got {:?}", other),
+            }
+        }
+        // The commander is disconnected, the HID client stays available
+        assert!(recv(&mut commander).await.is_none());
+        join(addr, "client1").await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions_beyond_limit_refused() {
+        let mut config = Config::default();
+        config.session.max_sessions = 1;
+        let addr = start_server(config).await;
+        let _first_client = connect_hid_client(addr, "client1").await;
+        let _second_client = connect_hid_client(addr, "client2").await;
+        let (_first, _) = join(addr, "client1").await;
+
+        let (_, reply) = connect_commander(addr, "client2").await;
+        assert_error(reply, "SESSION_LIMIT");
+    }
+}
+
+#[cfg(test)]
+mod heartbeat_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+
+    fn heartbeat_config() -> Config {
+        let mut config = Config::default();
+        config.server.heartbeat_interval_secs = 1;
+        config.server.max_missed_heartbeats = 2;
+        config
+    }
+
+    /// Next message that is not a heartbeat request
+    async fn recv_skipping_heartbeats(ws: &mut TestSocket) -> Option<Message> {
+        loop {
+            match recv(ws).await {
+                Some(Message { payload: MessagePayload::Status(StatusMessage::Heartbeat), .. }) => continue,
+                other => return other,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency_reported_to_commander() {
+        let addr = start_server(heartbeat_config()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        match recv(&mut hid).await.map(|m| m.payload) {
+            Some(MessagePayload::Status(StatusMessage::Heartbeat)) => {}
+            other => panic!("expected heartbeat, got {:?}", other),
+        }
+        send(&mut hid, Message::status(None, StatusMessage::Heartbeat)).await;
+
+        match recv_skipping_heartbeats(&mut commander).await {
+            Some(Message { session_id: id, payload: MessagePayload::Status(StatusMessage::ConnectionStatus { connected, latency_ms }), .. }) => {
+                assert_eq!(id, Some(session_id));
+                assert!(connected);
+                assert!(latency_ms.is_some());
+            }
+            other => panic!("expected ConnectionStatus, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_dropped() {
+        let addr = start_server(heartbeat_config()).await;
+        // The HID client never answers its heartbeats
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+        let commander_replies = tokio::spawn(async move {
+            let mut statuses = Vec::new();
+            while let Some(message) = recv(&mut commander).await {
+                match message.payload {
+                    MessagePayload::Status(StatusMessage::Heartbeat) => {
+                        send(&mut commander, Message::status(Some(session_id), StatusMessage::Heartbeat)).await;
+                    }
+                    payload => statuses.push(payload),
+                }
+            }
+            statuses
+        });
+
+        let mut heartbeats = 0;
+        while let Some(message) = recv(&mut hid).await {
+            if let MessagePayload::Status(StatusMessage::Heartbeat) = message.payload {
+                heartbeats += 1;
+            }
+        }
+        assert_eq!(heartbeats, 2);
+
+        let statuses = commander_replies.await.unwrap();
+        assert!(matches!(
+            statuses.as_slice(),
+            [
+                MessagePayload::Status(StatusMessage::ConnectionStatus { connected: false, latency_ms: None }),
+                MessagePayload::SessionControl(SessionControlMessage::SessionEnded { .. }),
+            ]
+        ), "unexpected messages: {:?}", statuses);
+    }
+}
+
+#[cfg(test)]
+mod client_list_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::time::Duration;
+
+    async fn list_clients(ws: &mut TestSocket) -> Vec<ClientInfo> {
+        send(ws, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        match recv(ws).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ClientList { clients })) => clients,
+            other => panic!("expected ClientList, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_clients_reports_registration_and_sessions() {
+        let addr = start_server(Config::default()).await;
+        let mut office = connect_as(addr, ClientType::HidClient, Some("office-pc")).await;
+        send(&mut office, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: "office-pc".to_string(),
+            client_name: Some("Office PC".to_string()),
+            platform: Some("Windows".to_string()),
+            requires_consent: false,
+        })).await;
+        let _lab = connect_hid_client(addr, "lab-mac").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Listing works before the commander joins anything
+        let mut observer = connect_as(addr, ClientType::Commander, None).await;
+        let clients = list_clients(&mut observer).await;
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].client_id, "lab-mac");
+        assert_eq!(clients[0].platform, "unknown");
+        assert_eq!(clients[1].client_id, "office-pc");
+        assert_eq!(clients[1].client_name.as_deref(), Some("Office PC"));
+        assert_eq!(clients[1].platform, "Windows");
+        assert!(clients.iter().all(|c| !c.commander_connected));
+
+        // ... and from inside a session
+        let (mut commander, _) = join(addr, "office-pc").await;
+        let clients = list_clients(&mut commander).await;
+        assert!(clients[1].commander_connected);
+        assert!(!clients[0].commander_connected);
+
+        // The observer can still list and then join a free client
+        let clients = list_clients(&mut observer).await;
+        assert!(clients[1].commander_connected);
+        send(&mut observer, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "lab-mac".to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        match recv(&mut observer).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { target_client_id, .. })) => {
+                assert_eq!(target_client_id, "lab-mac");
+            }
+            other => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_cannot_list_clients() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_as(addr, ClientType::HidClient, Some("client1")).await;
+
+        send(&mut hid, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        assert_error(recv(&mut hid).await, "PROTOCOL_VIOLATION");
+    }
+}
+
+#[cfg(test)]
+mod lockout_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+
+    fn lockout_config() -> Config {
+        let mut config = Config::default();
+        config.auth.max_failed_attempts = 2;
+        config.auth.lockout_duration_mins = 15;
+        config
+    }
+
+    /// Attempt a password login on a fresh connection and return the error code, if any
+    async fn attempt(addr: SocketAddr, username: &str, password: &str) -> Option<String> {
+        let mut ws = connect(addr, None).await;
+        match scram_login(&mut ws, username, password, ClientType::Commander, None).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success, error_code, .. })) => {
+                assert_eq!(success, error_code.is_none());
+                error_code
+            }
+            other => panic!("expected auth response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_username_locked_after_failures() {
+        let addr = start_server(lockout_config()).await;
+
+        assert_eq!(attempt(addr, "admin", "wrong").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        assert_eq!(attempt(addr, "admin", "wrong").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        // Even the right password is refused while locked out
+        assert_eq!(attempt(addr, "admin", "admin123").await.as_deref(), Some("LOCKED_OUT"));
+    }
+
+    #[tokio::test]
+    async fn test_address_locked_after_failures_across_usernames() {
+        let addr = start_server(lockout_config()).await;
+
+        assert_eq!(attempt(addr, "ghost1", "guess").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        assert_eq!(attempt(addr, "ghost2", "guess").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        assert_eq!(attempt(addr, "admin", "admin123").await.as_deref(), Some("LOCKED_OUT"));
+    }
+
+    #[tokio::test]
+    async fn test_successful_login_resets_failures() {
+        let addr = start_server(lockout_config()).await;
+
+        assert_eq!(attempt(addr, "admin", "wrong").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        assert_eq!(attempt(addr, "admin", "admin123").await, None);
+        assert_eq!(attempt(addr, "admin", "wrong").await.as_deref(), Some("INVALID_CREDENTIALS"));
+        assert_eq!(attempt(addr, "admin", "admin123").await, None);
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use super::support::*;
+    use crate::config::{CertificateIdentity, Config, TlsConfig};
+    use remote_hid_shared::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio_tungstenite::{connect_async, connect_async_tls_with_config, Connector};
+
+    /// Write a self-signed certificate for `localhost` and its key into `dir`
+    fn write_self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join("server.pem");
+        let key_path = dir.join("server.key");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn tls_config(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Config {
+        let mut config = Config::default();
+        config.server.tls = Some(TlsConfig {
+            cert_path: cert_path.display().to_string(),
+            key_path: key_path.display().to_string(),
+            client_ca_path: client_ca_path.map(|path| path.display().to_string()),
+            client_cert_optional: false,
+        });
+        config
+    }
+
+    /// Write a client CA and a client certificate it issued with the given common name;
+    /// returns the CA, certificate and key paths
+    fn write_client_certificate(dir: &Path, common_name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "Test client CA");
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::CommonName, common_name);
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+
+        let ca_path = dir.join("client-ca.pem");
+        let cert_path = dir.join("client.pem");
+        let key_path = dir.join("client.key");
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (ca_path, cert_path, key_path)
+    }
+
+    /// Server requiring certificates from a client CA, and a client certificate named `common_name`
+    fn mutual_tls(dir: &Path, common_name: &str, identity: Option<&str>) -> (Config, Arc<rustls::ClientConfig>) {
+        let (cert_path, key_path) = write_self_signed(dir);
+        let (ca_path, client_cert, client_key) = write_client_certificate(dir, common_name);
+        let mut config = tls_config(&cert_path, &key_path, Some(&ca_path));
+        if let Some(identity) = identity {
+            config.auth.client_certificates.insert(common_name.to_string(), identity.to_string().try_into().unwrap());
+        }
+        let client = tls::client_config_with_identity(Some(&cert_path), &client_cert, &client_key).unwrap();
+        (config, client)
+    }
+
+    async fn connect_tls(addr: std::net::SocketAddr, client: Arc<rustls::ClientConfig>) -> TestSocket {
+        let url = format!("wss://localhost:{}", addr.port());
+        let (ws, _) = connect_async_tls_with_config(url, None, false, Some(Connector::Rustls(client))).await.unwrap();
+        ws
+    }
+
+    async fn certificate_login(ws: &mut TestSocket, client_type: ClientType, client_id: Option<&str>) -> Option<Message> {
+        send(ws, Message::auth(AuthMessage::CertificateLogin {
+            client_type,
+            client_id: client_id.map(str::to_string),
+        })).await;
+        recv(ws).await
+    }
+
+    fn token_claims(token: &str) -> Claims {
+        AuthManager::new(TEST_JWT_SECRET, 1).validate_token(token).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_wss_login_with_custom_ca() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path) = write_self_signed(dir.path());
+        let addr = start_server(tls_config(&cert_path, &key_path, None)).await;
+
+        let connector = Connector::Rustls(tls::client_config(&cert_path).unwrap());
+        let url = format!("wss://localhost:{}", addr.port());
+        let (mut ws, _) = connect_async_tls_with_config(url, None, false, Some(connector)).await.unwrap();
+
+        let token = login(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        assert!(!token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plain_websocket_refused_by_tls_listener() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path) = write_self_signed(dir.path());
+        let addr = start_server(tls_config(&cert_path, &key_path, None)).await;
+
+        assert!(connect_async(format!("ws://{}", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required_with_client_ca() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path) = write_self_signed(dir.path());
+        let addr = start_server(tls_config(&cert_path, &key_path, Some(&cert_path))).await;
+
+        let connector = Connector::Rustls(tls::client_config(&cert_path).unwrap());
+        let url = format!("wss://localhost:{}", addr.port());
+        // With TLS 1.3 the missing certificate may only be reported after the handshake
+        if let Ok((mut ws, _)) = connect_async_tls_with_config(url, None, false, Some(connector)).await {
+            assert!(recv(&mut ws).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificate_login_as_mapped_user() {
+        let dir = TempDir::new().unwrap();
+        let (config, client) = mutual_tls(dir.path(), "ops-laptop-17", Some("user:admin"));
+        let addr = start_server(config).await;
+
+        let mut ws = connect_tls(addr, client).await;
+        let (token, _) = expect_tokens(certificate_login(&mut ws, ClientType::Commander, None).await);
+        let claims = token_claims(&token);
+        assert_eq!(claims.sub, "admin");
+        assert!(claims.has_role(Role::Admin));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_login_resets_failures() {
+        let dir = TempDir::new().unwrap();
+        let (mut config, client) = mutual_tls(dir.path(), "ops-laptop-17", Some("user:admin"));
+        config.auth.max_failed_attempts = 2;
+        let addr = start_server(config).await;
+
+        for _ in 0..2 {
+            let mut ws = connect_tls(addr, client.clone()).await;
+            assert_auth_error(scram_login(&mut ws, "admin", "wrong", ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+            let mut ws = connect_tls(addr, client.clone()).await;
+            expect_tokens(certificate_login(&mut ws, ClientType::Commander, None).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificate_login_as_hid_client() {
+        let dir = TempDir::new().unwrap();
+        let (config, client) = mutual_tls(dir.path(), "kiosk-1.hid.example.com", Some("client:kiosk-1"));
+        let addr = start_server(config).await;
+
+        // The certificate only authenticates the HID client it is mapped to
+        let mut ws = connect_tls(addr, client.clone()).await;
+        assert_auth_error(certificate_login(&mut ws, ClientType::HidClient, Some("kiosk-2")).await, "INVALID_CREDENTIALS");
+        let mut ws = connect_tls(addr, client.clone()).await;
+        assert_auth_error(certificate_login(&mut ws, ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+
+        let mut ws = connect_tls(addr, client.clone()).await;
+        let (token, refresh_token) = expect_tokens(certificate_login(&mut ws, ClientType::HidClient, Some("kiosk-1")).await);
+        let claims = token_claims(&token);
+        assert_eq!(claims.sub, "device:kiosk-1");
+        assert_eq!(claims.client_id.as_deref(), Some("kiosk-1"));
+
+        send(&mut ws, refresh(&refresh_token)).await;
+        expect_tokens(recv(&mut ws).await);
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_certificate_rejected() {
+        let dir = TempDir::new().unwrap();
+        let (config, client) = mutual_tls(dir.path(), "stranger", None);
+        let addr = start_server(config).await;
+
+        let mut ws = connect_tls(addr, client.clone()).await;
+        assert_auth_error(certificate_login(&mut ws, ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+
+        // A valid certificate still allows logging in with a password
+        let mut ws = connect_tls(addr, client).await;
+        let token = login(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        assert!(!token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let (mut config, _) = mutual_tls(dir.path(), "ops-laptop-17", Some("user:admin"));
+        if let Some(tls) = config.server.tls.as_mut() {
+            tls.client_cert_optional = true;
+        }
+        let addr = start_server(config).await;
+        let server_ca = dir.path().join("server.pem");
+
+        let mut ws = connect_tls(addr, tls::client_config(&server_ca).unwrap()).await;
+        assert_auth_error(certificate_login(&mut ws, ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+
+        let mut ws = connect_tls(addr, tls::client_config(&server_ca).unwrap()).await;
+        let token = login(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        assert!(!token.is_empty());
+    }
+
+    #[test]
+    fn test_certificate_identity_parsing() {
+        let parsed: crate::config::AuthConfig = toml::from_str(r#"
+            jwt_secret = "secret"
+            token_expiry_hours = 1
+            max_failed_attempts = 3
+            lockout_duration_mins = 15
+            [client_certificates]
+            "ops-laptop-17" = "user:alice"
+            "kiosk-1" = "client:kiosk-1"
+        "#).unwrap();
+        assert_eq!(parsed.client_certificates["ops-laptop-17"], CertificateIdentity::User("alice".to_string()));
+        assert_eq!(parsed.client_certificates["kiosk-1"], CertificateIdentity::Client("kiosk-1".to_string()));
+
+        for invalid in ["alice", "user:", "group:ops"] {
+            assert!(CertificateIdentity::try_from(invalid.to_string()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_certificate_fails_startup() {
+        let dir = TempDir::new().unwrap();
+        let config = tls_config(&dir.path().join("missing.pem"), &dir.path().join("missing.key"), None);
+        assert!(crate::server::SessionServer::new(config).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod shutdown_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::time::Duration;
+
+    fn assert_shutdown_notice(message: Option<Message>) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionEnded { reason })) => {
+                assert_eq!(reason, "server shutting down");
+            }
+            other => panic!("expected SessionEnded, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_and_disconnects_everyone() {
+        let (addr, shutdown, serving) = start_server_with_shutdown(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let mut idle_hid = connect_hid_client(addr, "client2").await;
+        let (mut commander, _) = join(addr, "client1").await;
+
+        shutdown.shutdown();
+
+        for ws in [&mut hid, &mut idle_hid, &mut commander] {
+            assert_shutdown_notice(recv(ws).await);
+            assert!(recv(ws).await.is_none());
+        }
+        tokio::time::timeout(Duration::from_secs(10), serving).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_reaches_unregistered_and_queued_connections() {
+        let (addr, shutdown, serving) = start_server_with_shutdown(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (_commander, _) = join(addr, "client1").await;
+        let (mut waiter, queued) = connect_commander(addr, "client1").await;
+        assert!(matches!(queued.map(|m| m.payload), Some(MessagePayload::SessionControl(SessionControlMessage::QueuePosition { position: 1, .. }))));
+        let mut unregistered = connect_as(addr, ClientType::Commander, None).await;
+
+        shutdown.shutdown();
+
+        for ws in [&mut waiter, &mut unregistered] {
+            assert_shutdown_notice(recv(ws).await);
+            assert!(recv(ws).await.is_none());
+        }
+        tokio::time::timeout(Duration::from_secs(10), serving).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_connections_accepted_after_shutdown() {
+        let (addr, shutdown, serving) = start_server_with_shutdown(Config::default()).await;
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(10), serving).await.unwrap().unwrap().unwrap();
+
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod refresh_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+
+    #[tokio::test]
+    async fn test_refresh_rotates_tokens_on_live_connection() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let (token, refresh_token) = login_with_refresh(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+
+        send(&mut ws, refresh(&refresh_token)).await;
+        let (new_token, new_refresh_token) = expect_tokens(recv(&mut ws).await);
+        assert_ne!(new_token, token);
+        assert_ne!(new_refresh_token, refresh_token);
+
+        let auth_manager = AuthManager::new(TEST_JWT_SECRET, 1);
+        assert_eq!(auth_manager.validate_token(&new_token).unwrap().sub, "admin");
+
+        // The old refresh token was consumed by the rotation
+        send(&mut ws, refresh(&refresh_token)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_TOKEN");
+
+        // The connection stays usable after a failed refresh
+        send(&mut ws, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ClientList { .. })) => {}
+            other => panic!("expected ClientList, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_authenticates_new_connection() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let (_, refresh_token) = login_with_refresh(&mut ws, "admin", "admin123", ClientType::HidClient, Some("client1")).await;
+        drop(ws);
+
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, refresh(&refresh_token)).await;
+        let (token, _) = expect_tokens(recv(&mut ws).await);
+        let claims = AuthManager::new(TEST_JWT_SECRET, 1).validate_token(&token).unwrap();
+        assert_eq!(claims.client_type, "HidClient");
+        assert_eq!(claims.client_id.as_deref(), Some("client1"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_rejected_as_bearer() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let (_, refresh_token) = login_with_refresh(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+
+        let mut ws = connect(addr, Some(&refresh_token)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_TOKEN");
+        assert!(recv(&mut ws).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_logout_disconnects_and_revokes_refresh_token() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let (token, refresh_token) = login_with_refresh(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+
+        send(&mut ws, Message::auth(AuthMessage::Logout)).await;
+        assert!(recv(&mut ws).await.is_none());
+
+        let mut ws = connect(addr, Some(&token)).await;
+        assert_auth_error(recv(&mut ws).await, "TOKEN_REVOKED");
+
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, refresh(&refresh_token)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_TOKEN");
+        assert!(recv(&mut ws).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_access_token_disconnects() {
+        let mut config = Config::default();
+        config.server.heartbeat_interval_secs = 1;
+        config.auth.token_expiry_hours = 0;
+        let addr = start_server(config).await;
+        let mut ws = connect(addr, None).await;
+        login_with_refresh(&mut ws, "admin", "admin123", ClientType::HidClient, Some("client1")).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: "client1".to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })).await;
+
+        assert_auth_error(recv(&mut ws).await, "TOKEN_EXPIRED");
+        assert!(recv(&mut ws).await.is_none());
+    }
+}
+
+#[cfg(test)]
+mod revocation_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::time::Duration;
+
+    fn jti(token: &str) -> String {
+        AuthManager::new(TEST_JWT_SECRET, 1).validate_token(token).unwrap().jti
+    }
+
+    async fn revoke(addr: std::net::SocketAddr, jti: Option<String>, username: Option<&str>) -> usize {
+        let mut admin = connect_as(addr, ClientType::Commander, None).await;
+        send(&mut admin, Message::auth(AuthMessage::Revoke { jti, username: username.map(str::to_string) })).await;
+        match recv(&mut admin).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Revoked { disconnected })) => disconnected,
+            other => panic!("expected Revoked, got {:?}", other),
+        }
+    }
+
+    /// Wait for the next second. A user revocation only reaches tokens issued in
+    /// earlier seconds unless the server issued them itself, which minted ones are not.
+    async fn next_second() {
+        let elapsed = chrono::Utc::now().timestamp_subsec_millis() as u64;
+        tokio::time::sleep(Duration::from_millis(1000 - elapsed.min(999) + 10)).await;
+    }
+
+    async fn register(ws: &mut TestSocket, client_id: &str) {
+        send(ws, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: client_id.to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    #[tokio::test]
+    async fn test_revoke_by_jti_disconnects_and_rejects_token() {
+        let addr = start_server(Config::default()).await;
+        let token = mint_token("admin", ClientType::HidClient, Some("client1"));
+        let mut hid = connect_with_token(addr, &token).await;
+        register(&mut hid, "client1").await;
+
+        assert_eq!(revoke(addr, Some(jti(&token)), None).await, 1);
+        assert_auth_error(recv(&mut hid).await, "TOKEN_REVOKED");
+        assert!(recv(&mut hid).await.is_none());
+
+        let mut ws = connect(addr, Some(&token)).await;
+        assert_auth_error(recv(&mut ws).await, "TOKEN_REVOKED");
+    }
+
+    #[tokio::test]
+    async fn test_revoke_by_username_disconnects_only_that_user() {
+        let addr = start_server(Config::default()).await;
+        let mut alice = connect_as_user(addr, "alice", ClientType::HidClient, Some("client1")).await;
+        register(&mut alice, "client1").await;
+        let mut bob = connect_as_user(addr, "bob", ClientType::HidClient, Some("client2")).await;
+        register(&mut bob, "client2").await;
+
+        next_second().await;
+        assert_eq!(revoke(addr, None, Some("alice")).await, 1);
+        assert_auth_error(recv(&mut alice).await, "TOKEN_REVOKED");
+        assert!(recv(&mut alice).await.is_none());
+
+        // Bob is still registered and receives events
+        let (mut commander, session_id) = join(addr, "client2").await;
+        send(&mut commander, mouse_move(session_id, 5)).await;
+        match recv(&mut bob).await.map(|m| m.payload) {
+            Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 5, .. })) => {}
+            other => panic!("expected bob to receive the event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_unregistered_connection_dropped_on_heartbeat() {
+        let mut config = Config::default();
+        config.server.heartbeat_interval_secs = 1;
+        let addr = start_server(config).await;
+        let mut alice = connect_as_user(addr, "alice", ClientType::Commander, None).await;
+
+        next_second().await;
+        assert_eq!(revoke(addr, None, Some("alice")).await, 0);
+        assert_auth_error(recv(&mut alice).await, "TOKEN_REVOKED");
+        assert!(recv(&mut alice).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_right_after_user_revocation_accepted() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let (before, _) = expect_tokens(scram_login(&mut ws, "admin", "admin123", ClientType::Commander, None).await);
+
+        // Most likely within the same second as the revocation
+        revoke(addr, None, Some("admin")).await;
+        let mut ws = connect(addr, None).await;
+        let (after, _) = expect_tokens(scram_login(&mut ws, "admin", "admin123", ClientType::Commander, None).await);
+
+        let mut ws = connect(addr, Some(&before)).await;
+        assert_auth_error(recv(&mut ws).await, "TOKEN_REVOKED");
+        let mut ws = connect(addr, Some(&after)).await;
+        assert!(matches!(recv(&mut ws).await.map(|m| m.payload), Some(MessagePayload::Auth(AuthMessage::Response { success: true, .. }))));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_requires_admin() {
+        let addr = start_server(Config::default()).await;
+        let mut alice = connect_as_user(addr, "alice", ClientType::Commander, None).await;
+
+        send(&mut alice, Message::auth(AuthMessage::Revoke { jti: None, username: Some("admin".to_string()) })).await;
+        assert_error(recv(&mut alice).await, "FORBIDDEN");
+    }
+
+    #[tokio::test]
+    async fn test_revocations_persist_across_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.auth.revocation_file = Some(dir.path().join("revoked.json").to_string_lossy().into_owned());
+        let token = mint_token("alice", ClientType::Commander, None);
+
+        let addr = start_server(config.clone()).await;
+        revoke(addr, Some(jti(&token)), None).await;
+
+        let addr = start_server(config).await;
+        let mut ws = connect(addr, Some(&token)).await;
+        assert_auth_error(recv(&mut ws).await, "TOKEN_REVOKED");
+    }
+}
+
+#[cfg(test)]
+mod signing_key_tests {
+    use super::support::*;
+    use crate::config::{Config, KeyConfig};
+    use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+    use remote_hid_shared::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/../shared/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn ed25519_key(id: &str, name: &str, signing: bool) -> KeyConfig {
+        KeyConfig {
+            id: id.to_string(),
+            algorithm: Algorithm::EdDSA,
+            public_key_path: testdata(&format!("{}.pub.pem", name)),
+            private_key_path: signing.then(|| testdata(&format!("{}.pem", name))),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_signed_with_configured_key_and_published() {
+        let dir = tempfile::tempdir().unwrap();
+        let jwks_path = dir.path().join("jwks.json");
+        let mut config = Config::default();
+        config.auth.signing_key_id = Some("ed-new".to_string());
+        config.auth.keys = vec![ed25519_key("ed-new", "ed25519", true), ed25519_key("ed-old", "ed25519-old", false)];
+        config.auth.jwks_path = Some(jwks_path.to_string_lossy().into_owned());
+        let addr = start_server(config).await;
+
+        let mut ws = connect(addr, None).await;
+        let token = login(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("ed-new"));
+
+        let jwks: JwkSet = serde_json::from_str(&std::fs::read_to_string(&jwks_path).unwrap()).unwrap();
+        assert_eq!(jwks.keys.len(), 2);
+        let key = DecodingKey::from_jwk(jwks.find("ed-new").unwrap()).unwrap();
+        let claims = decode::<Claims>(&token, &key, &Validation::new(Algorithm::EdDSA)).unwrap().claims;
+        assert_eq!(claims.sub, "admin");
+    }
+
+    #[tokio::test]
+    async fn test_tokens_from_rotated_out_signing_key_accepted() {
+        let old_signer = AuthManager::with_keys(
+            SigningKey::from_pem("ed-old", Algorithm::EdDSA, &std::fs::read(testdata("ed25519-old.pem")).unwrap()).unwrap(),
+            vec![VerificationKey::from_pem("ed-old", Algorithm::EdDSA, &std::fs::read(testdata("ed25519-old.pub.pem")).unwrap()).unwrap()],
+            1,
+        ).unwrap();
+        let old_token = old_signer.generate_token("admin", "Commander", None).unwrap();
+
+        let mut config = Config::default();
+        config.auth.signing_key_id = Some("ed-new".to_string());
+        config.auth.keys = vec![ed25519_key("ed-new", "ed25519", true), ed25519_key("ed-old", "ed25519-old", false)];
+        let addr = start_server(config).await;
+        connect_with_token(addr, &old_token).await;
+
+        // Tokens signed with the shared secret are no longer accepted
+        let mut ws = connect(addr, Some(&mint_token("admin", ClientType::Commander, None))).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::Auth(AuthMessage::Response { success, .. })) => assert!(!success),
+            other => panic!("expected auth failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_must_be_configured() {
+        let mut config = Config::default();
+        config.auth.signing_key_id = Some("missing".to_string());
+        config.auth.keys = vec![ed25519_key("ed-new", "ed25519", true)];
+        assert!(crate::server::SessionServer::new(config).await.is_err());
+
+        let mut config = Config::default();
+        config.auth.signing_key_id = Some("ed-old".to_string());
+        config.auth.keys = vec![ed25519_key("ed-old", "ed25519-old", false)];
+        assert!(crate::server::SessionServer::new(config).await.is_err());
+    }
+
+    #[test]
+    fn test_placeholder_secret_replaced_by_random_one() {
+        let placeholder = Config::default().auth;
+        let token = crate::keys::auth_manager(&placeholder).unwrap()
+            .generate_token("admin", "Commander", None)
+            .unwrap();
+
+        assert!(AuthManager::new(&placeholder.jwt_secret, 1).validate_token(&token).is_err());
+        assert!(crate::keys::auth_manager(&placeholder).unwrap().validate_token(&token).is_err());
+    }
+}
+
+#[cfg(test)]
+mod scram_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+
+    fn legacy_request(username: &str, password: &str) -> Message {
+        Message::auth_request(username.to_string(), password.to_string(), ClientType::Commander, None)
+    }
+
+    #[tokio::test]
+    async fn test_challenge_response_login() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+
+        let (token, refresh_token) = login_with_refresh(&mut ws, "admin", "admin123", ClientType::Commander, None).await;
+        let claims = AuthManager::new(TEST_JWT_SECRET, 24).validate_token(&token).unwrap();
+        assert_eq!(claims.sub, "admin");
+        assert!(!refresh_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_and_unknown_user_rejected_after_challenge() {
+        let addr = start_server(Config::default()).await;
+
+        for (username, password) in [("admin", "wrong"), ("nobody", "admin123")] {
+            let mut ws = connect(addr, None).await;
+            let mut client = ScramClient::new(username, password);
+            send(&mut ws, Message::auth(client.start(ClientType::Commander, None))).await;
+
+            // Unknown users are challenged just like real ones
+            let Some(MessagePayload::Auth(AuthMessage::ScramChallenge { nonce, salt, iterations })) = recv(&mut ws).await.map(|m| m.payload) else {
+                panic!("expected a challenge for {}", username);
+            };
+            send(&mut ws, Message::auth(client.respond(&nonce, &salt, iterations).unwrap())).await;
+            assert_auth_error(recv(&mut ws).await, "INVALID_CREDENTIALS");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_for_another_nonce_rejected() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+        let mut client = ScramClient::new("admin", "admin123");
+        send(&mut ws, Message::auth(client.start(ClientType::Commander, None))).await;
+
+        let Some(MessagePayload::Auth(AuthMessage::ScramChallenge { nonce, salt, iterations })) = recv(&mut ws).await.map(|m| m.payload) else {
+            panic!("expected a challenge");
+        };
+        let Ok(AuthMessage::ScramProof { proof, .. }) = client.respond(&nonce, &salt, iterations) else {
+            panic!("expected a proof");
+        };
+        send(&mut ws, Message::auth(AuthMessage::ScramProof { nonce: format!("{}x", nonce), proof })).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_CREDENTIALS");
+    }
+
+    #[tokio::test]
+    async fn test_password_login_disabled_by_default() {
+        let addr = start_server(Config::default()).await;
+        let mut ws = connect(addr, None).await;
+
+        send(&mut ws, legacy_request("admin", "admin123")).await;
+        assert_auth_error(recv(&mut ws).await, "PASSWORD_LOGIN_DISABLED");
+        assert!(recv(&mut ws).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_password_login_when_enabled() {
+        let mut config = Config::default();
+        config.auth.allow_password_login = true;
+        let addr = start_server(config).await;
+
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, legacy_request("admin", "admin123")).await;
+        let (token, _) = expect_tokens(recv(&mut ws).await);
+        assert_eq!(AuthManager::new(TEST_JWT_SECRET, 24).validate_token(&token).unwrap().sub, "admin");
+
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, legacy_request("admin", "wrong")).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_CREDENTIALS");
+    }
+}
+
+#[cfg(test)]
+mod user_command_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use crate::users::{self, AccessArgs, UserCommand};
+    use remote_hid_shared::*;
+
+    fn config_in(dir: &tempfile::TempDir) -> Config {
+        let mut config = Config::default();
+        config.auth.users_file = dir.path().join("users.json").to_string_lossy().into_owned();
+        config.auth.password_hashing.scram_iterations = MIN_SCRAM_ITERATIONS;
+        config
+    }
+
+    fn add(config: &Config, username: &str, password: &str) -> anyhow::Result<()> {
+        let access = AccessArgs { roles: vec![Role::Control], clients: vec!["*".to_string()], tags: Vec::new() };
+        users::run(UserCommand::Add { username: username.to_string(), password: Some(password.to_string()), access }, &config.auth)
+    }
+
+    #[test]
+    fn test_user_commands_edit_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let load = || UserStore::load(&config.auth.users_file).unwrap();
+
+        add(&config, "alice", "first-password").unwrap();
+        assert!(add(&config, "alice", "other").is_err());
+        assert!(load().authenticate("alice", "first-password", &auth_manager).unwrap());
+
+        users::run(UserCommand::Passwd { username: "alice".to_string(), password: Some("second-password".to_string()) }, &config.auth).unwrap();
+        let mut store = load();
+        assert!(!store.authenticate("alice", "first-password", &auth_manager).unwrap());
+        assert!(store.authenticate("alice", "second-password", &auth_manager).unwrap());
+
+        let access = AccessArgs { roles: vec![Role::View], clients: Vec::new(), tags: vec!["lab".to_string()] };
+        users::run(UserCommand::Access { username: "alice".to_string(), access }, &config.auth).unwrap();
+        assert_eq!(load().get_user("alice").unwrap().scopes(), ["view", "tag:lab"]);
+
+        users::run(UserCommand::Disable { username: "alice".to_string() }, &config.auth).unwrap();
+        assert!(!load().get_user("alice").unwrap().active);
+        users::run(UserCommand::Enable { username: "alice".to_string() }, &config.auth).unwrap();
+        assert!(load().get_user("alice").unwrap().active);
+        users::run(UserCommand::List, &config.auth).unwrap();
+
+        users::run(UserCommand::Remove { username: "alice".to_string() }, &config.auth).unwrap();
+        assert!(load().is_empty());
+        assert!(users::run(UserCommand::Remove { username: "alice".to_string() }, &config.auth).is_err());
+        assert!(users::run(UserCommand::Disable { username: "bob".to_string() }, &config.auth).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_uses_provisioned_accounts_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        add(&config, "alice", "alice-password").unwrap();
+        add(&config, "mallory", "mallory-password").unwrap();
+        users::run(UserCommand::Disable { username: "mallory".to_string() }, &config.auth).unwrap();
+        let addr = start_server(config).await;
+
+        let mut ws = connect(addr, None).await;
+        let token = login(&mut ws, "alice", "alice-password", ClientType::Commander, None).await;
+        let claims = AuthManager::new(TEST_JWT_SECRET, 1).validate_token(&token).unwrap();
+        assert_eq!(claims.scopes, ["control", "client:*"]);
+
+        // No default admin account, and disabled accounts cannot log in
+        for (username, password) in [("admin", "admin123"), ("mallory", "mallory-password")] {
+            let mut ws = connect(addr, None).await;
+            assert_auth_error(scram_login(&mut ws, username, password, ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_account_changes_apply_without_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        add(&config, "alice", "alice-password").unwrap();
+        let addr = start_server(config.clone()).await;
+
+        let mut ws = connect(addr, None).await;
+        let (_, refresh_token) = login_with_refresh(&mut ws, "alice", "alice-password", ClientType::Commander, None).await;
+
+        // Added while the server runs
+        add(&config, "bob", "bob-password").unwrap();
+        let mut ws = connect(addr, None).await;
+        login(&mut ws, "bob", "bob-password", ClientType::Commander, None).await;
+
+        // Disabled accounts can neither log in nor refresh, removed ones cannot log in
+        users::run(UserCommand::Disable { username: "alice".to_string() }, &config.auth).unwrap();
+        users::run(UserCommand::Remove { username: "bob".to_string() }, &config.auth).unwrap();
+        for (username, password) in [("alice", "alice-password"), ("bob", "bob-password")] {
+            let mut ws = connect(addr, None).await;
+            assert_auth_error(scram_login(&mut ws, username, password, ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+        }
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, refresh(&refresh_token)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_CREDENTIALS");
+    }
+}
+
+#[cfg(test)]
+mod authorization_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+
+    /// Ask to control `target` as a commander holding `scopes`
+    async fn join_with_scopes(addr: SocketAddr, scopes: &[&str], target: &str) -> Option<Message> {
+        let mut ws = connect_with_token(addr, &mint_scoped_token("alice", ClientType::Commander, None, scopes)).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: target.to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        recv(&mut ws).await
+    }
+
+    fn assert_joined(message: Option<Message>) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { .. })) => {}
+            other => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_limited_to_allowed_clients() {
+        let addr = start_server(Config::default()).await;
+        let _office = connect_hid_client(addr, "office-pc").await;
+        let _lab = connect_hid_client(addr, "lab-pc").await;
+
+        assert_error(join_with_scopes(addr, &["control", "client:office-pc"], "lab-pc").await, "FORBIDDEN");
+        assert_joined(join_with_scopes(addr, &["control", "client:office-pc"], "office-pc").await);
+    }
+
+    #[tokio::test]
+    async fn test_join_allowed_by_client_tag() {
+        let mut config = Config::default();
+        config.client_tags.insert("bench-1".to_string(), vec!["lab".to_string()]);
+        let addr = start_server(config).await;
+        let _bench = connect_hid_client(addr, "bench-1").await;
+        let _other = connect_hid_client(addr, "bench-2").await;
+
+        assert_error(join_with_scopes(addr, &["control", "tag:lab"], "bench-2").await, "FORBIDDEN");
+        assert_joined(join_with_scopes(addr, &["control", "tag:lab"], "bench-1").await);
+    }
+
+    #[tokio::test]
+    async fn test_view_only_and_unscoped_users_cannot_take_control() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+
+        assert_error(join_with_scopes(addr, &["view", "client:*"], "client1").await, "FORBIDDEN");
+        assert_error(join_with_scopes(addr, &[], "client1").await, "FORBIDDEN");
+        // The refusals left the client free
+        assert_joined(join_with_scopes(addr, &["control", "client:client1"], "client1").await);
+    }
+
+    #[tokio::test]
+    async fn test_client_list_filtered_by_scopes() {
+        let addr = start_server(Config::default()).await;
+        let _office = connect_hid_client(addr, "office-pc").await;
+        let _lab = connect_hid_client(addr, "lab-pc").await;
+
+        let mut ws = connect_with_token(addr, &mint_scoped_token("alice", ClientType::Commander, None, &["view", "client:lab-pc"])).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ClientList { clients })) => {
+                let ids: Vec<&str> = clients.iter().map(|c| c.client_id.as_str()).collect();
+                assert_eq!(ids, ["lab-pc"]);
+            }
+            other => panic!("expected ClientList, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod direction_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// Assert that nothing arrives on `ws` for a short while
+    async fn assert_silent(ws: &mut TestSocket) {
+        assert!(tokio::time::timeout(Duration::from_millis(300), recv(ws)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_cannot_inject_into_another_client() {
+        let addr = start_server(Config::default()).await;
+        let mut victim = connect_hid_client(addr, "victim").await;
+        let (_commander, session_id) = join(addr, "victim").await;
+        let mut rogue = connect_hid_client(addr, "rogue").await;
+
+        // Neither by sending HID events for the victim's session...
+        send(&mut rogue, mouse_move(session_id, 666)).await;
+        assert_error(recv(&mut rogue).await, "PROTOCOL_VIOLATION");
+
+        // ...nor by joining a session as a commander
+        send(&mut rogue, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "victim".to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        assert_error(recv(&mut rogue).await, "PROTOCOL_VIOLATION");
+
+        assert_silent(&mut victim).await;
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_token_cannot_join_before_registering() {
+        let addr = start_server(Config::default()).await;
+        let _victim = connect_hid_client(addr, "victim").await;
+        let mut rogue = connect_as(addr, ClientType::HidClient, Some("rogue")).await;
+
+        send(&mut rogue, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "victim".to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        assert_error(recv(&mut rogue).await, "PROTOCOL_VIOLATION");
+
+        // The victim is still free for a real commander
+        join(addr, "victim").await;
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_cannot_revoke_tokens() {
+        let addr = start_server(Config::default()).await;
+        let mut commander = connect_as_user(addr, "alice", ClientType::Commander, None).await;
+        // Admin roles on a HID client's token do not make it an administrator
+        let mut rogue = connect_hid_client(addr, "rogue").await;
+
+        send(&mut rogue, Message::auth(AuthMessage::Revoke { jti: None, username: Some("alice".to_string()) })).await;
+        assert_error(recv(&mut rogue).await, "PROTOCOL_VIOLATION");
+        assert_silent(&mut commander).await;
+    }
+
+    #[tokio::test]
+    async fn test_commander_cannot_register_as_hid_client() {
+        let addr = start_server(Config::default()).await;
+        let mut commander = connect_as(addr, ClientType::Commander, None).await;
+
+        send(&mut commander, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: "fake".to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })).await;
+        assert_error(recv(&mut commander).await, "PROTOCOL_VIOLATION");
+        let (_, reply) = connect_commander(addr, "fake").await;
+        assert_error(reply, "CLIENT_NOT_CONNECTED");
+    }
+
+    #[tokio::test]
+    async fn test_mislabelled_payload_not_forwarded() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        // A session control payload dressed up as a HID event
+        let mut smuggled = Message::session_control(Some(session_id), SessionControlMessage::SessionEnded {
+            reason: "spoofed".to_string(),
+        });
+        smuggled.message_type = MessageType::HidEvent;
+        send(&mut commander, smuggled).await;
+        assert_error(recv(&mut commander).await, "PROTOCOL_VIOLATION");
+        assert_silent(&mut hid).await;
+
+        // Server-only messages are refused outright
+        send(&mut commander, Message::session_control(Some(Uuid::new_v4()), SessionControlMessage::ClientList { clients: Vec::new() })).await;
+        assert_error(recv(&mut commander).await, "PROTOCOL_VIOLATION");
+    }
+    #[tokio::test]
+    async fn test_clients_may_report_errors_but_not_link_status() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+
+        let error = Message::status(Some(session_id), StatusMessage::Error {
+            error_code: "INJECTION_FAILED".to_string(),
+            error_message: "could not press key".to_string(),
+        });
+        for ws in [&mut hid, &mut commander] {
+            send(ws, error.clone()).await;
+            send(ws, Message::status(Some(session_id), StatusMessage::Heartbeat)).await;
+            assert_silent(ws).await;
+
+            send(ws, Message::status(Some(session_id), StatusMessage::ConnectionStatus { connected: false, latency_ms: None })).await;
+            assert_error(recv(ws).await, "PROTOCOL_VIOLATION");
+        }
+    }
+}
+
+#[cfg(test)]
+mod device_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use crate::devices::{self, DeviceCommand};
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+
+    fn config_in(dir: &tempfile::TempDir) -> Config {
+        let mut config = Config::default();
+        config.auth.devices_file = dir.path().join("devices.json").to_string_lossy().into_owned();
+        config
+    }
+
+    fn pairing_code(config: &Config, client_id: &str) -> String {
+        let mut store = DeviceStore::load(&config.auth.devices_file).unwrap();
+        let code = store.create_pairing_code(client_id, chrono::Duration::minutes(15));
+        store.save().unwrap();
+        code
+    }
+
+    /// Redeem a pairing code and return the device secret and the following auth response
+    async fn pair(addr: SocketAddr, code: &str, client_id: &str) -> (Option<String>, Option<Message>) {
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, Message::auth(AuthMessage::Pair { pairing_code: code.to_string(), client_id: client_id.to_string() })).await;
+        match recv(&mut ws).await {
+            Some(Message { payload: MessagePayload::Auth(AuthMessage::Paired { client_id: paired, device_secret }), .. }) => {
+                assert_eq!(paired, client_id);
+                (Some(device_secret), recv(&mut ws).await)
+            }
+            other => (None, other),
+        }
+    }
+
+    fn create_session(client_id: &str) -> Message {
+        Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: client_id.to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })
+    }
+
+    async fn device_login(addr: SocketAddr, client_id: &str, device_secret: &str) -> (TestSocket, Option<Message>) {
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, Message::auth(AuthMessage::DeviceLogin {
+            client_id: client_id.to_string(),
+            device_secret: device_secret.to_string(),
+        })).await;
+        let reply = recv(&mut ws).await;
+        (ws, reply)
+    }
+
+    #[tokio::test]
+    async fn test_pairing_code_exchanged_once_for_device_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        let code = pairing_code(&config, "kiosk-1");
+        let addr = start_server(config).await;
+
+        // A code only enrolls the client id it was created for
+        let (secret, reply) = pair(addr, &code, "kiosk-2").await;
+        assert!(secret.is_none());
+        assert_auth_error(reply, "INVALID_CREDENTIALS");
+
+        let (secret, reply) = pair(addr, &code, "kiosk-1").await;
+        assert!(secret.is_some());
+        let (token, _) = expect_tokens(reply);
+        let claims = AuthManager::new(TEST_JWT_SECRET, 1).validate_token(&token).unwrap();
+        assert_eq!(claims.sub, "device:kiosk-1");
+        assert_eq!(claims.client_id.as_deref(), Some("kiosk-1"));
+
+        let (secret, reply) = pair(addr, &code, "kiosk-1").await;
+        assert!(secret.is_none());
+        assert_auth_error(reply, "INVALID_CREDENTIALS");
+    }
+
+    #[tokio::test]
+    async fn test_device_credential_registers_its_own_client_id_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        let code = pairing_code(&config, "kiosk-1");
+        let addr = start_server(config).await;
+        let (Some(secret), _) = pair(addr, &code, "kiosk-1").await else {
+            panic!("pairing failed");
+        };
+
+        let (_, reply) = device_login(addr, "kiosk-1", "not-the-secret").await;
+        assert_auth_error(reply, "INVALID_CREDENTIALS");
+        let (_, reply) = device_login(addr, "kiosk-2", &secret).await;
+        assert_auth_error(reply, "INVALID_CREDENTIALS");
+
+        let (mut ws, reply) = device_login(addr, "kiosk-1", &secret).await;
+        let (_, refresh_token) = expect_tokens(reply);
+        send(&mut ws, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: "other-box".to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: false,
+        })).await;
+        assert_error(recv(&mut ws).await, "CLIENT_ID_MISMATCH");
+
+        // Device tokens can be refreshed like user tokens
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, refresh(&refresh_token)).await;
+        expect_tokens(recv(&mut ws).await);
+    }
+
+    #[tokio::test]
+    async fn test_paired_device_id_cannot_be_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        let code = pairing_code(&config, "kiosk-1");
+        let addr = start_server(config).await;
+        let (Some(secret), _) = pair(addr, &code, "kiosk-1").await else {
+            panic!("pairing failed");
+        };
+        let (mut device, reply) = device_login(addr, "kiosk-1", &secret).await;
+        expect_tokens(reply);
+        send(&mut device, create_session("kiosk-1")).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        // Neither a password login nor a token minted without a client id may register the device's id
+        let mut impostor = connect(addr, None).await;
+        login(&mut impostor, "admin", "admin123", ClientType::HidClient, None).await;
+        let unbound = connect_as(addr, ClientType::HidClient, None).await;
+        for mut ws in [impostor, unbound] {
+            send(&mut ws, create_session("kiosk-1")).await;
+            assert_error(recv(&mut ws).await, "CLIENT_ID_RESERVED");
+            assert!(recv(&mut ws).await.is_none());
+        }
+
+        // Commanders still reach the paired device
+        let (mut commander, session_id) = join(addr, "kiosk-1").await;
+        send(&mut commander, mouse_move(session_id, 7)).await;
+        assert!(matches!(recv(&mut device).await.map(|m| m.payload), Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 7, .. }))));
+    }
+
+    #[tokio::test]
+    async fn test_live_client_id_not_replaced_by_another_identity() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+
+        let mut other = connect_as_user(addr, "bob", ClientType::HidClient, None).await;
+        send(&mut other, create_session("client1")).await;
+        assert_error(recv(&mut other).await, "CLIENT_ID_IN_USE");
+
+        // The same identity reconnecting takes over its id, ending its old session
+        let (mut commander, session_id) = join(addr, "client1").await;
+        let _reconnected = connect_hid_client(addr, "client1").await;
+        assert_session_ended(recv(&mut commander).await, session_id);
+        join(addr, "client1").await;
+    }
+
+    #[tokio::test]
+    async fn test_removed_device_rejected_without_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        let code = pairing_code(&config, "kiosk-1");
+        let addr = start_server(config.clone()).await;
+        let (Some(secret), reply) = pair(addr, &code, "kiosk-1").await else {
+            panic!("pairing failed");
+        };
+        let (_, refresh_token) = expect_tokens(reply);
+
+        devices::run(DeviceCommand::Remove { client_id: "kiosk-1".to_string() }, &config.auth).unwrap();
+        assert!(devices::run(DeviceCommand::Remove { client_id: "kiosk-1".to_string() }, &config.auth).is_err());
+
+        let (_, reply) = device_login(addr, "kiosk-1", &secret).await;
+        assert_auth_error(reply, "INVALID_CREDENTIALS");
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, refresh(&refresh_token)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_CREDENTIALS");
+    }
+
+    #[test]
+    fn test_pair_command_creates_pending_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_in(&dir);
+        devices::run(DeviceCommand::Pair { client_id: "kiosk-1".to_string(), ttl_mins: 10 }, &config.auth).unwrap();
+        assert!(devices::run(DeviceCommand::Pair { client_id: "kiosk-1".to_string(), ttl_mins: 0 }, &config.auth).is_err());
+        devices::run(DeviceCommand::List, &config.auth).unwrap();
+
+        let store = DeviceStore::load(&config.auth.devices_file).unwrap();
+        let pending: Vec<&str> = store.pending_pairings().into_iter().map(|(client_id, _)| client_id).collect();
+        assert_eq!(pending, ["kiosk-1"]);
+    }
+}
+
+#[cfg(test)]
+mod totp_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use crate::users::{self, UserCommand};
+    use remote_hid_shared::*;
+    use std::sync::OnceLock;
+
+    /// Users file with `carol`/`carol-password`, who has TOTP enabled; returns its path and her secret
+    fn totp_users() -> (String, String) {
+        static USERS: OnceLock<(tempfile::TempPath, String)> = OnceLock::new();
+        let (path, secret) = USERS.get_or_init(|| {
+            let mut carol = User::new("carol".to_string(), "carol-password", &fast_scram()).unwrap();
+            carol.roles = vec![Role::Control];
+            carol.allowed_clients = vec!["*".to_string()];
+            let secret = carol.enable_totp();
+            let mut store = UserStore::new();
+            store.add_user(carol);
+            let file = tempfile::NamedTempFile::new().unwrap();
+            serde_json::to_writer(&file, &store).unwrap();
+            (file.into_temp_path(), secret)
+        });
+        (path.to_string_lossy().into_owned(), secret.clone())
+    }
+
+    async fn start_totp_server() -> (std::net::SocketAddr, String) {
+        let (users_file, secret) = totp_users();
+        let mut config = Config::default();
+        config.auth.users_file = users_file;
+        (start_server(config).await, secret)
+    }
+
+    fn current_code(secret: &str) -> String {
+        totp_code(secret, (chrono::Utc::now().timestamp() / TOTP_PERIOD_SECS) as u64).unwrap()
+    }
+
+    fn assert_totp_required(message: Option<Message>) {
+        assert!(
+            matches!(message.as_ref().map(|m| &m.payload), Some(MessagePayload::Auth(AuthMessage::TotpRequired))),
+            "expected TotpRequired, got {:?}",
+            message
+        );
+    }
+
+    async fn send_code(ws: &mut TestSocket, code: &str) -> Option<Message> {
+        send(ws, Message::auth(AuthMessage::TotpCode { code: code.to_string() })).await;
+        recv(ws).await
+    }
+
+    #[tokio::test]
+    async fn test_commander_login_needs_one_time_code() {
+        let (addr, secret) = start_totp_server().await;
+
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::Commander, None).await);
+        let code = current_code(&secret);
+        expect_tokens(send_code(&mut ws, &code).await);
+
+        // Each code is accepted once
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::Commander, None).await);
+        assert_auth_error(send_code(&mut ws, &code).await, "INVALID_TOTP_CODE");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_code_or_other_reply_rejected() {
+        let (addr, _) = start_totp_server().await;
+
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::Commander, None).await);
+        assert_auth_error(send_code(&mut ws, "not-a-code").await, "INVALID_TOTP_CODE");
+
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::Commander, None).await);
+        send(&mut ws, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        assert_auth_error(recv(&mut ws).await, "INVALID_TOTP_CODE");
+
+        // A wrong password is refused before any code is asked for
+        let mut ws = connect(addr, None).await;
+        assert_auth_error(scram_login(&mut ws, "carol", "wrong", ClientType::Commander, None).await, "INVALID_CREDENTIALS");
+    }
+
+    #[tokio::test]
+    async fn test_hid_client_login_needs_one_time_code() {
+        let (addr, secret) = start_totp_server().await;
+
+        // Claiming to be a HID client does not skip the second factor
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::HidClient, Some("bench-1")).await);
+        assert_auth_error(send_code(&mut ws, "not-a-code").await, "INVALID_TOTP_CODE");
+
+        let mut ws = connect(addr, None).await;
+        assert_totp_required(scram_login(&mut ws, "carol", "carol-password", ClientType::HidClient, Some("bench-1")).await);
+        expect_tokens(send_code(&mut ws, &current_code(&secret)).await);
+    }
+
+    #[test]
+    fn test_totp_commands_edit_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.auth.users_file = dir.path().join("users.json").to_string_lossy().into_owned();
+        let mut store = UserStore::load(&config.auth.users_file).unwrap();
+        store.add_user(User::new("dave".to_string(), "dave-password", &fast_scram()).unwrap());
+        store.save().unwrap();
+        let load = || UserStore::load(&config.auth.users_file).unwrap();
+
+        users::run(UserCommand::EnableTotp { username: "dave".to_string() }, &config.auth).unwrap();
+        assert!(load().requires_totp("dave"));
+
+        let secret = load().get_user("dave").unwrap().totp_secret.clone().unwrap();
+        let mut store = load();
+        assert!(store.verify_totp("dave", &current_code(&secret)));
+        assert!(!store.verify_totp("dave", &current_code(&secret)));
+
+        users::run(UserCommand::DisableTotp { username: "dave".to_string() }, &config.auth).unwrap();
+        assert!(!load().requires_totp("dave"));
+        assert!(users::run(UserCommand::EnableTotp { username: "erin".to_string() }, &config.auth).is_err());
+    }
+}
+
+#[cfg(test)]
+mod password_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use crate::users::{self, AccessArgs, UserCommand};
+    use remote_hid_shared::*;
+
+    /// Config with a users file in `dir` and cheap argon2id parameters
+    fn config_in(dir: &tempfile::TempDir) -> Config {
+        let mut config = Config::default();
+        config.auth.users_file = dir.path().join("users.json").to_string_lossy().into_owned();
+        config.auth.password_hashing.argon2_memory_kib = 64;
+        config.auth.password_hashing.argon2_iterations = 1;
+        config.auth.password_hashing.scram_iterations = MIN_SCRAM_ITERATIONS;
+        config
+    }
+
+    fn add(config: &Config, username: &str, password: &str) -> anyhow::Result<()> {
+        users::run(UserCommand::Add { username: username.to_string(), password: Some(password.to_string()), access: AccessArgs::default() }, &config.auth)
+    }
+
+    #[test]
+    fn test_policy_enforced_on_add_and_passwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config_in(&dir);
+        config.auth.password_policy.min_length = 12;
+        config.auth.password_policy.denied_passwords = vec!["remote-hid-admin".to_string()];
+
+        assert!(add(&config, "alice", "too-short").is_err());
+        assert!(add(&config, "alice", "Remote-HID-Admin").is_err());
+        assert!(UserStore::load(&config.auth.users_file).unwrap().is_empty());
+
+        add(&config, "alice", "a long passphrase").unwrap();
+        let hash = UserStore::load(&config.auth.users_file).unwrap().get_user("alice").unwrap().password_hash.clone();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+
+        let passwd = |password: &str| users::run(UserCommand::Passwd { username: "alice".to_string(), password: Some(password.to_string()) }, &config.auth);
+        assert!(passwd("short").is_err());
+        assert_eq!(UserStore::load(&config.auth.users_file).unwrap().get_user("alice").unwrap().password_hash, hash);
+        passwd("another long passphrase").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_login_upgrades_stored_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config_in(&dir);
+        config.auth.allow_password_login = true;
+
+        // An account from before argon2id: bcrypt hash and no challenge/response keys
+        let mut store = UserStore::load(&config.auth.users_file).unwrap();
+        let mut user = User::new("legacy".to_string(), "legacy-password", &AuthManager::new("test_secret", 1).with_password_hashing(config.auth.password_hashing.clone())).unwrap();
+        user.password_hash = bcrypt::hash("legacy-password", 4).unwrap();
+        user.scram = None;
+        store.add_user(user);
+        store.save().unwrap();
+        let addr = start_server(config.clone()).await;
+
+        let mut ws = connect(addr, None).await;
+        send(&mut ws, Message::auth_request("legacy".to_string(), "legacy-password".to_string(), ClientType::Commander, None)).await;
+        expect_tokens(recv(&mut ws).await);
+
+        let saved = UserStore::load(&config.auth.users_file).unwrap();
+        let saved = saved.get_user("legacy").unwrap();
+        assert!(saved.password_hash.starts_with("$argon2id$"));
+        assert!(saved.scram.is_some());
+
+        // The upgraded record also works for challenge/response after a restart
+        let addr = start_server(config).await;
+        let mut ws = connect(addr, None).await;
+        expect_tokens(scram_login(&mut ws, "legacy", "legacy-password", ClientType::Commander, None).await);
+    }
+
+    #[test]
+    fn test_hashing_config_parsed() {
+        let parsed: crate::config::AuthConfig = toml::from_str(r#"
+            jwt_secret = "secret"
+            token_expiry_hours = 1
+            max_failed_attempts = 3
+            lockout_duration_mins = 15
+            [password_hashing]
+            scheme = "bcrypt"
+            bcrypt_cost = 10
+            [password_policy]
+            min_length = 14
+        "#).unwrap();
+        assert_eq!(parsed.password_hashing.scheme, PasswordScheme::Bcrypt);
+        assert_eq!(parsed.password_hashing.bcrypt_cost, 10);
+        assert_eq!(parsed.password_hashing.argon2_iterations, PasswordHashing::default().argon2_iterations);
+        assert_eq!(parsed.password_policy.min_length, 14);
+        assert!(parsed.password_policy.denied_passwords.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod observer_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    /// Ask to watch `target` as a commander holding `scopes`
+    async fn observe_with_scopes(addr: SocketAddr, username: &str, scopes: &[&str], target: &str) -> (TestSocket, Option<Message>) {
+        let mut ws = connect_with_token(addr, &mint_scoped_token(username, ClientType::Commander, None, scopes)).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: target.to_string(),
+            mode: JoinMode::Observe,
+        })).await;
+        let reply = recv(&mut ws).await;
+        (ws, reply)
+    }
+
+    async fn observe(addr: SocketAddr, username: &str, target: &str) -> (TestSocket, Uuid) {
+        match observe_with_scopes(addr, username, &["view", "client:*"], target).await {
+            (ws, Some(Message { payload: MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id, .. }), .. })) => (ws, session_id),
+            (_, other) => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    fn assert_mouse_move(message: Option<Message>, session_id: Uuid, expected: i32) {
+        match message {
+            Some(Message { session_id: id, payload: MessagePayload::HidEvent(HidEvent::MouseMove { x, .. }), .. }) => {
+                assert_eq!(id, Some(session_id));
+                assert_eq!(x, expected);
+            }
+            other => panic!("expected mouse move {}, got {:?}", expected, other),
+        }
+    }
+
+    async fn observers_of(ws: &mut TestSocket, client_id: &str) -> Vec<String> {
+        send(ws, Message::session_control(None, SessionControlMessage::ListClients)).await;
+        match recv(ws).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ClientList { clients })) => {
+                clients.into_iter().find(|c| c.client_id == client_id).unwrap().observers
+            }
+            other => panic!("expected ClientList, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observer_mirrors_forwarded_events() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+        let (mut observer, observed_session) = observe(addr, "carol", "client1").await;
+        assert_eq!(observed_session, session_id);
+
+        for x in 0..3 {
+            send(&mut commander, mouse_move(session_id, x)).await;
+        }
+        for x in 0..3 {
+            assert_mouse_move(recv(&mut hid).await, session_id, x);
+            assert_mouse_move(recv(&mut observer).await, session_id, x);
+        }
+
+        // The observer cannot inject; the HID client only sees the commander's next event
+        send(&mut observer, mouse_move(session_id, 666)).await;
+        assert_error(recv(&mut observer).await, "VIEW_ONLY");
+        send(&mut commander, mouse_move(session_id, 3)).await;
+        assert_mouse_move(recv(&mut hid).await, session_id, 3);
+        assert_mouse_move(recv(&mut observer).await, session_id, 3);
+
+        // Ending the session tells the observer too
+        send(&mut commander, Message::session_control(Some(session_id), SessionControlMessage::EndSession)).await;
+        assert_session_ended(recv(&mut observer).await, session_id);
+        assert!(recv(&mut observer).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_observers_listed_and_can_leave() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut commander, session_id) = join(addr, "client1").await;
+        let (mut carol, _) = observe(addr, "carol", "client1").await;
+        let (_dave, _) = observe(addr, "dave", "client1").await;
+        assert_eq!(observers_of(&mut commander, "client1").await, ["carol", "dave"]);
+
+        // Leaving does not end the session for the controlling commander
+        send(&mut carol, Message::session_control(Some(session_id), SessionControlMessage::EndSession)).await;
+        assert_session_ended(recv(&mut carol).await, session_id);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(observers_of(&mut commander, "client1").await, ["dave"]);
+        send(&mut commander, mouse_move(session_id, 1)).await;
+        assert_mouse_move(recv(&mut hid).await, session_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_observing_needs_a_session_view_role_and_a_free_slot() {
+        let mut config = Config::default();
+        config.session.max_observers = 1;
+        let addr = start_server(config).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+
+        // Nothing to watch until a commander takes control
+        assert_error(observe_with_scopes(addr, "carol", &["view", "client:*"], "client1").await.1, "NO_SESSION");
+        let (_commander, _) = join(addr, "client1").await;
+
+        assert_error(observe_with_scopes(addr, "carol", &[], "client1").await.1, "FORBIDDEN");
+        assert_error(observe_with_scopes(addr, "carol", &["view", "client:other"], "client1").await.1, "FORBIDDEN");
+        let (_carol, _) = observe(addr, "carol", "client1").await;
+        assert_error(observe_with_scopes(addr, "dave", &["view", "client:*"], "client1").await.1, "OBSERVER_LIMIT");
+    }
+}
+
+#[cfg(test)]
+mod handoff_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    /// Join a running session as an observer holding `scopes`
+    async fn observe(addr: SocketAddr, username: &str, scopes: &[&str], target: &str) -> TestSocket {
+        let mut ws = connect_with_token(addr, &mint_scoped_token(username, ClientType::Commander, None, scopes)).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: target.to_string(),
+            mode: JoinMode::Observe,
+        })).await;
+        match recv(&mut ws).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { .. })) => ws,
+            other => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    async fn control(ws: &mut TestSocket, session_id: Uuid, control: SessionControlMessage) {
+        send(ws, Message::session_control(Some(session_id), control)).await;
+    }
+
+    /// Ask for control and return the participant id the commander in control is shown
+    async fn request_control(requester: &mut TestSocket, controller: &mut TestSocket, session_id: Uuid) -> String {
+        control(requester, session_id, SessionControlMessage::RequestControl).await;
+        match recv(controller).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlRequested { participant_id, username })) => {
+                assert_eq!(username, "bob");
+                participant_id
+            }
+            other => panic!("expected ControlRequested, got {:?}", other),
+        }
+    }
+
+    fn assert_control_changed(message: Option<Message>, expected_controller: &str, expected_in_control: bool) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlChanged { controller, in_control })) => {
+                assert_eq!(controller, expected_controller);
+                assert_eq!(in_control, expected_in_control);
+            }
+            other => panic!("expected ControlChanged, got {:?}", other),
+        }
+    }
+
+    fn key(key: KeyCode, pressed: bool) -> HidEvent {
+        HidEvent::KeyEvent { key, pressed, modifiers: KeyModifiers::default() }
+    }
+
+    fn assert_released(message: Option<Message>, expected: &HidEvent) {
+        match (message.map(|m| m.payload), expected) {
+            (Some(MessagePayload::HidEvent(HidEvent::KeyEvent { key, pressed: false, .. })), HidEvent::KeyEvent { key: expected, .. }) => {
+                assert_eq!(key, *expected);
+            }
+            (Some(MessagePayload::HidEvent(HidEvent::MouseClick { button, pressed: false, .. })), HidEvent::MouseClick { button: expected, .. }) => {
+                assert_eq!(button, *expected);
+            }
+            (other, _) => panic!("expected release of {:?}, got {:?}", expected, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_granting_control_releases_held_input_and_moves_forwarding() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut owner, session_id) = join(addr, "client1").await;
+
+        let shift = key(KeyCode::LeftShift, true);
+        let click = HidEvent::MouseClick { button: MouseButton::Left, pressed: true, x: None, y: None };
+        for event in [&shift, &click] {
+            send(&mut owner, Message::hid_event(session_id, event.clone())).await;
+            recv(&mut hid).await.unwrap();
+        }
+
+        let mut bob = observe(addr, "bob", &["control", "client:*"], "client1").await;
+        let bob_id = request_control(&mut bob, &mut owner, session_id).await;
+        control(&mut owner, session_id, SessionControlMessage::GrantControl { participant_id: bob_id }).await;
+
+        // What the previous controller held down is let go, and everyone hears who is in control
+        assert_released(recv(&mut hid).await, &shift);
+        assert_released(recv(&mut hid).await, &click);
+        assert_released(recv(&mut owner).await, &shift);
+        assert_released(recv(&mut owner).await, &click);
+        assert_control_changed(recv(&mut owner).await, "bob", false);
+        assert_control_changed(recv(&mut bob).await, "bob", true);
+
+        // Only the new controller's events are forwarded now
+        send(&mut owner, mouse_move(session_id, 1)).await;
+        assert_error(recv(&mut owner).await, "VIEW_ONLY");
+        send(&mut bob, mouse_move(session_id, 2)).await;
+        match recv(&mut hid).await.map(|m| m.payload) {
+            Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 2, .. })) => {}
+            other => panic!("expected bob's mouse move, got {:?}", other),
+        }
+        match recv(&mut owner).await.map(|m| m.payload) {
+            Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 2, .. })) => {}
+            other => panic!("expected mirrored mouse move, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_control_role_and_can_be_denied() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (mut owner, session_id) = join(addr, "client1").await;
+
+        let mut viewer = observe(addr, "vic", &["view", "client:*"], "client1").await;
+        control(&mut viewer, session_id, SessionControlMessage::RequestControl).await;
+        assert_error(recv(&mut viewer).await, "FORBIDDEN");
+
+        let mut bob = observe(addr, "bob", &["control", "client:*"], "client1").await;
+        let bob_id = request_control(&mut bob, &mut owner, session_id).await;
+        control(&mut owner, session_id, SessionControlMessage::DenyControl { participant_id: bob_id.clone() }).await;
+        match recv(&mut bob).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlDenied { .. })) => {}
+            other => panic!("expected ControlDenied, got {:?}", other),
+        }
+
+        // A request can only be answered once; observers cannot answer at all
+        control(&mut owner, session_id, SessionControlMessage::GrantControl { participant_id: bob_id.clone() }).await;
+        assert_error(recv(&mut owner).await, "INVALID_REQUEST");
+        control(&mut bob, session_id, SessionControlMessage::GrantControl { participant_id: bob_id }).await;
+        assert_error(recv(&mut bob).await, "INVALID_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn test_owner_revokes_control_and_gets_it_back_when_controller_leaves() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (mut owner, session_id) = join(addr, "client1").await;
+        let mut bob = observe(addr, "bob", &["control", "client:*"], "client1").await;
+
+        let bob_id = request_control(&mut bob, &mut owner, session_id).await;
+        control(&mut owner, session_id, SessionControlMessage::GrantControl { participant_id: bob_id }).await;
+        assert_control_changed(recv(&mut owner).await, "bob", false);
+        assert_control_changed(recv(&mut bob).await, "bob", true);
+
+        // Only the commander that started the session may take control back
+        control(&mut bob, session_id, SessionControlMessage::RevokeControl).await;
+        assert_error(recv(&mut bob).await, "FORBIDDEN");
+        control(&mut owner, session_id, SessionControlMessage::RevokeControl).await;
+        assert_control_changed(recv(&mut owner).await, "admin", true);
+        assert_control_changed(recv(&mut bob).await, "admin", false);
+
+        // A controller that leaves hands control back instead of ending the session
+        let bob_id = request_control(&mut bob, &mut owner, session_id).await;
+        control(&mut owner, session_id, SessionControlMessage::GrantControl { participant_id: bob_id }).await;
+        assert_control_changed(recv(&mut owner).await, "bob", false);
+        assert_control_changed(recv(&mut bob).await, "bob", true);
+        control(&mut bob, session_id, SessionControlMessage::EndSession).await;
+        assert_session_ended(recv(&mut bob).await, session_id);
+        assert_control_changed(recv(&mut owner).await, "admin", true);
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use uuid::Uuid;
+
+    fn assert_position(message: Option<Message>, expected: usize) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::QueuePosition { target_client_id, position })) => {
+                assert_eq!(target_client_id, "client1");
+                assert_eq!(position, expected);
+            }
+            other => panic!("expected QueuePosition {}, got {:?}", expected, other),
+        }
+    }
+
+    fn assert_ended_with(message: Option<Message>, expected: &str) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionEnded { reason })) => assert_eq!(reason, expected),
+            other => panic!("expected SessionEnded ({}), got {:?}", expected, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiters_take_over_in_order() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut first, first_session) = join(addr, "client1").await;
+
+        let (mut second, reply) = connect_commander(addr, "client1").await;
+        assert_position(reply, 1);
+        let (mut third, reply) = connect_commander(addr, "client1").await;
+        assert_position(reply, 2);
+
+        send(&mut first, Message::session_control(Some(first_session), SessionControlMessage::EndSession)).await;
+        assert_session_ended(recv(&mut first).await, first_session);
+        assert_session_ended(recv(&mut hid).await, first_session);
+
+        // The next in line gets a session of its own, the rest move up
+        let second_session = match recv(&mut second).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id, target_client_id })) => {
+                assert_eq!(target_client_id, "client1");
+                session_id
+            }
+            other => panic!("expected SessionJoined, got {:?}", other),
+        };
+        assert_ne!(second_session, first_session);
+        assert_position(recv(&mut third).await, 1);
+
+        send(&mut second, mouse_move(second_session, 7)).await;
+        match recv(&mut hid).await {
+            Some(Message { session_id, payload: MessagePayload::HidEvent(HidEvent::MouseMove { x: 7, .. }), .. }) => {
+                assert_eq!(session_id, Some(second_session));
+            }
+            other => panic!("expected forwarded mouse move, got {:?}", other),
+        }
+
+        // A waiter that hangs up gives up its turn
+        drop(third);
+        drop(second);
+        let (_fourth, reply) = connect_commander(addr, "client1").await;
+        assert!(matches!(
+            reply.map(|m| m.payload),
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_waiter_can_leave_queue() {
+        let addr = start_server(Config::default()).await;
+        let _hid = connect_hid_client(addr, "client1").await;
+        let (_first, _) = join(addr, "client1").await;
+        let (mut second, _) = connect_commander(addr, "client1").await;
+        let (mut third, _) = connect_commander(addr, "client1").await;
+
+        // Waiters cannot send input yet
+        send(&mut second, mouse_move(Uuid::new_v4(), 1)).await;
+        assert_error(recv(&mut second).await, "QUEUED");
+
+        send(&mut second, Message::session_control(None, SessionControlMessage::LeaveQueue)).await;
+        assert_ended_with(recv(&mut second).await, "left the queue");
+        assert!(recv(&mut second).await.is_none());
+        assert_position(recv(&mut third).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_refused_and_waiters_sent_away_with_client() {
+        let mut config = Config::default();
+        config.session.max_queue_length = 1;
+        let addr = start_server(config).await;
+        let hid = connect_hid_client(addr, "client1").await;
+        let (mut first, session_id) = join(addr, "client1").await;
+        let (mut second, reply) = connect_commander(addr, "client1").await;
+        assert_position(reply, 1);
+
+        let (_, reply) = connect_commander(addr, "client1").await;
+        assert_error(reply, "CLIENT_BUSY");
+
+        drop(hid);
+        assert!(matches!(
+            recv(&mut first).await.map(|m| m.payload),
+            Some(MessagePayload::Status(StatusMessage::ConnectionStatus { connected: false, .. }))
+        ));
+        assert_session_ended(recv(&mut first).await, session_id);
+        assert_ended_with(recv(&mut second).await, "HID client disconnected");
+    }
+
+    #[tokio::test]
+    async fn test_keys_held_by_departing_commander_released_before_next_in_line() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut first, first_session) = join(addr, "client1").await;
+        let (mut second, reply) = connect_commander(addr, "client1").await;
+        assert_position(reply, 1);
+
+        let ctrl = HidEvent::KeyEvent { key: KeyCode::LeftControl, pressed: true, modifiers: KeyModifiers::default() };
+        send(&mut first, Message::hid_event(first_session, ctrl)).await;
+        recv(&mut hid).await.unwrap();
+        drop(first);
+
+        match recv(&mut hid).await {
+            Some(Message { session_id, payload: MessagePayload::HidEvent(HidEvent::KeyEvent { key: KeyCode::LeftControl, pressed: false, .. }), .. }) => {
+                assert_eq!(session_id, Some(first_session));
+            }
+            other => panic!("expected release of LeftControl, got {:?}", other),
+        }
+        assert_session_ended(recv(&mut hid).await, first_session);
+        assert!(matches!(
+            recv(&mut second).await.map(|m| m.payload),
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { .. }))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod consent_tests {
+    use super::support::*;
+    use crate::config::Config;
+    use remote_hid_shared::*;
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    /// Register a HID client whose user approves each commander
+    async fn connect_attended_client(addr: SocketAddr, client_id: &str) -> TestSocket {
+        let mut ws = connect_as(addr, ClientType::HidClient, Some(client_id)).await;
+        send(&mut ws, Message::session_control(None, SessionControlMessage::CreateSession {
+            client_id: client_id.to_string(),
+            client_name: None,
+            platform: None,
+            requires_consent: true,
+        })).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        ws
+    }
+
+    fn assert_awaiting_consent(message: Option<Message>, timeout: u64) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::AwaitingConsent { target_client_id, timeout_secs })) => {
+                assert_eq!(target_client_id, "client1");
+                assert_eq!(timeout_secs, timeout);
+            }
+            other => panic!("expected AwaitingConsent, got {:?}", other),
+        }
+    }
+
+    /// The request the HID client is asked to answer
+    fn expect_consent_request(message: Option<Message>, username: &str) -> Uuid {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ConsentRequest { session_id, username: asking, .. })) => {
+                assert_eq!(asking, username);
+                session_id
+            }
+            other => panic!("expected ConsentRequest, got {:?}", other),
+        }
+    }
+
+    fn answer(session_id: Uuid, approved: bool) -> Message {
+        Message::session_control(Some(session_id), SessionControlMessage::ConsentResponse { session_id, approved })
+    }
+
+    #[tokio::test]
+    async fn test_session_established_once_approved() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_attended_client(addr, "client1").await;
+        let (mut commander, reply) = connect_commander(addr, "client1").await;
+        assert_awaiting_consent(reply, 30);
+        let session_id = expect_consent_request(recv(&mut hid).await, "admin");
+
+        // Nothing can be watched or driven before the user agrees
+        let mut observer = connect_as(addr, ClientType::Commander, None).await;
+        send(&mut observer, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "client1".to_string(),
+            mode: JoinMode::Observe,
+        })).await;
+        assert_error(recv(&mut observer).await, "NO_SESSION");
+        send(&mut commander, mouse_move(session_id, 1)).await;
+        assert_error(recv(&mut commander).await, "QUEUED");
+
+        // Only the HID client asked may answer
+        send(&mut commander, answer(session_id, true)).await;
+        assert_error(recv(&mut commander).await, "PROTOCOL_VIOLATION");
+
+        send(&mut hid, answer(session_id, true)).await;
+        match recv(&mut commander).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id: joined, .. })) => {
+                assert_eq!(joined, session_id);
+            }
+            other => panic!("expected SessionJoined, got {:?}", other),
+        }
+        send(&mut commander, mouse_move(session_id, 2)).await;
+        match recv(&mut hid).await {
+            Some(Message { payload: MessagePayload::HidEvent(HidEvent::MouseMove { x: 2, .. }), .. }) => {}
+            other => panic!("expected forwarded mouse move, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_declined_session_refused_and_next_waiter_asked() {
+        let addr = start_server(Config::default()).await;
+        let mut hid = connect_attended_client(addr, "client1").await;
+        let (mut first, reply) = connect_commander(addr, "client1").await;
+        assert_awaiting_consent(reply, 30);
+        let first_session = expect_consent_request(recv(&mut hid).await, "admin");
+
+        let mut second = connect_as_user(addr, "support", ClientType::Commander, None).await;
+        send(&mut second, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "client1".to_string(),
+            mode: JoinMode::Control,
+        })).await;
+        assert!(matches!(
+            recv(&mut second).await.map(|m| m.payload),
+            Some(MessagePayload::SessionControl(SessionControlMessage::QueuePosition { position: 1, .. }))
+        ));
+
+        send(&mut hid, answer(first_session, false)).await;
+        assert_error(recv(&mut first).await, "CONSENT_DENIED");
+        assert_session_ended(recv(&mut first).await, first_session);
+        assert_session_ended(recv(&mut hid).await, first_session);
+
+        // The user is asked again about whoever is next
+        assert_awaiting_consent(recv(&mut second).await, 30);
+        let second_session = expect_consent_request(recv(&mut hid).await, "support");
+        assert_ne!(second_session, first_session);
+
+        // A late answer to the first request changes nothing
+        send(&mut hid, answer(first_session, true)).await;
+        send(&mut hid, answer(second_session, true)).await;
+        match recv(&mut second).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { session_id, .. })) => assert_eq!(session_id, second_session),
+            other => panic!("expected SessionJoined, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_or_abandoned_request_withdrawn() {
+        let mut config = Config::default();
+        config.session.consent_timeout_secs = 1;
+        let addr = start_server(config).await;
+        let mut hid = connect_attended_client(addr, "client1").await;
+
+        let (mut commander, reply) = connect_commander(addr, "client1").await;
+        assert_awaiting_consent(reply, 1);
+        let session_id = expect_consent_request(recv(&mut hid).await, "admin");
+        assert_error(recv(&mut commander).await, "CONSENT_TIMEOUT");
+        assert_session_ended(recv(&mut commander).await, session_id);
+        assert_session_ended(recv(&mut hid).await, session_id);
+
+        // A commander that gives up withdraws its request
+        let (mut commander, reply) = connect_commander(addr, "client1").await;
+        assert_awaiting_consent(reply, 1);
+        let session_id = expect_consent_request(recv(&mut hid).await, "admin");
+        send(&mut commander, Message::session_control(None, SessionControlMessage::LeaveQueue)).await;
+        assert_session_ended(recv(&mut hid).await, session_id);
+
+        // Clients that do not ask for consent start sessions right away
+        let _unattended = connect_hid_client(addr, "client2").await;
+        let (_, reply) = connect_commander(addr, "client2").await;
+        assert!(matches!(
+            reply.map(|m| m.payload),
+            Some(MessagePayload::SessionControl(SessionControlMessage::SessionJoined { .. }))
+        ));
+    }
+
+    /// Start an approved session on an attended client and have bob ask the owner for control
+    async fn approved_session_with_requester(addr: SocketAddr, timeout: u64) -> (TestSocket, TestSocket, TestSocket, Uuid) {
+        let mut hid = connect_attended_client(addr, "client1").await;
+        let (mut owner, reply) = connect_commander(addr, "client1").await;
+        assert_awaiting_consent(reply, timeout);
+        let session_id = expect_consent_request(recv(&mut hid).await, "admin");
+        send(&mut hid, answer(session_id, true)).await;
+        recv(&mut owner).await.unwrap();
+
+        let mut bob = connect_with_token(addr, &mint_scoped_token("bob", ClientType::Commander, None, &["control", "client:*"])).await;
+        send(&mut bob, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "client1".to_string(),
+            mode: JoinMode::Observe,
+        })).await;
+        recv(&mut bob).await.unwrap();
+        (hid, owner, bob, session_id)
+    }
+
+    /// Ask for control as `requester` and grant it as `owner`
+    async fn request_and_grant(requester: &mut TestSocket, owner: &mut TestSocket, session_id: Uuid) {
+        send(requester, Message::session_control(Some(session_id), SessionControlMessage::RequestControl)).await;
+        let participant_id = match recv(owner).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlRequested { participant_id, .. })) => participant_id,
+            other => panic!("expected ControlRequested, got {:?}", other),
+        };
+        send(owner, Message::session_control(Some(session_id), SessionControlMessage::GrantControl { participant_id })).await;
+    }
+
+    fn assert_control_denied(message: Option<Message>) {
+        match message.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlDenied { .. })) => {}
+            other => panic!("expected ControlDenied, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handoff_waits_for_approval() {
+        let addr = start_server(Config::default()).await;
+        let (mut hid, mut owner, mut bob, session_id) = approved_session_with_requester(addr, 30).await;
+
+        request_and_grant(&mut bob, &mut owner, session_id).await;
+        assert_awaiting_consent(recv(&mut bob).await, 30);
+        assert_eq!(expect_consent_request(recv(&mut hid).await, "bob"), session_id);
+
+        // Only one handoff is held at a time
+        request_and_grant(&mut bob, &mut owner, session_id).await;
+        assert_error(recv(&mut owner).await, "HANDOFF_PENDING");
+        assert_control_denied(recv(&mut bob).await);
+
+        // A decline leaves the owner in control
+        send(&mut hid, answer(session_id, false)).await;
+        assert_control_denied(recv(&mut bob).await);
+        send(&mut owner, mouse_move(session_id, 1)).await;
+        match recv(&mut hid).await.map(|m| m.payload) {
+            Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 1, .. })) => {}
+            other => panic!("expected the owner's mouse move, got {:?}", other),
+        }
+        recv(&mut bob).await.unwrap();
+
+        request_and_grant(&mut bob, &mut owner, session_id).await;
+        assert_awaiting_consent(recv(&mut bob).await, 30);
+        expect_consent_request(recv(&mut hid).await, "bob");
+        send(&mut hid, answer(session_id, true)).await;
+        for (ws, in_control) in [(&mut owner, false), (&mut bob, true)] {
+            match recv(ws).await.map(|m| m.payload) {
+                Some(MessagePayload::SessionControl(SessionControlMessage::ControlChanged { controller, in_control: changed })) => {
+                    assert_eq!(controller, "bob");
+                    assert_eq!(changed, in_control);
+                }
+                other => panic!("expected ControlChanged, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_handoff_lapses() {
+        let mut config = Config::default();
+        config.session.consent_timeout_secs = 1;
+        let addr = start_server(config).await;
+        let (mut hid, mut owner, mut bob, session_id) = approved_session_with_requester(addr, 1).await;
+
+        request_and_grant(&mut bob, &mut owner, session_id).await;
+        assert_awaiting_consent(recv(&mut bob).await, 1);
+        expect_consent_request(recv(&mut hid).await, "bob");
+        assert_control_denied(recv(&mut bob).await);
+
+        // A late approval does not hand control over
+        send(&mut hid, answer(session_id, true)).await;
+        send(&mut owner, mouse_move(session_id, 3)).await;
+        match recv(&mut hid).await.map(|m| m.payload) {
+            Some(MessagePayload::HidEvent(HidEvent::MouseMove { x: 3, .. })) => {}
+            other => panic!("expected the owner's mouse move, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod recording_tests {
+    use super::support::*;
+    use crate::config::{Config, RecordingConfig};
+    use crate::recording::RecordingWriter;
+    use remote_hid_shared::*;
+    use std::{fs, path::Path, time::{Duration, SystemTime}};
+    use uuid::Uuid;
+
+    fn recording_config(directory: &Path, max_file_bytes: u64) -> RecordingConfig {
+        RecordingConfig {
+            enabled: true,
+            directory: directory.to_string_lossy().into_owned(),
+            max_file_bytes,
+            retention_days: 30,
+        }
+    }
+
+    fn recorded(session_id: Uuid, x: i32) -> RecordedMessage {
+        RecordedMessage::new("127.0.0.1:5000", "alice", vec!["client1".to_string()], &mouse_move(session_id, x))
+    }
+
+    fn read_segment(path: &Path) -> (RecordingHeader, Vec<RecordedMessage>) {
+        let reader = RecordingReader::open(path).unwrap();
+        let header = reader.header().clone();
+        (header, reader.map(|recorded| recorded.unwrap()).collect())
+    }
+
+    #[test]
+    fn test_segments_rotate_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        // Room for the header and one message per segment
+        let mut writer = RecordingWriter::new(recording_config(dir.path(), 400));
+        let session_id = Uuid::new_v4();
+        for x in 0..3 {
+            writer.record(session_id, "client1", &recorded(session_id, x)).unwrap();
+        }
+        writer.finish(session_id);
+
+        for segment in 0..3 {
+            let (header, messages) = read_segment(&dir.path().join(recording_file_name(session_id, segment)));
+            assert_eq!(header.segment, segment);
+            assert_eq!(header.hid_client_id, "client1");
+            assert_eq!(header.version, RECORDING_VERSION);
+            assert_eq!(messages.len(), 1);
+            assert!(matches!(messages[0].message.payload, MessagePayload::HidEvent(HidEvent::MouseMove { x, .. }) if x == segment as i32));
+        }
+
+        // A finished session recorded again continues in a new file rather than touching the old ones
+        writer.record(session_id, "client1", &recorded(session_id, 9)).unwrap();
+        let (header, _) = read_segment(&dir.path().join(recording_file_name(session_id, 3)));
+        assert_eq!(header.segment, 3);
+
+        // Only recordings past their retention are deleted, and never the open segment
+        fs::write(dir.path().join("notes.txt"), "keep").unwrap();
+        writer.prune(SystemTime::now());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 5);
+        writer.prune(SystemTime::now() + Duration::from_secs(31 * 24 * 60 * 60));
+        let mut left: Vec<String> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+        assert_eq!(left, [recording_file_name(session_id, 3), "notes.txt".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_session_messages_recorded_per_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { recording: recording_config(dir.path(), 1024 * 1024), ..Config::default() };
+        let addr = start_server(config).await;
+        let mut hid = connect_hid_client(addr, "client1").await;
+        let (mut owner, session_id) = join(addr, "client1").await;
+
+        send(&mut owner, mouse_move(session_id, 1)).await;
+        // Events that are not forwarded are not recorded
+        send(&mut owner, mouse_move(Uuid::new_v4(), 3)).await;
+        assert!(recv(&mut hid).await.is_some());
+        assert_error(recv(&mut owner).await, "INVALID_SESSION");
+
+        // Handing control over is recorded along with the input
+        let mut bob = connect_with_token(addr, &mint_scoped_token("bob", ClientType::Commander, None, &["control", "client:*"])).await;
+        send(&mut bob, Message::session_control(None, SessionControlMessage::JoinSession {
+            target_client_id: "client1".to_string(),
+            mode: JoinMode::Observe,
+        })).await;
+        recv(&mut bob).await.unwrap();
+        send(&mut bob, Message::session_control(Some(session_id), SessionControlMessage::RequestControl)).await;
+        let participant_id = match recv(&mut owner).await.map(|m| m.payload) {
+            Some(MessagePayload::SessionControl(SessionControlMessage::ControlRequested { participant_id, .. })) => participant_id,
+            other => panic!("expected ControlRequested, got {:?}", other),
+        };
+        send(&mut owner, Message::session_control(Some(session_id), SessionControlMessage::GrantControl { participant_id: participant_id.clone() })).await;
+        recv(&mut bob).await.unwrap();
+        recv(&mut owner).await.unwrap();
+        send(&mut bob, mouse_move(session_id, 2)).await;
+        assert!(recv(&mut hid).await.is_some());
+        // Mirrored to the owner, now observing
+        recv(&mut owner).await.unwrap();
+        send(&mut owner, Message::session_control(Some(session_id), SessionControlMessage::EndSession)).await;
+        assert_session_ended(recv(&mut owner).await, session_id);
+
+        // Written in the background
+        let path = dir.path().join(recording_file_name(session_id, 0));
+        let mut messages = Vec::new();
+        for _ in 0..50 {
+            if path.exists() {
+                let (header, read) = read_segment(&path);
+                assert_eq!(header.session_id, session_id);
+                assert_eq!(header.hid_client_id, "client1");
+                messages = read;
+                if messages.len() == 8 {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let summary: Vec<String> = messages.iter().map(|m| {
+            let payload = match &m.message.payload {
+                MessagePayload::HidEvent(HidEvent::MouseMove { x, .. }) => format!("MouseMove {}", x),
+                MessagePayload::SessionControl(control) => format!("{:?}", control).split([' ', '{']).next().unwrap().to_string(),
+                other => format!("{:?}", other),
+            };
+            format!("{} {} {}", m.username, payload, m.sent_to.len())
+        }).collect();
+        assert_eq!(summary, [
+            "admin SessionJoined 1",
+            "admin MouseMove 1 1",
+            "admin SessionJoined 1",
+            "admin ControlRequested 1",
+            "admin ControlChanged 1",
+            "admin ControlChanged 1",
+            "bob MouseMove 2 2",
+            "bob SessionEnded 3",
+        ]);
+        assert!(messages.iter().all(|m| m.message.session_id == Some(session_id)));
+        assert!(messages.windows(2).all(|pair| pair[0].received_at <= pair[1].received_at));
+        assert_eq!(messages[1].sent_to, ["client1"]);
+        assert_eq!(messages[4].sent_to, [participant_id]);
+        assert_eq!(messages[6].sent_to[0], "client1");
+    }
+}
@@ -0,0 +1,32 @@
+use std::{path::Path, sync::Arc};
+use anyhow::Context;
+use rustls::{server::WebPkiClientVerifier, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use remote_hid_shared::tls::{load_certs, load_private_key, load_root_store};
+
+use crate::config::TlsConfig;
+
+/// Build the TLS acceptor for `wss://` connections
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let certs = load_certs(Path::new(&config.cert_path))?;
+    let key = load_private_key(Path::new(&config.key_path))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca_path {
+        // Client certificates must be issued by this CA; unless optional, peers without one are refused
+        Some(client_ca_path) => {
+            let roots = load_root_store(Path::new(client_ca_path))?;
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if config.client_cert_optional { verifier.allow_unauthenticated() } else { verifier };
+            let verifier = verifier.build().context("invalid client CA bundle")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key do not match")?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,194 @@
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use std::io::{self, BufRead, Write};
+
+use remote_hid_shared::{totp_uri, AuthManager, Role, User, UserStore};
+
+use crate::config::AuthConfig;
+
+/// `session-server user ...`: manage accounts in the user database.
+///
+/// The running server re-reads the database on every login and token refresh, so
+/// changes apply without a restart. Tokens already issued stay valid until they
+/// expire or are revoked with `commander --revoke-user`.
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Create an account
+    Add {
+        username: String,
+        /// Password; prompted for on stdin when omitted
+        #[arg(long)]
+        password: Option<String>,
+        #[command(flatten)]
+        access: AccessArgs,
+    },
+    /// Replace an account's roles and client allow lists
+    Access {
+        username: String,
+        #[command(flatten)]
+        access: AccessArgs,
+    },
+    /// Delete an account
+    Remove { username: String },
+    /// Set a new password
+    Passwd {
+        username: String,
+        /// Password; prompted for on stdin when omitted
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Stop an account from logging in, keeping its record
+    Disable { username: String },
+    /// Allow a disabled account to log in again
+    Enable { username: String },
+    /// Require a one-time code from an authenticator app for commander logins; prints a new secret
+    EnableTotp { username: String },
+    /// Stop asking for one-time codes
+    DisableTotp { username: String },
+    /// Show all accounts
+    List,
+}
+
+/// What an account may do and which HID clients it may reach
+#[derive(clap::Args, Debug, Default)]
+pub struct AccessArgs {
+    /// admin, control or view; repeatable
+    #[arg(long = "role")]
+    pub roles: Vec<Role>,
+    /// HID client id the user may reach (`*` for all); repeatable
+    #[arg(long = "client")]
+    pub clients: Vec<String>,
+    /// HID client tag (from `[client_tags]`) the user may reach; repeatable
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+}
+
+impl AccessArgs {
+    fn apply(self, user: &mut User) {
+        user.roles = self.roles;
+        user.allowed_clients = self.clients;
+        user.allowed_tags = self.tags;
+    }
+}
+
+/// Issuer shown in authenticator apps
+const TOTP_ISSUER: &str = "Remote HID";
+
+/// Apply a user command to the database named in `auth.users_file`
+pub fn run(command: UserCommand, auth: &AuthConfig) -> Result<()> {
+    let mut store = UserStore::load(&auth.users_file)?;
+    // Only used for hashing; signing keys are not needed here
+    let auth_manager = AuthManager::new(&auth.jwt_secret, auth.token_expiry_hours)
+        .with_password_hashing(auth.password_hashing.clone());
+
+    match command {
+        UserCommand::Add { username, password, access } => {
+            if store.get_user(&username).is_some() {
+                bail!("User {} already exists", username);
+            }
+            // `device:<client id>` names paired HID clients
+            if username.is_empty() || username.contains(':') {
+                bail!("Usernames must be non-empty and may not contain ':'");
+            }
+            let password = password_or_prompt(password)?;
+            auth.password_policy.check(&username, &password)?;
+            let mut user = User::new(username.clone(), &password, &auth_manager)?;
+            access.apply(&mut user);
+            store.add_user(user);
+            store.save()?;
+            println!("Added user {}", username);
+        }
+        UserCommand::Access { username, access } => {
+            let Some(user) = store.get_user_mut(&username) else {
+                bail!("No such user: {}", username);
+            };
+            access.apply(user);
+            store.save()?;
+            println!("Updated access for {}", username);
+        }
+        UserCommand::Remove { username } => {
+            if store.remove_user(&username).is_none() {
+                bail!("No such user: {}", username);
+            }
+            store.save()?;
+            println!("Removed user {}", username);
+        }
+        UserCommand::Passwd { username, password } => {
+            if store.get_user(&username).is_none() {
+                bail!("No such user: {}", username);
+            }
+            let password = password_or_prompt(password)?;
+            auth.password_policy.check(&username, &password)?;
+            if let Some(user) = store.get_user_mut(&username) {
+                user.set_password(&password, &auth_manager)?;
+            }
+            store.save()?;
+            println!("Changed password for {}", username);
+        }
+        UserCommand::Disable { username } => set_active(&mut store, &username, false)?,
+        UserCommand::Enable { username } => set_active(&mut store, &username, true)?,
+        UserCommand::EnableTotp { username } => {
+            let Some(user) = store.get_user_mut(&username) else {
+                bail!("No such user: {}", username);
+            };
+            let secret = user.enable_totp();
+            store.save()?;
+            println!("Enabled one-time codes for {}; add this secret to their authenticator app:", username);
+            println!("  {}", secret);
+            println!("  {}", totp_uri(&secret, TOTP_ISSUER, &username));
+        }
+        UserCommand::DisableTotp { username } => {
+            let Some(user) = store.get_user_mut(&username) else {
+                bail!("No such user: {}", username);
+            };
+            user.totp_secret = None;
+            store.save()?;
+            println!("Disabled one-time codes for {}", username);
+        }
+        UserCommand::List => {
+            if store.is_empty() {
+                println!("No users in {}", auth.users_file);
+            }
+            for user in store.users() {
+                let scopes = user.scopes();
+                println!(
+                    "{:<20} {:<8} {:<4} created {}  {}",
+                    user.username,
+                    if user.active { "active" } else { "disabled" },
+                    if user.totp_secret.is_some() { "totp" } else { "" },
+                    user.created_at.format("%Y-%m-%d %H:%M"),
+                    if scopes.is_empty() { "no access".to_string() } else { scopes.join(" ") },
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_active(store: &mut UserStore, username: &str, active: bool) -> Result<()> {
+    let Some(user) = store.get_user_mut(username) else {
+        bail!("No such user: {}", username);
+    };
+    user.active = active;
+    store.save()?;
+    println!("{} user {}", if active { "Enabled" } else { "Disabled" }, username);
+    Ok(())
+}
+
+/// Use the `--password` value, or read one line from stdin
+fn password_or_prompt(password: Option<String>) -> Result<String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            eprint!("Password: ");
+            io::stderr().flush().ok();
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).context("Failed to read password")?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        bail!("Password must not be empty");
+    }
+    Ok(password)
+}
@@ -1,9 +1,16 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc, Duration};
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use jsonwebtoken::{encode, decode, decode_header, jwk::JwkSet, Header, Algorithm, Validation, EncodingKey, DecodingKey};
 use thiserror::Error;
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Mutex, RwLock}};
+
+use crate::error::RemoteHidError;
+use crate::keys::{self, SigningKey, VerificationKey};
+use crate::devices::DeviceCredential;
+use crate::scram::{random_bytes, ScramCredentials};
+use crate::totp::{generate_totp_secret, verify_totp};
+use crate::password::PasswordHashing;
 
 /// JWT claims structure
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -14,6 +21,92 @@ pub struct Claims {
     pub exp: i64,              // Expiration timestamp
     pub iat: i64,              // Issued at timestamp
     pub jti: String,           // JWT ID (unique identifier)
+    #[serde(default)]
+    pub token_use: TokenUse,   // Access or refresh token
+    #[serde(default)]
+    pub scopes: Vec<String>,   // Roles and reachable clients, see `Role` and `Claims::may_access_client`
+}
+
+impl Claims {
+    /// Whether the token carries this role; admins hold every role
+    pub fn has_role(&self, role: Role) -> bool {
+        let held = |role: Role| self.scopes.iter().any(|scope| scope == role.as_str());
+        held(Role::Admin) || held(role) || (role == Role::View && held(Role::Control))
+    }
+    
+    /// Whether the token reaches the HID client with this id and server-assigned tags.
+    /// Scopes name clients as `client:<id>` (`client:*` for all) or `tag:<tag>`.
+    pub fn may_access_client(&self, client_id: &str, tags: &[String]) -> bool {
+        self.has_role(Role::Admin)
+            || self.scopes.iter().any(|scope| {
+                match (scope.strip_prefix("client:"), scope.strip_prefix("tag:")) {
+                    (Some(allowed), _) => allowed == "*" || allowed == client_id,
+                    (_, Some(tag)) => tags.iter().any(|t| t == tag),
+                    _ => false,
+                }
+            })
+    }
+}
+
+/// What a user may do; carried in tokens as scopes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Every permission, on every client, including revoking tokens
+    Admin,
+    /// Take control of allowed clients
+    Control,
+    /// Watch allowed clients without sending input
+    View,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Control => "control",
+            Role::View => "view",
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "control" => Ok(Role::Control),
+            "view" => Ok(Role::View),
+            other => Err(format!("unknown role {:?} (expected admin, control or view)", other)),
+        }
+    }
+}
+
+/// What a token may be used for
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+    /// Authenticates a connection
+    #[default]
+    Access,
+    /// Can only be exchanged for a new token pair
+    Refresh,
+}
+
+/// Access token issued together with the refresh token that renews it
+#[derive(Debug, Clone)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub access_claims: Claims,
+    pub refresh_token: String,
+    pub refresh_claims: Claims,
 }
 
 /// Authentication errors
@@ -25,37 +118,194 @@ pub enum AuthError {
     TokenExpired,
     #[error("Invalid token")]
     InvalidToken,
+    #[error("Token revoked")]
+    TokenRevoked,
     #[error("JWT encoding error: {0}")]
     JwtEncoding(#[from] jsonwebtoken::errors::Error),
     #[error("Password hashing error: {0}")]
-    PasswordHashing(#[from] bcrypt::BcryptError),
+    PasswordHashing(String),
+    #[error("Too many failed login attempts; try again after {until}")]
+    LockedOut { until: DateTime<Utc> },
+    #[error("Invalid key {kid}: {reason}")]
+    InvalidKey { kid: String, reason: String },
+    #[error("Password login is disabled; use the challenge/response exchange")]
+    PasswordLoginDisabled,
+    #[error("Invalid one-time code")]
+    InvalidTotpCode,
+    #[error("Password {0}")]
+    WeakPassword(String),
+    #[error("Server asked for {0} key derivation iterations, outside the accepted range")]
+    UnacceptableIterations(u32),
+}
+
+impl From<bcrypt::BcryptError> for AuthError {
+    fn from(error: bcrypt::BcryptError) -> Self {
+        AuthError::PasswordHashing(error.to_string())
+    }
+}
+
+impl AuthError {
+    /// Machine readable code sent alongside a failed `AuthMessage::Response`
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "INVALID_CREDENTIALS",
+            AuthError::TokenExpired => "TOKEN_EXPIRED",
+            AuthError::InvalidToken => "INVALID_TOKEN",
+            AuthError::TokenRevoked => "TOKEN_REVOKED",
+            AuthError::LockedOut { .. } => "LOCKED_OUT",
+            AuthError::PasswordLoginDisabled => "PASSWORD_LOGIN_DISABLED",
+            AuthError::InvalidTotpCode => "INVALID_TOTP_CODE",
+            AuthError::WeakPassword(_) => "WEAK_PASSWORD",
+            AuthError::JwtEncoding(_)
+            | AuthError::PasswordHashing(_)
+            | AuthError::InvalidKey { .. }
+            | AuthError::UnacceptableIterations(_) => "AUTH_ERROR",
+        }
+    }
+}
+
+/// Credentials a client presents to the session server
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// Username and password, exchanged for a token during the handshake
+    Password { username: String, password: String },
+    /// Previously issued token, sent as an `Authorization: Bearer` header
+    Token(String),
+    /// One-time pairing code, exchanged for a device credential saved at `credential_path`
+    PairingCode { code: String, credential_path: PathBuf },
+    /// Device credential from an earlier pairing; only valid for its own client id
+    Device(DeviceCredential),
+    /// The TLS client certificate, mapped to a user or client id by the server
+    Certificate,
 }
 
 /// Authentication manager for handling JWT tokens and password verification
 pub struct AuthManager {
     encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
-    validation: Validation,
+    header: Header,
+    verification: Verification,
     token_expiry_hours: i64,
+    refresh_token_expiry_hours: i64,
+    revocations: RwLock<RevocationList>,
+    recently_issued: Mutex<RecentTokens>,
+    password_hashing: PasswordHashing,
+}
+
+/// Tokens issued during the current second. Their `iat` cannot tell them apart
+/// from tokens issued later in the same second, so revoking a user revokes these by `jti`.
+#[derive(Debug, Default)]
+struct RecentTokens {
+    second: i64,
+    // (username, jti, exp)
+    tokens: Vec<(String, String, i64)>,
+}
+
+/// How incoming tokens are verified
+enum Verification {
+    /// HS256 with the same shared secret tokens are signed with
+    Secret(DecodingKey),
+    /// Asymmetric public keys, selected by the token's `kid` header
+    Keys(HashMap<String, VerificationKey>),
 }
 
+/// Refresh tokens outlive access tokens by this much unless configured otherwise
+const DEFAULT_REFRESH_TOKEN_EXPIRY_HOURS: i64 = 24 * 30;
+
 impl AuthManager {
     /// Create a new authentication manager with a secret key
     pub fn new(secret: &str, token_expiry_hours: i64) -> Self {
-        let encoding_key = EncodingKey::from_secret(secret.as_bytes());
-        let decoding_key = DecodingKey::from_secret(secret.as_bytes());
-        
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.validate_exp = true;
+        Self::from_secret(secret.as_bytes(), token_expiry_hours)
+    }
+    
+    /// Sign with a secret generated for this process alone, so its tokens stop
+    /// validating once it exits
+    pub fn with_random_secret(token_expiry_hours: i64) -> Self {
+        Self::from_secret(&random_bytes::<32>(), token_expiry_hours)
+    }
+    
+    fn from_secret(secret: &[u8], token_expiry_hours: i64) -> Self {
+        let encoding_key = EncodingKey::from_secret(secret);
+        let decoding_key = DecodingKey::from_secret(secret);
         
         Self {
             encoding_key,
-            decoding_key,
-            validation,
+            header: Header::new(Algorithm::HS256),
+            verification: Verification::Secret(decoding_key),
             token_expiry_hours,
+            refresh_token_expiry_hours: DEFAULT_REFRESH_TOKEN_EXPIRY_HOURS,
+            revocations: RwLock::new(RevocationList::new()),
+            recently_issued: Mutex::default(),
+            password_hashing: PasswordHashing::default(),
         }
     }
     
+    /// Create an authentication manager that signs with an asymmetric key.
+    ///
+    /// Tokens signed by any of `verification_keys` are accepted, so keys can be
+    /// rotated without invalidating tokens issued under the previous one. The
+    /// public half of `signing_key` must be among them.
+    pub fn with_keys(signing_key: SigningKey, verification_keys: Vec<VerificationKey>, token_expiry_hours: i64) -> Result<Self, AuthError> {
+        let mismatch = |reason: &str| AuthError::InvalidKey {
+            kid: signing_key.kid.clone(),
+            reason: reason.to_string(),
+        };
+        match verification_keys.iter().find(|key| key.kid == signing_key.kid) {
+            Some(key) if key.algorithm == signing_key.algorithm => {}
+            Some(_) => return Err(mismatch("public key uses a different algorithm")),
+            None => return Err(mismatch("no public key with this id")),
+        }
+        
+        let mut header = Header::new(signing_key.algorithm);
+        header.kid = Some(signing_key.kid.clone());
+        let manager = Self {
+            encoding_key: signing_key.key,
+            header,
+            verification: Verification::Keys(verification_keys.into_iter().map(|key| (key.kid.clone(), key)).collect()),
+            token_expiry_hours,
+            refresh_token_expiry_hours: DEFAULT_REFRESH_TOKEN_EXPIRY_HOURS,
+            revocations: RwLock::new(RevocationList::new()),
+            recently_issued: Mutex::default(),
+            password_hashing: PasswordHashing::default(),
+        };
+        
+        // Catch a private key paired with the wrong public key before issuing anything
+        let probe = manager.generate_token("key-check", "", None)?;
+        if manager.validate_token(&probe).is_err() {
+            return Err(mismatch("private key does not match its public key"));
+        }
+        Ok(manager)
+    }
+    
+    /// Public verification keys as a JWK set; empty when signing with a shared secret
+    pub fn jwk_set(&self) -> JwkSet {
+        match &self.verification {
+            Verification::Secret(_) => JwkSet { keys: Vec::new() },
+            Verification::Keys(verification_keys) => {
+                let mut verification_keys: Vec<&VerificationKey> = verification_keys.values().collect();
+                verification_keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+                keys::jwk_set(verification_keys)
+            }
+        }
+    }
+    
+    /// Use a previously loaded revocation list, e.g. one persisted to disk
+    pub fn with_revocation_list(mut self, revocations: RevocationList) -> Self {
+        self.revocations = RwLock::new(revocations);
+        self
+    }
+    
+    /// Set how long refresh tokens stay valid
+    pub fn with_refresh_token_expiry(mut self, hours: i64) -> Self {
+        self.refresh_token_expiry_hours = hours;
+        self
+    }
+    
+    /// Set the scheme and cost of newly hashed passwords
+    pub fn with_password_hashing(mut self, password_hashing: PasswordHashing) -> Self {
+        self.password_hashing = password_hashing;
+        self
+    }
+    
     /// Generate a JWT token for authenticated user
     pub fn generate_token(
         &self,
@@ -63,25 +313,89 @@ impl AuthManager {
         client_type: &str,
         client_id: Option<String>,
     ) -> Result<String, AuthError> {
-        let now = Utc::now();
-        let exp = now + Duration::hours(self.token_expiry_hours);
+        self.generate_scoped_token(username, client_type, client_id, Vec::new())
+    }
+    
+    /// Generate a JWT token carrying the user's roles and client allow list
+    pub fn generate_scoped_token(
+        &self,
+        username: &str,
+        client_type: &str,
+        client_id: Option<String>,
+        scopes: Vec<String>,
+    ) -> Result<String, AuthError> {
+        let claims = self.claims(username, client_type, client_id, scopes, TokenUse::Access);
+        let token = encode(&self.header, &claims, &self.encoding_key)?;
+        Ok(token)
+    }
+    
+    /// Generate an access token and the refresh token that renews it
+    pub fn generate_token_pair(
+        &self,
+        username: &str,
+        client_type: &str,
+        client_id: Option<String>,
+        scopes: Vec<String>,
+    ) -> Result<TokenPair, AuthError> {
+        let access_claims = self.claims(username, client_type, client_id.clone(), scopes.clone(), TokenUse::Access);
+        let refresh_claims = self.claims(username, client_type, client_id, scopes, TokenUse::Refresh);
+        
+        Ok(TokenPair {
+            access_token: encode(&self.header, &access_claims, &self.encoding_key)?,
+            access_claims,
+            refresh_token: encode(&self.header, &refresh_claims, &self.encoding_key)?,
+            refresh_claims,
+        })
+    }
+    
+    fn claims(&self, username: &str, client_type: &str, client_id: Option<String>, scopes: Vec<String>, token_use: TokenUse) -> Claims {
+        let lifetime = match token_use {
+            TokenUse::Access => Duration::hours(self.token_expiry_hours),
+            TokenUse::Refresh => Duration::hours(self.refresh_token_expiry_hours),
+        };
         
+        // Stamped under the lock, so `revoke_user` sees every token issued before it
+        let mut recent = self.recently_issued.lock().unwrap();
+        let now = Utc::now();
         let claims = Claims {
             sub: username.to_string(),
             client_type: client_type.to_string(),
             client_id,
-            exp: exp.timestamp(),
+            exp: (now + lifetime).timestamp(),
             iat: now.timestamp(),
             jti: Uuid::new_v4().to_string(),
+            token_use,
+            scopes,
         };
-        
-        let token = encode(&Header::default(), &claims, &self.encoding_key)?;
-        Ok(token)
+        if recent.second != claims.iat {
+            *recent = RecentTokens { second: claims.iat, tokens: Vec::new() };
+        }
+        recent.tokens.push((claims.sub.clone(), claims.jti.clone(), claims.exp));
+        claims
     }
     
-    /// Validate and decode a JWT token
+    /// Validate and decode an access token
     pub fn validate_token(&self, token: &str) -> Result<Claims, AuthError> {
-        let token_data = decode::<Claims>(token, &self.decoding_key, &self.validation)?;
+        self.decode_token(token, TokenUse::Access)
+    }
+    
+    /// Validate and decode a refresh token
+    pub fn validate_refresh_token(&self, token: &str) -> Result<Claims, AuthError> {
+        self.decode_token(token, TokenUse::Refresh)
+    }
+    
+    fn decode_token(&self, token: &str, token_use: TokenUse) -> Result<Claims, AuthError> {
+        let (decoding_key, algorithm) = match &self.verification {
+            Verification::Secret(decoding_key) => (decoding_key, Algorithm::HS256),
+            Verification::Keys(verification_keys) => {
+                let kid = decode_header(token)?.kid.ok_or(AuthError::InvalidToken)?;
+                let key = verification_keys.get(&kid).ok_or(AuthError::InvalidToken)?;
+                (&key.key, key.algorithm)
+            }
+        };
+        let mut validation = Validation::new(algorithm);
+        validation.validate_exp = true;
+        let token_data = decode::<Claims>(token, decoding_key, &validation)?;
         
         // Check if token is expired
         let now = Utc::now().timestamp();
@@ -89,19 +403,70 @@ impl AuthManager {
             return Err(AuthError::TokenExpired);
         }
         
+        // A refresh token must not be accepted as an access token, and vice versa
+        if token_data.claims.token_use != token_use {
+            return Err(AuthError::InvalidToken);
+        }
+        
+        if self.is_revoked(&token_data.claims) {
+            return Err(AuthError::TokenRevoked);
+        }
+        
         Ok(token_data.claims)
     }
     
-    /// Hash a password using bcrypt
+    /// Whether the token behind these claims has been revoked
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        self.revocations.read().unwrap().is_revoked(claims)
+    }
+    
+    /// Revoke a single token by its `jti`.
+    /// The revocation takes effect even if persisting it fails.
+    pub fn revoke_token(&self, jti: &str) -> crate::error::Result<()> {
+        // Any token this manager issued has expired by then, so the entry can be dropped
+        let exp = (Utc::now() + self.max_token_lifetime()).timestamp();
+        self.revocations.write().unwrap().revoke_token(jti, exp)
+    }
+    
+    /// Revoke every token issued to `username` so far.
+    /// The revocation takes effect even if persisting it fails.
+    pub fn revoke_user(&self, username: &str) -> crate::error::Result<()> {
+        // Held until the cut is made, so no token is issued in between
+        let recent = self.recently_issued.lock().unwrap();
+        let mut revocations = self.revocations.write().unwrap();
+        for (_, jti, exp) in recent.tokens.iter().filter(|(sub, _, _)| sub == username) {
+            revocations.revoke_token(jti, *exp)?;
+        }
+        revocations.revoke_user(username, self.max_token_lifetime().num_seconds())
+    }
+    
+    fn max_token_lifetime(&self) -> Duration {
+        Duration::hours(self.token_expiry_hours.max(self.refresh_token_expiry_hours))
+    }
+    
+    /// Hash a password with the configured scheme (argon2id unless configured otherwise)
     pub fn hash_password(&self, password: &str) -> Result<String, AuthError> {
-        let hashed = hash(password, DEFAULT_COST)?;
-        Ok(hashed)
+        self.password_hashing.hash(password)
     }
     
-    /// Verify a password against its hash
+    /// Verify a password against its hash, whichever supported scheme produced it
     pub fn verify_password(&self, password: &str, hash: &str) -> Result<bool, AuthError> {
-        let valid = verify(password, hash)?;
-        Ok(valid)
+        self.password_hashing.verify(password, hash)
+    }
+    
+    /// Whether a stored hash should be replaced by one from `hash_password`
+    pub fn password_needs_rehash(&self, hash: &str) -> bool {
+        self.password_hashing.needs_rehash(hash)
+    }
+    
+    /// Derive challenge/response keys for a password with the configured iterations
+    pub fn scram_credentials(&self, password: &str) -> Result<ScramCredentials, AuthError> {
+        self.password_hashing.scram_credentials(password)
+    }
+    
+    /// Whether stored challenge/response keys should be derived again
+    pub fn scram_needs_rederive(&self, scram: Option<&ScramCredentials>) -> bool {
+        self.password_hashing.scram_needs_rederive(scram)
     }
 }
 
@@ -113,6 +478,23 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     pub last_login: Option<DateTime<Utc>>,
     pub active: bool,
+    /// Keys for the challenge/response login; derived on the next password login when missing
+    #[serde(default)]
+    pub scram: Option<ScramCredentials>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Client ids this user may reach; `*` allows every client
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+    /// Client tags (assigned in the server config) this user may reach
+    #[serde(default)]
+    pub allowed_tags: Vec<String>,
+    /// Base32 TOTP secret; when set, commander logins must also enter a one-time code
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// Counter of the last accepted one-time code, so it cannot be used twice
+    #[serde(skip)]
+    totp_last_counter: Option<u64>,
 }
 
 impl User {
@@ -126,9 +508,23 @@ impl User {
             created_at: Utc::now(),
             last_login: None,
             active: true,
+            scram: Some(auth_manager.scram_credentials(password)?),
+            roles: Vec::new(),
+            allowed_clients: Vec::new(),
+            allowed_tags: Vec::new(),
+            totp_secret: None,
+            totp_last_counter: None,
         })
     }
     
+    /// Token scopes for this user's roles and allow lists
+    pub fn scopes(&self) -> Vec<String> {
+        self.roles.iter().map(|role| role.as_str().to_string())
+            .chain(self.allowed_clients.iter().map(|client| format!("client:{}", client)))
+            .chain(self.allowed_tags.iter().map(|tag| format!("tag:{}", tag)))
+            .collect()
+    }
+    
     /// Verify password for this user
     pub fn verify_password(&self, password: &str, auth_manager: &AuthManager) -> Result<bool, AuthError> {
         if !self.active {
@@ -138,17 +534,47 @@ impl User {
         auth_manager.verify_password(password, &self.password_hash)
     }
     
+    /// Replace the password hash and challenge/response keys
+    pub fn set_password(&mut self, password: &str, auth_manager: &AuthManager) -> Result<(), AuthError> {
+        self.password_hash = auth_manager.hash_password(password)?;
+        self.scram = Some(auth_manager.scram_credentials(password)?);
+        Ok(())
+    }
+    
+    /// Enroll a new TOTP secret, returning it for the user's authenticator app
+    pub fn enable_totp(&mut self) -> String {
+        let secret = generate_totp_secret();
+        self.totp_secret = Some(secret.clone());
+        self.totp_last_counter = None;
+        secret
+    }
+    
+    /// Check a one-time code, consuming it
+    pub fn verify_totp(&mut self, code: &str) -> bool {
+        let Some(secret) = &self.totp_secret else {
+            return false;
+        };
+        match verify_totp(secret, code, Utc::now(), self.totp_last_counter) {
+            Some(counter) => {
+                self.totp_last_counter = Some(counter);
+                true
+            }
+            None => false,
+        }
+    }
+    
     /// Update last login timestamp
     pub fn update_last_login(&mut self) {
         self.last_login = Some(Utc::now());
     }
 }
 
-/// Simple in-memory user store (for demonstration)
-/// In production, this would be backed by a database
-#[derive(Debug, Default)]
+/// User accounts keyed by username, optionally backed by a JSON file
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct UserStore {
-    users: std::collections::HashMap<String, User>,
+    users: HashMap<String, User>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
 impl UserStore {
@@ -157,11 +583,63 @@ impl UserStore {
         Self::default()
     }
     
+    /// Load the users saved at `path`; a missing file yields an empty store saved there by `save`
+    pub fn load(path: impl AsRef<Path>) -> crate::error::Result<Self> {
+        let path = path.as_ref();
+        let mut store: Self = read_json_or_default(path)?;
+        store.path = Some(path.to_path_buf());
+        Ok(store)
+    }
+    
+    /// Write the store back to the file it was loaded from
+    pub fn save(&self) -> crate::error::Result<()> {
+        match &self.path {
+            Some(path) => write_private_json(path, self),
+            None => Ok(()),
+        }
+    }
+    
+    /// Re-read the file the store was loaded from, so accounts edited since apply.
+    /// What only lives in memory is kept: last logins and the replay guard of
+    /// one-time codes, as long as the user's TOTP secret is unchanged.
+    pub fn reload(&mut self) -> crate::error::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let mut reloaded = Self::load(&path)?;
+        for (username, user) in &mut reloaded.users {
+            let Some(current) = self.users.get(username) else { continue };
+            user.last_login = user.last_login.max(current.last_login);
+            if user.totp_secret == current.totp_secret {
+                user.totp_last_counter = current.totp_last_counter;
+            }
+        }
+        *self = reloaded;
+        Ok(())
+    }
+    
     /// Add a user to the store
     pub fn add_user(&mut self, user: User) {
         self.users.insert(user.username.clone(), user);
     }
     
+    /// Remove a user, returning it if it existed
+    pub fn remove_user(&mut self, username: &str) -> Option<User> {
+        self.users.remove(username)
+    }
+    
+    /// All users, sorted by username
+    pub fn users(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+    
+    /// Whether the store has no users
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+    
     /// Get a user by username
     pub fn get_user(&self, username: &str) -> Option<&User> {
         self.users.get(username)
@@ -172,7 +650,12 @@ impl UserStore {
         self.users.get_mut(username)
     }
     
-    /// Authenticate a user with username/password
+    /// Authenticate a user with username/password.
+    ///
+    /// While the password is at hand, a hash from an older scheme or cost is
+    /// replaced and challenge/response keys that are missing or use other
+    /// iterations are derived again; callers that keep the store on disk should
+    /// save the user afterwards.
     pub fn authenticate(
         &mut self,
         username: &str,
@@ -183,6 +666,12 @@ impl UserStore {
             let valid = user.verify_password(password, auth_manager)?;
             if valid {
                 user.update_last_login();
+                if auth_manager.password_needs_rehash(&user.password_hash) {
+                    user.password_hash = auth_manager.hash_password(password)?;
+                }
+                if auth_manager.scram_needs_rederive(user.scram.as_ref()) {
+                    user.scram = Some(auth_manager.scram_credentials(password)?);
+                }
             }
             Ok(valid)
         } else {
@@ -190,17 +679,314 @@ impl UserStore {
         }
     }
     
-    /// Create default admin user for testing
-    pub fn create_default_admin(&mut self, auth_manager: &AuthManager) -> Result<(), AuthError> {
-        let admin_user = User::new("admin".to_string(), "admin123", auth_manager)?;
-        self.add_user(admin_user);
-        Ok(())
+    /// Whether logging in as `username` needs a one-time code after the password or
+    /// certificate, whichever client type the login claims to be for
+    pub fn requires_totp(&self, username: &str) -> bool {
+        self.get_user(username).is_some_and(|user| user.totp_secret.is_some())
+    }
+    
+    /// Check a user's one-time code; each code is accepted once
+    pub fn verify_totp(&mut self, username: &str, code: &str) -> bool {
+        self.get_user_mut(username).is_some_and(|user| user.active && user.verify_totp(code))
+    }
+    
+    /// Challenge/response keys of an active user
+    pub fn scram_credentials(&self, username: &str) -> Option<&ScramCredentials> {
+        self.get_user(username)
+            .filter(|user| user.active)
+            .and_then(|user| user.scram.as_ref())
+    }
+}
+
+/// Write secrets as JSON readable only by the current user.
+///
+/// A sibling file is written and renamed over `path`, so a crash never leaves
+/// a truncated file behind.
+pub(crate) fn write_private_json<T: Serialize>(path: &Path, value: &T) -> crate::error::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        std::io::Write::write_all(&mut options.open(&tmp)?, &serde_json::to_vec_pretty(value)?)?;
+        std::fs::rename(&tmp, path)
+    };
+    write().map_err(|e| RemoteHidError::Configuration(format!("{}: {}", path.display(), e)))
+}
+
+/// Read a JSON file written by `write_private_json`; a missing file yields the default
+pub(crate) fn read_json_or_default<T: serde::de::DeserializeOwned + Default>(path: &Path) -> crate::error::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| RemoteHidError::Configuration(format!("{}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(RemoteHidError::Configuration(format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Refresh token that has been issued and not yet used or revoked
+#[derive(Debug, Clone)]
+struct IssuedRefreshToken {
+    sub: String,
+    access_jti: String,
+    exp: i64,
+}
+
+/// Server-side record of outstanding refresh tokens, keyed by their `jti`.
+///
+/// Every refresh token is single use: redeeming it removes it, so a token
+/// that was already rotated (or revoked on logout) cannot be replayed.
+#[derive(Debug, Default)]
+pub struct RefreshTokenStore {
+    tokens: std::collections::HashMap<String, IssuedRefreshToken>,
+}
+
+impl RefreshTokenStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Remember the refresh token of a newly issued pair
+    pub fn insert(&mut self, pair: &TokenPair) {
+        let now = Utc::now().timestamp();
+        self.tokens.retain(|_, issued| issued.exp >= now);
+        self.tokens.insert(pair.refresh_claims.jti.clone(), IssuedRefreshToken {
+            sub: pair.refresh_claims.sub.clone(),
+            access_jti: pair.access_claims.jti.clone(),
+            exp: pair.refresh_claims.exp,
+        });
+    }
+    
+    /// Consume a refresh token; returns `false` if it is unknown, already used or revoked
+    pub fn redeem(&mut self, claims: &Claims) -> bool {
+        match self.tokens.get(&claims.jti) {
+            Some(issued) if issued.sub == claims.sub => {
+                self.tokens.remove(&claims.jti);
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    /// Revoke the refresh token issued alongside an access token
+    pub fn revoke_for_access_token(&mut self, access_jti: &str) -> bool {
+        let before = self.tokens.len();
+        self.tokens.retain(|_, issued| issued.access_jti != access_jti);
+        self.tokens.len() < before
+    }
+    
+    /// Number of outstanding refresh tokens
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    
+    /// Whether no refresh tokens are outstanding
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+/// Revoked tokens, by `jti`, and users whose earlier tokens were all revoked.
+///
+/// When created with [`RevocationList::load`] every change is written back to
+/// the file, so revocations survive a server restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RevocationList {
+    // jti -> expiry of the revoked token, after which the entry is pointless
+    tokens: HashMap<String, i64>,
+    // username -> time of revocation; tokens issued in earlier seconds are revoked
+    users: HashMap<String, i64>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl RevocationList {
+    /// Create an empty, in-memory list
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Load a list persisted at `path`; a missing file yields an empty list saved there on first change
+    pub fn load(path: impl AsRef<Path>) -> crate::error::Result<Self> {
+        let path = path.as_ref();
+        let mut list: Self = read_json_or_default(path)?;
+        list.path = Some(path.to_path_buf());
+        Ok(list)
+    }
+    
+    /// Revoke the token with this `jti`; `exp` is when it would have expired anyway
+    pub fn revoke_token(&mut self, jti: &str, exp: i64) -> crate::error::Result<()> {
+        let now = Utc::now().timestamp();
+        self.tokens.retain(|_, token_exp| *token_exp >= now);
+        self.tokens.insert(jti.to_string(), exp);
+        self.save()
+    }
+    
+    /// Revoke every token issued to `username` before the current second; tokens
+    /// issued earlier in this second must be revoked by `jti`. Users' entries are
+    /// dropped once `max_token_lifetime` seconds have passed since their revocation.
+    pub fn revoke_user(&mut self, username: &str, max_token_lifetime: i64) -> crate::error::Result<()> {
+        let now = Utc::now().timestamp();
+        self.users.retain(|_, revoked_at| *revoked_at + max_token_lifetime >= now);
+        self.users.insert(username.to_string(), now);
+        self.save()
+    }
+    
+    /// Whether the token behind these claims has been revoked
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        self.tokens.contains_key(&claims.jti)
+            || self.users.get(&claims.sub).is_some_and(|revoked_at| claims.iat < *revoked_at)
+    }
+    
+    fn save(&self) -> crate::error::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_private_json(path, self)
+    }
+}
+
+/// Client-side schedule for silently refreshing an access token before it expires
+#[derive(Debug, Default)]
+pub struct TokenRefresher {
+    refresh_token: Option<String>,
+    refresh_at: Option<DateTime<Utc>>,
+}
+
+impl TokenRefresher {
+    /// Create a refresher with nothing to refresh yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Record the tokens from a successful `AuthMessage::Response`.
+    /// The refresh is scheduled once four fifths of the access token's lifetime have passed.
+    pub fn update(&mut self, refresh_token: Option<String>, expires_at: Option<DateTime<Utc>>) {
+        if refresh_token.is_some() {
+            self.refresh_token = refresh_token;
+        }
+        let now = Utc::now();
+        self.refresh_at = expires_at.map(|expires_at| now + (expires_at - now) * 4 / 5);
+    }
+    
+    /// Time left until the access token should be refreshed, if it can be
+    pub fn due_in(&self) -> Option<std::time::Duration> {
+        self.refresh_token.as_ref()?;
+        let refresh_at = self.refresh_at?;
+        Some((refresh_at - Utc::now()).to_std().unwrap_or_default())
+    }
+    
+    /// Take the refresh token for an `AuthMessage::Refresh`; it can only be used once
+    pub fn take(&mut self) -> Option<String> {
+        self.refresh_token.take()
+    }
+    
+    /// Resolve once the access token is due for a refresh; never, if it cannot be refreshed
+    pub fn refresh_due(&self) -> impl std::future::Future<Output = ()> + 'static {
+        let due_in = self.due_in();
+        async move {
+            match due_in {
+                Some(due_in) => tokio::time::sleep(due_in).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Consecutive failed logins for one username or source address
+#[derive(Debug, Clone)]
+struct FailedLogins {
+    count: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Counts consecutive failed logins per key (username or source address)
+/// and locks a key out once it reaches the configured limit
+#[derive(Debug)]
+pub struct LoginLimiter {
+    max_failed_attempts: u32,
+    lockout_duration: Duration,
+    failures: std::collections::HashMap<String, FailedLogins>,
+}
+
+impl LoginLimiter {
+    /// Create a limiter; a `max_failed_attempts` of zero disables lockouts
+    pub fn new(max_failed_attempts: u32, lockout_duration_mins: u32) -> Self {
+        Self {
+            max_failed_attempts,
+            lockout_duration: Duration::minutes(lockout_duration_mins as i64),
+            failures: std::collections::HashMap::new(),
+        }
+    }
+    
+    /// When the lockout for `key` ends, if it is currently locked out
+    pub fn locked_until(&self, key: &str) -> Option<DateTime<Utc>> {
+        self.failures
+            .get(key)
+            .and_then(|failed| failed.locked_until)
+            .filter(|until| *until > Utc::now())
+    }
+    
+    /// Record a failed login; returns the lockout end if this failure triggered one
+    pub fn record_failure(&mut self, key: &str) -> Option<DateTime<Utc>> {
+        if self.max_failed_attempts == 0 {
+            return None;
+        }
+        
+        let now = Utc::now();
+        let lockout_duration = self.lockout_duration;
+        // Failures older than the lockout window no longer count as consecutive
+        self.failures.retain(|_, failed| {
+            failed.locked_until.is_some_and(|until| until > now) || now - failed.last_failure < lockout_duration
+        });
+        
+        let failed = self.failures.entry(key.to_string()).or_insert(FailedLogins {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if failed.locked_until.is_some_and(|until| until <= now) {
+            failed.count = 0;
+            failed.locked_until = None;
+        }
+        failed.count += 1;
+        failed.last_failure = now;
+        
+        if failed.count >= self.max_failed_attempts {
+            let until = now + lockout_duration;
+            failed.locked_until = Some(until);
+            Some(until)
+        } else {
+            None
+        }
+    }
+    
+    /// Number of consecutive failures currently counted against `key`
+    pub fn failure_count(&self, key: &str) -> u32 {
+        self.failures.get(key).map_or(0, |failed| failed.count)
+    }
+    
+    /// Forget the failures for `key` after a successful login
+    pub fn record_success(&mut self, key: &str) {
+        self.failures.remove(key);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scram::MIN_SCRAM_ITERATIONS;
+    
+    /// Default password hashing with quick challenge/response keys
+    fn user_auth_manager() -> AuthManager {
+        AuthManager::new("test_secret", 24).with_password_hashing(PasswordHashing {
+            scram_iterations: MIN_SCRAM_ITERATIONS,
+            ..PasswordHashing::default()
+        })
+    }
     
     #[test]
     fn test_auth_manager() {
@@ -229,7 +1015,7 @@ mod tests {
     
     #[test]
     fn test_user_creation() {
-        let auth_manager = AuthManager::new("test_secret", 24);
+        let auth_manager = user_auth_manager();
         let user = User::new("testuser".to_string(), "password123", &auth_manager).unwrap();
         
         assert_eq!(user.username, "testuser");
@@ -239,7 +1025,7 @@ mod tests {
     
     #[test]
     fn test_user_store() {
-        let auth_manager = AuthManager::new("test_secret", 24);
+        let auth_manager = user_auth_manager();
         let mut store = UserStore::new();
         
         let user = User::new("testuser".to_string(), "password123", &auth_manager).unwrap();
@@ -249,4 +1035,269 @@ mod tests {
         assert!(!store.authenticate("testuser", "wrong_password", &auth_manager).unwrap());
         assert!(!store.authenticate("nonexistent", "password123", &auth_manager).unwrap());
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_login_upgrades_bcrypt_hash() {
+        let auth_manager = AuthManager::new("test_secret", 24).with_password_hashing(PasswordHashing {
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            scram_iterations: MIN_SCRAM_ITERATIONS,
+            ..PasswordHashing::default()
+        });
+        let mut user = User::new("legacy".to_string(), "old-password", &auth_manager).unwrap();
+        user.password_hash = bcrypt::hash("old-password", 4).unwrap();
+        let mut store = UserStore::new();
+        store.add_user(user);
+        
+        assert!(!store.authenticate("legacy", "wrong-password", &auth_manager).unwrap());
+        assert!(store.get_user("legacy").unwrap().password_hash.starts_with("$2"));
+        
+        assert!(store.authenticate("legacy", "old-password", &auth_manager).unwrap());
+        let upgraded = store.get_user("legacy").unwrap().password_hash.clone();
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert!(store.authenticate("legacy", "old-password", &auth_manager).unwrap());
+        assert_eq!(store.get_user("legacy").unwrap().password_hash, upgraded);
+    }
+    
+    #[test]
+    fn test_login_rederives_scram_keys_with_new_iterations() {
+        let mut store = UserStore::new();
+        store.add_user(User::new("alice".to_string(), "password123", &user_auth_manager()).unwrap());
+        let raised = AuthManager::new("test_secret", 24).with_password_hashing(PasswordHashing {
+            scram_iterations: MIN_SCRAM_ITERATIONS + 1,
+            ..PasswordHashing::default()
+        });
+        
+        assert!(!store.authenticate("alice", "wrong-password", &raised).unwrap());
+        assert_eq!(store.scram_credentials("alice").unwrap().iterations, MIN_SCRAM_ITERATIONS);
+        assert!(store.authenticate("alice", "password123", &raised).unwrap());
+        assert_eq!(store.scram_credentials("alice").unwrap().iterations, MIN_SCRAM_ITERATIONS + 1);
+        
+        // Clients would refuse challenges outside this range, so neither is configurable
+        for iterations in [MIN_SCRAM_ITERATIONS - 1, crate::scram::MAX_SCRAM_ITERATIONS + 1] {
+            let hashing = PasswordHashing { scram_iterations: iterations, ..PasswordHashing::default() };
+            assert!(matches!(hashing.scram_credentials("password123"), Err(AuthError::PasswordHashing(_))));
+        }
+    }
+    
+    #[test]
+    fn test_user_store_persists_to_file() {
+        let auth_manager = user_auth_manager();
+        let path = std::env::temp_dir().join(format!("remote-hid-users-{}.json", Uuid::new_v4()));
+        
+        let mut store = UserStore::load(&path).unwrap();
+        assert!(store.is_empty());
+        store.add_user(User::new("bob".to_string(), "password123", &auth_manager).unwrap());
+        store.add_user(User::new("alice".to_string(), "password123", &auth_manager).unwrap());
+        store.get_user_mut("bob").unwrap().active = false;
+        store.save().unwrap();
+        
+        let mut reloaded = UserStore::load(&path).unwrap();
+        let names: Vec<&str> = reloaded.users().iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert!(reloaded.authenticate("alice", "password123", &auth_manager).unwrap());
+        assert!(!reloaded.authenticate("bob", "password123", &auth_manager).unwrap());
+        assert!(reloaded.scram_credentials("bob").is_none());
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+    
+    #[test]
+    fn test_login_limiter_locks_after_max_failures() {
+        let mut limiter = LoginLimiter::new(3, 15);
+        
+        assert!(limiter.record_failure("alice").is_none());
+        assert!(limiter.record_failure("alice").is_none());
+        assert!(limiter.locked_until("alice").is_none());
+        
+        let until = limiter.record_failure("alice").unwrap();
+        assert!(until > Utc::now() + Duration::minutes(14));
+        assert_eq!(limiter.locked_until("alice"), Some(until));
+        assert!(limiter.locked_until("bob").is_none());
+    }
+    
+    #[test]
+    fn test_login_limiter_success_resets_count() {
+        let mut limiter = LoginLimiter::new(3, 15);
+        
+        limiter.record_failure("10.0.0.1");
+        limiter.record_failure("10.0.0.1");
+        limiter.record_success("10.0.0.1");
+        assert_eq!(limiter.failure_count("10.0.0.1"), 0);
+        
+        assert!(limiter.record_failure("10.0.0.1").is_none());
+        assert_eq!(limiter.failure_count("10.0.0.1"), 1);
+    }
+    
+    #[test]
+    fn test_login_limiter_disabled() {
+        let mut limiter = LoginLimiter::new(0, 15);
+        for _ in 0..10 {
+            assert!(limiter.record_failure("alice").is_none());
+        }
+        assert!(limiter.locked_until("alice").is_none());
+    }
+    
+    #[test]
+    fn test_token_pair() {
+        let auth_manager = AuthManager::new("test_secret", 1).with_refresh_token_expiry(48);
+        let pair = auth_manager.generate_token_pair("testuser", "HidClient", Some("client123".to_string()), Vec::new()).unwrap();
+        
+        let access = auth_manager.validate_token(&pair.access_token).unwrap();
+        let refresh = auth_manager.validate_refresh_token(&pair.refresh_token).unwrap();
+        assert_eq!(access.token_use, TokenUse::Access);
+        assert_eq!(refresh.token_use, TokenUse::Refresh);
+        assert_eq!(refresh.client_id, Some("client123".to_string()));
+        assert!(refresh.exp > access.exp);
+        
+        // Neither token is accepted in place of the other
+        assert!(matches!(auth_manager.validate_token(&pair.refresh_token), Err(AuthError::InvalidToken)));
+        assert!(matches!(auth_manager.validate_refresh_token(&pair.access_token), Err(AuthError::InvalidToken)));
+    }
+    
+    #[test]
+    fn test_scopes_grant_roles_and_clients() {
+        let auth_manager = user_auth_manager();
+        let mut user = User::new("alice".to_string(), "password123", &auth_manager).unwrap();
+        user.roles = vec![Role::Control];
+        user.allowed_clients = vec!["office-pc".to_string()];
+        user.allowed_tags = vec!["lab".to_string()];
+        
+        let token = auth_manager.generate_scoped_token("alice", "Commander", None, user.scopes()).unwrap();
+        let claims = auth_manager.validate_token(&token).unwrap();
+        assert_eq!(claims.scopes, ["control", "client:office-pc", "tag:lab"]);
+        assert!(claims.has_role(Role::Control));
+        assert!(claims.has_role(Role::View));
+        assert!(!claims.has_role(Role::Admin));
+        assert!(claims.may_access_client("office-pc", &[]));
+        assert!(claims.may_access_client("bench-1", &["lab".to_string()]));
+        assert!(!claims.may_access_client("bench-2", &["prod".to_string()]));
+        
+        let admin = auth_manager.validate_token(&auth_manager.generate_scoped_token("root", "Commander", None, vec!["admin".to_string()]).unwrap()).unwrap();
+        assert!(admin.has_role(Role::Control) && admin.may_access_client("anything", &[]));
+        
+        // Tokens from before scopes existed grant nothing
+        let unscoped = auth_manager.validate_token(&auth_manager.generate_token("alice", "Commander", None).unwrap()).unwrap();
+        assert!(!unscoped.has_role(Role::View));
+        assert!(!unscoped.may_access_client("office-pc", &[]));
+        assert_eq!("view".parse::<Role>(), Ok(Role::View));
+        assert!("root".parse::<Role>().is_err());
+    }
+    
+    #[test]
+    fn test_refresh_token_store_single_use() {
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let mut store = RefreshTokenStore::new();
+        let pair = auth_manager.generate_token_pair("testuser", "Commander", None, Vec::new()).unwrap();
+        store.insert(&pair);
+        
+        assert!(store.redeem(&pair.refresh_claims));
+        assert!(!store.redeem(&pair.refresh_claims));
+        assert!(store.is_empty());
+    }
+    
+    #[test]
+    fn test_refresh_token_store_revoke_for_access_token() {
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let mut store = RefreshTokenStore::new();
+        let pair = auth_manager.generate_token_pair("testuser", "Commander", None, Vec::new()).unwrap();
+        let other = auth_manager.generate_token_pair("testuser", "Commander", None, Vec::new()).unwrap();
+        store.insert(&pair);
+        store.insert(&other);
+        
+        assert!(store.revoke_for_access_token(&pair.access_claims.jti));
+        assert!(!store.redeem(&pair.refresh_claims));
+        assert!(store.redeem(&other.refresh_claims));
+    }
+    
+    #[test]
+    fn test_token_refresher_schedule() {
+        let mut refresher = TokenRefresher::new();
+        assert!(refresher.due_in().is_none());
+        
+        refresher.update(Some("refresh".to_string()), Some(Utc::now() + Duration::minutes(10)));
+        let due_in = refresher.due_in().unwrap();
+        assert!(due_in > std::time::Duration::from_secs(7 * 60));
+        assert!(due_in <= std::time::Duration::from_secs(8 * 60));
+        
+        assert_eq!(refresher.take(), Some("refresh".to_string()));
+        assert!(refresher.due_in().is_none());
+    }
+    
+    #[test]
+    fn test_revoked_token_rejected() {
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let pair = auth_manager.generate_token_pair("testuser", "Commander", None, Vec::new()).unwrap();
+        
+        auth_manager.revoke_token(&pair.access_claims.jti).unwrap();
+        assert!(matches!(auth_manager.validate_token(&pair.access_token), Err(AuthError::TokenRevoked)));
+        assert!(auth_manager.validate_refresh_token(&pair.refresh_token).is_ok());
+    }
+    
+    #[test]
+    fn test_revoke_user_covers_earlier_tokens() {
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let pair = auth_manager.generate_token_pair("testuser", "HidClient", None, Vec::new()).unwrap();
+        let other = auth_manager.generate_token("otheruser", "HidClient", None).unwrap();
+        
+        auth_manager.revoke_user("testuser").unwrap();
+        assert!(matches!(auth_manager.validate_token(&pair.access_token), Err(AuthError::TokenRevoked)));
+        assert!(matches!(auth_manager.validate_refresh_token(&pair.refresh_token), Err(AuthError::TokenRevoked)));
+        assert!(auth_manager.validate_token(&other).is_ok());
+        
+        // Tokens issued after the revocation are valid, even within the same second
+        let after = auth_manager.generate_token("testuser", "HidClient", None).unwrap();
+        assert!(auth_manager.validate_token(&after).is_ok());
+        let mut later = auth_manager.validate_token(&after).unwrap();
+        later.iat += 1;
+        assert!(!auth_manager.is_revoked(&later));
+    }
+    
+    #[test]
+    fn test_user_revocations_pruned_once_tokens_expired() {
+        let mut list = RevocationList::new();
+        let now = Utc::now().timestamp();
+        list.users.insert("old".to_string(), now - 7200);
+        list.users.insert("recent".to_string(), now - 60);
+        
+        list.revoke_user("testuser", 3600).unwrap();
+        assert!(!list.users.contains_key("old"));
+        assert!(list.users.contains_key("recent"));
+        assert!(list.users.contains_key("testuser"));
+    }
+    
+    #[test]
+    fn test_revocation_list_persistence() {
+        let path = std::env::temp_dir().join(format!("remote-hid-revoked-{}.json", Uuid::new_v4()));
+        let auth_manager = AuthManager::new("test_secret", 1);
+        let token = auth_manager.generate_token("testuser", "Commander", None).unwrap();
+        let claims = auth_manager.validate_token(&token).unwrap();
+        
+        let mut list = RevocationList::load(&path).unwrap();
+        list.revoke_token(&claims.jti, claims.exp).unwrap();
+        list.revoke_user("otheruser", 3600).unwrap();
+        
+        let reloaded = RevocationList::load(&path).unwrap();
+        assert!(reloaded.is_revoked(&claims));
+        let auth_manager = AuthManager::new("test_secret", 1).with_revocation_list(reloaded);
+        assert!(matches!(auth_manager.validate_token(&token), Err(AuthError::TokenRevoked)));
+        
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+    
+    #[test]
+    fn test_auth_error_codes() {
+        assert_eq!(AuthError::InvalidCredentials.code(), "INVALID_CREDENTIALS");
+        assert_eq!(AuthError::LockedOut { until: Utc::now() }.code(), "LOCKED_OUT");
+    }
+}
@@ -0,0 +1,225 @@
+//! Unattended HID client enrollment.
+//!
+//! An admin creates a one-time pairing code for a client id; the HID client
+//! redeems it once for a long-lived device secret, stores that on disk, and
+//! authenticates with it from then on. The server keeps only hashes of both.
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{read_json_or_default, write_private_json, AuthError};
+use crate::scram::{constant_time_eq, random_bytes};
+
+/// Pairing codes avoid characters that are easily confused when typed
+const PAIRING_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PAIRING_CODE_LEN: usize = 10;
+
+/// Token subject for a paired device, kept apart from user names
+pub fn device_subject(client_id: &str) -> String {
+    format!("device:{}", client_id)
+}
+
+/// A pairing code waiting to be redeemed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingPairing {
+    client_id: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// An enrolled HID client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub client_id: String,
+    secret_hash: String,
+    pub enrolled_at: DateTime<Utc>,
+}
+
+/// Pending pairing codes and enrolled devices, optionally backed by a JSON file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeviceStore {
+    /// Keyed by the hash of the code
+    #[serde(default)]
+    pairing_codes: HashMap<String, PendingPairing>,
+    #[serde(default)]
+    devices: HashMap<String, Device>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl DeviceStore {
+    /// Create an empty, in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the store saved at `path`; a missing file yields an empty store saved there by `save`
+    pub fn load(path: impl AsRef<Path>) -> crate::error::Result<Self> {
+        let path = path.as_ref();
+        let mut store: Self = read_json_or_default(path)?;
+        store.path = Some(path.to_path_buf());
+        Ok(store)
+    }
+
+    /// Write the store back to the file it was loaded from
+    pub fn save(&self) -> crate::error::Result<()> {
+        match &self.path {
+            Some(path) => write_private_json(path, self),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a one-time code that enrolls `client_id` if redeemed within `ttl`
+    pub fn create_pairing_code(&mut self, client_id: &str, ttl: Duration) -> String {
+        let now = Utc::now();
+        self.pairing_codes.retain(|_, pending| pending.expires_at > now);
+
+        let code: String = random_bytes::<PAIRING_CODE_LEN>()
+            .iter()
+            .map(|byte| PAIRING_ALPHABET[*byte as usize % PAIRING_ALPHABET.len()] as char)
+            .collect();
+        self.pairing_codes.insert(hash(&normalize(&code)), PendingPairing {
+            client_id: client_id.to_string(),
+            expires_at: now + ttl,
+        });
+        format!("{}-{}", &code[..PAIRING_CODE_LEN / 2], &code[PAIRING_CODE_LEN / 2..])
+    }
+
+    /// Redeem a pairing code issued for `client_id`, returning the new device secret.
+    /// The code is spent, and any earlier credential of the client id is replaced.
+    pub fn pair(&mut self, code: &str, client_id: &str) -> Result<String, AuthError> {
+        let key = hash(&normalize(code));
+        match self.pairing_codes.get(&key) {
+            Some(pending) if pending.client_id == client_id && pending.expires_at > Utc::now() => {}
+            _ => return Err(AuthError::InvalidCredentials),
+        }
+        self.pairing_codes.remove(&key);
+
+        let secret = STANDARD.encode(random_bytes::<32>());
+        self.devices.insert(client_id.to_string(), Device {
+            client_id: client_id.to_string(),
+            secret_hash: hash(&secret),
+            enrolled_at: Utc::now(),
+        });
+        Ok(secret)
+    }
+
+    /// Whether `secret` is the current credential of an enrolled `client_id`
+    pub fn authenticate(&self, client_id: &str, secret: &str) -> bool {
+        self.devices.get(client_id).is_some_and(|device| {
+            constant_time_eq(device.secret_hash.as_bytes(), hash(secret).as_bytes())
+        })
+    }
+
+    /// Get an enrolled device by client id
+    pub fn get_device(&self, client_id: &str) -> Option<&Device> {
+        self.devices.get(client_id)
+    }
+
+    /// Unenroll a device, returning it if it existed
+    pub fn remove_device(&mut self, client_id: &str) -> Option<Device> {
+        self.devices.remove(client_id)
+    }
+
+    /// All enrolled devices, sorted by client id
+    pub fn devices(&self) -> Vec<&Device> {
+        let mut devices: Vec<&Device> = self.devices.values().collect();
+        devices.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        devices
+    }
+
+    /// Client ids with an unexpired pairing code, and when each code expires
+    pub fn pending_pairings(&self) -> Vec<(&str, DateTime<Utc>)> {
+        let now = Utc::now();
+        let mut pending: Vec<(&str, DateTime<Utc>)> = self.pairing_codes.values()
+            .filter(|pending| pending.expires_at > now)
+            .map(|pending| (pending.client_id.as_str(), pending.expires_at))
+            .collect();
+        pending.sort();
+        pending
+    }
+}
+
+/// Credential a paired HID client keeps on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCredential {
+    pub client_id: String,
+    pub device_secret: String,
+}
+
+impl DeviceCredential {
+    pub fn load(path: impl AsRef<Path>) -> crate::error::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| crate::error::RemoteHidError::Configuration(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&content)
+            .map_err(|e| crate::error::RemoteHidError::Configuration(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Save the credential readable only by the current user
+    pub fn save(&self, path: impl AsRef<Path>) -> crate::error::Result<()> {
+        write_private_json(path.as_ref(), self)
+    }
+}
+
+/// Codes are typed by people: ignore case and the separator
+fn normalize(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+fn hash(value: &str) -> String {
+    STANDARD.encode(digest::digest(&digest::SHA256, value.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pairing_code_redeemed_once_for_its_client() {
+        let mut store = DeviceStore::new();
+        let code = store.create_pairing_code("kiosk-1", Duration::minutes(15));
+        assert_eq!(code.len(), PAIRING_CODE_LEN + 1);
+
+        assert!(store.pair(&code, "kiosk-2").is_err());
+        let secret = store.pair(&code.to_lowercase().replace('-', ""), "kiosk-1").unwrap();
+        assert!(store.pair(&code, "kiosk-1").is_err());
+
+        assert!(store.authenticate("kiosk-1", &secret));
+        assert!(!store.authenticate("kiosk-1", "guess"));
+        assert!(!store.authenticate("kiosk-2", &secret));
+        assert!(store.remove_device("kiosk-1").is_some());
+        assert!(!store.authenticate("kiosk-1", &secret));
+    }
+
+    #[test]
+    fn test_expired_pairing_code_rejected() {
+        let mut store = DeviceStore::new();
+        let code = store.create_pairing_code("kiosk-1", Duration::minutes(-1));
+        assert!(store.pair(&code, "kiosk-1").is_err());
+        assert!(store.pending_pairings().is_empty());
+    }
+
+    #[test]
+    fn test_device_store_and_credential_persist() {
+        let dir = std::env::temp_dir();
+        let store_path = dir.join(format!("remote-hid-devices-{}.json", uuid::Uuid::new_v4()));
+        let credential_path = dir.join(format!("remote-hid-device-{}.json", uuid::Uuid::new_v4()));
+
+        let mut store = DeviceStore::load(&store_path).unwrap();
+        let code = store.create_pairing_code("kiosk-1", Duration::minutes(15));
+        store.save().unwrap();
+
+        let mut store = DeviceStore::load(&store_path).unwrap();
+        let secret = store.pair(&code, "kiosk-1").unwrap();
+        store.save().unwrap();
+        DeviceCredential { client_id: "kiosk-1".to_string(), device_secret: secret }.save(&credential_path).unwrap();
+
+        let credential = DeviceCredential::load(&credential_path).unwrap();
+        assert!(DeviceStore::load(&store_path).unwrap().authenticate(&credential.client_id, &credential.device_secret));
+        std::fs::remove_file(&store_path).ok();
+        std::fs::remove_file(&credential_path).ok();
+    }
+}
@@ -0,0 +1,240 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey,
+};
+use simple_asn1::{from_der, oid, ASN1Block};
+
+use crate::auth::AuthError;
+
+/// Private key that new tokens are signed with, identified by its `kid`
+pub struct SigningKey {
+    pub(crate) kid: String,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) key: EncodingKey,
+}
+
+impl SigningKey {
+    /// Load a PKCS#8 (or, for RS256, PKCS#1) PEM private key
+    pub fn from_pem(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Result<Self, AuthError> {
+        let key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(pem),
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(pem),
+            other => return Err(unsupported(kid, other)),
+        }
+        .map_err(|e| invalid_key(kid, e))?;
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm,
+            key,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+}
+
+/// Public key that tokens carrying its `kid` are verified with
+pub struct VerificationKey {
+    pub(crate) kid: String,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) key: DecodingKey,
+    jwk: Jwk,
+}
+
+impl VerificationKey {
+    /// Load a `BEGIN PUBLIC KEY` (SubjectPublicKeyInfo) PEM file's contents
+    pub fn from_pem(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Result<Self, AuthError> {
+        let spki = match rustls_pemfile::read_one_from_slice(pem) {
+            Ok(Some((rustls_pemfile::Item::SubjectPublicKeyInfo(spki), _))) => spki,
+            _ => return Err(invalid_key(kid, "expected a PEM encoded public key")),
+        };
+        let (key_oid, public_key) = parse_spki(spki.as_ref()).ok_or_else(|| invalid_key(kid, "malformed public key"))?;
+
+        let (key, key_algorithm, parameters) = match algorithm {
+            Algorithm::EdDSA => {
+                if key_oid != oid!(1, 3, 101, 112) || public_key.len() != 32 {
+                    return Err(invalid_key(kid, "not an Ed25519 public key"));
+                }
+                let parameters = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(&public_key),
+                });
+                (DecodingKey::from_ed_der(&public_key), KeyAlgorithm::EdDSA, parameters)
+            }
+            Algorithm::RS256 => {
+                if key_oid != oid!(1, 2, 840, 113549, 1, 1, 1) {
+                    return Err(invalid_key(kid, "not an RSA public key"));
+                }
+                let (modulus, exponent) = parse_rsa_public_key(&public_key).ok_or_else(|| invalid_key(kid, "malformed RSA public key"))?;
+                let parameters = AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(&modulus),
+                    e: URL_SAFE_NO_PAD.encode(&exponent),
+                });
+                (DecodingKey::from_rsa_raw_components(&modulus, &exponent), KeyAlgorithm::RS256, parameters)
+            }
+            other => return Err(unsupported(kid, other)),
+        };
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        };
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm,
+            key,
+            jwk,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The key in JWK form, for publishing
+    pub fn jwk(&self) -> &Jwk {
+        &self.jwk
+    }
+}
+
+/// Publishable JWK set of the given verification keys
+pub fn jwk_set<'a>(keys: impl IntoIterator<Item = &'a VerificationKey>) -> JwkSet {
+    JwkSet {
+        keys: keys.into_iter().map(|key| key.jwk.clone()).collect(),
+    }
+}
+
+/// Split a SubjectPublicKeyInfo into its algorithm OID and public key bits
+fn parse_spki(der: &[u8]) -> Option<(simple_asn1::OID, Vec<u8>)> {
+    let blocks = from_der(der).ok()?;
+    let [ASN1Block::Sequence(_, spki)] = blocks.as_slice() else {
+        return None;
+    };
+    let [ASN1Block::Sequence(_, algorithm), ASN1Block::BitString(_, _, public_key)] = spki.as_slice() else {
+        return None;
+    };
+    let Some(ASN1Block::ObjectIdentifier(_, key_oid)) = algorithm.first() else {
+        return None;
+    };
+    Some((key_oid.clone(), public_key.clone()))
+}
+
+/// Big-endian modulus and exponent of a PKCS#1 `RSAPublicKey`
+fn parse_rsa_public_key(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let blocks = from_der(der).ok()?;
+    let [ASN1Block::Sequence(_, fields)] = blocks.as_slice() else {
+        return None;
+    };
+    let [ASN1Block::Integer(_, modulus), ASN1Block::Integer(_, exponent)] = fields.as_slice() else {
+        return None;
+    };
+    Some((modulus.to_bytes_be().1, exponent.to_bytes_be().1))
+}
+
+fn unsupported(kid: &str, algorithm: Algorithm) -> AuthError {
+    invalid_key(kid, format!("unsupported algorithm {:?}; use EdDSA or RS256", algorithm))
+}
+
+fn invalid_key(kid: &str, reason: impl std::fmt::Display) -> AuthError {
+    AuthError::InvalidKey {
+        kid: kid.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthManager;
+    use jsonwebtoken::{decode, decode_header, Validation};
+    
+    const ED25519_PRIVATE: &str = include_str!("../testdata/ed25519.pem");
+    const ED25519_PUBLIC: &str = include_str!("../testdata/ed25519.pub.pem");
+    const ED25519_OLD_PRIVATE: &str = include_str!("../testdata/ed25519-old.pem");
+    const ED25519_OLD_PUBLIC: &str = include_str!("../testdata/ed25519-old.pub.pem");
+    const RSA_PRIVATE: &str = include_str!("../testdata/rsa.pem");
+    const RSA_PUBLIC: &str = include_str!("../testdata/rsa.pub.pem");
+    
+    fn manager(kid: &str, algorithm: Algorithm, private: &str, public_keys: &[(&str, Algorithm, &str)]) -> Result<AuthManager, AuthError> {
+        let signing_key = SigningKey::from_pem(kid, algorithm, private.as_bytes())?;
+        let verification_keys = public_keys
+            .iter()
+            .map(|(kid, algorithm, pem)| VerificationKey::from_pem(kid, *algorithm, pem.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        AuthManager::with_keys(signing_key, verification_keys, 1)
+    }
+    
+    #[test]
+    fn test_eddsa_tokens_carry_kid() {
+        let auth_manager = manager("ed-1", Algorithm::EdDSA, ED25519_PRIVATE, &[("ed-1", Algorithm::EdDSA, ED25519_PUBLIC)]).unwrap();
+        let token = auth_manager.generate_token("testuser", "Commander", None).unwrap();
+        
+        let header = decode_header(&token).unwrap();
+        assert_eq!(header.alg, Algorithm::EdDSA);
+        assert_eq!(header.kid.as_deref(), Some("ed-1"));
+        assert_eq!(auth_manager.validate_token(&token).unwrap().sub, "testuser");
+    }
+    
+    #[test]
+    fn test_published_rsa_key_verifies_tokens() {
+        let auth_manager = manager("rsa-1", Algorithm::RS256, RSA_PRIVATE, &[("rsa-1", Algorithm::RS256, RSA_PUBLIC)]).unwrap();
+        let token = auth_manager.generate_token("testuser", "HidClient", None).unwrap();
+        
+        // Another service only has the published JWK set
+        let jwks: JwkSet = serde_json::from_str(&serde_json::to_string(&auth_manager.jwk_set()).unwrap()).unwrap();
+        let jwk = jwks.find("rsa-1").unwrap();
+        assert!(matches!(jwk.algorithm, AlgorithmParameters::RSA(_)));
+        let claims = decode::<crate::auth::Claims>(&token, &DecodingKey::from_jwk(jwk).unwrap(), &Validation::new(Algorithm::RS256))
+            .unwrap()
+            .claims;
+        assert_eq!(claims.sub, "testuser");
+    }
+    
+    #[test]
+    fn test_rotated_key_still_verifies() {
+        let old = manager("ed-old", Algorithm::EdDSA, ED25519_OLD_PRIVATE, &[("ed-old", Algorithm::EdDSA, ED25519_OLD_PUBLIC)]).unwrap();
+        let old_token = old.generate_token("testuser", "Commander", None).unwrap();
+        
+        let rotated = manager("ed-new", Algorithm::EdDSA, ED25519_PRIVATE, &[
+            ("ed-new", Algorithm::EdDSA, ED25519_PUBLIC),
+            ("ed-old", Algorithm::EdDSA, ED25519_OLD_PUBLIC),
+        ]).unwrap();
+        assert!(rotated.validate_token(&old_token).is_ok());
+        assert_eq!(rotated.jwk_set().keys.len(), 2);
+        
+        // Once the old key is retired its tokens are rejected
+        let retired = manager("ed-new", Algorithm::EdDSA, ED25519_PRIVATE, &[("ed-new", Algorithm::EdDSA, ED25519_PUBLIC)]).unwrap();
+        assert!(matches!(retired.validate_token(&old_token), Err(AuthError::InvalidToken)));
+        
+        // Secret-signed tokens carry no kid and are rejected too
+        let hs_token = AuthManager::new("test_secret", 1).generate_token("testuser", "Commander", None).unwrap();
+        assert!(retired.validate_token(&hs_token).is_err());
+    }
+    
+    #[test]
+    fn test_mismatched_keys_rejected() {
+        let err = manager("ed-1", Algorithm::EdDSA, ED25519_PRIVATE, &[("ed-1", Algorithm::EdDSA, ED25519_OLD_PUBLIC)]).err().unwrap();
+        assert!(matches!(err, AuthError::InvalidKey { .. }));
+        
+        let err = manager("ed-1", Algorithm::EdDSA, ED25519_PRIVATE, &[("other", Algorithm::EdDSA, ED25519_PUBLIC)]).err().unwrap();
+        assert!(matches!(err, AuthError::InvalidKey { .. }));
+        
+        assert!(VerificationKey::from_pem("rsa-1", Algorithm::EdDSA, RSA_PUBLIC.as_bytes()).is_err());
+        assert!(VerificationKey::from_pem("ed-1", Algorithm::RS256, ED25519_PUBLIC.as_bytes()).is_err());
+        assert!(VerificationKey::from_pem("ed-1", Algorithm::EdDSA, ED25519_PRIVATE.as_bytes()).is_err());
+        assert!(SigningKey::from_pem("hs", Algorithm::HS256, b"secret").is_err());
+    }
+}
@@ -1,8 +1,24 @@
 pub mod protocol;
 pub mod auth;
+pub mod keys;
+pub mod password;
+pub mod scram;
+pub mod totp;
+pub mod recording;
+pub mod devices;
 pub mod error;
+pub mod tls;
+pub mod login;
+pub mod terminal;
 mod tests;
 
 pub use protocol::*;
 pub use auth::*;
+pub use keys::*;
+pub use password::*;
+pub use scram::*;
+pub use totp::*;
+pub use recording::*;
+pub use devices::*;
 pub use error::*;
+pub use login::*;
@@ -0,0 +1,192 @@
+//! Client side of the login handshake, shared by HID clients and commanders.
+//!
+//! A client opens with one [`FirstFrame`] (or none, when it sent a bearer token
+//! with the upgrade request) and then answers whatever the server asks until it
+//! is told the login succeeded or failed.
+
+use std::future::Future;
+use anyhow::{anyhow, bail, Result};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::info;
+
+use crate::auth::TokenRefresher;
+use crate::devices::DeviceCredential;
+use crate::protocol::{AuthMessage, ClientType, Message, MessagePayload, StatusMessage};
+use crate::scram::ScramClient;
+
+/// The message a login opens with, and the challenge/response exchange it starts, if any
+pub struct FirstFrame {
+    message: AuthMessage,
+    scram: Option<ScramClient>,
+}
+
+impl FirstFrame {
+    /// Prove a password through a challenge/response exchange; the password itself is never sent
+    pub fn password(username: &str, password: &str, client_type: ClientType, client_id: Option<String>) -> Self {
+        let scram = ScramClient::new(username, password);
+        Self {
+            message: scram.start(client_type, client_id),
+            scram: Some(scram),
+        }
+    }
+
+    /// Open with a message the server answers directly, e.g. `Pair` or `CertificateLogin`
+    pub fn new(message: AuthMessage) -> Self {
+        Self { message, scram: None }
+    }
+}
+
+/// How a client answers the prompts some logins bring up.
+/// By default neither is expected and the login fails.
+pub trait LoginPrompts {
+    /// One-time code for an account with TOTP enrolled
+    fn totp_code(&self) -> impl Future<Output = Result<String>> {
+        async { bail!("This account needs a one-time code to log in") }
+    }
+
+    /// Keep the device credential a successful pairing issued
+    fn paired(&self, _credential: DeviceCredential) -> Result<()> {
+        bail!("Unexpected device credential from session server")
+    }
+}
+
+/// Run the login handshake on a fresh connection, sending `first_frame` if given.
+/// The returned refresher holds the refresh token the login was issued.
+pub async fn client_login<S, R, E>(
+    sender: &mut S,
+    receiver: &mut R,
+    first_frame: Option<FirstFrame>,
+    prompts: &impl LoginPrompts,
+) -> Result<TokenRefresher>
+where
+    S: Sink<WsMessage> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    R: Stream<Item = std::result::Result<WsMessage, E>> + Unpin,
+{
+    let mut scram = None;
+    if let Some(first_frame) = first_frame {
+        sender.send(WsMessage::Text(serde_json::to_string(&Message::auth(first_frame.message))?)).await?;
+        scram = first_frame.scram;
+    }
+
+    let mut refresher = TokenRefresher::new();
+    let mut server_verified = false;
+    loop {
+        let text = match receiver.next().await {
+            Some(Ok(WsMessage::Text(text))) => text,
+            _ => return Err(anyhow!("Connection closed during authentication")),
+        };
+        let reply = match serde_json::from_str::<Message>(&text)?.payload {
+            MessagePayload::Auth(AuthMessage::ScramChallenge { nonce, salt, iterations }) => {
+                let exchange = scram.as_mut().ok_or_else(|| anyhow!("Unexpected authentication challenge"))?;
+                exchange.respond(&nonce, &salt, iterations)?
+            }
+            MessagePayload::Auth(AuthMessage::ScramServerFinal { server_signature }) => {
+                if !scram.as_ref().is_some_and(|exchange| exchange.verify_server(&server_signature)) {
+                    bail!("Session server could not prove it holds this account's credentials");
+                }
+                server_verified = true;
+                continue;
+            }
+            MessagePayload::Auth(AuthMessage::TotpRequired) => AuthMessage::TotpCode { code: prompts.totp_code().await? },
+            MessagePayload::Auth(AuthMessage::Paired { client_id, device_secret }) => {
+                prompts.paired(DeviceCredential { client_id, device_secret })?;
+                continue;
+            }
+            MessagePayload::Auth(AuthMessage::Response { success: true, expires_at, refresh_token, .. }) => {
+                if scram.is_some() && !server_verified {
+                    bail!("Session server accepted the login without proving its identity");
+                }
+                info!("Authenticated with session server (token expires {:?})", expires_at);
+                refresher.update(refresh_token, expires_at);
+                return Ok(refresher);
+            }
+            MessagePayload::Auth(AuthMessage::Response { error_message, .. }) => {
+                bail!("Authentication failed: {}", error_message.unwrap_or_default());
+            }
+            MessagePayload::Status(StatusMessage::Error { error_code, error_message }) => {
+                bail!("Server rejected connection ({}): {}", error_code, error_message);
+            }
+            _ => bail!("Unexpected response to authentication"),
+        };
+        sender.send(WsMessage::Text(serde_json::to_string(&Message::auth(reply))?)).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+    use futures_util::{sink, stream};
+    use std::convert::Infallible;
+    use tokio::sync::mpsc;
+
+    struct Unattended;
+    impl LoginPrompts for Unattended {}
+
+    struct Interactive;
+    impl LoginPrompts for Interactive {
+        async fn totp_code(&self) -> Result<String> {
+            Ok("123456".to_string())
+        }
+    }
+
+    /// A sink that keeps every frame sent to it
+    fn recorder() -> (impl Sink<WsMessage, Error = Infallible> + Unpin, mpsc::UnboundedReceiver<WsMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let sink = sink::unfold(tx, |tx, frame| async move {
+            tx.send(frame).ok();
+            Ok::<_, Infallible>(tx)
+        });
+        (Box::pin(sink), rx)
+    }
+
+    fn frame(auth: AuthMessage) -> std::result::Result<WsMessage, Infallible> {
+        Ok(WsMessage::Text(serde_json::to_string(&Message::auth(auth)).unwrap()))
+    }
+
+    fn success(refresh_token: Option<&str>) -> AuthMessage {
+        AuthMessage::Response {
+            success: true,
+            token: Some("access".to_string()),
+            expires_at: Some(Utc::now() + Duration::hours(1)),
+            error_message: None,
+            error_code: None,
+            refresh_token: refresh_token.map(str::to_string),
+        }
+    }
+
+    fn sent(frames: &mut mpsc::UnboundedReceiver<WsMessage>) -> Vec<AuthMessage> {
+        let mut sent = Vec::new();
+        while let Ok(frame) = frames.try_recv() {
+            match serde_json::from_str::<Message>(frame.to_text().unwrap()).unwrap().payload {
+                MessagePayload::Auth(auth) => sent.push(auth),
+                other => panic!("expected an auth message, got {:?}", other),
+            }
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_one_time_code_asked_for_and_sent() {
+        let (mut sender, mut frames) = recorder();
+        let mut receiver = stream::iter(vec![frame(AuthMessage::TotpRequired), frame(success(Some("refresh")))]);
+        let first_frame = FirstFrame::new(AuthMessage::CertificateLogin { client_type: ClientType::Commander, client_id: None });
+
+        let refresher = client_login(&mut sender, &mut receiver, Some(first_frame), &Interactive).await.unwrap();
+        assert!(refresher.due_in().is_some());
+        assert!(matches!(&sent(&mut frames)[..], [AuthMessage::CertificateLogin { .. }, AuthMessage::TotpCode { code }] if code == "123456"));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_prompts_fail_the_login() {
+        let mut receiver = stream::iter(vec![frame(AuthMessage::TotpRequired)]);
+        assert!(client_login(&mut recorder().0, &mut receiver, None, &Unattended).await.is_err());
+
+        // A password login must hear the server prove itself before it is accepted
+        let mut receiver = stream::iter(vec![frame(success(None))]);
+        let first_frame = FirstFrame::password("alice", "secret", ClientType::HidClient, Some("bench-1".to_string()));
+        assert!(client_login(&mut recorder().0, &mut receiver, Some(first_frame), &Unattended).await.is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Password hashing schemes and the policy new passwords must meet.
+//!
+//! New hashes use the configured scheme (argon2id by default). Verification
+//! detects the scheme from the stored PHC string, so older bcrypt hashes keep
+//! working until they are upgraded on the next login that sees the password.
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
+
+use crate::auth::AuthError;
+use crate::scram::{random_bytes, ScramCredentials, DEFAULT_SCRAM_ITERATIONS, MAX_SCRAM_ITERATIONS, MIN_SCRAM_ITERATIONS};
+
+/// Algorithm used for newly hashed passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordScheme {
+    Argon2id,
+    Bcrypt,
+}
+
+/// How passwords are hashed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashing {
+    pub scheme: PasswordScheme,
+    /// Argon2id memory cost in KiB
+    pub argon2_memory_kib: u32,
+    /// Argon2id passes over memory
+    pub argon2_iterations: u32,
+    /// Argon2id lanes
+    pub argon2_parallelism: u32,
+    /// bcrypt cost (log2 rounds), when `scheme` is `bcrypt`
+    pub bcrypt_cost: u32,
+    /// PBKDF2-SHA256 iterations for the challenge/response keys stored next to
+    /// the hash. Anyone holding the users file can attack those keys instead, so
+    /// they should be about as costly; clients compute them on every login.
+    pub scram_iterations: u32,
+}
+
+impl Default for PasswordHashing {
+    /// Argon2id with the OWASP recommended minimum (19 MiB, 2 passes, 1 lane)
+    fn default() -> Self {
+        Self {
+            scheme: PasswordScheme::Argon2id,
+            argon2_memory_kib: Params::DEFAULT_M_COST,
+            argon2_iterations: Params::DEFAULT_T_COST,
+            argon2_parallelism: Params::DEFAULT_P_COST,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            scram_iterations: DEFAULT_SCRAM_ITERATIONS,
+        }
+    }
+}
+
+impl PasswordHashing {
+    /// Hash `password` with the configured scheme and cost
+    pub fn hash(&self, password: &str) -> Result<String, AuthError> {
+        match self.scheme {
+            PasswordScheme::Argon2id => {
+                let salt = SaltString::encode_b64(&random_bytes::<16>()).map_err(argon2_error)?;
+                let hash = self.argon2()?.hash_password(password.as_bytes(), &salt).map_err(argon2_error)?;
+                Ok(hash.to_string())
+            }
+            PasswordScheme::Bcrypt => Ok(bcrypt::hash(password, self.bcrypt_cost)?),
+        }
+    }
+
+    /// Verify `password` against a stored argon2 or bcrypt hash
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, AuthError> {
+        if is_bcrypt(hash) {
+            return Ok(bcrypt::verify(password, hash)?);
+        }
+        let parsed = PasswordHash::new(hash).map_err(argon2_error)?;
+        // The hash records its own algorithm and parameters
+        Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    }
+
+    /// Whether a stored hash uses a different scheme or cost than new hashes would
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match self.scheme {
+            PasswordScheme::Bcrypt => bcrypt_cost(hash) != Some(self.bcrypt_cost),
+            PasswordScheme::Argon2id => {
+                let Ok(parsed) = PasswordHash::new(hash) else {
+                    return true;
+                };
+                let Ok(params) = Params::try_from(&parsed) else {
+                    return true;
+                };
+                parsed.algorithm != Algorithm::Argon2id.ident()
+                    || parsed.version != Some(Version::V0x13.into())
+                    || params.m_cost() != self.argon2_memory_kib
+                    || params.t_cost() != self.argon2_iterations
+                    || params.p_cost() != self.argon2_parallelism
+            }
+        }
+    }
+
+    /// Derive challenge/response keys for `password` with the configured iterations
+    pub fn scram_credentials(&self, password: &str) -> Result<ScramCredentials, AuthError> {
+        if !(MIN_SCRAM_ITERATIONS..=MAX_SCRAM_ITERATIONS).contains(&self.scram_iterations) {
+            return Err(AuthError::PasswordHashing(format!(
+                "scram_iterations must be between {} and {}, which clients accept",
+                MIN_SCRAM_ITERATIONS, MAX_SCRAM_ITERATIONS,
+            )));
+        }
+        Ok(ScramCredentials::new(password, self.scram_iterations))
+    }
+
+    /// Whether challenge/response keys are missing or derived with other iterations than new ones would be
+    pub fn scram_needs_rederive(&self, scram: Option<&ScramCredentials>) -> bool {
+        scram.is_none_or(|scram| scram.iterations != self.scram_iterations)
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, AuthError> {
+        let params = Params::new(self.argon2_memory_kib, self.argon2_iterations, self.argon2_parallelism, None)
+            .map_err(argon2_error)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+/// Cost of a `$2b$12$...` style hash
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    if !is_bcrypt(hash) {
+        return None;
+    }
+    hash.get(4..6)?.parse().ok()
+}
+
+fn argon2_error(error: impl std::fmt::Display) -> AuthError {
+    AuthError::PasswordHashing(error.to_string())
+}
+
+/// Rules passwords must meet when accounts are created or passwords changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    /// Minimum length in characters
+    pub min_length: usize,
+    /// Passwords that are refused regardless of length, compared case-insensitively
+    pub denied_passwords: Vec<String>,
+    /// File with one more denied password per line, e.g. a common-passwords list
+    pub deny_list_file: Option<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            denied_passwords: Vec::new(),
+            deny_list_file: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Check a new password for `username`, explaining why it is refused
+    pub fn check(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let weak = |reason: String| Err(AuthError::WeakPassword(reason));
+        if password.chars().count() < self.min_length {
+            return weak(format!("must be at least {} characters", self.min_length));
+        }
+        let lowered = password.to_lowercase();
+        if lowered == username.to_lowercase() {
+            return weak("must not be the username".to_string());
+        }
+        if self.denied_passwords.iter().any(|denied| denied.to_lowercase() == lowered) || self.deny_list()?.contains(&lowered) {
+            return weak("is on the list of denied passwords".to_string());
+        }
+        Ok(())
+    }
+
+    fn deny_list(&self) -> Result<HashSet<String>, AuthError> {
+        let Some(path) = &self.deny_list_file else {
+            return Ok(HashSet::new());
+        };
+        let content = fs::read_to_string(Path::new(path))
+            .map_err(|e| AuthError::WeakPassword(format!("cannot be checked; failed to read {}: {}", path, e)))?;
+        Ok(content.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters; the defaults are deliberately slow
+    fn fast_argon2() -> PasswordHashing {
+        PasswordHashing {
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            ..PasswordHashing::default()
+        }
+    }
+
+    #[test]
+    fn test_argon2id_hash_and_verify() {
+        let hashing = fast_argon2();
+        let hash = hashing.hash("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert!(hashing.verify("correct horse", &hash).unwrap());
+        assert!(!hashing.verify("wrong horse", &hash).unwrap());
+        assert!(!hashing.needs_rehash(&hash));
+
+        let stronger = PasswordHashing { argon2_memory_kib: 128, ..fast_argon2() };
+        assert!(stronger.verify("correct horse", &hash).unwrap());
+        assert!(stronger.needs_rehash(&hash));
+    }
+
+    #[test]
+    fn test_bcrypt_hashes_detected_and_upgraded() {
+        let bcrypt_hash = bcrypt::hash("correct horse", 4).unwrap();
+        let hashing = fast_argon2();
+        assert!(hashing.verify("correct horse", &bcrypt_hash).unwrap());
+        assert!(!hashing.verify("wrong horse", &bcrypt_hash).unwrap());
+        assert!(hashing.needs_rehash(&bcrypt_hash));
+
+        let bcrypt = PasswordHashing { scheme: PasswordScheme::Bcrypt, bcrypt_cost: 4, ..PasswordHashing::default() };
+        assert!(!bcrypt.needs_rehash(&bcrypt_hash));
+        assert!(bcrypt.needs_rehash(&hashing.hash("correct horse").unwrap()));
+        assert!(hashing.verify("x", "not a hash").is_err());
+    }
+
+    #[test]
+    fn test_policy_rejects_short_denied_and_username_passwords() {
+        let deny_file = std::env::temp_dir().join(format!("remote-hid-deny-{}.txt", uuid::Uuid::new_v4()));
+        fs::write(&deny_file, "letmein123\n\nPassword1234\n").unwrap();
+        let policy = PasswordPolicy {
+            min_length: 10,
+            denied_passwords: vec!["correcthorse".to_string()],
+            deny_list_file: Some(deny_file.to_string_lossy().into_owned()),
+        };
+
+        assert!(policy.check("alice", "long enough passphrase").is_ok());
+        assert!(matches!(policy.check("alice", "short"), Err(AuthError::WeakPassword(_))));
+        assert!(policy.check("alice", "CorrectHorse").is_err());
+        assert!(policy.check("alice", "password1234").is_err());
+        assert!(policy.check("alice-admin", "Alice-Admin").is_err());
+
+        fs::remove_file(&deny_file).ok();
+        assert!(policy.check("alice", "long enough passphrase").is_err());
+    }
+}
@@ -39,7 +39,8 @@ pub enum MessagePayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action")]
 pub enum AuthMessage {
-    /// Request authentication with credentials
+    /// Request authentication with credentials.
+    /// Legacy: sends the password itself, so servers only accept it when configured to
     Request {
         username: String,
         password: String,
@@ -52,13 +53,76 @@ pub enum AuthMessage {
         token: Option<String>,
         expires_at: Option<DateTime<Utc>>,
         error_message: Option<String>,
+        /// Machine readable reason for a failure, e.g. `LOCKED_OUT`
+        #[serde(default)]
+        error_code: Option<String>,
+        /// Single-use token for `Refresh`; issued alongside each new access token
+        #[serde(default)]
+        refresh_token: Option<String>,
     },
-    /// Refresh authentication token
+    /// Exchange a refresh token for a new access/refresh token pair
     Refresh {
         refresh_token: String,
     },
-    /// Logout and invalidate token
+    /// Logout, revoke the connection's tokens and disconnect
     Logout,
+    /// Start a challenge/response login; the password itself is never sent
+    ScramStart {
+        username: String,
+        client_nonce: String,
+        client_type: ClientType,
+        client_id: Option<String>,
+    },
+    /// Server challenge: the client nonce extended by the server, and the user's salt
+    ScramChallenge {
+        nonce: String,
+        salt: String,
+        iterations: u32,
+    },
+    /// Client proof that it knows the password
+    ScramProof {
+        nonce: String,
+        proof: String,
+    },
+    /// Server proof that it holds the user's credentials; followed by a `Response`
+    ScramServerFinal {
+        server_signature: String,
+    },
+    /// Enroll a HID client with a one-time pairing code
+    Pair {
+        pairing_code: String,
+        client_id: String,
+    },
+    /// Device credential issued for a redeemed pairing code; followed by a `Response`
+    Paired {
+        client_id: String,
+        device_secret: String,
+    },
+    /// Authenticate a paired HID client with its device credential
+    DeviceLogin {
+        client_id: String,
+        device_secret: String,
+    },
+    /// Authenticate as the identity the server maps the TLS client certificate to
+    CertificateLogin {
+        client_type: ClientType,
+        client_id: Option<String>,
+    },
+    /// The password was accepted, but the account also needs a one-time code
+    TotpRequired,
+    /// One-time code from the user's authenticator app, answering `TotpRequired`
+    TotpCode {
+        code: String,
+    },
+    /// Admin request to revoke a token by `jti`, or every token of a user
+    Revoke {
+        jti: Option<String>,
+        username: Option<String>,
+    },
+    /// Revocation applied; live connections using the revoked tokens were closed
+    Revoked {
+        disconnected: usize,
+    },
 }
 
 /// Client type identification
@@ -68,6 +132,15 @@ pub enum ClientType {
     Commander,
 }
 
+impl std::fmt::Display for ClientType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientType::HidClient => write!(f, "HidClient"),
+            ClientType::Commander => write!(f, "Commander"),
+        }
+    }
+}
+
 /// HID input event types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event_type")]
@@ -162,10 +235,24 @@ pub enum SessionControlMessage {
     CreateSession {
         client_id: String,
         client_name: Option<String>,
+        /// Operating system the HID client runs on
+        #[serde(default)]
+        platform: Option<String>,
+        /// Ask before each commander takes control (see `ConsentRequest`)
+        #[serde(default)]
+        requires_consent: bool,
     },
     /// Join an existing session (Commander)
     JoinSession {
         target_client_id: String,
+        /// Take control, or only watch a session another commander is driving
+        #[serde(default)]
+        mode: JoinMode,
+    },
+    /// Session accepted; HID events must carry this session id (Commander)
+    SessionJoined {
+        session_id: Uuid,
+        target_client_id: String,
     },
     /// List available HID clients
     ListClients,
@@ -179,6 +266,72 @@ pub enum SessionControlMessage {
     SessionEnded {
         reason: String,
     },
+    /// The target is busy; the commander waits in line and is sent `SessionJoined`
+    /// when its turn comes. Sent again whenever the position changes
+    QueuePosition {
+        target_client_id: String,
+        /// 1 when next in line
+        position: usize,
+    },
+    /// Stop waiting for a busy HID client (Commander)
+    LeaveQueue,
+    /// A commander wants to take control; answer with `ConsentResponse` within
+    /// `timeout_secs`. A `SessionEnded` for the same session withdraws the request (HID Client)
+    ConsentRequest {
+        session_id: Uuid,
+        username: String,
+        timeout_secs: u64,
+    },
+    /// The local user's answer to `ConsentRequest` (HID Client)
+    ConsentResponse {
+        session_id: Uuid,
+        approved: bool,
+    },
+    /// The HID client's user is being asked to approve; `SessionJoined` follows
+    /// if they do, or for a handoff of control, `ControlChanged` (Commander)
+    AwaitingConsent {
+        target_client_id: String,
+        timeout_secs: u64,
+    },
+    /// Ask the commander in control to hand control over (Observer)
+    RequestControl,
+    /// A participant asks for control; answer with `GrantControl` or `DenyControl` (Commander in control)
+    ControlRequested {
+        participant_id: String,
+        username: String,
+    },
+    /// Hand control to a participant that requested it (Commander in control)
+    GrantControl {
+        participant_id: String,
+    },
+    /// Turn down a participant's request for control (Commander in control)
+    DenyControl {
+        participant_id: String,
+    },
+    /// The request for control was turned down
+    ControlDenied {
+        reason: String,
+    },
+    /// Take control back from whoever holds it (the commander that started the session)
+    RevokeControl,
+    /// Another commander now controls the session; sent to every participant
+    ControlChanged {
+        /// Username of the commander now in control
+        controller: String,
+        /// Whether the recipient is the one now in control
+        in_control: bool,
+    },
+}
+
+/// How a commander takes part in a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinMode {
+    /// Drive the HID client; a session has exactly one controlling commander
+    #[default]
+    Control,
+    /// Receive a mirror of the session's HID events and status without injecting any
+    Observe,
 }
 
 /// Information about a connected HID client
@@ -189,6 +342,9 @@ pub struct ClientInfo {
     pub platform: String,
     pub connected_at: DateTime<Utc>,
     pub commander_connected: bool,
+    /// Usernames of the observers watching the client's session
+    #[serde(default)]
+    pub observers: Vec<String>,
 }
 
 /// Status and health messages
@@ -234,6 +390,15 @@ impl Message {
         )
     }
     
+    /// Create an authentication message
+    pub fn auth(auth: AuthMessage) -> Self {
+        Self::new(
+            MessageType::Auth,
+            None,
+            MessagePayload::Auth(auth),
+        )
+    }
+    
     /// Create a HID event message
     pub fn hid_event(session_id: Uuid, event: HidEvent) -> Self {
         Self::new(
@@ -332,15 +497,18 @@ mod tests {
             token: Some("jwt_token_here".to_string()),
             expires_at: Some(Utc::now()),
             error_message: None,
+            error_code: None,
+            refresh_token: Some("refresh_token_here".to_string()),
         };
         
         let json = serde_json::to_string(&response).unwrap();
         let deserialized: AuthMessage = serde_json::from_str(&json).unwrap();
         
         match deserialized {
-            AuthMessage::Response { success, token, .. } => {
+            AuthMessage::Response { success, token, refresh_token, .. } => {
                 assert!(success);
                 assert_eq!(token, Some("jwt_token_here".to_string()));
+                assert_eq!(refresh_token, Some("refresh_token_here".to_string()));
             }
             _ => panic!("Wrong auth message type"),
         }
@@ -429,11 +597,13 @@ mod tests {
         let create = SessionControlMessage::CreateSession {
             client_id: "test_client".to_string(),
             client_name: Some("Test Client".to_string()),
+            platform: None,
+            requires_consent: false,
         };
         let json = serde_json::to_string(&create).unwrap();
         let deserialized: SessionControlMessage = serde_json::from_str(&json).unwrap();
         match deserialized {
-            SessionControlMessage::CreateSession { client_id, client_name } => {
+            SessionControlMessage::CreateSession { client_id, client_name, .. } => {
                 assert_eq!(client_id, "test_client");
                 assert_eq!(client_name, Some("Test Client".to_string()));
             }
@@ -443,11 +613,34 @@ mod tests {
         // Test JoinSession
         let join = SessionControlMessage::JoinSession {
             target_client_id: "target123".to_string(),
+            mode: JoinMode::Observe,
         };
         let json = serde_json::to_string(&join).unwrap();
+        assert!(json.contains(r#""mode":"observe""#));
+        let deserialized: SessionControlMessage = serde_json::from_str(&json).unwrap();
+        match deserialized {
+            SessionControlMessage::JoinSession { target_client_id, mode } => {
+                assert_eq!(target_client_id, "target123");
+                assert_eq!(mode, JoinMode::Observe);
+            }
+            _ => panic!("Wrong session control message type"),
+        }
+        
+        // Joins from older commanders carry no mode and take control
+        let legacy: SessionControlMessage = serde_json::from_str(r#"{"action":"JoinSession","target_client_id":"target123"}"#).unwrap();
+        assert!(matches!(legacy, SessionControlMessage::JoinSession { mode: JoinMode::Control, .. }));
+        
+        // Test SessionJoined
+        let session_id = Uuid::new_v4();
+        let joined = SessionControlMessage::SessionJoined {
+            session_id,
+            target_client_id: "target123".to_string(),
+        };
+        let json = serde_json::to_string(&joined).unwrap();
         let deserialized: SessionControlMessage = serde_json::from_str(&json).unwrap();
         match deserialized {
-            SessionControlMessage::JoinSession { target_client_id } => {
+            SessionControlMessage::SessionJoined { session_id: id, target_client_id } => {
+                assert_eq!(id, session_id);
                 assert_eq!(target_client_id, "target123");
             }
             _ => panic!("Wrong session control message type"),
@@ -461,6 +654,7 @@ mod tests {
                 platform: "macOS".to_string(),
                 connected_at: Utc::now(),
                 commander_connected: false,
+                observers: Vec::new(),
             },
             ClientInfo {
                 client_id: "client2".to_string(),
@@ -468,6 +662,7 @@ mod tests {
                 platform: "Windows".to_string(),
                 connected_at: Utc::now(),
                 commander_connected: true,
+                observers: vec!["support".to_string()],
             },
         ];
         
@@ -482,9 +677,21 @@ mod tests {
                 assert_eq!(deserialized_clients[0].client_id, "client1");
                 assert_eq!(deserialized_clients[1].platform, "Windows");
                 assert!(deserialized_clients[1].commander_connected);
+                assert_eq!(deserialized_clients[1].observers, ["support"]);
             }
             _ => panic!("Wrong session control message type"),
         }
+        
+        // Control handoff messages are not mistaken for auth messages of similar names
+        for control in [
+            SessionControlMessage::RequestControl,
+            SessionControlMessage::RevokeControl,
+            SessionControlMessage::GrantControl { participant_id: "p1".to_string() },
+        ] {
+            let json = serde_json::to_string(&Message::session_control(Some(session_id), control)).unwrap();
+            let deserialized: Message = serde_json::from_str(&json).unwrap();
+            assert!(matches!(deserialized.payload, MessagePayload::SessionControl(_)), "{}", json);
+        }
     }
     
     #[test]
@@ -620,6 +827,12 @@ mod tests {
         }
     }
     
+    #[test]
+    fn test_client_type_display() {
+        assert_eq!(ClientType::HidClient.to_string(), "HidClient");
+        assert_eq!(ClientType::Commander.to_string(), "Commander");
+    }
+    
     #[test]
     fn test_message_timestamp() {
         let before = Utc::now();
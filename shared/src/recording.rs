@@ -0,0 +1,231 @@
+//! Session recordings: the messages the session server forwarded in a session.
+//!
+//! A recording is one or more segment files per session, each holding JSON
+//! lines. The first line is a [`RecordingHeader`] naming the format and its
+//! version; every further line is a [`RecordedMessage`]. Segments are only
+//! ever appended to, and a new one is started once a segment grows too large.
+//!
+//! Version 1 recordings hold only what was forwarded to the HID client; since
+//! version 2, the session's control messages are recorded too, along with who
+//! each message was sent to.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    path::Path,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::protocol::Message;
+
+/// Value of [`RecordingHeader::format`]
+pub const RECORDING_FORMAT: &str = "remote-hid-recording";
+/// Version written by this build; readers refuse newer ones
+pub const RECORDING_VERSION: u32 = 2;
+/// Extension of recording segment files
+pub const RECORDING_EXTENSION: &str = "jsonl";
+
+/// First line of every segment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub format: String,
+    pub version: u32,
+    pub session_id: Uuid,
+    pub hid_client_id: String,
+    /// 0 for the first segment of a session, counting up as segments rotate
+    pub segment: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl RecordingHeader {
+    pub fn new(session_id: Uuid, hid_client_id: String, segment: u32) -> Self {
+        Self {
+            format: RECORDING_FORMAT.to_string(),
+            version: RECORDING_VERSION,
+            session_id,
+            hid_client_id,
+            segment,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// A message the server sent in a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// When the server received it from the commander, or sent it if the server wrote it
+    pub received_at: DateTime<Utc>,
+    /// Connection id of the commander in control
+    pub commander_id: String,
+    pub username: String,
+    /// The HID client id and participant connection ids it was sent to; empty in
+    /// version 1 recordings, where everything went to the HID client
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sent_to: Vec<String>,
+    pub message: Message,
+}
+
+impl RecordedMessage {
+    /// A message sent now while `commander_id`, logged in as `username`, is in control
+    pub fn new(commander_id: &str, username: &str, sent_to: Vec<String>, message: &Message) -> Self {
+        Self {
+            received_at: Utc::now(),
+            commander_id: commander_id.to_string(),
+            username: username.to_string(),
+            sent_to,
+            message: message.clone(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RecordingError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {source}")]
+    Malformed { line: usize, source: serde_json::Error },
+    #[error("not a session recording")]
+    NotARecording,
+    #[error("recording format version {0} is newer than this reader supports ({RECORDING_VERSION})")]
+    UnsupportedVersion(u32),
+}
+
+/// File name of a segment: `<session id>-<segment>.jsonl`
+pub fn recording_file_name(session_id: Uuid, segment: u32) -> String {
+    format!("{}-{:04}.{}", session_id, segment, RECORDING_EXTENSION)
+}
+
+/// Session and segment of a file named by [`recording_file_name`]
+pub fn parse_recording_file_name(name: &str) -> Option<(Uuid, u32)> {
+    let stem = name.strip_suffix(RECORDING_EXTENSION)?.strip_suffix('.')?;
+    let (session_id, segment) = stem.rsplit_once('-')?;
+    Some((session_id.parse().ok()?, segment.parse().ok()?))
+}
+
+/// Reads the messages of one segment, after checking its header
+pub struct RecordingReader {
+    header: RecordingHeader,
+    lines: Lines<BufReader<File>>,
+    line: usize,
+}
+
+impl RecordingReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let first = lines.next().ok_or(RecordingError::NotARecording)??;
+        let header: RecordingHeader = serde_json::from_str(&first).map_err(|_| RecordingError::NotARecording)?;
+        if header.format != RECORDING_FORMAT {
+            return Err(RecordingError::NotARecording);
+        }
+        if header.version > RECORDING_VERSION {
+            return Err(RecordingError::UnsupportedVersion(header.version));
+        }
+        Ok(Self { header, lines, line: 1 })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = Result<RecordedMessage, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            // A crash can leave the last line cut short; blank lines carry nothing
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(serde_json::from_str(&line).map_err(|source| RecordingError::Malformed { line: self.line, source }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HidEvent, MessagePayload};
+    use std::{fs, io::Write, path::PathBuf};
+
+    fn temp_file() -> (PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("remote-hid-recording-{}.jsonl", Uuid::new_v4()));
+        let file = File::create(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_file_names_round_trip() {
+        let session_id = Uuid::new_v4();
+        let name = recording_file_name(session_id, 12);
+        assert_eq!(name, format!("{}-0012.jsonl", session_id));
+        assert_eq!(parse_recording_file_name(&name), Some((session_id, 12)));
+        assert_eq!(parse_recording_file_name("notes.jsonl"), None);
+        assert_eq!(parse_recording_file_name(&format!("{}-0001.txt", session_id)), None);
+    }
+
+    #[test]
+    fn test_reader_checks_header_and_yields_messages() {
+        let session_id = Uuid::new_v4();
+        let (path, mut file) = temp_file();
+        let header = RecordingHeader::new(session_id, "client1".to_string(), 0);
+        let message = Message::hid_event(session_id, HidEvent::MouseMove { x: 3, y: 4, absolute: false });
+        let recorded = RecordedMessage::new("127.0.0.1:5000", "alice", vec!["client1".to_string()], &message);
+        writeln!(file, "{}", serde_json::to_string(&header).unwrap()).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&recorded).unwrap()).unwrap();
+        writeln!(file).unwrap();
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.header(), &header);
+        let message = reader.next().unwrap().unwrap();
+        assert_eq!(message.username, "alice");
+        assert_eq!(message.sent_to, ["client1"]);
+        assert!(matches!(message.message.payload, MessagePayload::HidEvent(HidEvent::MouseMove { x: 3, y: 4, .. })));
+        assert!(reader.next().is_none());
+
+        // A cut-off line is reported with its line number
+        write!(file, "{{\"received_at\":").unwrap();
+        let mut reader = RecordingReader::open(&path).unwrap();
+        reader.next().unwrap().unwrap();
+        assert!(matches!(reader.next(), Some(Err(RecordingError::Malformed { line: 4, .. }))));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_reader_accepts_version_1() {
+        let session_id = Uuid::new_v4();
+        let (path, mut file) = temp_file();
+        let header = RecordingHeader { version: 1, ..RecordingHeader::new(session_id, "client1".to_string(), 0) };
+        writeln!(file, "{}", serde_json::to_string(&header).unwrap()).unwrap();
+        let message = serde_json::to_value(Message::hid_event(session_id, HidEvent::MouseMove { x: 3, y: 4, absolute: false })).unwrap();
+        let line = serde_json::json!({ "received_at": Utc::now(), "commander_id": "127.0.0.1:5000", "username": "alice", "message": message });
+        writeln!(file, "{}", line).unwrap();
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.header().version, 1);
+        assert!(reader.next().unwrap().unwrap().sent_to.is_empty());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_reader_refuses_other_files_and_newer_versions() {
+        let (path, mut file) = temp_file();
+        writeln!(file, "{{\"hello\":\"world\"}}").unwrap();
+        assert!(matches!(RecordingReader::open(&path), Err(RecordingError::NotARecording)));
+        fs::remove_file(&path).ok();
+
+        let (path, mut file) = temp_file();
+        let header = RecordingHeader { version: RECORDING_VERSION + 1, ..RecordingHeader::new(Uuid::new_v4(), "client1".to_string(), 0) };
+        writeln!(file, "{}", serde_json::to_string(&header).unwrap()).unwrap();
+        assert!(matches!(RecordingReader::open(&path), Err(RecordingError::UnsupportedVersion(_))));
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,284 @@
+//! SCRAM-SHA-256 style challenge/response login (after RFC 5802 and RFC 7677).
+//!
+//! The server stores only salted, derived keys; the client proves it knows the
+//! password and the server proves it knows the stored keys, without the
+//! password itself ever crossing the socket.
+
+use std::num::NonZeroU32;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{digest, hmac, pbkdf2, rand::{SecureRandom, SystemRandom}};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthError;
+use crate::protocol::{AuthMessage, ClientType};
+
+/// PBKDF2 iterations for newly derived credentials, after OWASP's recommendation for PBKDF2-HMAC-SHA256
+pub const DEFAULT_SCRAM_ITERATIONS: u32 = 600_000;
+/// Fewest iterations a client will compute for a server's challenge (RFC 7677's minimum)
+pub const MIN_SCRAM_ITERATIONS: u32 = 4096;
+/// Most iterations a client will compute for a server's challenge
+pub const MAX_SCRAM_ITERATIONS: u32 = 1_000_000;
+
+/// What the server stores for a user instead of the password
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScramCredentials {
+    /// Base64 encoded salt
+    pub salt: String,
+    pub iterations: u32,
+    /// Base64 encoded `H(ClientKey)`
+    pub stored_key: String,
+    /// Base64 encoded `ServerKey`
+    pub server_key: String,
+}
+
+impl ScramCredentials {
+    /// Derive credentials for `password` with a fresh random salt
+    pub fn new(password: &str, iterations: u32) -> Self {
+        let salt = random_bytes::<16>();
+        let salted_password = salted_password(password, &salt, iterations);
+        Self::from_salted_password(&salt, iterations, &salted_password)
+    }
+
+    /// Stand-in credentials for a user that does not exist or cannot log in.
+    ///
+    /// The salt is stable per username so the challenge looks the same as for a
+    /// real user on every attempt, but no proof will ever match. `iterations`
+    /// should be what real users' keys are derived with.
+    pub fn unknown_user(username: &str, server_secret: &[u8], iterations: u32) -> Self {
+        let salt = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, server_secret), username.as_bytes());
+        Self {
+            salt: STANDARD.encode(&salt.as_ref()[..16]),
+            iterations,
+            stored_key: STANDARD.encode(random_bytes::<32>()),
+            server_key: STANDARD.encode(random_bytes::<32>()),
+        }
+    }
+
+    fn from_salted_password(salt: &[u8], iterations: u32, salted_password: &[u8]) -> Self {
+        let client_key = hmac_sha256(salted_password, b"Client Key");
+        Self {
+            salt: STANDARD.encode(salt),
+            iterations,
+            stored_key: STANDARD.encode(digest::digest(&digest::SHA256, &client_key)),
+            server_key: STANDARD.encode(hmac_sha256(salted_password, b"Server Key")),
+        }
+    }
+}
+
+/// Server side of one challenge/response exchange
+pub struct ScramServer {
+    username: String,
+    client_nonce: String,
+    nonce: String,
+    credentials: ScramCredentials,
+}
+
+impl ScramServer {
+    /// Begin an exchange for the client's `ScramStart`
+    pub fn new(username: &str, client_nonce: &str, credentials: ScramCredentials) -> Self {
+        Self {
+            username: username.to_string(),
+            client_nonce: client_nonce.to_string(),
+            nonce: format!("{}{}", client_nonce, STANDARD.encode(random_bytes::<18>())),
+            credentials,
+        }
+    }
+
+    /// The `ScramChallenge` to send to the client
+    pub fn challenge(&self) -> AuthMessage {
+        AuthMessage::ScramChallenge {
+            nonce: self.nonce.clone(),
+            salt: self.credentials.salt.clone(),
+            iterations: self.credentials.iterations,
+        }
+    }
+
+    /// Check the client's proof; on success returns the base64 server signature for `ScramServerFinal`
+    pub fn verify(&self, nonce: &str, proof: &str) -> Result<String, AuthError> {
+        if nonce != self.nonce {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let proof = STANDARD.decode(proof).map_err(|_| AuthError::InvalidCredentials)?;
+        let stored_key = decode_key(&self.credentials.stored_key)?;
+        let server_key = decode_key(&self.credentials.server_key)?;
+
+        let auth_message = auth_message(&self.username, &self.client_nonce, &self.nonce, &self.credentials.salt, self.credentials.iterations);
+        let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let client_key: Vec<u8> = proof.iter().zip(&client_signature).map(|(a, b)| a ^ b).collect();
+        if !constant_time_eq(digest::digest(&digest::SHA256, &client_key).as_ref(), &stored_key) {
+            return Err(AuthError::InvalidCredentials);
+        }
+        Ok(STANDARD.encode(hmac_sha256(&server_key, auth_message.as_bytes())))
+    }
+}
+
+/// Client side of one challenge/response exchange
+pub struct ScramClient {
+    username: String,
+    password: String,
+    client_nonce: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl ScramClient {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            password: password.to_string(),
+            client_nonce: STANDARD.encode(random_bytes::<18>()),
+            server_signature: None,
+        }
+    }
+
+    /// The `ScramStart` that opens the exchange
+    pub fn start(&self, client_type: ClientType, client_id: Option<String>) -> AuthMessage {
+        AuthMessage::ScramStart {
+            username: self.username.clone(),
+            client_nonce: self.client_nonce.clone(),
+            client_type,
+            client_id,
+        }
+    }
+
+    /// Answer the server's `ScramChallenge` with a `ScramProof`
+    pub fn respond(&mut self, nonce: &str, salt: &str, iterations: u32) -> Result<AuthMessage, AuthError> {
+        // The server must extend our nonce, not replace it
+        if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len() {
+            return Err(AuthError::InvalidToken);
+        }
+        // A spoofed server could ask for a proof that is cheap to crack offline,
+        // or for one that takes forever to compute
+        if !(MIN_SCRAM_ITERATIONS..=MAX_SCRAM_ITERATIONS).contains(&iterations) {
+            return Err(AuthError::UnacceptableIterations(iterations));
+        }
+        let salt_bytes = STANDARD.decode(salt).map_err(|_| AuthError::InvalidToken)?;
+        let salted_password = salted_password(&self.password, &salt_bytes, iterations);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = digest::digest(&digest::SHA256, &client_key);
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        let auth_message = auth_message(&self.username, &self.client_nonce, nonce, salt, iterations);
+        let client_signature = hmac_sha256(stored_key.as_ref(), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(&client_signature).map(|(a, b)| a ^ b).collect();
+        self.server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes()));
+
+        Ok(AuthMessage::ScramProof {
+            nonce: nonce.to_string(),
+            proof: STANDARD.encode(proof),
+        })
+    }
+
+    /// Whether the server's `ScramServerFinal` proves it holds this user's credentials
+    pub fn verify_server(&self, server_signature: &str) -> bool {
+        match (&self.server_signature, STANDARD.decode(server_signature)) {
+            (Some(expected), Ok(actual)) => constant_time_eq(expected, &actual),
+            _ => false,
+        }
+    }
+}
+
+/// The transcript both sides sign, in the layout of RFC 5802's `AuthMessage`
+fn auth_message(username: &str, client_nonce: &str, nonce: &str, salt: &str, iterations: u32) -> String {
+    format!("n={},r={},r={},s={},i={},c=biws,r={}", username, client_nonce, nonce, salt, iterations, nonce)
+}
+
+fn salted_password(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let iterations = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN);
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut out);
+    out
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>, AuthError> {
+    STANDARD.decode(key).map_err(|_| AuthError::InvalidCredentials)
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes).expect("system random number generator failed");
+    bytes
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(stored_password: &str, attempted_password: &str) -> (Result<String, AuthError>, ScramClient) {
+        let credentials = ScramCredentials::new(stored_password, MIN_SCRAM_ITERATIONS);
+        let mut client = ScramClient::new("testuser", attempted_password);
+        let AuthMessage::ScramStart { username, client_nonce, .. } = client.start(ClientType::Commander, None) else {
+            panic!("expected ScramStart");
+        };
+        let server = ScramServer::new(&username, &client_nonce, credentials);
+        let AuthMessage::ScramChallenge { nonce, salt, iterations } = server.challenge() else {
+            panic!("expected ScramChallenge");
+        };
+        let AuthMessage::ScramProof { nonce, proof } = client.respond(&nonce, &salt, iterations).unwrap() else {
+            panic!("expected ScramProof");
+        };
+        (server.verify(&nonce, &proof), client)
+    }
+
+    #[test]
+    fn test_exchange_with_correct_password() {
+        let (result, client) = exchange("password123", "password123");
+        let server_signature = result.unwrap();
+        assert!(client.verify_server(&server_signature));
+        assert!(!client.verify_server(&STANDARD.encode([0u8; 32])));
+    }
+
+    #[test]
+    fn test_exchange_with_wrong_password() {
+        let (result, _) = exchange("password123", "wrong_password");
+        assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+    }
+
+    #[test]
+    fn test_unknown_user_never_verifies() {
+        let secret = b"server secret";
+        let credentials = ScramCredentials::unknown_user("ghost", secret, MIN_SCRAM_ITERATIONS);
+        assert_eq!(credentials.salt, ScramCredentials::unknown_user("ghost", secret, MIN_SCRAM_ITERATIONS).salt);
+        assert_ne!(credentials.salt, ScramCredentials::unknown_user("other", secret, MIN_SCRAM_ITERATIONS).salt);
+
+        let mut client = ScramClient::new("ghost", "guess");
+        let server = ScramServer::new("ghost", &client.client_nonce.clone(), credentials);
+        let AuthMessage::ScramChallenge { nonce, salt, iterations } = server.challenge() else {
+            panic!("expected ScramChallenge");
+        };
+        let AuthMessage::ScramProof { nonce, proof } = client.respond(&nonce, &salt, iterations).unwrap() else {
+            panic!("expected ScramProof");
+        };
+        assert!(server.verify(&nonce, &proof).is_err());
+    }
+
+    #[test]
+    fn test_client_rejects_nonce_not_extending_its_own() {
+        let mut client = ScramClient::new("testuser", "password123");
+        let salt = STANDARD.encode([1u8; 16]);
+        assert!(client.respond("attacker-chosen-nonce", &salt, MIN_SCRAM_ITERATIONS).is_err());
+        let own = client.client_nonce.clone();
+        assert!(client.respond(&own, &salt, MIN_SCRAM_ITERATIONS).is_err());
+    }
+
+    #[test]
+    fn test_client_rejects_unsafe_iteration_counts() {
+        let mut client = ScramClient::new("testuser", "password123");
+        let salt = STANDARD.encode([1u8; 16]);
+        let nonce = format!("{}server", client.client_nonce);
+        for iterations in [0, 1, MIN_SCRAM_ITERATIONS - 1, MAX_SCRAM_ITERATIONS + 1, u32::MAX] {
+            assert!(matches!(client.respond(&nonce, &salt, iterations), Err(AuthError::UnacceptableIterations(i)) if i == iterations));
+        }
+        assert!(client.respond(&nonce, &salt, MIN_SCRAM_ITERATIONS).is_ok());
+    }
+}
@@ -0,0 +1,20 @@
+//! Reading the terminal from async code.
+
+use std::io::BufRead;
+use tokio::sync::mpsc;
+
+/// Lines typed on stdin. Read on a plain thread, as a blocking task would keep
+/// the runtime from shutting down while it waits for input. Only one reader
+/// should be started per process, since each would take lines from the other.
+pub fn stdin_lines() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
@@ -29,6 +29,8 @@ mod integration_tests {
                 SessionControlMessage::CreateSession {
                     client_id: "hid-client-1".to_string(),
                     client_name: Some("Test Machine".to_string()),
+                    platform: None,
+                    requires_consent: false,
                 }
             ),
         ];
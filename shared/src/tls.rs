@@ -0,0 +1,160 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+use simple_asn1::{from_der, oid, ASN1Block};
+
+use crate::error::{RemoteHidError, Result};
+
+/// Load every certificate from a PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = open(path)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| config_error(path, e))?;
+    if certs.is_empty() {
+        return Err(config_error(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key (PKCS#8, PKCS#1 or SEC1) from a PEM file
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = open(path)?;
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(|e| config_error(path, e))?
+        .ok_or_else(|| config_error(path, "no private key found"))
+}
+
+/// Build a root store from a PEM bundle of CA certificates
+pub fn load_root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| config_error(path, e))?;
+    }
+    Ok(roots)
+}
+
+/// Client TLS settings that trust only the CAs in `ca_bundle`
+pub fn client_config(ca_bundle: &Path) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(load_root_store(ca_bundle)?)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Client TLS settings that present a client certificate for mutual TLS.
+/// Servers are verified against `ca_bundle`, or the public web PKI roots when it is `None`.
+pub fn client_config_with_identity(ca_bundle: Option<&Path>, cert_path: &Path, key_path: &Path) -> Result<Arc<ClientConfig>> {
+    let roots = match ca_bundle {
+        Some(path) => load_root_store(path)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+        .map_err(|e| config_error(key_path, e))?;
+    Ok(Arc::new(config))
+}
+
+/// TLS settings for a client's `wss://` connections, or `None` to use the defaults.
+/// `client_cert` is a certificate and key path pair for mutual TLS.
+pub fn connector_config(ca_bundle: Option<&Path>, client_cert: Option<(&Path, &Path)>) -> Result<Option<Arc<ClientConfig>>> {
+    match (ca_bundle, client_cert) {
+        (ca_bundle, Some((cert_path, key_path))) => client_config_with_identity(ca_bundle, cert_path, key_path).map(Some),
+        (Some(ca_bundle), None) => client_config(ca_bundle).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Common name (CN) from a certificate's subject, if it has one
+pub fn certificate_common_name(cert: &CertificateDer) -> Option<String> {
+    let blocks = from_der(cert.as_ref()).ok()?;
+    let Some(ASN1Block::Sequence(_, certificate)) = blocks.first() else {
+        return None;
+    };
+    let Some(ASN1Block::Sequence(_, tbs)) = certificate.first() else {
+        return None;
+    };
+    // The version field is an optional explicitly tagged [0] before the serial number
+    let fields = match tbs.first() {
+        Some(ASN1Block::Explicit(..)) => &tbs[1..],
+        _ => &tbs[..],
+    };
+    // serialNumber, signature, issuer, validity, subject
+    let Some(ASN1Block::Sequence(_, subject)) = fields.get(4) else {
+        return None;
+    };
+    let common_name = oid!(2, 5, 4, 3);
+    subject.iter().find_map(|rdn| {
+        let ASN1Block::Set(_, attributes) = rdn else {
+            return None;
+        };
+        attributes.iter().find_map(|attribute| {
+            let ASN1Block::Sequence(_, pair) = attribute else {
+                return None;
+            };
+            match pair.as_slice() {
+                [ASN1Block::ObjectIdentifier(_, id), ASN1Block::UTF8String(_, name)
+                | ASN1Block::PrintableString(_, name)
+                | ASN1Block::IA5String(_, name)
+                | ASN1Block::TeletexString(_, name)] if *id == common_name => Some(name.clone()),
+                _ => None,
+            }
+        })
+    })
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| config_error(path, e))
+}
+
+fn config_error(path: &Path, error: impl std::fmt::Display) -> RemoteHidError {
+    RemoteHidError::Configuration(format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_missing_file_is_configuration_error() {
+        let err = load_certs(Path::new("/nonexistent/ca.pem")).unwrap_err();
+        assert!(matches!(err, RemoteHidError::Configuration(_)));
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+    }
+    
+    #[test]
+    fn test_file_without_pem_blocks_rejected() {
+        let path = std::env::temp_dir().join(format!("remote-hid-empty-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        
+        assert!(load_certs(&path).is_err());
+        assert!(load_private_key(&path).is_err());
+        assert!(client_config(&path).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+    
+    #[test]
+    fn test_common_name_read_from_subject() {
+        let mut params = rcgen::CertificateParams::new(vec!["kiosk-1.example.com".to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::OrganizationName, "Example");
+        params.distinguished_name.push(rcgen::DnType::CommonName, "kiosk-1");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = CertificateDer::from(cert.serialize_der().unwrap());
+        assert_eq!(certificate_common_name(&der).as_deref(), Some("kiosk-1"));
+        
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = CertificateDer::from(cert.serialize_der().unwrap());
+        assert_eq!(certificate_common_name(&der), None);
+        assert_eq!(certificate_common_name(&CertificateDer::from(vec![1, 2, 3])), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! Time-based one-time passwords (RFC 6238), the second factor for commander logins.
+//!
+//! Secrets are exchanged as unpadded base32, the format authenticator apps
+//! expect in `otpauth://` URIs.
+
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use ring::hmac;
+
+use crate::scram::{constant_time_eq, random_bytes};
+
+/// Digits in a code
+pub const TOTP_DIGITS: u32 = 6;
+/// Seconds each code is valid for
+pub const TOTP_PERIOD_SECS: i64 = 30;
+/// Periods either side of the current one that are still accepted, for clock drift
+const TOTP_SKEW_PERIODS: i64 = 1;
+
+/// New random 160-bit secret, base32 encoded
+pub fn generate_totp_secret() -> String {
+    BASE32_NOPAD.encode(&random_bytes::<20>())
+}
+
+/// Code for the period starting at `counter * TOTP_PERIOD_SECS`, or `None` for a malformed secret
+pub fn totp_code(secret: &str, counter: u64) -> Option<String> {
+    let key = decode_secret(secret)?;
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &key), &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    // Dynamic truncation (RFC 4226 section 5.3)
+    let offset = (tag[tag.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([tag[offset] & 0x7f, tag[offset + 1], tag[offset + 2], tag[offset + 3]]);
+    Some(format!("{:0width$}", value % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize))
+}
+
+/// Check `code` against the periods around `now`.
+///
+/// Returns the matching counter, which must be later than `last_counter` so a
+/// code cannot be replayed.
+pub fn verify_totp(secret: &str, code: &str, now: DateTime<Utc>, last_counter: Option<u64>) -> Option<u64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let current = now.timestamp().div_euclid(TOTP_PERIOD_SECS);
+    (current - TOTP_SKEW_PERIODS..=current + TOTP_SKEW_PERIODS)
+        .filter_map(|counter| u64::try_from(counter).ok())
+        .filter(|counter| last_counter.is_none_or(|last| *counter > last))
+        .find(|counter| totp_code(secret, *counter).is_some_and(|expected| constant_time_eq(expected.as_bytes(), code.as_bytes())))
+}
+
+/// `otpauth://` URI for enrolling the secret in an authenticator app
+pub fn totp_uri(secret: &str, issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_PERIOD_SECS}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+    )
+}
+
+fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    let normalized: String = secret.chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    BASE32_NOPAD.decode(normalized.as_bytes()).ok().filter(|key| !key.is_empty())
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // RFC 6238 appendix B, SHA-1 key "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The RFC lists 8 digit codes; 6 digit codes are their last six digits
+        assert_eq!(totp_code(RFC_SECRET, 59 / 30).as_deref(), Some("287082"));
+        assert_eq!(totp_code(RFC_SECRET, 1111111109 / 30).as_deref(), Some("081804"));
+        assert_eq!(totp_code(RFC_SECRET, 2000000000 / 30).as_deref(), Some("279037"));
+        assert_eq!(totp_code("not base32!", 1), None);
+    }
+
+    #[test]
+    fn test_verify_accepts_drift_and_rejects_replay() {
+        let now = Utc.timestamp_opt(1111111109, 0).unwrap();
+        let counter = 1111111109 / 30;
+        let previous = totp_code(RFC_SECRET, counter as u64 - 1).unwrap();
+
+        assert_eq!(verify_totp(RFC_SECRET, "081 804", now, None), Some(counter as u64));
+        assert_eq!(verify_totp(RFC_SECRET, &previous, now, None), Some(counter as u64 - 1));
+        assert_eq!(verify_totp(RFC_SECRET, "081804", now, Some(counter as u64)), None);
+        assert_eq!(verify_totp(RFC_SECRET, "000000", now, None), None);
+
+        let later = Utc.timestamp_opt(1111111109 + 120, 0).unwrap();
+        assert_eq!(verify_totp(RFC_SECRET, "081804", later, None), None);
+    }
+
+    #[test]
+    fn test_generated_secret_round_trips() {
+        let secret = generate_totp_secret();
+        assert_eq!(secret.len(), 32);
+        assert!(totp_code(&secret, 0).is_some());
+        assert!(totp_code(&secret.to_lowercase(), 0).is_some());
+        assert!(totp_uri(&secret, "Remote HID", "alice@example").starts_with("otpauth://totp/Remote%20HID:alice%40example?secret="));
+    }
+}
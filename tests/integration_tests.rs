@@ -20,6 +20,8 @@ fn test_full_message_protocol_compatibility() {
         SessionControlMessage::CreateSession {
             client_id: "integration_test_client".to_string(),
             client_name: Some("Integration Test HID Client".to_string()),
+            platform: None,
+            requires_consent: false,
         }
     );
     
@@ -32,6 +34,7 @@ fn test_full_message_protocol_compatibility() {
         None,
         SessionControlMessage::JoinSession {
             target_client_id: "integration_test_client".to_string(),
+            mode: JoinMode::Control,
         }
     );
     
@@ -287,6 +290,8 @@ fn test_session_lifecycle_flow() {
         SessionControlMessage::CreateSession {
             client_id: client_id.clone(),
             client_name: Some("Lifecycle Test Client".to_string()),
+            platform: None,
+            requires_consent: false,
         }
     );
     
@@ -298,6 +303,7 @@ fn test_session_lifecycle_flow() {
         None,
         SessionControlMessage::JoinSession {
             target_client_id: client_id.clone(),
+            mode: JoinMode::Control,
         }
     );
     
@@ -401,6 +407,8 @@ fn test_concurrent_session_handling() {
             SessionControlMessage::CreateSession {
                 client_id: client_id.to_string(),
                 client_name: Some(format!("Test Client {}", i + 1)),
+                platform: None,
+                requires_consent: false,
             }
         );
         